@@ -0,0 +1,725 @@
+use crate::crypto::Crypto;
+use crate::error::Error;
+use crate::storage::{MemoryStorage, PasswordHistoryEntry, SqliteStorage, Storage, VaultItem};
+use crate::Result;
+use chrono::{DateTime, Utc};
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Cursor, Read, Seek, Write};
+use std::path::{Component, Path, PathBuf};
+use uuid::Uuid;
+use walkdir::WalkDir;
+use zeroize::Zeroizing;
+use zip::write::{FileOptions, ZipWriter};
+use zip::ZipArchive;
+
+mod schedule;
+mod sealed;
+
+pub use self::schedule::{
+    apply_retention, backup_before_migration, backup_before_operation, backup_now, expired_backups, list_backups, record_write, remove_migration_backups, run_scheduled_backup, take_backup, verify_backup,
+    verify_backups, BackupCheck, BackupInfo, BackupSchedule, BackupTrigger, RetentionPolicy,
+};
+pub use self::sealed::{generate_key_pair, BackupIdentity, BackupKeyPair, BackupRecipient};
+
+/// Files every vault archive must contain, as written by `export_encrypted_vault`.
+const REQUIRED_FILES: [&str; 3] = ["vault.db", "salt", "verify"];
+const DATA_DIR: &str = "data";
+const MANIFEST_FILE: &str = "manifest.json";
+const MANIFEST_VERSION: u32 = 1;
+const SYMLINK_MODE: u32 = 0o120000;
+const FILE_TYPE_MASK: u32 = 0o170000;
+const COPY_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Lists every file of an archive with its SHA-256, so a restore can tell a
+/// damaged or tampered archive from a good one.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Manifest {
+    pub version: u32,
+    pub created_at: DateTime<Utc>,
+    pub entries: Vec<ManifestEntry>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct ManifestEntry {
+    /// Path inside the archive, always `/`-separated.
+    pub path: String,
+    pub size: u64,
+    /// Lowercase hex SHA-256 of the entry's contents.
+    pub sha256: String,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct ArchiveProgress {
+    pub files_done: usize,
+    pub files_total: usize,
+    pub bytes_done: u64,
+    pub bytes_total: u64,
+}
+
+/// The directory of a vault kept on disk. Archives are made from and restored
+/// into that directory, so vaults without one cannot be archived.
+pub fn vault_directory(storage: &dyn Storage) -> Result<&Path> {
+    storage
+        .get_vault_path()
+        .ok_or_else(|| Error::InvalidInput("This vault is not kept on disk and cannot be archived".to_string()))
+}
+
+/// Streams the vault directory into a zip at `destination`, reporting progress
+/// after each file. The archive is written next to the destination first and
+/// only moved into place once complete.
+pub fn export_archive(vault_path: &Path, destination: &Path, on_progress: impl FnMut(&ArchiveProgress)) -> Result<Manifest> {
+    let partial = sibling_path(destination, "partial");
+
+    let written = File::create(&partial)
+        .map_err(Error::from)
+        .and_then(|file| write_archive(vault_path, BufWriter::new(file), on_progress))
+        .and_then(|(writer, manifest)| {
+            writer.into_inner().map_err(|e| Error::Io(e.to_string()))?.sync_all()?;
+            Ok(manifest)
+        })
+        .and_then(|manifest| {
+            fs::rename(&partial, destination)?;
+            Ok(manifest)
+        });
+    if written.is_err() {
+        if let Err(e) = fs::remove_file(&partial) {
+            warn!("Failed to remove partial backup {}: {}", partial.display(), e);
+        }
+    }
+    written
+}
+
+/// Like [`export_archive`], but writes a `.fetchbak` sealed for `recipients`
+/// so the backup cannot be opened with the master key alone.
+pub fn export_sealed_archive(
+    vault_path: &Path,
+    destination: &Path,
+    recipients: &[BackupRecipient],
+    on_progress: impl FnMut(&ArchiveProgress),
+) -> Result<Manifest> {
+    let archive = sibling_path(destination, "archive.partial");
+    let partial = sibling_path(destination, "partial");
+
+    let sealed = export_archive(vault_path, &archive, on_progress).and_then(|manifest| {
+        let mut reader = BufReader::new(File::open(&archive)?);
+        let mut writer = BufWriter::new(File::create(&partial)?);
+        sealed::seal(&mut reader, &mut writer, recipients)?;
+        writer.into_inner().map_err(|e| Error::Io(e.to_string()))?.sync_all()?;
+        fs::rename(&partial, destination)?;
+        Ok(manifest)
+    });
+
+    for leftover in [&archive, &partial] {
+        if leftover.exists() {
+            if let Err(e) = fs::remove_file(leftover) {
+                warn!("Failed to remove {}: {}", leftover.display(), e);
+            }
+        }
+    }
+    sealed
+}
+
+/// Reads a backup for restoring or merging, opening it with `identity` first
+/// if it is a sealed `.fetchbak`.
+pub fn read_backup(path: &Path, identity: Option<&BackupIdentity>) -> Result<Vec<u8>> {
+    let data = fs::read(path)?;
+    if !sealed::is_sealed(&data) {
+        return Ok(data);
+    }
+
+    let identity = identity.ok_or_else(|| Error::InvalidInput("This backup is sealed. Enter its backup passphrase or private key.".into()))?;
+    let mut archive = Vec::new();
+    sealed::open(&mut data.as_slice(), &mut archive, identity)?;
+    Ok(archive)
+}
+
+/// Zips the vault's top-level files and `data/` into `writer`, one file at a
+/// time, and appends a manifest of their checksums.
+pub fn write_archive<W: Write + Seek>(vault_path: &Path, writer: W, mut on_progress: impl FnMut(&ArchiveProgress)) -> Result<(W, Manifest)> {
+    let files = archive_files(vault_path)?;
+    let mut progress = ArchiveProgress {
+        files_done: 0,
+        files_total: files.len(),
+        bytes_done: 0,
+        bytes_total: files.iter().map(|(_, size)| size).sum(),
+    };
+    on_progress(&progress);
+
+    let mut zip = ZipWriter::new(writer);
+    let options = FileOptions::default().compression_method(zip::CompressionMethod::Stored).large_file(true);
+    let mut entries = Vec::with_capacity(files.len());
+    zip.add_directory(DATA_DIR, options)?;
+    for (path, _) in files {
+        zip.start_file(path.as_str(), options)?;
+        let mut file = File::open(vault_path.join(&path))?;
+        let (size, sha256) = copy_hashed(&mut file, &mut zip)?;
+        entries.push(ManifestEntry { path, size, sha256 });
+
+        progress.files_done += 1;
+        progress.bytes_done += size;
+        on_progress(&progress);
+    }
+
+    let manifest = Manifest { version: MANIFEST_VERSION, created_at: Utc::now(), entries };
+    zip.start_file(MANIFEST_FILE, options)?;
+    zip.write_all(&serde_json::to_vec_pretty(&manifest)?)?;
+    let writer = zip.finish()?;
+
+    info!("Wrote vault archive with {} files.", manifest.entries.len());
+    Ok((writer, manifest))
+}
+
+/// The files that make up a vault, as archive paths with their sizes.
+fn archive_files(vault_path: &Path) -> Result<Vec<(String, u64)>> {
+    let mut files = Vec::new();
+    for entry in WalkDir::new(vault_path).min_depth(1).max_depth(2).sort_by_file_name() {
+        let entry = entry.map_err(io::Error::from)?;
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let relative = entry.path().strip_prefix(vault_path).map_err(|e| Error::Internal(e.to_string()))?;
+        let parts: Vec<&str> = relative.iter().filter_map(|part| part.to_str()).collect();
+        match parts.as_slice() {
+            [_] | [DATA_DIR, _] => files.push((parts.join("/"), entry.metadata().map_err(io::Error::from)?.len())),
+            _ => debug!("Leaving {} out of the vault archive", relative.display()),
+        }
+    }
+    Ok(files)
+}
+
+/// Copies `reader` into `writer`, returning the byte count and lowercase hex SHA-256.
+fn copy_hashed(reader: &mut impl Read, writer: &mut impl Write) -> Result<(u64, String)> {
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; COPY_BUFFER_SIZE];
+    let mut size = 0u64;
+    loop {
+        let read = reader.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+        writer.write_all(&buffer[..read])?;
+        size += read as u64;
+    }
+    Ok((size, format!("{:x}", hasher.finalize())))
+}
+
+/// Extracts a vault archive into `dest`. Only top-level files and files directly
+/// under `data/` are accepted; absolute paths, `..` components and symlinks are
+/// rejected so a crafted archive cannot write outside `dest`. Archives with a
+/// manifest must match it exactly.
+pub fn extract_archive(data: &[u8], dest: &Path) -> Result<()> {
+    let mut archive = ZipArchive::new(Cursor::new(data))?;
+    fs::create_dir_all(dest.join(DATA_DIR))?;
+
+    let mut manifest: Option<Manifest> = None;
+    let mut extracted = HashMap::new();
+    for index in 0..archive.len() {
+        let mut entry = archive.by_index(index)?;
+        let relative = checked_entry_path(entry.name(), entry.enclosed_name())?;
+        if entry.unix_mode().is_some_and(|mode| mode & FILE_TYPE_MASK == SYMLINK_MODE) {
+            return Err(Error::InvalidInput(format!("Backup contains a symbolic link: {}", entry.name())));
+        }
+
+        let target = dest.join(&relative);
+        if entry.is_dir() {
+            fs::create_dir_all(&target)?;
+            continue;
+        }
+        if relative == Path::new(MANIFEST_FILE) {
+            let mut json = Vec::new();
+            entry.read_to_end(&mut json)?;
+            manifest = Some(serde_json::from_slice(&json)?);
+            continue;
+        }
+
+        debug!("Extracting {} from backup", relative.display());
+        let mut file = File::create(&target)?;
+        extracted.insert(archive_path(&relative), copy_hashed(&mut entry, &mut file)?);
+    }
+
+    if let Some(manifest) = manifest {
+        verify_manifest(&manifest, &extracted)?;
+    }
+    for name in REQUIRED_FILES {
+        if !dest.join(name).is_file() {
+            return Err(Error::InvalidInput(format!("Backup is missing '{}'", name)));
+        }
+    }
+    Ok(())
+}
+
+/// Checks an archive against its manifest without extracting it. Archives
+/// written before manifests existed cannot be verified this way.
+pub fn verify_archive(data: &[u8]) -> Result<Manifest> {
+    let mut archive = ZipArchive::new(Cursor::new(data))?;
+
+    let mut manifest: Option<Manifest> = None;
+    let mut hashed = HashMap::new();
+    for index in 0..archive.len() {
+        let mut entry = archive.by_index(index)?;
+        let relative = checked_entry_path(entry.name(), entry.enclosed_name())?;
+        if entry.is_dir() {
+            continue;
+        }
+        if relative == Path::new(MANIFEST_FILE) {
+            let mut json = Vec::new();
+            entry.read_to_end(&mut json)?;
+            manifest = Some(serde_json::from_slice(&json)?);
+            continue;
+        }
+        hashed.insert(archive_path(&relative), copy_hashed(&mut entry, &mut io::sink())?);
+    }
+
+    let manifest = manifest.ok_or_else(|| Error::InvalidInput("Backup has no manifest to verify against".into()))?;
+    verify_manifest(&manifest, &hashed)?;
+    if let Some(name) = REQUIRED_FILES.iter().find(|name| !hashed.contains_key(**name)) {
+        return Err(Error::InvalidInput(format!("Backup is missing '{}'", name)));
+    }
+    Ok(manifest)
+}
+
+/// The `/`-separated form of a checked entry path, as listed in the manifest.
+fn archive_path(relative: &Path) -> String {
+    relative.iter().filter_map(|part| part.to_str()).collect::<Vec<_>>().join("/")
+}
+
+fn verify_manifest(manifest: &Manifest, extracted: &HashMap<String, (u64, String)>) -> Result<()> {
+    for entry in &manifest.entries {
+        match extracted.get(&entry.path) {
+            Some((size, sha256)) if *size == entry.size && *sha256 == entry.sha256 => {}
+            Some(_) => return Err(Error::InvalidInput(format!("Backup entry '{}' does not match its checksum", entry.path))),
+            None => return Err(Error::InvalidInput(format!("Backup is missing '{}'", entry.path))),
+        }
+    }
+    if extracted.len() != manifest.entries.len() {
+        let listed: HashSet<&str> = manifest.entries.iter().map(|entry| entry.path.as_str()).collect();
+        if let Some(extra) = extracted.keys().find(|path| !listed.contains(path.as_str())) {
+            return Err(Error::InvalidInput(format!("Backup entry '{}' is not listed in its manifest", extra)));
+        }
+    }
+    debug!("Backup matches its manifest of {} entries.", manifest.entries.len());
+    Ok(())
+}
+
+fn checked_entry_path(name: &str, enclosed: Option<&Path>) -> Result<PathBuf> {
+    let unsafe_path = || Error::InvalidInput(format!("Backup contains an unsafe path: {}", name));
+    let path = enclosed.ok_or_else(unsafe_path)?;
+    let components: Vec<&str> = path
+        .components()
+        .map(|component| match component {
+            Component::Normal(part) => part.to_str().ok_or_else(unsafe_path),
+            _ => Err(unsafe_path()),
+        })
+        .collect::<Result<_>>()?;
+
+    match components.as_slice() {
+        [_] | [DATA_DIR, _] => Ok(path.to_path_buf()),
+        _ => Err(Error::InvalidInput(format!("Unexpected entry in backup: {}", name))),
+    }
+}
+
+/// Derives the key for `storage` from the master key and checks it against the
+/// vault's verification token. Returns a crypto unlocked for that vault.
+pub fn unlock_storage(storage: &dyn Storage, master_key: &str) -> Result<(Crypto, Zeroizing<Vec<u8>>)> {
+    let salt = storage.get_salt()?;
+    let strength = storage.get_key_derivation_strength()?;
+    let verification_token = storage.get_verification_token()?;
+
+    let mut crypto = Crypto::new();
+    let key = Zeroizing::new(crypto.derive_key(master_key, &salt, strength)?);
+    crypto.unlock(&key)?;
+    if crypto.decrypt(&verification_token).is_err() {
+        return Err(Error::InvalidMasterKey);
+    }
+    Ok((crypto, key))
+}
+
+/// Replaces the vault behind `storage` with the one in `data`. The archive is
+/// extracted next to the live vault and must open with `master_key` before
+/// anything is touched; the previous vault is kept until the new one is in
+/// place. On success `crypto` is unlocked with the restored vault's key.
+pub fn restore_archive(storage: &mut Box<dyn Storage>, crypto: &mut Crypto, data: &[u8], master_key: &str) -> Result<()> {
+    let vault_path = vault_directory(storage.as_ref())?.to_path_buf();
+    let staging = sibling_path(&vault_path, "restore");
+    let previous = sibling_path(&vault_path, "previous");
+
+    remove_dir_if_exists(&staging)?;
+    let staged = (|| -> Result<Zeroizing<Vec<u8>>> {
+        extract_archive(data, &staging)?;
+        let staged = SqliteStorage::new_staged(staging.clone())?;
+        let (staged_crypto, key) = unlock_storage(&staged, master_key)?;
+        // Reading every row proves the database was encrypted with this key.
+        staged.get_all_items_recursive(&staged_crypto)?;
+        Ok(key)
+    })();
+    let key = match staged {
+        Ok(key) => key,
+        Err(e) => {
+            error!("Backup rejected: {}", e);
+            if let Err(remove_err) = fs::remove_dir_all(&staging) {
+                error!("Failed to remove staged backup {}: {}", staging.display(), remove_err);
+            }
+            return Err(e);
+        }
+    };
+
+    remove_dir_if_exists(&previous)?;
+    // Windows cannot rename the vault directory while its database is open, so
+    // the old storage is closed for the swap and reopened if the swap fails.
+    drop(std::mem::replace(storage, Box::new(MemoryStorage::new())));
+    if let Err(e) = fs::rename(&vault_path, &previous) {
+        error!("Failed to move the previous vault aside: {}", e);
+        *storage = Box::new(SqliteStorage::new(vault_path)?);
+        return Err(e.into());
+    }
+    if let Err(e) = fs::rename(&staging, &vault_path) {
+        error!("Failed to move restored vault into place: {}", e);
+        fs::rename(&previous, &vault_path)?;
+        *storage = Box::new(SqliteStorage::new(vault_path)?);
+        return Err(e.into());
+    }
+
+    match SqliteStorage::new(vault_path.clone()) {
+        Ok(restored) => *storage = Box::new(restored),
+        Err(e) => {
+            error!("Failed to open restored vault, putting the previous one back: {}", e);
+            fs::rename(&vault_path, &staging)?;
+            fs::rename(&previous, &vault_path)?;
+            *storage = Box::new(SqliteStorage::new(vault_path)?);
+            return Err(e);
+        }
+    }
+    crypto.unlock(&key)?;
+
+    if let Err(e) = fs::remove_dir_all(&previous) {
+        error!("Failed to remove previous vault {}: {}", previous.display(), e);
+    }
+    info!("Vault restored from backup.");
+    Ok(())
+}
+
+/// What to do with a merged item that has the same id as an existing item, or
+/// the same name under the same folder.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    /// Keep the existing item and leave the incoming one out.
+    #[default]
+    Skip,
+    /// Import the incoming item under a fresh id and a free name.
+    Rename,
+    /// Replace the existing item, and anything stored under it, with the incoming one.
+    Overwrite,
+}
+
+#[derive(Debug, Serialize, Deserialize, Default, Clone)]
+pub struct MergeResult {
+    pub added_count: usize,
+    pub renamed_count: usize,
+    pub overwritten_count: usize,
+    pub skipped_count: usize,
+}
+
+/// Copies the items of another vault archive, unlocked with its own master
+/// key, into `storage` under `parent_id`. Contents and password history are
+/// re-encrypted with `crypto`. Folders that already exist by name are merged;
+/// other conflicts are settled by `policy`. Everything is written in one
+/// transaction.
+pub fn merge_archive(
+    storage: &dyn Storage,
+    crypto: &Crypto,
+    data: &[u8],
+    master_key: &str,
+    parent_id: Option<String>,
+    policy: ConflictPolicy,
+) -> Result<MergeResult> {
+    let staging = sibling_path(vault_directory(storage)?, "merge");
+    remove_dir_if_exists(&staging)?;
+
+    let result = (|| -> Result<MergeResult> {
+        extract_archive(data, &staging)?;
+        let source = SqliteStorage::new_staged(staging.clone())?;
+        let (source_crypto, _) = unlock_storage(&source, master_key)?;
+        VaultMerge::new(storage, crypto, policy)?.merge(&source, &source_crypto, parent_id)
+    })();
+
+    if let Err(e) = fs::remove_dir_all(&staging) {
+        error!("Failed to remove extracted archive {}: {}", staging.display(), e);
+    }
+    result
+}
+
+struct VaultMerge<'a> {
+    storage: &'a dyn Storage,
+    crypto: &'a Crypto,
+    policy: ConflictPolicy,
+    existing: HashMap<String, VaultItem>,
+    /// Existing items by parent, name and whether they are folders.
+    by_name: HashMap<(Option<String>, String, bool), String>,
+    /// Every name in use under each parent, including merged items.
+    taken_names: HashSet<(Option<String>, String)>,
+    batch: Vec<(VaultItem, Option<Vec<u8>>)>,
+    password_history: Vec<PasswordHistoryEntry>,
+    replaced_ids: Vec<String>,
+    result: MergeResult,
+}
+
+impl<'a> VaultMerge<'a> {
+    fn new(storage: &'a dyn Storage, crypto: &'a Crypto, policy: ConflictPolicy) -> Result<Self> {
+        let existing: HashMap<String, VaultItem> = storage
+            .get_all_items_recursive(crypto)?
+            .into_iter()
+            .map(|item| (item.id.clone(), item))
+            .collect();
+        let by_name = existing
+            .values()
+            .map(|item| ((item.parent_id.clone(), item.name.clone(), item.item_type == "folder"), item.id.clone()))
+            .collect();
+        let taken_names = existing.values().map(|item| (item.parent_id.clone(), item.name.clone())).collect();
+
+        Ok(Self {
+            storage,
+            crypto,
+            policy,
+            existing,
+            by_name,
+            taken_names,
+            batch: Vec::new(),
+            password_history: Vec::new(),
+            replaced_ids: Vec::new(),
+            result: MergeResult::default(),
+        })
+    }
+
+    fn merge(mut self, source: &dyn Storage, source_crypto: &Crypto, parent_id: Option<String>) -> Result<MergeResult> {
+        let items = source.get_all_items_recursive(source_crypto)?;
+        let ids: HashSet<&str> = items.iter().map(|item| item.id.as_str()).collect();
+        let mut children: HashMap<Option<&str>, Vec<&VaultItem>> = HashMap::new();
+        for item in &items {
+            let parent = item.parent_id.as_deref().filter(|parent| ids.contains(parent));
+            children.entry(parent).or_default().push(item);
+        }
+
+        let mut pending: Vec<(&VaultItem, Option<String>)> = children
+            .remove(&None)
+            .unwrap_or_default()
+            .into_iter()
+            .map(|item| (item, parent_id.clone()))
+            .collect();
+        while let Some((item, parent_id)) = pending.pop() {
+            let Some(new_id) = self.merge_item(source, source_crypto, item, parent_id)? else { continue };
+            for &child in children.get(&Some(item.id.as_str())).into_iter().flatten() {
+                pending.push((child, Some(new_id.clone())));
+            }
+        }
+
+        self.storage.replace_items_atomically(&self.replaced_ids, &self.batch, &self.password_history, self.crypto)?;
+        info!(
+            "Merged vault archive: {} added, {} renamed, {} overwritten, {} skipped.",
+            self.result.added_count, self.result.renamed_count, self.result.overwritten_count, self.result.skipped_count
+        );
+        Ok(self.result)
+    }
+
+    /// Queues one item and returns the id its children should hang under, or
+    /// `None` if it was skipped.
+    fn merge_item(&mut self, source: &dyn Storage, source_crypto: &Crypto, item: &VaultItem, parent_id: Option<String>) -> Result<Option<String>> {
+        let is_folder = item.item_type == "folder";
+        // A folder and an item of the same name do not conflict.
+        let name_key = (parent_id.clone(), item.name.clone(), is_folder);
+        let same_name = self.by_name.get(&name_key).and_then(|id| self.existing.get(id));
+
+        if let (true, Some(folder)) = (is_folder, same_name) {
+            debug!("Merging folder {} into existing folder {}", item.name, folder.id);
+            return Ok(Some(folder.id.clone()));
+        }
+
+        let same_id = self.existing.contains_key(&item.id);
+        let mut merged = item.clone();
+        merged.parent_id = parent_id;
+        if same_id || same_name.is_some() {
+            match self.policy {
+                ConflictPolicy::Skip => {
+                    debug!("Skipping {} from archive, it conflicts with an existing item", item.name);
+                    self.result.skipped_count += 1;
+                    return Ok(None);
+                }
+                ConflictPolicy::Rename => {
+                    if same_id {
+                        merged.id = Uuid::new_v4().to_string();
+                    }
+                    if same_name.is_some() {
+                        merged.name = self.free_name(&merged.parent_id, &item.name);
+                    }
+                    self.result.renamed_count += 1;
+                }
+                ConflictPolicy::Overwrite => {
+                    let replaced_id = if same_id { item.id.clone() } else { self.by_name[&name_key].clone() };
+                    self.forget_existing(&replaced_id);
+                    self.replaced_ids.push(replaced_id);
+                    self.result.overwritten_count += 1;
+                }
+            }
+        } else {
+            self.result.added_count += 1;
+        }
+
+        let content = if item.data_path.is_empty() {
+            None
+        } else {
+            merged.data_path = Uuid::new_v4().to_string();
+            Some(source.read_encrypted_file(&item.data_path, source_crypto)?)
+        };
+        for entry in source.get_password_history(&item.id, source_crypto)? {
+            self.password_history.push(PasswordHistoryEntry {
+                id: Uuid::new_v4().to_string(),
+                item_id: merged.id.clone(),
+                ..entry
+            });
+        }
+
+        self.taken_names.insert((merged.parent_id.clone(), merged.name.clone()));
+        if is_folder {
+            // Later folders of the same name from the archive merge into this one.
+            self.by_name.insert((merged.parent_id.clone(), merged.name.clone(), true), merged.id.clone());
+            self.existing.insert(merged.id.clone(), merged.clone());
+        }
+        let new_id = merged.id.clone();
+        self.batch.push((merged, content));
+        Ok(Some(new_id))
+    }
+
+    /// Drops an item being replaced, and everything under it, from the conflict maps.
+    fn forget_existing(&mut self, id: &str) {
+        let mut queue = vec![id.to_string()];
+        while let Some(current) = queue.pop() {
+            if let Some(item) = self.existing.remove(&current) {
+                self.by_name.remove(&(item.parent_id.clone(), item.name.clone(), item.item_type == "folder"));
+                self.taken_names.remove(&(item.parent_id, item.name));
+            }
+            queue.extend(self.existing.values().filter(|item| item.parent_id.as_deref() == Some(current.as_str())).map(|item| item.id.clone()));
+        }
+    }
+
+    fn free_name(&self, parent_id: &Option<String>, name: &str) -> String {
+        (2..)
+            .map(|n| format!("{} ({})", name, n))
+            .find(|candidate| !self.taken_names.contains(&(parent_id.clone(), candidate.clone())))
+            .unwrap_or_else(|| name.to_string())
+    }
+}
+
+/// `<path>.<suffix>` in the same directory, so renames stay on one filesystem.
+fn sibling_path(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".");
+    name.push(suffix);
+    path.with_file_name(name)
+}
+
+fn remove_dir_if_exists(path: &Path) -> Result<()> {
+    match fs::remove_dir_all(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VAULT_FILES: [(&str, &[u8]); 4] = [("vault.db", b"database"), ("salt", b"salt"), ("verify", b"token"), ("data/abc", b"content")];
+
+    fn zip_of(entries: &[(&str, &[u8])], manifest: Option<&Manifest>, build: impl FnOnce(&mut ZipWriter<Cursor<Vec<u8>>>)) -> Vec<u8> {
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, contents) in entries {
+            zip.start_file(*name, FileOptions::default()).unwrap();
+            zip.write_all(contents).unwrap();
+        }
+        if let Some(manifest) = manifest {
+            zip.start_file(MANIFEST_FILE, FileOptions::default()).unwrap();
+            zip.write_all(&serde_json::to_vec(manifest).unwrap()).unwrap();
+        }
+        build(&mut zip);
+        zip.finish().unwrap().into_inner()
+    }
+
+    fn manifest_of(entries: &[(&str, &[u8])]) -> Manifest {
+        Manifest {
+            version: MANIFEST_VERSION,
+            created_at: Utc::now(),
+            entries: entries
+                .iter()
+                .map(|(path, contents)| ManifestEntry { path: path.to_string(), size: contents.len() as u64, sha256: format!("{:x}", Sha256::digest(contents)) })
+                .collect(),
+        }
+    }
+
+    /// Extracts `data` into a fresh directory, which is removed again afterwards.
+    fn extract(data: &[u8]) -> Result<()> {
+        let dest = std::env::temp_dir().join(format!("fetch-extract-{}", Uuid::new_v4()));
+        let result = extract_archive(data, &dest);
+        remove_dir_if_exists(&dest).unwrap();
+        result
+    }
+
+    fn with_extra_file(name: &str) -> Vec<u8> {
+        zip_of(&VAULT_FILES, None, |zip| {
+            zip.start_file(name, FileOptions::default()).unwrap();
+            zip.write_all(b"payload").unwrap();
+        })
+    }
+
+    fn assert_rejected(data: &[u8], message: &str) {
+        match extract(data) {
+            Err(Error::InvalidInput(error)) => assert!(error.contains(message), "unexpected error: {}", error),
+            other => panic!("expected the archive to be rejected, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn extracts_an_archive_that_matches_its_manifest() {
+        let data = zip_of(&VAULT_FILES, Some(&manifest_of(&VAULT_FILES)), |_| {});
+        extract(&data).unwrap();
+        assert_eq!(verify_archive(&data).unwrap().entries.len(), VAULT_FILES.len());
+    }
+
+    #[test]
+    fn rejects_paths_outside_the_vault() {
+        assert_rejected(&with_extra_file("../x"), "unsafe path");
+        assert_rejected(&with_extra_file("data/../../x"), "unsafe path");
+        assert_rejected(&with_extra_file("/etc/x"), "unsafe path");
+    }
+
+    #[test]
+    fn rejects_nested_data_entries() {
+        assert_rejected(&with_extra_file("data/a/b"), "Unexpected entry");
+    }
+
+    #[test]
+    fn rejects_symbolic_links() {
+        let data = zip_of(&VAULT_FILES, None, |zip| zip.add_symlink("data/link", "/etc/passwd", FileOptions::default()).unwrap());
+        assert_rejected(&data, "symbolic link");
+    }
+
+    #[test]
+    fn rejects_entries_that_do_not_match_the_manifest() {
+        let mut manifest = manifest_of(&VAULT_FILES);
+        manifest.entries[3].sha256 = format!("{:x}", Sha256::digest(b"other"));
+        let data = zip_of(&VAULT_FILES, Some(&manifest), |_| {});
+        assert_rejected(&data, "does not match its checksum");
+        assert!(verify_archive(&data).is_err());
+
+        let unlisted = zip_of(&VAULT_FILES, Some(&manifest_of(&VAULT_FILES[..3])), |_| {});
+        assert_rejected(&unlisted, "not listed in its manifest");
+    }
+
+    #[test]
+    fn rejects_archives_without_the_vault_files() {
+        assert_rejected(&zip_of(&VAULT_FILES[1..], None, |_| {}), "missing 'vault.db'");
+    }
+}
@@ -0,0 +1,406 @@
+//! Automatic backups into a directory of the user's choosing, taken on a
+//! schedule, every so many writes and right before operations that rewrite
+//! much of the vault. Old backups are pruned grandfather-father-son style:
+//! the newest backup of each of the last few days, weeks and months is kept.
+
+use super::{export_archive, vault_directory, verify_archive, Manifest};
+use crate::error::Error;
+use crate::storage::Storage;
+use crate::Result;
+use chrono::{DateTime, Datelike, Duration, NaiveDateTime, SubsecRound, TimeZone, Utc};
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::collections::HashSet;
+use std::fs;
+use std::hash::Hash;
+use std::io;
+use std::path::{Path, PathBuf};
+
+const FILE_PREFIX: &str = "fetch-backup-";
+const FILE_EXTENSION: &str = ".zip";
+const TIMESTAMP_FORMAT: &str = "%Y%m%dT%H%M%S%3fZ";
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct BackupSchedule {
+    pub enabled: bool,
+    pub directory: Option<PathBuf>,
+    /// Hours between scheduled backups, `0` to only back up on the other triggers.
+    pub interval_hours: u32,
+    /// Writes after which a backup is taken, `0` to not count writes.
+    pub every_writes: u32,
+    pub before_risky_operations: bool,
+    pub retention: RetentionPolicy,
+}
+
+impl Default for BackupSchedule {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            directory: None,
+            interval_hours: 24,
+            every_writes: 50,
+            before_risky_operations: true,
+            retention: RetentionPolicy::default(),
+        }
+    }
+}
+
+impl BackupSchedule {
+    /// The directory to back up into, if automatic backups are on.
+    pub fn active_directory(&self) -> Option<&Path> {
+        self.directory.as_deref().filter(|_| self.enabled)
+    }
+
+    /// Checks that backups would land somewhere usable: an absolute directory
+    /// outside the vault, which is created if it does not exist yet.
+    pub fn validate(&self, vault_path: &Path) -> Result<()> {
+        let Some(directory) = &self.directory else {
+            return if self.enabled {
+                Err(Error::InvalidInput("Choose a directory for automatic backups".into()))
+            } else {
+                Ok(())
+            };
+        };
+        if !directory.is_absolute() {
+            return Err(Error::InvalidInput("The backup directory must be an absolute path".into()));
+        }
+        fs::create_dir_all(directory)?;
+        if fs::canonicalize(directory)?.starts_with(fs::canonicalize(vault_path)?) {
+            return Err(Error::InvalidInput("The backup directory cannot be inside the vault".into()));
+        }
+        Ok(())
+    }
+}
+
+/// How many daily, weekly and monthly backups to keep. The newest backup is
+/// always kept, even when every count is zero.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(default)]
+pub struct RetentionPolicy {
+    pub daily: u32,
+    pub weekly: u32,
+    pub monthly: u32,
+}
+
+impl Default for RetentionPolicy {
+    fn default() -> Self {
+        Self { daily: 7, weekly: 4, monthly: 12 }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum BackupTrigger {
+    Scheduled,
+    Writes,
+    BeforeOperation,
+    BeforeMigration,
+}
+
+impl BackupTrigger {
+    fn as_str(self) -> &'static str {
+        match self {
+            BackupTrigger::Scheduled => "scheduled",
+            BackupTrigger::Writes => "writes",
+            BackupTrigger::BeforeOperation => "before-operation",
+            BackupTrigger::BeforeMigration => "before-migration",
+        }
+    }
+
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "scheduled" => Some(BackupTrigger::Scheduled),
+            "writes" => Some(BackupTrigger::Writes),
+            "before-operation" => Some(BackupTrigger::BeforeOperation),
+            "before-migration" => Some(BackupTrigger::BeforeMigration),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct BackupInfo {
+    pub file_name: String,
+    pub path: PathBuf,
+    pub created_at: DateTime<Utc>,
+    pub trigger: BackupTrigger,
+    pub size: u64,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct BackupCheck {
+    pub backup: BackupInfo,
+    pub valid: bool,
+    /// Why the backup failed verification, when it did.
+    pub error: Option<String>,
+}
+
+/// Writes a backup of the vault into `directory`, named after its creation time
+/// and trigger so the directory can be listed without opening every archive.
+pub fn take_backup(vault_path: &Path, directory: &Path, trigger: BackupTrigger) -> Result<BackupInfo> {
+    fs::create_dir_all(directory)?;
+    // Listing reads the time back from the name, which keeps milliseconds.
+    let created_at = Utc::now().trunc_subsecs(3);
+    let file_name = format!("{}{}-{}{}", FILE_PREFIX, created_at.format(TIMESTAMP_FORMAT), trigger.as_str(), FILE_EXTENSION);
+    let path = directory.join(&file_name);
+
+    export_archive(vault_path, &path, |_| {})?;
+    let size = fs::metadata(&path)?.len();
+    info!("Took {} backup {}", trigger.as_str(), path.display());
+    Ok(BackupInfo { file_name, path, created_at, trigger, size })
+}
+
+/// The automatic backups in `directory`, newest first. Other files are ignored.
+pub fn list_backups(directory: &Path) -> Result<Vec<BackupInfo>> {
+    let entries = match fs::read_dir(directory) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut backups = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        let file_name = entry.file_name().to_string_lossy().into_owned();
+        let Some((created_at, trigger)) = parse_file_name(&file_name) else { continue };
+        let metadata = entry.metadata()?;
+        if !metadata.is_file() {
+            continue;
+        }
+        backups.push(BackupInfo { file_name, path: entry.path(), created_at, trigger, size: metadata.len() });
+    }
+    backups.sort_by_key(|backup| Reverse(backup.created_at));
+    Ok(backups)
+}
+
+fn parse_file_name(file_name: &str) -> Option<(DateTime<Utc>, BackupTrigger)> {
+    let stem = file_name.strip_prefix(FILE_PREFIX)?.strip_suffix(FILE_EXTENSION)?;
+    let (timestamp, trigger) = stem.split_once('-')?;
+    let created_at = Utc.from_utc_datetime(&NaiveDateTime::parse_from_str(timestamp, TIMESTAMP_FORMAT).ok()?);
+    Some((created_at, BackupTrigger::parse(trigger)?))
+}
+
+/// Checks a backup against the manifest written with it.
+pub fn verify_backup(path: &Path) -> Result<Manifest> {
+    verify_archive(&fs::read(path)?)
+}
+
+/// Verifies every backup in `directory`, newest first.
+pub fn verify_backups(directory: &Path) -> Result<Vec<BackupCheck>> {
+    let checks = list_backups(directory)?
+        .into_iter()
+        .map(|backup| match verify_backup(&backup.path) {
+            Ok(_) => BackupCheck { backup, valid: true, error: None },
+            Err(e) => {
+                warn!("Backup {} failed verification: {}", backup.file_name, e);
+                BackupCheck { backup, valid: false, error: Some(e.to_string()) }
+            }
+        })
+        .collect();
+    Ok(checks)
+}
+
+/// The backups `policy` no longer keeps. `backups` must be newest first.
+pub fn expired_backups<'a>(backups: &'a [BackupInfo], policy: &RetentionPolicy) -> Vec<&'a BackupInfo> {
+    let mut kept: HashSet<usize> = HashSet::new();
+    if !backups.is_empty() {
+        kept.insert(0);
+    }
+    keep_newest_per_period(backups, policy.daily, |date| date.date_naive(), &mut kept);
+    keep_newest_per_period(backups, policy.weekly, |date| date.iso_week(), &mut kept);
+    keep_newest_per_period(backups, policy.monthly, |date| (date.year(), date.month()), &mut kept);
+
+    backups.iter().enumerate().filter(|(index, _)| !kept.contains(index)).map(|(_, backup)| backup).collect()
+}
+
+/// Marks the newest backup of each of the last `periods` periods that have one.
+fn keep_newest_per_period<K: Eq + Hash>(backups: &[BackupInfo], periods: u32, period_of: impl Fn(&DateTime<Utc>) -> K, kept: &mut HashSet<usize>) {
+    let mut seen = HashSet::new();
+    for (index, backup) in backups.iter().enumerate() {
+        if seen.len() >= periods as usize {
+            break;
+        }
+        if seen.insert(period_of(&backup.created_at)) {
+            kept.insert(index);
+        }
+    }
+}
+
+/// Deletes the backups in `directory` that `policy` no longer keeps and
+/// returns them.
+pub fn apply_retention(directory: &Path, policy: &RetentionPolicy) -> Result<Vec<BackupInfo>> {
+    let backups = list_backups(directory)?;
+    let expired: Vec<BackupInfo> = expired_backups(&backups, policy).into_iter().cloned().collect();
+    for backup in &expired {
+        debug!("Removing expired backup {}", backup.file_name);
+        fs::remove_file(&backup.path)?;
+    }
+    if !expired.is_empty() {
+        info!("Removed {} expired backups from {}", expired.len(), directory.display());
+    }
+    Ok(expired)
+}
+
+/// Backs up `storage` into the scheduled directory, resets the write counter
+/// and prunes old backups. Does nothing when automatic backups are off.
+pub fn backup_now(storage: &dyn Storage, trigger: BackupTrigger) -> Result<Option<BackupInfo>> {
+    let schedule = storage.get_backup_schedule()?;
+    let Some(directory) = schedule.active_directory() else {
+        return Ok(None);
+    };
+
+    let backup = take_backup(vault_directory(storage)?, directory, trigger)?;
+    storage.set_writes_since_backup(0)?;
+    // A backup that was written is worth reporting even if pruning fails.
+    if let Err(e) = apply_retention(directory, &schedule.retention) {
+        error!("Failed to prune old backups in {}: {}", directory.display(), e);
+    }
+    Ok(Some(backup))
+}
+
+/// Takes the scheduled backup if the newest backup is older than the interval.
+pub fn run_scheduled_backup(storage: &dyn Storage, now: DateTime<Utc>) -> Result<Option<BackupInfo>> {
+    let schedule = storage.get_backup_schedule()?;
+    let Some(directory) = schedule.active_directory() else {
+        return Ok(None);
+    };
+    if schedule.interval_hours == 0 || !storage.is_initialized() {
+        return Ok(None);
+    }
+
+    let interval = Duration::hours(schedule.interval_hours.into());
+    let due = list_backups(directory)?.first().map_or(true, |newest| now - newest.created_at >= interval);
+    if !due {
+        return Ok(None);
+    }
+    backup_now(storage, BackupTrigger::Scheduled)
+}
+
+/// Counts a write to the vault, backing up once `every_writes` have piled up.
+pub fn record_write(storage: &dyn Storage) -> Result<Option<BackupInfo>> {
+    let schedule = storage.get_backup_schedule()?;
+    if schedule.active_directory().is_none() || schedule.every_writes == 0 {
+        return Ok(None);
+    }
+
+    let writes = storage.get_writes_since_backup()?.saturating_add(1);
+    if writes < schedule.every_writes {
+        storage.set_writes_since_backup(writes)?;
+        return Ok(None);
+    }
+    backup_now(storage, BackupTrigger::Writes)
+}
+
+/// Backs up before `operation` if the schedule asks for it. An error means the
+/// operation should not go ahead.
+pub fn backup_before_operation(storage: &dyn Storage, operation: &str) -> Result<Option<BackupInfo>> {
+    if !storage.get_backup_schedule()?.before_risky_operations {
+        return Ok(None);
+    }
+    info!("Backing up the vault before {}", operation);
+    backup_now(storage, BackupTrigger::BeforeOperation)
+        .map_err(|e| Error::Internal(format!("Could not back up the vault before {}: {}", operation, e)))
+}
+
+/// Backs up before the schema is migrated. This happens whatever the schedule
+/// says: into the backup directory if one is set, otherwise next to the vault,
+/// where [`remove_migration_backups`] finds them again. Older backups in that
+/// directory are pruned by the schedule's retention either way.
+pub fn backup_before_migration(storage: &dyn Storage) -> Result<BackupInfo> {
+    let vault_path = vault_directory(storage)?;
+    let schedule = storage.get_backup_schedule().unwrap_or_default();
+    let directory = match schedule.active_directory() {
+        Some(directory) => directory.to_path_buf(),
+        None => vault_path
+            .parent()
+            .map(Path::to_path_buf)
+            .ok_or_else(|| Error::Internal("The vault directory has no parent to back up into".to_string()))?,
+    };
+    let backup = take_backup(vault_path, &directory, BackupTrigger::BeforeMigration)?;
+    if let Err(e) = apply_retention(&directory, &schedule.retention) {
+        error!("Failed to prune old backups in {}: {}", directory.display(), e);
+    }
+    Ok(backup)
+}
+
+/// Removes the backups taken next to the vault at `vault_path` before schema
+/// migrations, for when the vault itself is deleted. Backups in a chosen
+/// backup directory are left alone.
+pub fn remove_migration_backups(vault_path: &Path) -> Result<usize> {
+    let Some(directory) = vault_path.parent() else {
+        return Ok(0);
+    };
+    let backups: Vec<BackupInfo> = list_backups(directory)?.into_iter().filter(|backup| backup.trigger == BackupTrigger::BeforeMigration).collect();
+    for backup in &backups {
+        debug!("Removing migration backup {}", backup.file_name);
+        fs::remove_file(&backup.path)?;
+    }
+    if !backups.is_empty() {
+        info!("Removed {} migration backups from {}", backups.len(), directory.display());
+    }
+    Ok(backups.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Backups taken at the given RFC 3339 times, which must be newest first.
+    fn backups(times: &[&str]) -> Vec<BackupInfo> {
+        times
+            .iter()
+            .map(|time| BackupInfo {
+                file_name: time.to_string(),
+                path: PathBuf::from(time),
+                created_at: DateTime::parse_from_rfc3339(time).unwrap().with_timezone(&Utc),
+                trigger: BackupTrigger::Scheduled,
+                size: 0,
+            })
+            .collect()
+    }
+
+    fn expired(times: &[&str], daily: u32, weekly: u32, monthly: u32) -> Vec<String> {
+        let backups = backups(times);
+        expired_backups(&backups, &RetentionPolicy { daily, weekly, monthly }).into_iter().map(|backup| backup.file_name.clone()).collect()
+    }
+
+    #[test]
+    fn keeps_the_newest_backup_of_recent_days() {
+        let times = ["2024-03-10T18:00:00Z", "2024-03-10T09:00:00Z", "2024-03-09T20:00:00Z", "2024-03-09T08:00:00Z", "2024-03-08T12:00:00Z"];
+        assert_eq!(expired(&times, 2, 0, 0), ["2024-03-10T09:00:00Z", "2024-03-09T08:00:00Z", "2024-03-08T12:00:00Z"]);
+        assert_eq!(expired(&times, 3, 0, 0), ["2024-03-10T09:00:00Z", "2024-03-09T08:00:00Z"]);
+    }
+
+    #[test]
+    fn always_keeps_the_newest_backup() {
+        let times = ["2024-03-10T18:00:00Z", "2024-03-09T20:00:00Z", "2024-01-01T00:00:00Z"];
+        assert_eq!(expired(&times, 0, 0, 0), ["2024-03-09T20:00:00Z", "2024-01-01T00:00:00Z"]);
+        assert!(expired(&[], 0, 0, 0).is_empty());
+    }
+
+    #[test]
+    fn groups_weekly_backups_by_iso_week() {
+        // 2021-01-03 is a Sunday in week 53 of 2020.
+        let times = ["2021-01-04T10:00:00Z", "2021-01-03T10:00:00Z", "2020-12-31T10:00:00Z", "2020-12-27T10:00:00Z"];
+        assert_eq!(expired(&times, 0, 2, 0), ["2020-12-31T10:00:00Z", "2020-12-27T10:00:00Z"]);
+
+        // 2025-12-29 is a Monday in week 1 of 2026.
+        let times = ["2026-01-02T10:00:00Z", "2025-12-29T10:00:00Z", "2025-12-28T10:00:00Z"];
+        assert_eq!(expired(&times, 0, 2, 0), ["2025-12-29T10:00:00Z"]);
+    }
+
+    #[test]
+    fn keeps_the_newest_backup_of_recent_months() {
+        let times = ["2024-03-02T10:00:00Z", "2024-02-29T10:00:00Z", "2024-02-01T10:00:00Z", "2024-01-31T10:00:00Z", "2023-03-15T10:00:00Z"];
+        assert_eq!(expired(&times, 0, 0, 2), ["2024-02-01T10:00:00Z", "2024-01-31T10:00:00Z", "2023-03-15T10:00:00Z"]);
+        assert_eq!(expired(&times, 0, 0, 4), ["2024-02-01T10:00:00Z"]);
+    }
+
+    #[test]
+    fn keeps_backups_any_period_wants() {
+        let times = ["2024-03-10T18:00:00Z", "2024-03-10T09:00:00Z", "2024-03-04T12:00:00Z", "2024-03-01T12:00:00Z", "2024-02-20T12:00:00Z"];
+        // 2024-03-10 is a Sunday, in the same week as 2024-03-04.
+        assert_eq!(expired(&times, 1, 2, 2), ["2024-03-10T09:00:00Z", "2024-03-04T12:00:00Z"]);
+    }
+}
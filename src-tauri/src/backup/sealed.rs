@@ -0,0 +1,473 @@
+//! The `.fetchbak` format: a vault archive encrypted again under a key that is
+//! independent of the master key.
+//!
+//! ```text
+//! "FETCHBAK" | version: u8 | header length: u32 LE | header JSON | chunks
+//! ```
+//!
+//! A random file key encrypts the archive in chunks with AES-256-GCM. Each
+//! chunk's nonce is the header's 7-byte prefix, the chunk counter (u32 BE) and
+//! a flag byte set on the last chunk, so chunks cannot be reordered or the
+//! stream truncated. Every chunk is bound to the header through its SHA-256 as
+//! associated data. The header holds the file key wrapped once per recipient:
+//! under an Argon2id key for a passphrase, or under an X25519 + HKDF key for a
+//! public key.
+
+use crate::crypto;
+use crate::error::Error;
+use crate::Result;
+use aes_gcm::aead::{Aead, KeyInit, Payload};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use argon2::{Algorithm, Argon2, Version};
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use chrono::{DateTime, Utc};
+use hkdf::Hkdf;
+use log::{debug, info};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::io::{self, Read, Write};
+use x25519_dalek::{PublicKey, StaticSecret};
+use zeroize::Zeroizing;
+
+const MAGIC: &[u8; 8] = b"FETCHBAK";
+const FORMAT_VERSION: u8 = 1;
+const MAX_HEADER_LENGTH: usize = 64 * 1024;
+
+const CHUNK_SIZE: usize = 64 * 1024;
+const TAG_LENGTH: usize = 16;
+const NONCE_PREFIX_LENGTH: usize = 7;
+const KEY_LENGTH: usize = 32;
+const SALT_LENGTH: usize = 16;
+
+const PASSPHRASE_MEMORY_KIB: u32 = 64 * 1024;
+const PASSPHRASE_ITERATIONS: u32 = 3;
+const PASSPHRASE_PARALLELISM: u32 = 4;
+
+const PUBLIC_KEY_PREFIX: &str = "fetch-backup-pub:";
+const PRIVATE_KEY_PREFIX: &str = "fetch-backup-key:";
+const X25519_INFO: &[u8] = b"fetchbak x25519 v1";
+
+/// Who can open a sealed backup.
+pub enum BackupRecipient {
+    Passphrase(Zeroizing<String>),
+    PublicKey(PublicKey),
+}
+
+impl BackupRecipient {
+    /// Parses a public key as printed by [`generate_key_pair`].
+    pub fn public_key(encoded: &str) -> Result<Self> {
+        let bytes = decode_key(encoded, PUBLIC_KEY_PREFIX, "backup public key")?;
+        Ok(Self::PublicKey(PublicKey::from(*bytes)))
+    }
+}
+
+/// How to open a sealed backup: the passphrase or the private key of one of its recipients.
+pub enum BackupIdentity {
+    Passphrase(Zeroizing<String>),
+    PrivateKey(StaticSecret),
+}
+
+impl BackupIdentity {
+    pub fn private_key(encoded: &str) -> Result<Self> {
+        let bytes = decode_key(encoded, PRIVATE_KEY_PREFIX, "backup private key")?;
+        Ok(Self::PrivateKey(StaticSecret::from(*bytes)))
+    }
+}
+
+/// A key pair for sealing backups to a public key, e.g. one held in escrow.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct BackupKeyPair {
+    pub public_key: String,
+    pub private_key: String,
+}
+
+pub fn generate_key_pair() -> BackupKeyPair {
+    let secret = StaticSecret::random_from_rng(OsRng);
+    let public = PublicKey::from(&secret);
+    BackupKeyPair {
+        public_key: format!("{}{}", PUBLIC_KEY_PREFIX, STANDARD.encode(public.as_bytes())),
+        private_key: format!("{}{}", PRIVATE_KEY_PREFIX, STANDARD.encode(secret.to_bytes())),
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Header {
+    created_at: DateTime<Utc>,
+    chunk_size: usize,
+    nonce_prefix: String,
+    recipients: Vec<Stanza>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Stanza {
+    Passphrase {
+        salt: String,
+        memory_kib: u32,
+        iterations: u32,
+        parallelism: u32,
+        wrapped_key: String,
+    },
+    X25519 {
+        ephemeral_public_key: String,
+        wrapped_key: String,
+    },
+}
+
+/// Whether `data` starts like a sealed backup.
+pub fn is_sealed(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
+
+/// Encrypts everything from `reader` into `writer` for the given recipients.
+pub fn seal(reader: &mut impl Read, writer: &mut impl Write, recipients: &[BackupRecipient]) -> Result<()> {
+    if recipients.is_empty() {
+        return Err(Error::InvalidInput("A sealed backup needs a passphrase or at least one public key".into()));
+    }
+
+    let mut file_key = Zeroizing::new([0u8; KEY_LENGTH]);
+    OsRng.fill_bytes(file_key.as_mut());
+    let mut nonce_prefix = [0u8; NONCE_PREFIX_LENGTH];
+    OsRng.fill_bytes(&mut nonce_prefix);
+
+    let recipients = recipients.iter().map(|recipient| wrap_file_key(&file_key, recipient)).collect::<Result<Vec<_>>>()?;
+    let header = Header {
+        created_at: Utc::now(),
+        chunk_size: CHUNK_SIZE,
+        nonce_prefix: STANDARD.encode(nonce_prefix),
+        recipients,
+    };
+    let header_json = serde_json::to_vec(&header)?;
+    writer.write_all(MAGIC)?;
+    writer.write_all(&[FORMAT_VERSION])?;
+    writer.write_all(&(header_json.len() as u32).to_le_bytes())?;
+    writer.write_all(&header_json)?;
+
+    let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(file_key.as_ref()));
+    let aad = header_digest(&header_json);
+    let mut current = vec![0u8; CHUNK_SIZE];
+    let mut current_len = read_full(reader, &mut current)?;
+    let mut next = vec![0u8; CHUNK_SIZE];
+    let mut counter: u32 = 0;
+    loop {
+        let next_len = if current_len == CHUNK_SIZE { read_full(reader, &mut next)? } else { 0 };
+        let last = next_len == 0;
+        let nonce = chunk_nonce(&nonce_prefix, counter, last);
+        let sealed = cipher
+            .encrypt(Nonce::from_slice(&nonce), Payload { msg: &current[..current_len], aad: &aad })
+            .map_err(|e| Error::Encryption(e.to_string()))?;
+        writer.write_all(&sealed)?;
+        if last {
+            break;
+        }
+        std::mem::swap(&mut current, &mut next);
+        current_len = next_len;
+        counter = counter.checked_add(1).ok_or_else(|| Error::Encryption("Backup is too large to seal".into()))?;
+    }
+    writer.flush()?;
+
+    info!("Sealed backup in {} chunks for {} recipients.", counter + 1, header.recipients.len());
+    Ok(())
+}
+
+/// Decrypts a sealed backup from `reader` into `writer`. Fails without writing
+/// a partial chunk if any chunk has been altered, reordered or cut off.
+pub fn open(reader: &mut impl Read, writer: &mut impl Write, identity: &BackupIdentity) -> Result<()> {
+    let mut preamble = [0u8; MAGIC.len() + 1 + 4];
+    reader.read_exact(&mut preamble).map_err(|_| not_sealed())?;
+    if &preamble[..MAGIC.len()] != MAGIC {
+        return Err(not_sealed());
+    }
+    if preamble[MAGIC.len()] != FORMAT_VERSION {
+        return Err(Error::InvalidInput(format!("Unsupported backup format version {}", preamble[MAGIC.len()])));
+    }
+    let header_length = u32::from_le_bytes(preamble[MAGIC.len() + 1..].try_into().unwrap_or_default()) as usize;
+    if header_length > MAX_HEADER_LENGTH {
+        return Err(Error::InvalidInput("Backup header is too large".into()));
+    }
+    let mut header_json = vec![0u8; header_length];
+    reader.read_exact(&mut header_json)?;
+    let header: Header = serde_json::from_slice(&header_json)?;
+    let nonce_prefix: [u8; NONCE_PREFIX_LENGTH] = decode_fixed(&header.nonce_prefix, "nonce prefix")?;
+    if header.chunk_size == 0 || header.chunk_size > 16 * CHUNK_SIZE {
+        return Err(Error::InvalidInput("Invalid backup chunk size".into()));
+    }
+
+    let file_key = header
+        .recipients
+        .iter()
+        .find_map(|stanza| unwrap_file_key(stanza, identity).transpose())
+        .transpose()?
+        .ok_or_else(|| Error::Decryption("Invalid backup passphrase or key".into()))?;
+
+    let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(file_key.as_ref()));
+    let aad = header_digest(&header_json);
+    let sealed_chunk_size = header.chunk_size + TAG_LENGTH;
+    let mut current = vec![0u8; sealed_chunk_size];
+    let mut current_len = read_full(reader, &mut current)?;
+    let mut next = vec![0u8; sealed_chunk_size];
+    let mut counter: u32 = 0;
+    loop {
+        let next_len = if current_len == sealed_chunk_size { read_full(reader, &mut next)? } else { 0 };
+        let last = next_len == 0;
+        let nonce = chunk_nonce(&nonce_prefix, counter, last);
+        let chunk = Zeroizing::new(
+            cipher
+                .decrypt(Nonce::from_slice(&nonce), Payload { msg: &current[..current_len], aad: &aad })
+                .map_err(|_| Error::Decryption("Backup is damaged or has been tampered with".into()))?,
+        );
+        writer.write_all(&chunk)?;
+        if last {
+            break;
+        }
+        std::mem::swap(&mut current, &mut next);
+        current_len = next_len;
+        counter = counter.checked_add(1).ok_or_else(|| Error::Decryption("Backup has too many chunks".into()))?;
+    }
+    writer.flush()?;
+
+    debug!("Opened sealed backup of {} chunks.", counter + 1);
+    Ok(())
+}
+
+fn wrap_file_key(file_key: &[u8; KEY_LENGTH], recipient: &BackupRecipient) -> Result<Stanza> {
+    match recipient {
+        BackupRecipient::Passphrase(passphrase) => {
+            let mut salt = [0u8; SALT_LENGTH];
+            OsRng.fill_bytes(&mut salt);
+            let kek = passphrase_kek(passphrase, &salt, PASSPHRASE_MEMORY_KIB, PASSPHRASE_ITERATIONS, PASSPHRASE_PARALLELISM)?;
+            Ok(Stanza::Passphrase {
+                salt: STANDARD.encode(salt),
+                memory_kib: PASSPHRASE_MEMORY_KIB,
+                iterations: PASSPHRASE_ITERATIONS,
+                parallelism: PASSPHRASE_PARALLELISM,
+                wrapped_key: STANDARD.encode(wrap(&kek, file_key)?),
+            })
+        }
+        BackupRecipient::PublicKey(public_key) => {
+            let ephemeral = StaticSecret::random_from_rng(OsRng);
+            let ephemeral_public = PublicKey::from(&ephemeral);
+            let kek = x25519_kek(&ephemeral, public_key, &ephemeral_public, public_key)?;
+            Ok(Stanza::X25519 {
+                ephemeral_public_key: STANDARD.encode(ephemeral_public.as_bytes()),
+                wrapped_key: STANDARD.encode(wrap(&kek, file_key)?),
+            })
+        }
+    }
+}
+
+/// Returns the file key if `stanza` was written for `identity`, `None` if it is for someone else.
+fn unwrap_file_key(stanza: &Stanza, identity: &BackupIdentity) -> Result<Option<Zeroizing<[u8; KEY_LENGTH]>>> {
+    let (kek, wrapped_key) = match (stanza, identity) {
+        (Stanza::Passphrase { salt, memory_kib, iterations, parallelism, wrapped_key }, BackupIdentity::Passphrase(passphrase)) => {
+            let salt = STANDARD.decode(salt).map_err(|_| Error::InvalidInput("Invalid backup salt".into()))?;
+            (passphrase_kek(passphrase, &salt, *memory_kib, *iterations, *parallelism)?, wrapped_key)
+        }
+        (Stanza::X25519 { ephemeral_public_key, wrapped_key }, BackupIdentity::PrivateKey(secret)) => {
+            let ephemeral_public = PublicKey::from(decode_fixed::<KEY_LENGTH>(ephemeral_public_key, "ephemeral key")?);
+            (x25519_kek(secret, &ephemeral_public, &ephemeral_public, &PublicKey::from(secret))?, wrapped_key)
+        }
+        _ => return Ok(None),
+    };
+
+    let wrapped_key = STANDARD.decode(wrapped_key).map_err(|_| Error::InvalidInput("Invalid wrapped backup key".into()))?;
+    let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(kek.as_ref()));
+    let Ok(file_key) = cipher.decrypt(Nonce::from_slice(&[0u8; 12]), wrapped_key.as_slice()) else {
+        return Ok(None);
+    };
+    let file_key = Zeroizing::new(file_key);
+    let mut key = Zeroizing::new([0u8; KEY_LENGTH]);
+    if file_key.len() != KEY_LENGTH {
+        return Err(Error::InvalidInput("Invalid wrapped backup key".into()));
+    }
+    key.copy_from_slice(&file_key);
+    Ok(Some(key))
+}
+
+/// Every key-encryption key is used once, so a fixed nonce is safe here.
+fn wrap(kek: &[u8; KEY_LENGTH], file_key: &[u8; KEY_LENGTH]) -> Result<Vec<u8>> {
+    Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(kek))
+        .encrypt(Nonce::from_slice(&[0u8; 12]), file_key.as_slice())
+        .map_err(|e| Error::Encryption(e.to_string()))
+}
+
+fn passphrase_kek(passphrase: &str, salt: &[u8], memory_kib: u32, iterations: u32, parallelism: u32) -> Result<Zeroizing<[u8; KEY_LENGTH]>> {
+    // The costs come from the backup header when opening, so a crafted backup could ask for anything.
+    let params = crypto::untrusted_argon2_params(memory_kib, iterations, parallelism, KEY_LENGTH)?;
+    let mut kek = Zeroizing::new([0u8; KEY_LENGTH]);
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password_into(passphrase.as_bytes(), salt, kek.as_mut())
+        .map_err(|e| Error::KeyDerivation(e.to_string()))?;
+    Ok(kek)
+}
+
+/// `secret` is the ephemeral key when sealing and the recipient's key when
+/// opening; both sides salt HKDF with the ephemeral and recipient public keys.
+fn x25519_kek(secret: &StaticSecret, their_public: &PublicKey, ephemeral_public: &PublicKey, recipient_public: &PublicKey) -> Result<Zeroizing<[u8; KEY_LENGTH]>> {
+    let shared = secret.diffie_hellman(their_public);
+    if !shared.was_contributory() {
+        return Err(Error::KeyDerivation("Invalid backup public key".into()));
+    }
+
+    let mut salt = Vec::with_capacity(2 * KEY_LENGTH);
+    salt.extend_from_slice(ephemeral_public.as_bytes());
+    salt.extend_from_slice(recipient_public.as_bytes());
+    let mut kek = Zeroizing::new([0u8; KEY_LENGTH]);
+    Hkdf::<Sha256>::new(Some(&salt), shared.as_bytes())
+        .expand(X25519_INFO, kek.as_mut())
+        .map_err(|e| Error::KeyDerivation(e.to_string()))?;
+    Ok(kek)
+}
+
+fn chunk_nonce(prefix: &[u8; NONCE_PREFIX_LENGTH], counter: u32, last: bool) -> [u8; 12] {
+    let mut nonce = [0u8; 12];
+    nonce[..NONCE_PREFIX_LENGTH].copy_from_slice(prefix);
+    nonce[NONCE_PREFIX_LENGTH..11].copy_from_slice(&counter.to_be_bytes());
+    nonce[11] = u8::from(last);
+    nonce
+}
+
+fn header_digest(header_json: &[u8]) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(MAGIC);
+    hasher.update([FORMAT_VERSION]);
+    hasher.update(header_json);
+    hasher.finalize().to_vec()
+}
+
+/// Fills `buffer` as far as the reader allows, returning how much was read.
+fn read_full(reader: &mut impl Read, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match reader.read(&mut buffer[filled..]) {
+            Ok(0) => break,
+            Ok(read) => filled += read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+fn decode_key(encoded: &str, prefix: &str, what: &str) -> Result<Zeroizing<[u8; KEY_LENGTH]>> {
+    let encoded = encoded.trim();
+    let encoded = encoded.strip_prefix(prefix).unwrap_or(encoded);
+    Ok(Zeroizing::new(decode_fixed(encoded, what)?))
+}
+
+fn decode_fixed<const N: usize>(encoded: &str, what: &str) -> Result<[u8; N]> {
+    let bytes = Zeroizing::new(STANDARD.decode(encoded.trim()).map_err(|_| Error::InvalidInput(format!("Invalid {}", what)))?);
+    bytes.as_slice().try_into().map_err(|_| Error::InvalidInput(format!("Invalid {}", what)))
+}
+
+fn not_sealed() -> Error {
+    Error::InvalidInput("Not a sealed Fetch backup".into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    fn seal_to_key(data: &[u8]) -> (Vec<u8>, BackupIdentity) {
+        let pair = generate_key_pair();
+        let mut sealed = Vec::new();
+        seal(&mut &data[..], &mut sealed, &[BackupRecipient::public_key(&pair.public_key).unwrap()]).unwrap();
+        (sealed, BackupIdentity::private_key(&pair.private_key).unwrap())
+    }
+
+    fn open_to_vec(sealed: &[u8], identity: &BackupIdentity) -> Result<Vec<u8>> {
+        let mut opened = Vec::new();
+        open(&mut &sealed[..], &mut opened, identity).map(|_| opened)
+    }
+
+    /// Offset of the first chunk in a sealed backup.
+    fn chunks_start(sealed: &[u8]) -> usize {
+        let header_length = u32::from_le_bytes(sealed[MAGIC.len() + 1..MAGIC.len() + 5].try_into().unwrap()) as usize;
+        MAGIC.len() + 5 + header_length
+    }
+
+    #[test]
+    fn round_trips_for_a_public_key() {
+        for len in [0, 1, CHUNK_SIZE, 3 * CHUNK_SIZE + 17] {
+            let data = sample(len);
+            let (sealed, identity) = seal_to_key(&data);
+            assert!(is_sealed(&sealed));
+            assert_eq!(open_to_vec(&sealed, &identity).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn round_trips_for_a_passphrase() {
+        let data = sample(CHUNK_SIZE + 5);
+        let mut sealed = Vec::new();
+        let passphrase = || Zeroizing::new("correct horse".to_string());
+        seal(&mut &data[..], &mut sealed, &[BackupRecipient::Passphrase(passphrase())]).unwrap();
+
+        assert_eq!(open_to_vec(&sealed, &BackupIdentity::Passphrase(passphrase())).unwrap(), data);
+        let wrong = BackupIdentity::Passphrase(Zeroizing::new("battery staple".to_string()));
+        assert!(matches!(open_to_vec(&sealed, &wrong), Err(Error::Decryption(_))));
+    }
+
+    #[test]
+    fn rejects_another_key() {
+        let (sealed, _) = seal_to_key(&sample(100));
+        let other = BackupIdentity::private_key(&generate_key_pair().private_key).unwrap();
+        assert!(matches!(open_to_vec(&sealed, &other), Err(Error::Decryption(_))));
+    }
+
+    #[test]
+    fn rejects_truncation() {
+        let (sealed, identity) = seal_to_key(&sample(3 * CHUNK_SIZE + 17));
+        let sealed_chunk = CHUNK_SIZE + TAG_LENGTH;
+        let start = chunks_start(&sealed);
+
+        // Dropping the last chunk leaves a chunk that was not sealed as the last one.
+        assert!(open_to_vec(&sealed[..start + 3 * sealed_chunk], &identity).is_err());
+        assert!(open_to_vec(&sealed[..sealed.len() - 1], &identity).is_err());
+        assert!(open_to_vec(&sealed[..start + 10], &identity).is_err());
+        assert!(open_to_vec(&sealed[..start - 1], &identity).is_err());
+    }
+
+    #[test]
+    fn rejects_reordered_or_altered_chunks() {
+        let (sealed, identity) = seal_to_key(&sample(3 * CHUNK_SIZE + 17));
+        let sealed_chunk = CHUNK_SIZE + TAG_LENGTH;
+        let start = chunks_start(&sealed);
+
+        let mut reordered = sealed.clone();
+        let (first, second) = reordered[start..].split_at_mut(sealed_chunk);
+        first.swap_with_slice(&mut second[..sealed_chunk]);
+        assert!(open_to_vec(&reordered, &identity).is_err());
+
+        let mut altered = sealed.clone();
+        altered[start + sealed_chunk + 3] ^= 1;
+        assert!(open_to_vec(&altered, &identity).is_err());
+
+        let mut altered_header = sealed;
+        altered_header[MAGIC.len() + 5 + 2] ^= 1;
+        assert!(open_to_vec(&altered_header, &identity).is_err());
+    }
+
+    #[test]
+    fn rejects_costly_passphrase_stanzas() {
+        let identity = BackupIdentity::Passphrase(Zeroizing::new("passphrase".to_string()));
+        let stanza = |memory_kib, iterations, parallelism| Stanza::Passphrase {
+            salt: STANDARD.encode([0u8; SALT_LENGTH]),
+            memory_kib,
+            iterations,
+            parallelism,
+            wrapped_key: STANDARD.encode([0u8; KEY_LENGTH + TAG_LENGTH]),
+        };
+
+        for (memory_kib, iterations, parallelism) in [(u32::MAX, 3, 4), (64 * 1024, u32::MAX, 4), (64 * 1024, 3, 1024)] {
+            let result = unwrap_file_key(&stanza(memory_kib, iterations, parallelism), &identity);
+            assert!(matches!(result, Err(Error::KeyDerivation(_))));
+        }
+    }
+}
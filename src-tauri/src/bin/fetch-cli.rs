@@ -0,0 +1,751 @@
+//! Headless access to the Fetch vault for scripts and terminals. It opens the
+//! same vault directory as the desktop app and goes through the same library
+//! code, so its writes are sealed and backed up exactly like the app's.
+
+use chrono::Utc;
+use clap::{Parser, Subcommand, ValueEnum};
+use fetch::backup;
+use fetch::crypto::{Crypto, KeyDerivationStrength};
+use fetch::error::{Error, Result};
+use fetch::inject::{self, OutputMasker, ResolvedEnvironment, SecretReference};
+use fetch::import::{self, BitwardenImporter, CsvImportOptions, CsvImporter, DecryptedExportImporter, Importer, KeePassImporter, OnePasswordImporter};
+use fetch::integrity;
+use fetch::storage::{SqliteStorage, Storage, VaultItem};
+use fetch::template::{self, RenderReport};
+use fetch::unlock::UnlockReport;
+use fetch::{export, kdbx, vault};
+use serde::Serialize;
+use serde_json::json;
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{self, ExitCode, Stdio};
+use std::thread;
+use zeroize::Zeroizing;
+
+/// The `identifier` in `tauri.conf.json`, which names the app's data directory.
+const APP_IDENTIFIER: &str = "com.fetch.app";
+
+/// Exit code of `verify` when the vault has integrity issues or was tampered with.
+const EXIT_UNHEALTHY: u8 = 2;
+
+#[derive(Parser)]
+#[command(name = "fetch-cli", version, about = "Script the Fetch vault from the command line")]
+struct Cli {
+    /// The vault directory. Defaults to the one the desktop app uses.
+    #[arg(long, global = true, env = "FETCH_VAULT")]
+    vault: Option<PathBuf>,
+
+    /// Read the master key from the first line of standard input instead of prompting for it.
+    #[arg(long, global = true)]
+    password_stdin: bool,
+
+    /// Print results as JSON.
+    #[arg(long, global = true)]
+    json: bool,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Create a new vault.
+    Init {
+        #[arg(long, value_enum, default_value_t = Strength::Recommended)]
+        strength: Strength,
+    },
+    /// List the items in a folder, or at the top level.
+    List {
+        folder: Option<String>,
+        /// Include the contents of subfolders.
+        #[arg(long, short)]
+        recursive: bool,
+    },
+    /// Print an item's content, or one field of it.
+    Get {
+        /// The item's id or its path, such as `Work/Servers/db01`.
+        item: String,
+        /// A field of a text item, such as `password`, `username` or a custom label.
+        #[arg(long, short)]
+        field: Option<String>,
+        /// Write to this file, readable only by you, instead of printing.
+        #[arg(long, short)]
+        output: Option<PathBuf>,
+    },
+    /// Add an item. Its content is read from standard input unless `--file` is given.
+    Add {
+        /// Path of the new item. The folders leading to it must exist.
+        path: String,
+        /// Store a copy of this file.
+        #[arg(long, conflicts_with = "folder")]
+        file: Option<PathBuf>,
+        /// Type of content read from standard input, such as `text` or `key`.
+        #[arg(long = "type", default_value = "text", conflicts_with_all = ["file", "folder"])]
+        item_type: String,
+        #[arg(long = "tag")]
+        tags: Vec<String>,
+        /// Create a folder instead.
+        #[arg(long)]
+        folder: bool,
+    },
+    /// Delete an item, and everything in it when it is a folder.
+    Rm {
+        item: String,
+    },
+    /// Move an item into a folder, or to a new path to rename it. `/` is the top level.
+    Mv {
+        item: String,
+        destination: String,
+    },
+    /// Show or change an item's tags. Without an item, lists every tag in use.
+    Tag {
+        item: Option<String>,
+        #[arg(long, requires = "item")]
+        add: Vec<String>,
+        #[arg(long, requires = "item")]
+        remove: Vec<String>,
+    },
+    /// Import items from another password manager or a Fetch export.
+    Import {
+        #[arg(value_enum)]
+        format: ImportFormat,
+        file: PathBuf,
+        /// Folder to import into, instead of the top level.
+        #[arg(long)]
+        into: Option<String>,
+        /// File holding the password of an encrypted KeePass database or Bitwarden export.
+        #[arg(long)]
+        source_password_file: Option<PathBuf>,
+        /// KeePass key file.
+        #[arg(long)]
+        key_file: Option<PathBuf>,
+        /// Give items from a Fetch export new ids, so existing copies are kept.
+        #[arg(long)]
+        new_ids: bool,
+    },
+    /// Export the vault.
+    Export {
+        #[arg(value_enum)]
+        format: ExportFormat,
+        destination: PathBuf,
+        /// File holding the password for the KeePass database.
+        #[arg(long)]
+        target_password_file: Option<PathBuf>,
+        /// KeePass key file.
+        #[arg(long)]
+        key_file: Option<PathBuf>,
+    },
+    /// Check the vault for damaged items and for changes made outside Fetch.
+    Verify,
+    /// Render a template's `{{ fetch "item" "field" }}` placeholders into a
+    /// file readable only by you.
+    Render {
+        template: PathBuf,
+        output: PathBuf,
+        /// Shred the rendered file after this many seconds. Keeps running until
+        /// then; if interrupted, the next run of fetch-cli or the app shreds it.
+        #[arg(long, value_name = "SECONDS")]
+        shred_after: Option<u64>,
+    },
+    /// Run a command with `FETCH://folder/item/field` references in its
+    /// environment replaced by their values, masking those values in its output.
+    Run {
+        /// Also set the variables in this `.env` file. Its values override the inherited environment.
+        #[arg(long = "env-file")]
+        env_files: Vec<PathBuf>,
+        /// Pass the command's output through untouched.
+        #[arg(long)]
+        no_masking: bool,
+        #[arg(required = true, trailing_var_arg = true, allow_hyphen_values = true)]
+        command: Vec<OsString>,
+    },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Strength {
+    Fast,
+    Recommended,
+    Paranoid,
+}
+
+impl From<Strength> for KeyDerivationStrength {
+    fn from(strength: Strength) -> Self {
+        match strength {
+            Strength::Fast => KeyDerivationStrength::Fast,
+            Strength::Recommended => KeyDerivationStrength::Recommended,
+            Strength::Paranoid => KeyDerivationStrength::Paranoid,
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum ImportFormat {
+    Csv,
+    Keepass,
+    Bitwarden,
+    #[value(name = "1password", alias = "onepassword")]
+    OnePassword,
+    /// A decrypted export written by Fetch.
+    Fetch,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum ExportFormat {
+    /// Every item with its decrypted content, as written by the app.
+    Json,
+    Keepass,
+    /// The encrypted vault as a zip archive, which can be restored in the app.
+    Archive,
+}
+
+/// An item together with its path, as listed.
+#[derive(Serialize)]
+struct ListedItem<'a> {
+    path: &'a str,
+    #[serde(flatten)]
+    item: &'a VaultItem,
+}
+
+fn main() -> ExitCode {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn")).init();
+    let cli = Cli::parse();
+
+    shred_due_rendered_files(&cli);
+    let code = match run(&cli) {
+        Ok(code) => code,
+        Err(e) => {
+            if cli.json {
+                eprintln!("{}", json!({ "error": e.to_string() }));
+            } else {
+                eprintln!("fetch-cli: {}", e);
+            }
+            ExitCode::FAILURE
+        }
+    };
+    shred_due_rendered_files(&cli);
+    code
+}
+
+/// Shreds rendered files that have come due, including those of an earlier
+/// `render --shred-after` that was interrupted before its time.
+fn shred_due_rendered_files(cli: &Cli) {
+    let Ok(vault_path) = vault_path(cli) else {
+        return;
+    };
+    if let Err(e) = template::shred_due(&vault_path, Utc::now()) {
+        eprintln!("warning: failed to shred rendered files: {}", e);
+    }
+}
+
+fn run(cli: &Cli) -> Result<ExitCode> {
+    match &cli.command {
+        Command::Init { strength } => {
+            let storage = open_storage(cli, true)?;
+            if storage.is_initialized() {
+                return Err(Error::VaultAlreadyInitialized);
+            }
+            let master_key = if cli.password_stdin { read_password(cli, "")? } else { read_new_password("Master key")? };
+            let mut crypto = Crypto::new();
+            vault::initialize(storage.as_ref(), &mut crypto, &master_key, (*strength).into())?;
+
+            let path = vault_path(cli)?;
+            print_done(cli, json!({ "vault": path }), &format!("Created a vault in {}", path.display()))?;
+        }
+        Command::List { folder, recursive } => {
+            let (storage, crypto) = unlock(cli)?;
+            let folder = find_folder(storage.as_ref(), &crypto, folder.as_deref())?;
+            let items = storage.get_all_items_recursive(&crypto)?;
+            let paths = vault::item_paths(&items);
+            let by_id: HashMap<&str, &VaultItem> = items.iter().map(|item| (item.id.as_str(), item)).collect();
+
+            let folder_id = folder.as_ref().map(|folder| folder.id.as_str());
+            let mut listed: Vec<ListedItem> = items
+                .iter()
+                .filter(|item| {
+                    if *recursive {
+                        folder_id.map_or(true, |folder_id| is_inside(&by_id, item, folder_id))
+                    } else {
+                        item.parent_id.as_deref() == folder_id
+                    }
+                })
+                .map(|item| ListedItem { path: &paths[&item.id], item })
+                .collect();
+            listed.sort_by(|a, b| a.path.cmp(b.path));
+
+            if cli.json {
+                print_json(&listed)?;
+            } else {
+                let mut stdout = io::stdout().lock();
+                for entry in listed {
+                    let suffix = if entry.item.item_type == "folder" { "/" } else { "" };
+                    writeln!(stdout, "{}{}\t{}", entry.path, suffix, entry.item.id)?;
+                }
+            }
+        }
+        Command::Get { item, field, output } => {
+            let (storage, crypto) = unlock(cli)?;
+            let item = vault::find_item(storage.as_ref(), &crypto, item)?;
+            let content = match field {
+                Some(field) => Zeroizing::new(vault::item_field(storage.as_ref(), &crypto, &item, field)?.as_bytes().to_vec()),
+                None => Zeroizing::new(vault::item_content(storage.as_ref(), &crypto, &item.id)?),
+            };
+
+            if let Some(output) = output {
+                vault::write_private_file(output, &content)?;
+                print_done(cli, json!({ "id": item.id, "path": output }), &format!("Wrote '{}' to {}", item.name, output.display()))?;
+            } else if cli.json {
+                let mut value = serde_json::to_value(&item)?;
+                if let Some(field) = field {
+                    value["field"] = json!(field);
+                }
+                match std::str::from_utf8(&content) {
+                    Ok(text) => value["content"] = json!(text),
+                    Err(_) => value["content_base64"] = json!(base64_encode(&content)),
+                }
+                print_json(&value)?;
+            } else {
+                let mut stdout = io::stdout().lock();
+                stdout.write_all(&content)?;
+                if field.is_some() {
+                    stdout.write_all(b"\n")?;
+                }
+                stdout.flush()?;
+            }
+        }
+        Command::Add { path, file, item_type, tags, folder } => {
+            let (storage, crypto) = unlock(cli)?;
+            let (parent_path, name) = split_path(path);
+            let parent_id = find_folder(storage.as_ref(), &crypto, parent_path)?.map(|folder| folder.id);
+
+            let item = if *folder {
+                vault::add_folder(storage.as_ref(), &crypto, name.to_string(), None, parent_id)?
+            } else if let Some(file) = file {
+                vault::add_file_item(storage.as_ref(), &crypto, name.to_string(), file, None, tags.clone(), parent_id)?
+            } else {
+                let mut content = Zeroizing::new(Vec::new());
+                io::stdin().lock().read_to_end(&mut content)?;
+                vault::add_text_item(storage.as_ref(), &crypto, name.to_string(), item_type.clone(), &content, tags.clone(), parent_id)?
+            };
+            print_done(cli, serde_json::to_value(&item)?, &item.id)?;
+        }
+        Command::Rm { item } => {
+            let (storage, crypto) = unlock(cli)?;
+            let item = vault::find_item(storage.as_ref(), &crypto, item)?;
+            vault::delete_item(storage.as_ref(), &crypto, &item.id)?;
+            print_done(cli, json!({ "id": item.id }), &format!("Deleted '{}'", item.name))?;
+        }
+        Command::Mv { item, destination } => {
+            let (storage, crypto) = unlock(cli)?;
+            let item = vault::find_item(storage.as_ref(), &crypto, item)?;
+
+            let (parent, name) = match destination_folder(storage.as_ref(), &crypto, destination)? {
+                Some(folder) => (folder, None),
+                None => {
+                    let (parent_path, name) = split_path(destination);
+                    (find_folder(storage.as_ref(), &crypto, parent_path)?, Some(name.to_string()))
+                }
+            };
+            let parent_id = parent.as_ref().map(|folder| folder.id.as_str());
+            let item = vault::move_item(storage.as_ref(), &crypto, &item.id, parent_id, name)?;
+            print_done(cli, serde_json::to_value(&item)?, &format!("Moved '{}'", item.name))?;
+        }
+        Command::Tag { item: None, .. } => {
+            let (storage, crypto) = unlock(cli)?;
+            print_lines(cli, &vault::all_tags(storage.as_ref(), &crypto)?)?;
+        }
+        Command::Tag { item: Some(item), add, remove } => {
+            let (storage, crypto) = unlock(cli)?;
+            let item = vault::find_item(storage.as_ref(), &crypto, item)?;
+            let item = vault::update_tags(storage.as_ref(), &crypto, &item.id, add, remove)?;
+            print_lines(cli, &item.tags)?;
+        }
+        Command::Import { format, file, into, source_password_file, key_file, new_ids } => {
+            let (storage, crypto) = unlock(cli)?;
+            let parent_id = find_folder(storage.as_ref(), &crypto, into.as_deref())?.map(|folder| folder.id);
+            let source_password = source_password_file.as_deref().map(read_password_file).transpose()?;
+
+            let data = fs::read(file)?;
+            let importer: Box<dyn Importer> = match format {
+                ImportFormat::Csv => Box::new(CsvImporter::new(CsvImportOptions::default())),
+                ImportFormat::Keepass => {
+                    let password = match source_password {
+                        Some(password) => Some(password),
+                        None if key_file.is_none() => Some(prompt_password("KeePass password: ")?),
+                        None => None,
+                    };
+                    Box::new(KeePassImporter::new(keepass_key(password, key_file.as_deref())?))
+                }
+                ImportFormat::Bitwarden => Box::new(BitwardenImporter::new(source_password.map(|password| password.to_string()))),
+                ImportFormat::OnePassword => Box::new(OnePasswordImporter::new()),
+                ImportFormat::Fetch => Box::new(DecryptedExportImporter::new(*new_ids)),
+            };
+
+            let parsed = importer.parse(&data)?;
+            if matches!(format, ImportFormat::Fetch) && !new_ids {
+                import::ensure_ids_are_new(storage.as_ref(), &crypto, &parsed)?;
+            }
+            let result = import::store_items(storage.as_ref(), &crypto, parsed, parent_id)?;
+            vault::record_write(storage.as_ref(), &crypto);
+
+            if cli.json {
+                print_json(&result)?;
+            } else {
+                writeln!(io::stdout().lock(), "Imported {} items from {}.", result.success_count, importer.name())?;
+                for error in &result.errors {
+                    eprintln!("{}", error);
+                }
+            }
+        }
+        Command::Export { format: ExportFormat::Archive, destination, .. } => {
+            let storage = open_storage(cli, false)?;
+            let manifest = backup::export_archive(backup::vault_directory(storage.as_ref())?, destination, |_| {})?;
+            print_done(cli, serde_json::to_value(&manifest)?, &format!("Exported the encrypted vault to {}", destination.display()))?;
+        }
+        Command::Export { format, destination, target_password_file, key_file } => {
+            let (storage, crypto) = unlock(cli)?;
+            let data = match format {
+                ExportFormat::Json => Zeroizing::new(vault::decrypted_export(storage.as_ref(), &crypto)?.into_bytes()),
+                ExportFormat::Keepass => {
+                    let password = match target_password_file {
+                        Some(path) => Some(read_password_file(path)?),
+                        None if key_file.is_none() => Some(read_new_password("KeePass password")?),
+                        None => None,
+                    };
+                    let database = export::to_keepass(storage.as_ref(), &crypto)?;
+                    Zeroizing::new(kdbx::write(&database, &keepass_key(password, key_file.as_deref())?)?)
+                }
+                ExportFormat::Archive => unreachable!("archives are exported without unlocking"),
+            };
+            vault::write_private_file(destination, &data)?;
+            print_done(cli, json!({ "path": destination }), &format!("Exported the vault to {}", destination.display()))?;
+        }
+        Command::Verify => {
+            let (storage, crypto, unlock_report) = unlock_with_report(cli)?;
+            let vault_state = unlock_report.vault_state;
+            let report = integrity::verify_vault(storage.as_ref(), &crypto)?;
+            let healthy = report.is_healthy() && vault_state.as_ref().is_some_and(|check| check.is_intact());
+
+            if cli.json {
+                print_json(&json!({ "healthy": healthy, "integrity": report, "vault_state": vault_state }))?;
+            } else {
+                let mut stdout = io::stdout().lock();
+                writeln!(stdout, "Checked {} items and {} data files.", report.items_checked, report.data_files_checked)?;
+                for issue in &report.issues {
+                    writeln!(stdout, "{:?}", issue)?;
+                }
+                match &vault_state {
+                    Some(check) => {
+                        writeln!(stdout, "Vault state: {:?}", check.status)?;
+                        for change in &check.changes {
+                            writeln!(stdout, "{:?} {:?} {}", change.change, change.kind, change.id)?;
+                        }
+                    }
+                    None => writeln!(stdout, "Vault state could not be checked.")?,
+                }
+            }
+            if !healthy {
+                return Ok(ExitCode::from(EXIT_UNHEALTHY));
+            }
+        }
+        Command::Render { template, output, shred_after } => {
+            let (storage, crypto) = unlock(cli)?;
+            let shred_at = template::shred_time(Utc::now(), *shred_after)?;
+            let vault_path = vault_path(cli)?;
+            let placeholders = template::render_to_file(storage.as_ref(), &crypto, template, output)?;
+            drop((storage, crypto));
+            if let Some(shred_at) = shred_at {
+                template::schedule_shred(&vault_path, output, shred_at)?;
+            }
+
+            let report = RenderReport { output: output.clone(), placeholders, shred_at };
+            let message = match shred_at {
+                Some(shred_at) => format!("Rendered {} values into {}, to be shredded at {}", placeholders, output.display(), shred_at),
+                None => format!("Rendered {} values into {}", placeholders, output.display()),
+            };
+            print_done(cli, serde_json::to_value(&report)?, &message)?;
+
+            if let Some(shred_at) = shred_at {
+                thread::sleep((shred_at - Utc::now()).to_std().unwrap_or_default());
+                template::shred_due(&vault_path, Utc::now())?;
+            }
+        }
+        Command::Run { env_files, no_masking, command } => {
+            // Inherited variables that are not references are left for the child to inherit.
+            let mut variables: Vec<(String, String)> = std::env::vars_os()
+                .filter_map(|(name, value)| Some((name.into_string().ok()?, value.into_string().ok()?)))
+                .filter(|(_, value)| SecretReference::parse(value).is_some())
+                .collect();
+            for env_file in env_files {
+                variables.extend(inject::parse_env_file(&fs::read_to_string(env_file)?)?);
+            }
+
+            let environment = if variables.iter().any(|(_, value)| SecretReference::parse(value).is_some()) {
+                let (storage, crypto) = unlock(cli)?;
+                inject::resolve_environment(storage.as_ref(), &crypto, variables)?
+            } else {
+                ResolvedEnvironment {
+                    variables: variables.into_iter().map(|(name, value)| (name, Zeroizing::new(value))).collect(),
+                    secrets: Vec::new(),
+                }
+            };
+            return run_command(command, environment, !no_masking);
+        }
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Runs the command with the resolved environment and returns its exit code.
+/// When masking, its output is read through pipes and copied to ours with the
+/// secrets concealed; its input is ours either way.
+fn run_command(command: &[OsString], environment: ResolvedEnvironment, mask: bool) -> Result<ExitCode> {
+    let mut child_command = process::Command::new(&command[0]);
+    child_command
+        .args(&command[1..])
+        .envs(environment.variables.iter().map(|(name, value)| (name, value.as_str())));
+    if mask {
+        child_command.stdout(Stdio::piped()).stderr(Stdio::piped());
+    }
+
+    let mut child = child_command
+        .spawn()
+        .map_err(|e| Error::InvalidInput(format!("Could not run {}: {}", command[0].to_string_lossy(), e)))?;
+    let copies = [
+        child.stdout.take().map(|stdout| mask_output(stdout, io::stdout(), &environment.secrets)),
+        child.stderr.take().map(|stderr| mask_output(stderr, io::stderr(), &environment.secrets)),
+    ];
+    drop(environment);
+
+    let status = child.wait()?;
+    for copy in copies.into_iter().flatten() {
+        copy.join().map_err(|_| Error::Internal("Output copy thread panicked".into()))??;
+    }
+    Ok(ExitCode::from(exit_code(status)))
+}
+
+fn mask_output(mut from: impl Read + Send + 'static, to: impl Write + Send + 'static, secrets: &[Zeroizing<String>]) -> thread::JoinHandle<io::Result<()>> {
+    let mut masker = OutputMasker::new(to, secrets);
+    thread::spawn(move || {
+        let mut buffer = [0u8; 8192];
+        loop {
+            let read = match from.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(read) => read,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            masker.write_all(&buffer[..read])?;
+            masker.flush()?;
+        }
+        masker.finish().map(drop)
+    })
+}
+
+/// The child's exit code, or 128 plus the signal that killed it, as shells report it.
+fn exit_code(status: process::ExitStatus) -> u8 {
+    #[cfg(unix)]
+    if let Some(signal) = std::os::unix::process::ExitStatusExt::signal(&status) {
+        return 128u8.saturating_add(signal as u8);
+    }
+    status.code().map_or(1, |code| code as u8)
+}
+
+fn vault_path(cli: &Cli) -> Result<PathBuf> {
+    match &cli.vault {
+        Some(path) => Ok(path.clone()),
+        None => dirs::data_dir()
+            .map(|dir| dir.join(APP_IDENTIFIER).join("vault"))
+            .ok_or_else(|| Error::Internal("Could not find the data directory. Pass --vault.".into())),
+    }
+}
+
+/// Opens the vault directory, which must already hold a vault unless `create` is set.
+fn open_storage(cli: &Cli, create: bool) -> Result<Box<dyn Storage>> {
+    let path = vault_path(cli)?;
+    if !create && !path.join("vault.db").exists() {
+        return Err(Error::InvalidInput(format!("No vault in {}. Create one with `fetch-cli init`.", path.display())));
+    }
+    let storage = SqliteStorage::new(path)?;
+    if !create && !storage.is_initialized() {
+        return Err(Error::InvalidInput("The vault has not been set up yet. Create it with `fetch-cli init`.".into()));
+    }
+    Ok(Box::new(storage))
+}
+
+fn unlock(cli: &Cli) -> Result<(Box<dyn Storage>, Crypto)> {
+    let (storage, crypto, _) = unlock_with_report(cli)?;
+    Ok((storage, crypto))
+}
+
+/// Opens and unlocks the vault, warning on stderr about anything the unlock turned up.
+fn unlock_with_report(cli: &Cli) -> Result<(Box<dyn Storage>, Crypto, UnlockReport)> {
+    let storage = open_storage(cli, false)?;
+    let master_key = read_password(cli, "Master key: ")?;
+    let mut crypto = Crypto::new();
+    let report = vault::unlock(storage.as_ref(), &mut crypto, &master_key, Utc::now())?;
+
+    if report.failed_attempts > 0 {
+        eprintln!("warning: {} failed unlock attempts since the vault was last opened", report.failed_attempts);
+    }
+    for setting in &report.tampered_settings {
+        eprintln!("warning: setting '{}' was changed outside Fetch and has been reset", setting);
+    }
+    if let Some(check) = report.vault_state.as_ref().filter(|check| !check.is_intact()) {
+        eprintln!("warning: the vault does not match its last sealed state ({:?}); run `fetch-cli verify`", check.status);
+    }
+    Ok((storage, crypto, report))
+}
+
+fn read_password(cli: &Cli, prompt: &str) -> Result<Zeroizing<String>> {
+    if cli.password_stdin {
+        let password = read_stdin_line()?;
+        if password.is_empty() {
+            return Err(Error::InvalidInput("No master key on standard input".into()));
+        }
+        Ok(password)
+    } else {
+        prompt_password(prompt)
+    }
+}
+
+/// Reads the first line of standard input a byte at a time, bypassing the
+/// buffer of `io::stdin()`, so whatever follows the master key on a pipe is
+/// left for the command that `run` starts or for an item's content.
+fn read_stdin_line() -> Result<Zeroizing<String>> {
+    let mut stdin = unbuffered_stdin()?;
+    let mut line = Zeroizing::new(Vec::new());
+    let mut byte = [0u8; 1];
+    loop {
+        match stdin.read(&mut byte) {
+            Ok(0) => break,
+            Ok(_) if byte[0] == b'\n' => break,
+            Ok(_) => line.push(byte[0]),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+        byte[0] = 0;
+    }
+    let line = std::str::from_utf8(&line).map_err(|_| Error::InvalidInput("The master key on standard input is not UTF-8".into()))?;
+    Ok(Zeroizing::new(line.trim_end_matches('\r').to_string()))
+}
+
+#[cfg(unix)]
+fn unbuffered_stdin() -> Result<fs::File> {
+    use std::os::unix::io::AsFd;
+    Ok(io::stdin().as_fd().try_clone_to_owned()?.into())
+}
+
+#[cfg(windows)]
+fn unbuffered_stdin() -> Result<fs::File> {
+    use std::os::windows::io::AsHandle;
+    Ok(io::stdin().as_handle().try_clone_to_owned()?.into())
+}
+
+/// Prompts for a password being set, asking twice.
+fn read_new_password(label: &str) -> Result<Zeroizing<String>> {
+    let password = prompt_password(&format!("{}: ", label))?;
+    if password.is_empty() {
+        return Err(Error::InvalidInput(format!("{} cannot be empty", label)));
+    }
+    if *prompt_password(&format!("Repeat {}: ", label.to_lowercase()))? != *password {
+        return Err(Error::InvalidInput(format!("{}s do not match", label)));
+    }
+    Ok(password)
+}
+
+fn prompt_password(prompt: &str) -> Result<Zeroizing<String>> {
+    Ok(Zeroizing::new(rpassword::prompt_password(prompt)?))
+}
+
+/// Reads a password from the first line of a file.
+fn read_password_file(path: &Path) -> Result<Zeroizing<String>> {
+    let content = Zeroizing::new(fs::read_to_string(path)?);
+    Ok(Zeroizing::new(content.lines().next().unwrap_or_default().to_string()))
+}
+
+fn keepass_key(password: Option<Zeroizing<String>>, key_file: Option<&Path>) -> Result<kdbx::DatabaseKey> {
+    let keyfile = key_file.map(fs::read).transpose()?;
+    kdbx::DatabaseKey::new(password.map(|password| password.to_string()), keyfile)
+}
+
+/// Splits an item path into its parent folder path and its name.
+fn split_path(path: &str) -> (Option<&str>, &str) {
+    let path = path.trim_matches(vault::PATH_SEPARATOR);
+    match path.rsplit_once(vault::PATH_SEPARATOR) {
+        Some((parent, name)) => (Some(parent), name),
+        None => (None, path),
+    }
+}
+
+/// Finds a folder by id or path; `None` stands for the top level.
+fn find_folder(storage: &dyn Storage, crypto: &Crypto, reference: Option<&str>) -> Result<Option<VaultItem>> {
+    let Some(reference) = reference.filter(|reference| !reference.trim_matches(vault::PATH_SEPARATOR).is_empty()) else {
+        return Ok(None);
+    };
+    let folder = vault::find_item(storage, crypto, reference)?;
+    if folder.item_type != "folder" {
+        return Err(Error::InvalidInput(format!("'{}' is not a folder", folder.name)));
+    }
+    Ok(Some(folder))
+}
+
+/// The folder `mv` moves into when the destination names one: `Some(None)` for
+/// the top level, `None` when the destination is a new path.
+fn destination_folder(storage: &dyn Storage, crypto: &Crypto, destination: &str) -> Result<Option<Option<VaultItem>>> {
+    if destination.trim_matches(vault::PATH_SEPARATOR).is_empty() {
+        return Ok(Some(None));
+    }
+    match vault::find_item(storage, crypto, destination) {
+        Ok(item) if item.item_type == "folder" => Ok(Some(Some(item))),
+        Ok(item) => Err(Error::InvalidInput(format!("'{}' already exists", item.name))),
+        Err(Error::ItemNotFound(_)) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+fn is_inside(by_id: &HashMap<&str, &VaultItem>, item: &VaultItem, folder_id: &str) -> bool {
+    let mut parent_id = item.parent_id.as_deref();
+    for _ in 0..by_id.len() {
+        match parent_id {
+            Some(id) if id == folder_id => return true,
+            Some(id) => parent_id = by_id.get(id).and_then(|parent| parent.parent_id.as_deref()),
+            None => return false,
+        }
+    }
+    false
+}
+
+/// Output goes through `writeln!` so a closed pipe ends the command with an
+/// error instead of a panic.
+fn print_json<T: Serialize + ?Sized>(value: &T) -> Result<()> {
+    writeln!(io::stdout().lock(), "{}", serde_json::to_string_pretty(value)?)?;
+    Ok(())
+}
+
+fn print_done(cli: &Cli, value: serde_json::Value, message: &str) -> Result<()> {
+    if cli.json {
+        print_json(&value)
+    } else {
+        writeln!(io::stdout().lock(), "{}", message)?;
+        Ok(())
+    }
+}
+
+fn print_lines(cli: &Cli, lines: &[String]) -> Result<()> {
+    if cli.json {
+        print_json(lines)
+    } else {
+        let mut stdout = io::stdout().lock();
+        for line in lines {
+            writeln!(stdout, "{}", line)?;
+        }
+        Ok(())
+    }
+}
+
+fn base64_encode(data: &[u8]) -> String {
+    use base64::{engine::general_purpose::STANDARD, Engine as _};
+    STANDARD.encode(data)
+}
@@ -0,0 +1,283 @@
+use crate::error::Error;
+use crate::Result;
+use log::info;
+use md4::Md4;
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use std::cmp::Ordering;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+const RANGE_PREFIX_LENGTH: usize = 5;
+/// Lines checked for hash order when a list is opened. The download ordered by
+/// prevalence starts with the most common passwords, far from hash order.
+const SORT_CHECK_LINES: usize = 100;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum HashKind {
+    Sha1,
+    Ntlm,
+}
+
+impl HashKind {
+    fn from_hash_length(length: usize) -> Option<Self> {
+        match length {
+            40 => Some(HashKind::Sha1),
+            32 => Some(HashKind::Ntlm),
+            _ => None,
+        }
+    }
+
+    pub fn hash(&self, password: &str) -> String {
+        let digest = match self {
+            HashKind::Sha1 => Sha1::digest(password.as_bytes()).to_vec(),
+            HashKind::Ntlm => {
+                let utf16: Vec<u8> = password.encode_utf16().flat_map(|unit| unit.to_le_bytes()).collect();
+                Md4::digest(&utf16).to_vec()
+            }
+        };
+        digest.iter().map(|byte| format!("{:02X}", byte)).collect()
+    }
+}
+
+#[derive(Debug)]
+enum Layout {
+    /// One file with every `HASH:COUNT` line, sorted by hash.
+    SingleFile(PathBuf),
+    /// A directory of `PREFIX.txt` range files holding `SUFFIX:COUNT` lines.
+    RangeDirectory(PathBuf),
+}
+
+/// A local copy of the Have I Been Pwned "Pwned Passwords" list. Lookups
+/// binary-search the sorted file on disk, so the list is never loaded into memory.
+#[derive(Debug)]
+pub struct PwnedPasswords {
+    layout: Layout,
+    kind: HashKind,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct BreachResult {
+    pub id: String,
+    pub name: String,
+    pub breach_count: u64,
+}
+
+impl PwnedPasswords {
+    pub fn open(path: &Path) -> Result<Self> {
+        let metadata = fs::metadata(path)?;
+        let (layout, sample_file, prefix_length) = if metadata.is_dir() {
+            let sample = fs::read_dir(path)?
+                .filter_map(|entry| entry.ok())
+                .map(|entry| entry.path())
+                .find(|p| p.is_file() && is_range_file(p))
+                .ok_or_else(|| Error::InvalidInput(format!("No Pwned Passwords range files found in {}", path.display())))?;
+            (Layout::RangeDirectory(path.to_path_buf()), sample, RANGE_PREFIX_LENGTH)
+        } else {
+            (Layout::SingleFile(path.to_path_buf()), path.to_path_buf(), 0)
+        };
+
+        let mut first_line = String::new();
+        BufReader::new(File::open(&sample_file)?).read_line(&mut first_line)?;
+        check_sorted(&sample_file)?;
+        let hash_length = first_line.trim_start_matches('\u{feff}').split(':').next().unwrap_or_default().trim().len() + prefix_length;
+        let kind = HashKind::from_hash_length(hash_length)
+            .ok_or_else(|| Error::InvalidInput(format!("Unrecognised Pwned Passwords file format: {}", sample_file.display())))?;
+
+        info!("Opened Pwned Passwords {:?} with {:?} hashes", layout, kind);
+        Ok(Self { layout, kind })
+    }
+
+    pub fn kind(&self) -> HashKind {
+        self.kind
+    }
+
+    /// Returns how many times the password appears in the breach corpus, or 0.
+    pub fn breach_count(&self, password: &str) -> Result<u64> {
+        let hash = self.kind.hash(password);
+
+        match &self.layout {
+            Layout::SingleFile(path) => search_sorted_file(path, &hash),
+            Layout::RangeDirectory(dir) => {
+                let (prefix, suffix) = hash.split_at(RANGE_PREFIX_LENGTH);
+                let range_file = dir.join(format!("{}.txt", prefix));
+                if !range_file.exists() {
+                    // A partial download would otherwise report breached passwords as clean.
+                    return Err(Error::InvalidInput(format!(
+                        "Range file {} is missing; the Pwned Passwords download is incomplete",
+                        range_file.display()
+                    )));
+                }
+                search_sorted_file(&range_file, suffix)
+            }
+        }
+    }
+}
+
+fn is_range_file(path: &Path) -> bool {
+    let is_txt = path.extension().map(|ext| ext.eq_ignore_ascii_case("txt")).unwrap_or(false);
+    let stem_is_prefix = path
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .map(|stem| stem.len() == RANGE_PREFIX_LENGTH && stem.chars().all(|c| c.is_ascii_hexdigit()))
+        .unwrap_or(false);
+    is_txt && stem_is_prefix
+}
+
+/// Fails unless the first lines of `path` are in ascending hash order, which
+/// the binary search relies on.
+fn check_sorted(path: &Path) -> Result<()> {
+    let mut previous: Option<String> = None;
+    for line in BufReader::new(File::open(path)?).lines().take(SORT_CHECK_LINES) {
+        let line = line?;
+        let hash = line.trim_start_matches('\u{feff}').split(':').next().unwrap_or_default().trim().to_string();
+        if previous.as_deref().is_some_and(|previous| compare_hex(previous, &hash) != Ordering::Less) {
+            return Err(Error::InvalidInput(format!(
+                "{} is not sorted by hash. Download the Pwned Passwords list ordered by hash.",
+                path.display()
+            )));
+        }
+        previous = Some(hash);
+    }
+    Ok(())
+}
+
+/// Binary search over the line starts of a file of `HASH:COUNT` lines sorted by hash.
+/// The target line, if present, always starts inside `[low, high)`.
+fn search_sorted_file(path: &Path, hash: &str) -> Result<u64> {
+    let file = File::open(path)?;
+    let mut high = file.metadata()?.len();
+    let mut reader = BufReader::new(file);
+    let mut low = 0u64;
+    let mut line = Vec::new();
+
+    while low < high {
+        let mid = low + (high - low) / 2;
+
+        // Find the first line starting at or after `mid`.
+        let line_start = if mid == 0 {
+            reader.seek(SeekFrom::Start(0))?;
+            0
+        } else {
+            reader.seek(SeekFrom::Start(mid - 1))?;
+            line.clear();
+            mid - 1 + reader.read_until(b'\n', &mut line)? as u64
+        };
+
+        line.clear();
+        let read = reader.read_until(b'\n', &mut line)? as u64;
+        if line_start >= high || read == 0 {
+            high = mid;
+            continue;
+        }
+
+        let text = String::from_utf8_lossy(&line);
+        let text = text.trim_start_matches('\u{feff}');
+        let (line_hash, count) = text.trim_end().split_once(':').unwrap_or((text.trim_end(), "0"));
+
+        match compare_hex(line_hash, hash) {
+            Ordering::Equal => {
+                return count.trim().parse().map_err(|e| Error::InvalidInput(format!("Invalid breach count in {}: {}", path.display(), e)));
+            }
+            Ordering::Less => low = line_start + read,
+            Ordering::Greater => high = mid,
+        }
+    }
+
+    Ok(0)
+}
+
+fn compare_hex(a: &str, b: &str) -> Ordering {
+    a.bytes().map(|c| c.to_ascii_uppercase()).cmp(b.bytes().map(|c| c.to_ascii_uppercase()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    /// SHA-1 of "password", split as in the range download.
+    const PASSWORD_PREFIX: &str = "5BAA6";
+    const PASSWORD_SUFFIX: &str = "1E4C9B93F3F0682250B6CF8331B7EE68FD8";
+
+    struct Fixture(PathBuf);
+
+    impl Fixture {
+        fn new() -> Self {
+            let dir = std::env::temp_dir().join(format!("fetch-breach-{}", Uuid::new_v4()));
+            fs::create_dir_all(&dir).unwrap();
+            Fixture(dir)
+        }
+
+        fn file(&self, name: &str, content: &str) -> PathBuf {
+            let path = self.0.join(name);
+            fs::write(&path, content).unwrap();
+            path
+        }
+    }
+
+    impl Drop for Fixture {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn sorted_lines(line_ending: &str) -> String {
+        ["0000000000000000000000000000000000000001:7", "5BAA61E4C9B93F3F0682250B6CF8331B7EE68FD8:3861493", "8000000000000000000000000000000000000000:2", "FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF:1"]
+            .map(|line| format!("{}{}", line, line_ending))
+            .concat()
+    }
+
+    #[test]
+    fn finds_hashes_in_a_sorted_file() {
+        for line_ending in ["\n", "\r\n"] {
+            let fixture = Fixture::new();
+            let path = fixture.file("pwned.txt", &sorted_lines(line_ending));
+            assert_eq!(search_sorted_file(&path, "0000000000000000000000000000000000000001").unwrap(), 7);
+            assert_eq!(search_sorted_file(&path, "8000000000000000000000000000000000000000").unwrap(), 2);
+            assert_eq!(search_sorted_file(&path, "ffffffffffffffffffffffffffffffffffffffff").unwrap(), 1);
+            assert_eq!(search_sorted_file(&path, "0000000000000000000000000000000000000000").unwrap(), 0);
+            assert_eq!(search_sorted_file(&path, "7000000000000000000000000000000000000000").unwrap(), 0);
+
+            let pwned = PwnedPasswords::open(&path).unwrap();
+            assert_eq!(pwned.kind(), HashKind::Sha1);
+            assert_eq!(pwned.breach_count("password").unwrap(), 3_861_493);
+            assert_eq!(pwned.breach_count("not in the list").unwrap(), 0);
+        }
+    }
+
+    #[test]
+    fn skips_a_byte_order_mark() {
+        let fixture = Fixture::new();
+        let path = fixture.file("pwned.txt", &format!("\u{feff}{}", sorted_lines("\n")));
+        assert_eq!(search_sorted_file(&path, "0000000000000000000000000000000000000001").unwrap(), 7);
+        assert_eq!(PwnedPasswords::open(&path).unwrap().breach_count("password").unwrap(), 3_861_493);
+    }
+
+    #[test]
+    fn rejects_a_file_ordered_by_prevalence() {
+        let fixture = Fixture::new();
+        let path = fixture.file("pwned.txt", "7C4A8D09CA3762AF61E59520943DC26494F8941B:37359195\n5BAA61E4C9B93F3F0682250B6CF8331B7EE68FD8:3861493\n");
+        assert!(PwnedPasswords::open(&path).is_err());
+    }
+
+    #[test]
+    fn searches_range_files() {
+        let fixture = Fixture::new();
+        fixture.file(&format!("{}.txt", PASSWORD_PREFIX), &format!("00000000000000000000000000000000001:1\r\n{}:3861493\r\n", PASSWORD_SUFFIX));
+        let pwned = PwnedPasswords::open(&fixture.0).unwrap();
+        assert_eq!(pwned.breach_count("password").unwrap(), 3_861_493);
+        // SHA-1 of "hunter2" starts with F3BBB, which this partial download lacks.
+        assert!(pwned.breach_count("hunter2").is_err());
+    }
+
+    #[test]
+    fn recognises_ntlm_lists() {
+        let fixture = Fixture::new();
+        let path = fixture.file("ntlm.txt", &format!("{}:1\n", HashKind::Ntlm.hash("password")));
+        let pwned = PwnedPasswords::open(&path).unwrap();
+        assert_eq!(pwned.kind(), HashKind::Ntlm);
+        assert_eq!(pwned.breach_count("password").unwrap(), 1);
+    }
+}
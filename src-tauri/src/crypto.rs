@@ -6,9 +6,12 @@ use argon2::{
     password_hash::{SaltString}, // Removed PasswordHasher
     Argon2, Params, ParamsBuilder,
 };
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
 use rand::{rngs::OsRng, RngCore};
 use serde::{Deserialize, Serialize};
-use zeroize::Zeroize;
+use sha2::Sha256;
+use zeroize::{Zeroize, Zeroizing};
 
 use crate::error::Error;
 use crate::Result;
@@ -16,6 +19,7 @@ use crate::Result;
 const SALT_LENGTH: usize = 16;
 const NONCE_LENGTH: usize = 12;
 const TOKEN_LENGTH: usize = 32;
+const MAC_KEY_INFO: &[u8] = b"fetch vault_meta mac";
 
 #[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
 pub enum KeyDerivationStrength {
@@ -26,6 +30,25 @@ pub enum KeyDerivationStrength {
 }
 
 impl KeyDerivationStrength {
+    fn salt_tag(&self) -> u8 {
+        match self {
+            KeyDerivationStrength::Fast => 1,
+            KeyDerivationStrength::Recommended => 2,
+            KeyDerivationStrength::Paranoid => 3,
+        }
+    }
+
+    /// The strength recorded at the end of a salt from `Crypto::generate_salt`,
+    /// or `None` for salts from before it was recorded there.
+    pub fn from_salt(salt: &[u8]) -> Option<Self> {
+        if salt.len() != SALT_LENGTH + 1 {
+            return None;
+        }
+        [KeyDerivationStrength::Fast, KeyDerivationStrength::Recommended, KeyDerivationStrength::Paranoid]
+            .into_iter()
+            .find(|strength| salt.last() == Some(&strength.salt_tag()))
+    }
+
     fn get_params(&self) -> Result<Params> {
         let mut params = ParamsBuilder::new();
         let builder = match self {
@@ -41,14 +64,40 @@ impl KeyDerivationStrength {
     }
 }
 
+/// Most memory, in KiB, that Argon2 parameters read from a file may ask for.
+const MAX_UNTRUSTED_ARGON2_MEMORY_KIB: u32 = 1024 * 1024;
+/// Most memory passes, in KiB: ten over the largest allowed memory, or more
+/// over less, as some KeePass databases use many passes over a small buffer.
+const MAX_UNTRUSTED_ARGON2_WORK_KIB: u64 = 10 * MAX_UNTRUSTED_ARGON2_MEMORY_KIB as u64;
+const MAX_UNTRUSTED_ARGON2_PARALLELISM: u32 = 16;
+
+/// Builds Argon2 parameters that came from an imported file or a backup,
+/// rejecting costs a crafted file could use to exhaust memory or hang the app.
+pub fn untrusted_argon2_params(memory_kib: u32, iterations: u32, parallelism: u32, output_len: usize) -> Result<Params> {
+    if memory_kib > MAX_UNTRUSTED_ARGON2_MEMORY_KIB
+        || u64::from(memory_kib) * u64::from(iterations) > MAX_UNTRUSTED_ARGON2_WORK_KIB
+        || parallelism > MAX_UNTRUSTED_ARGON2_PARALLELISM
+    {
+        return Err(Error::KeyDerivation(format!(
+            "Argon2 parameters are too costly ({} KiB, {} iterations, {} lanes)",
+            memory_kib, iterations, parallelism
+        )));
+    }
+    Params::new(memory_kib, iterations, parallelism, Some(output_len)).map_err(|e| Error::KeyDerivation(e.to_string()))
+}
+
 
 pub struct Crypto {
     cipher: Option<Aes256Gcm>,
+    /// HMAC key derived from the vault key, for values that must stay readable
+    /// while the vault is locked but should not be editable.
+    mac_key: Option<Zeroizing<[u8; 32]>>,
 }
 
 impl Zeroize for Crypto {
     fn zeroize(&mut self) {
         self.cipher = None;
+        self.mac_key = None;
     }
 }
 
@@ -60,7 +109,7 @@ impl Drop for Crypto {
 
 impl Crypto {
     pub fn new() -> Self {
-        Self { cipher: None }
+        Self { cipher: None, mac_key: None }
     }
 
     pub fn is_unlocked(&self) -> bool {
@@ -90,8 +139,14 @@ impl Crypto {
     }
 
     pub fn unlock(&mut self, key: &[u8]) -> Result<()> {
+        let mut mac_key = Zeroizing::new([0u8; 32]);
+        Hkdf::<Sha256>::new(None, key)
+            .expand(MAC_KEY_INFO, mac_key.as_mut())
+            .map_err(|e| Error::KeyDerivation(e.to_string()))?;
+
         let key = Key::<Aes256Gcm>::from_slice(key);
         self.cipher = Some(Aes256Gcm::new(key));
+        self.mac_key = Some(mac_key);
         Ok(())
     }
 
@@ -134,9 +189,29 @@ impl Crypto {
         Ok(plaintext)
     }
 
-    pub fn generate_salt() -> Vec<u8> {
-        let mut salt = vec![0u8; SALT_LENGTH];
-        OsRng.fill_bytes(&mut salt);
+    pub fn mac(&self, data: &[u8]) -> Result<Vec<u8>> {
+        Ok(self.hmac(data)?.finalize().into_bytes().to_vec())
+    }
+
+    /// Checks `tag` against the MAC of `data` in constant time.
+    pub fn verify_mac(&self, data: &[u8], tag: &[u8]) -> Result<bool> {
+        Ok(self.hmac(data)?.verify_slice(tag).is_ok())
+    }
+
+    fn hmac(&self, data: &[u8]) -> Result<Hmac<Sha256>> {
+        let mac_key = self.mac_key.as_ref().ok_or(Error::VaultLocked)?;
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(mac_key.as_ref()).map_err(|e| Error::Crypto(e.to_string()))?;
+        mac.update(data);
+        Ok(mac)
+    }
+
+    /// A random salt with `strength` appended. The whole salt goes into the key
+    /// derivation, so editing the recorded strength breaks the key just as
+    /// editing the salt would, and unlocking never has to guess it.
+    pub fn generate_salt(strength: KeyDerivationStrength) -> Vec<u8> {
+        let mut salt = vec![0u8; SALT_LENGTH + 1];
+        OsRng.fill_bytes(&mut salt[..SALT_LENGTH]);
+        salt[SALT_LENGTH] = strength.salt_tag();
         salt
     }
     
@@ -45,6 +45,9 @@ pub enum Error {
     #[error("Invalid master key")]
     InvalidMasterKey,
 
+    #[error("Too many failed unlock attempts. Try again in {0} minutes.")]
+    TooManyAttempts(i64),
+
     #[error("Vault is already initialized")]
     VaultAlreadyInitialized,
 
@@ -102,6 +105,7 @@ impl From<serde_json::Error> for Error {
     }
 }
 
+#[cfg(feature = "desktop")]
 impl From<tauri::Error> for Error {
     fn from(err: tauri::Error) -> Self {
         Error::TauriError(err.to_string())
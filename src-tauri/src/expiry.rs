@@ -0,0 +1,84 @@
+use crate::storage::VaultItem;
+use chrono::{DateTime, Duration, Utc};
+use serde::Serialize;
+use std::collections::HashSet;
+
+/// Days before expiry at which a reminder is shown. `0` fires once the date has passed.
+pub const REMINDER_DAYS: [i64; 4] = [30, 7, 1, 0];
+
+#[derive(Debug, Serialize, Clone)]
+pub struct UpcomingExpiration {
+    pub id: String,
+    pub name: String,
+    pub expires_at: DateTime<Utc>,
+    pub days_remaining: i64,
+    pub expired: bool,
+}
+
+impl UpcomingExpiration {
+    pub fn reminder_message(&self) -> String {
+        match self.days_remaining {
+            _ if self.expired => format!("'{}' has expired.", self.name),
+            0 => format!("'{}' expires today.", self.name),
+            1 => format!("'{}' expires tomorrow.", self.name),
+            d => format!("'{}' expires in {} days.", self.name, d),
+        }
+    }
+}
+
+/// Items with an expiry date on or before `now + within`, soonest first. Already
+/// expired items are included.
+pub fn upcoming_expirations(items: &[VaultItem], now: DateTime<Utc>, within: Duration) -> Vec<UpcomingExpiration> {
+    let horizon = now + within;
+    let mut upcoming: Vec<UpcomingExpiration> = items
+        .iter()
+        .filter_map(|item| {
+            let expires_at = item.expires_at?;
+            if expires_at > horizon {
+                return None;
+            }
+            Some(UpcomingExpiration {
+                id: item.id.clone(),
+                name: item.name.clone(),
+                expires_at,
+                days_remaining: (expires_at - now).num_days(),
+                expired: expires_at <= now,
+            })
+        })
+        .collect();
+
+    upcoming.sort_by_key(|expiration| expiration.expires_at);
+    upcoming
+}
+
+/// Tracks which reminders have been shown so each threshold fires once per item
+/// and expiry date.
+#[derive(Debug, Default)]
+pub struct ReminderTracker {
+    sent: HashSet<(String, DateTime<Utc>, i64)>,
+}
+
+impl ReminderTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the expirations that crossed a reminder threshold since they were last seen.
+    pub fn due(&mut self, items: &[VaultItem], now: DateTime<Utc>) -> Vec<UpcomingExpiration> {
+        let window = Duration::days(REMINDER_DAYS[0]);
+        upcoming_expirations(items, now, window)
+            .into_iter()
+            .filter(|expiration| {
+                let threshold = REMINDER_DAYS
+                    .iter()
+                    .copied()
+                    .filter(|days| expiration.expires_at - now <= Duration::days(*days))
+                    .min();
+                match threshold {
+                    Some(days) => self.sent.insert((expiration.id.clone(), expiration.expires_at, days)),
+                    None => false,
+                }
+            })
+            .collect()
+    }
+}
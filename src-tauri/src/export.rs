@@ -0,0 +1,168 @@
+use crate::crypto::Crypto;
+use crate::kdbx::{self, Attachment, Database, Entry, Group, Times};
+use crate::login::LoginFields;
+use crate::ssh::SSH_KEY_ITEM_TYPE;
+use crate::storage::{ItemMetadata, Storage, VaultItem};
+use crate::Result;
+use log::{info, warn};
+use std::collections::{HashMap, HashSet};
+use uuid::Uuid;
+
+const KEEPASS_DATABASE_NAME: &str = "Fetch";
+
+/// Builds a KeePass database from the whole vault. Folders become groups, text
+/// items become entries with their password history, and files stored under an
+/// item become its attachments.
+pub fn to_keepass(storage: &dyn Storage, crypto: &Crypto) -> Result<Database> {
+    let items = storage.get_all_items_recursive(crypto)?;
+
+    let ids: HashSet<&str> = items.iter().map(|item| item.id.as_str()).collect();
+    let mut children: HashMap<Option<&str>, Vec<&VaultItem>> = HashMap::new();
+    for item in &items {
+        // Items whose parent no longer exists are exported at the top level.
+        let parent_id = item.parent_id.as_deref().filter(|parent_id| ids.contains(parent_id));
+        children.entry(parent_id).or_default().push(item);
+    }
+
+    let mut root = Group {
+        uuid: Uuid::new_v4(),
+        name: KEEPASS_DATABASE_NAME.to_string(),
+        ..Default::default()
+    };
+    let exporter = KeePassExporter { storage, crypto, children };
+    exporter.fill_group(&mut root, None)?;
+
+    info!("Exported {} vault items to KeePass.", items.len());
+    Ok(Database {
+        name: KEEPASS_DATABASE_NAME.to_string(),
+        root,
+        recycle_bin: None,
+    })
+}
+
+struct KeePassExporter<'a> {
+    storage: &'a dyn Storage,
+    crypto: &'a Crypto,
+    children: HashMap<Option<&'a str>, Vec<&'a VaultItem>>,
+}
+
+impl<'a> KeePassExporter<'a> {
+    fn children_of(&self, parent_id: Option<&'a str>) -> &[&'a VaultItem] {
+        self.children.get(&parent_id).map(Vec::as_slice).unwrap_or_default()
+    }
+
+    fn fill_group(&self, group: &mut Group, parent_id: Option<&'a str>) -> Result<()> {
+        for &item in self.children_of(parent_id) {
+            if item.item_type == "folder" {
+                let mut child = Group {
+                    uuid: item_uuid(item),
+                    name: item.name.clone(),
+                    times: item_times(item),
+                    ..Default::default()
+                };
+                self.fill_group(&mut child, Some(&item.id))?;
+                group.groups.push(child);
+            } else {
+                group.entries.push(self.entry(item)?);
+            }
+        }
+        Ok(())
+    }
+
+    fn content(&self, item: &VaultItem) -> Result<Vec<u8>> {
+        if item.data_path.is_empty() {
+            return Ok(Vec::new());
+        }
+        self.storage.read_encrypted_file(&item.data_path, self.crypto)
+    }
+
+    fn entry(&self, item: &'a VaultItem) -> Result<Entry> {
+        let content = self.content(item)?;
+        let mut entry = Entry {
+            uuid: item_uuid(item),
+            tags: item.tags.clone(),
+            times: item_times(item),
+            ..Default::default()
+        };
+        entry.set(kdbx::TITLE, &item.name, false);
+
+        if item.item_type.starts_with("text/") {
+            let text = String::from_utf8_lossy(&content);
+            let fields = LoginFields::parse(&text);
+            if fields.has_fields() {
+                set_login_fields(&mut entry, &fields);
+            } else {
+                entry.set(kdbx::NOTES, text.trim(), false);
+            }
+        } else if item.item_type == "key" {
+            entry.set(kdbx::PASSWORD, String::from_utf8_lossy(&content).trim(), true);
+        } else {
+            if let (SSH_KEY_ITEM_TYPE, Some(ItemMetadata::SshKey(metadata))) = (item.item_type.as_str(), &item.metadata) {
+                entry.set(kdbx::NOTES, &metadata.public_key, false);
+            }
+            entry.attachments.push(Attachment { name: item.name.clone(), data: content });
+        }
+
+        for child in self.children_of(Some(&item.id)) {
+            if child.item_type == "folder" {
+                warn!("Skipping folder '{}' nested under item '{}' in KeePass export.", child.name, item.name);
+                continue;
+            }
+            entry.attachments.push(Attachment { name: child.name.clone(), data: self.content(child)? });
+        }
+
+        // Each earlier version was saved when the password before it was replaced.
+        let mut history = self.storage.get_password_history(&item.id, self.crypto)?;
+        history.reverse();
+        let mut saved_at = item.created_at;
+        for old in history {
+            let mut version = Entry {
+                uuid: entry.uuid,
+                fields: entry.fields.clone(),
+                tags: entry.tags.clone(),
+                times: Times { modified: Some(saved_at), ..entry.times.clone() },
+                ..Default::default()
+            };
+            version.set(kdbx::PASSWORD, old.password, true);
+            entry.history.push(version);
+            saved_at = old.changed_at;
+        }
+
+        Ok(entry)
+    }
+}
+
+fn set_login_fields(entry: &mut Entry, fields: &LoginFields) {
+    let standard = [
+        (kdbx::USERNAME, &fields.username, false),
+        (kdbx::PASSWORD, &fields.password, true),
+        (kdbx::URL, &fields.url, false),
+        (kdbx::NOTES, &fields.notes, false),
+    ];
+    for (key, value, protected) in standard {
+        if let Some(value) = value {
+            entry.set(key, value.as_str(), protected);
+        }
+    }
+
+    if let Some(totp) = &fields.totp {
+        // KeePassXC reads `otpauth://` URIs from `otp` and bare secrets from `TOTP Seed`.
+        let key = if totp.starts_with("otpauth://") { "otp" } else { "TOTP Seed" };
+        entry.set(key, totp.as_str(), true);
+    }
+    for (label, value) in &fields.fields {
+        entry.set(label, value.as_str(), false);
+    }
+}
+
+fn item_uuid(item: &VaultItem) -> Uuid {
+    Uuid::parse_str(&item.id).unwrap_or_else(|_| Uuid::new_v4())
+}
+
+fn item_times(item: &VaultItem) -> Times {
+    Times {
+        created: Some(item.created_at),
+        modified: Some(item.updated_at),
+        expires: item.expires_at,
+    }
+}
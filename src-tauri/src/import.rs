@@ -1,89 +1,316 @@
-use log::{info, error};
+use log::{info, error, debug};
+use crate::crypto::Crypto;
 use crate::error::Error;
-use crate::storage::VaultItem;
+use crate::login::LoginFields;
+use crate::storage::{PasswordHistoryEntry, Storage, VaultItem};
+use crate::Result;
+use chrono::{DateTime, Datelike, Duration, NaiveDate, Utc};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use uuid::Uuid;
 
-pub type Result<T> = std::result::Result<T, Error>;
+mod bitwarden;
+mod csv;
+mod decrypted;
+mod keepass;
+mod onepassword;
 
-#[derive(Debug, Serialize, Deserialize)]
+pub use self::bitwarden::BitwardenImporter;
+pub use self::csv::{CsvColumn, CsvColumnMapping, CsvImportOptions, CsvImporter, CsvLayout, TextEncoding};
+pub use self::decrypted::DecryptedExportImporter;
+pub use self::keepass::KeePassImporter;
+pub use self::onepassword::OnePasswordImporter;
+
+/// Tag given to items the source password manager marked as favorites.
+const FAVORITE_TAG: &str = "favorite";
+
+#[derive(Debug, Serialize, Deserialize, Default, Clone)]
 pub struct ImportResult {
     pub success_count: usize,
     pub error_count: usize,
     pub errors: Vec<String>,
 }
 
+impl ImportResult {
+    pub fn record_error(&mut self, row: usize, message: impl std::fmt::Display) {
+        error!("Import row {}: {}", row, message);
+        self.errors.push(format!("Row {}: {}", row, message));
+        self.error_count += 1;
+    }
+}
+
+/// A vault item produced by an importer, with the plaintext content to store
+/// alongside it. Items whose `parent_id` is `None` land in the import target folder.
 #[derive(Debug)]
 pub struct ImportedItem {
     pub vault_item: VaultItem,
-    pub password_data: Option<String>,
-}
-
-pub struct Importer;
-
-impl Importer {
-    pub fn import_csv(file_path: &str) -> Result<(Vec<ImportedItem>, ImportResult)> {
-        info!("Attempting to open CSV file: {}", file_path);
-        
-        // Convert the path to a PathBuf and normalize it
-        let path = std::path::Path::new(file_path);
-        info!("File path exists: {}", path.exists());
-        info!("File path is absolute: {}", path.is_absolute());
-        info!("File path components: {:?}", path.components().collect::<Vec<_>>());
-        
-        // Get the canonical path (resolves any .. or . in the path)
-        let canonical_path = path.canonicalize()?;
-        info!("Canonical path: {}", canonical_path.display());
-        
-        // Open and read the file
-        let file = std::fs::File::open(&canonical_path)?;
-        let mut rdr = csv::Reader::from_reader(file);
-        
-        let mut imported_items = Vec::new();
-        let mut success_count = 0;
-        let mut error_count = 0;
-        let mut errors = Vec::new();
-        
-        // Read the CSV records
-        for result in rdr.records() {
-            match result {
-                Ok(record) => {
-                    match parse_csv_record(&record) {
-                        Ok(item) => {
-                            imported_items.push(item);
-                            success_count += 1;
-                        }
-                        Err(e) => {
-                            error!("Failed to parse CSV record: {}", e);
-                            errors.push(format!("Failed to parse record: {}", e));
-                            error_count += 1;
-                        }
-                    }
+    pub content: Option<Vec<u8>>,
+    /// Earlier passwords of the item, recorded as its password history.
+    pub password_history: Vec<PasswordHistoryEntry>,
+}
+
+/// The output of an importer: the items it could parse and the rows it could not.
+#[derive(Debug, Default)]
+pub struct ParsedImport {
+    pub items: Vec<ImportedItem>,
+    pub result: ImportResult,
+}
+
+/// A source format that can be turned into vault items. Importers only parse;
+/// writing to the vault is done by [`store_items`].
+pub trait Importer {
+    fn name(&self) -> &'static str;
+
+    fn parse(&self, data: &[u8]) -> Result<ParsedImport>;
+}
+
+pub fn new_item(name: String, item_type: &str, parent_id: Option<String>, tags: Vec<String>) -> VaultItem {
+    let now = Utc::now();
+    VaultItem {
+        id: Uuid::new_v4().to_string(),
+        parent_id,
+        name,
+        data_path: String::new(),
+        item_type: item_type.to_string(),
+        folder_type: None,
+        tags,
+        created_at: now,
+        updated_at: now,
+        expires_at: None,
+        metadata: None,
+    }
+}
+
+/// The last second of a month, used as the expiry of cards and similar items.
+pub fn end_of_month(year: i32, month: u32) -> Option<DateTime<Utc>> {
+    let first_of_month = NaiveDate::from_ymd_opt(year, month, 1)?;
+    let next_month = if month == 12 {
+        NaiveDate::from_ymd_opt(year + 1, 1, 1)?
+    } else {
+        first_of_month.with_month(month + 1)?
+    };
+    Some(next_month.and_hms_opt(0, 0, 0)?.and_utc() - Duration::seconds(1))
+}
+
+/// Creates folder items for slash-separated folder paths, once per path.
+#[derive(Default)]
+pub struct FolderBuilder {
+    ids: HashMap<String, String>,
+}
+
+impl FolderBuilder {
+    /// Returns the id of the innermost folder of `path`, adding any missing
+    /// folders along the way.
+    pub fn folder_for(&mut self, path: &str, items: &mut Vec<ImportedItem>) -> Option<String> {
+        let mut parent_id: Option<String> = None;
+        let mut current_path = String::new();
+
+        for segment in path.split(['/', '\\']).map(str::trim).filter(|s| !s.is_empty()) {
+            if !current_path.is_empty() {
+                current_path.push('/');
+            }
+            current_path.push_str(segment);
+
+            let id = match self.ids.get(&current_path) {
+                Some(id) => id.clone(),
+                None => {
+                    let folder = new_item(segment.to_string(), "folder", parent_id.clone(), Vec::new());
+                    let id = folder.id.clone();
+                    items.push(ImportedItem { vault_item: folder, content: None, password_history: Vec::new() });
+                    self.ids.insert(current_path.clone(), id.clone());
+                    id
                 }
-                Err(e) => {
-                    error!("Failed to read CSV record: {}", e);
-                    errors.push(format!("Failed to read record: {}", e));
-                    error_count += 1;
+            };
+            parent_id = Some(id);
+        }
+
+        parent_id
+    }
+}
+
+/// How an imported item will be stored, shown in the import preview.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum ImportedKind {
+    Login,
+    Note,
+    File,
+    Folder,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct PreviewItem {
+    pub name: String,
+    pub kind: ImportedKind,
+    pub item_type: String,
+    pub tags: Vec<String>,
+}
+
+/// What an import would write, without touching the vault.
+#[derive(Debug, Serialize, Clone)]
+pub struct ImportPreview {
+    pub items: Vec<PreviewItem>,
+    pub result: ImportResult,
+}
+
+pub fn preview(parsed: &ParsedImport) -> ImportPreview {
+    let items = parsed
+        .items
+        .iter()
+        .map(|imported| {
+            let item = &imported.vault_item;
+            let kind = if item.item_type == "folder" {
+                ImportedKind::Folder
+            } else if item.item_type.starts_with("text/") {
+                let content = imported.content.as_deref().unwrap_or_default();
+                if LoginFields::parse(&String::from_utf8_lossy(content)).is_login() {
+                    ImportedKind::Login
+                } else {
+                    ImportedKind::Note
                 }
+            } else {
+                ImportedKind::File
+            };
+            PreviewItem {
+                name: item.name.clone(),
+                kind,
+                item_type: item.item_type.clone(),
+                tags: item.tags.clone(),
             }
+        })
+        .collect();
+
+    let mut result = parsed.result.clone();
+    // Folders are created along the way; only entries count as imported.
+    result.success_count = parsed.items.iter().filter(|imported| imported.vault_item.item_type != "folder").count();
+    ImportPreview { items, result }
+}
+
+/// Fails if any parsed item keeps the id of an item already in the vault, as
+/// a Fetch export imported without new ids would.
+pub fn ensure_ids_are_new(storage: &dyn Storage, crypto: &Crypto, parsed: &ParsedImport) -> Result<()> {
+    for imported in &parsed.items {
+        if storage.get_item(&imported.vault_item.id, crypto)?.is_some() {
+            return Err(Error::InvalidInput(format!(
+                "Item '{}' already exists in the vault. Import with new ids to keep both copies.",
+                imported.vault_item.name
+            )));
         }
-        
-        let content = std::fs::read_to_string(&canonical_path)?;
-        info!("Successfully read CSV file, content length: {}", content.len());
-        
-        let import_result = ImportResult {
-            success_count,
-            error_count,
-            errors,
+    }
+    Ok(())
+}
+
+/// Writes parsed items to the vault in a single transaction. Rows that failed to
+/// parse stay in the returned error list; a failure while storing rolls back the
+/// whole import.
+///
+/// Imported folders that match an existing folder by name under the same parent
+/// are merged into it. Importers must list folders before their contents.
+pub fn store_items(storage: &dyn Storage, crypto: &Crypto, parsed: ParsedImport, parent_id: Option<String>) -> Result<ImportResult> {
+    let ParsedImport { items, mut result } = parsed;
+
+    let mut existing_folders: HashMap<(Option<String>, String), String> = storage
+        .get_all_items_recursive(crypto)?
+        .into_iter()
+        .filter(|item| item.item_type == "folder")
+        .map(|folder| ((folder.parent_id, folder.name), folder.id))
+        .collect();
+    let mut merged_folders: HashMap<String, String> = HashMap::new();
+
+    let mut password_history = Vec::new();
+    let mut batch: Vec<(VaultItem, Option<Vec<u8>>)> = Vec::new();
+    for imported in items {
+        let mut item = imported.vault_item;
+        item.parent_id = match item.parent_id {
+            Some(id) => Some(merged_folders.get(&id).cloned().unwrap_or(id)),
+            None => parent_id.clone(),
         };
-        
-        info!("CSV import completed: {} successful, {} errors", success_count, error_count);
-        
-        Ok((imported_items, import_result))
+
+        if item.item_type == "folder" {
+            let key = (item.parent_id.clone(), item.name.clone());
+            if let Some(existing_id) = existing_folders.get(&key) {
+                debug!("Merging imported folder {} into existing folder {}", item.name, existing_id);
+                merged_folders.insert(item.id, existing_id.clone());
+                continue;
+            }
+            existing_folders.insert(key, item.id.clone());
+        }
+
+        if imported.content.is_some() {
+            item.data_path = Uuid::new_v4().to_string();
+        }
+        debug!("Prepared imported item {} ({})", item.name, item.id);
+        password_history.extend(imported.password_history);
+        batch.push((item, imported.content));
     }
+
+    storage.add_items_atomically(&batch, &password_history, crypto)?;
+    result.success_count = batch.iter().filter(|(item, _)| item.item_type != "folder").count();
+
+    info!("Import completed: {} successful, {} errors", result.success_count, result.error_count);
+    Ok(result)
 }
 
-fn parse_csv_record(record: &csv::StringRecord) -> Result<ImportedItem> {
-    // This is a placeholder implementation - you'll need to implement the actual CSV parsing logic
-    // based on your CSV format and VaultItem structure
-    todo!("Implement CSV record parsing")
-} 
\ No newline at end of file
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+    use crate::vault;
+
+    fn imported(vault_item: VaultItem, content: Option<&[u8]>) -> ImportedItem {
+        ImportedItem { vault_item, content: content.map(<[u8]>::to_vec), password_history: Vec::new() }
+    }
+
+    #[test]
+    fn stores_items_and_merges_folders_by_name() {
+        let (storage, crypto) = MemoryStorage::unlocked();
+        let existing = vault::add_folder(&storage, &crypto, "Work".to_string(), None, None).unwrap();
+
+        let folder = new_item("Work".to_string(), "folder", None, Vec::new());
+        let note = new_item("Note".to_string(), "text/plain", Some(folder.id.clone()), vec!["imported".to_string()]);
+        let note_id = note.id.clone();
+        let mut parsed = ParsedImport { items: vec![imported(folder, None), imported(note, Some(b"secret"))], result: ImportResult::default() };
+        parsed.result.record_error(3, "unreadable row");
+
+        let result = store_items(&storage, &crypto, parsed, None).unwrap();
+
+        assert_eq!((result.success_count, result.error_count), (1, 1));
+        assert_eq!(storage.get_all_items_recursive(&crypto).unwrap().len(), 2);
+        let note = storage.get_item(&note_id, &crypto).unwrap().unwrap();
+        assert_eq!(note.parent_id.as_deref(), Some(existing.id.as_str()));
+        assert_eq!(vault::item_content(&storage, &crypto, &note_id).unwrap(), b"secret");
+    }
+
+    #[test]
+    fn previews_without_counting_folders() {
+        let folder = new_item("Work".to_string(), "folder", None, Vec::new());
+        let login = new_item("Mail".to_string(), "text/plain", Some(folder.id.clone()), Vec::new());
+        let note = new_item("Note".to_string(), "text/plain", Some(folder.id.clone()), Vec::new());
+        let file = new_item("scan.pdf".to_string(), "application/pdf", None, Vec::new());
+        let parsed = ParsedImport {
+            items: vec![imported(folder, None), imported(login, Some(b"Password: hunter2")), imported(note, Some(b"Meeting: Tuesday")), imported(file, Some(b"%PDF"))],
+            result: ImportResult::default(),
+        };
+
+        let preview = preview(&parsed);
+        let kinds: Vec<ImportedKind> = preview.items.iter().map(|item| item.kind).collect();
+        assert_eq!(kinds, [ImportedKind::Folder, ImportedKind::Login, ImportedKind::Note, ImportedKind::File]);
+        assert_eq!(preview.result.success_count, 3);
+    }
+
+    #[test]
+    fn rolls_back_the_whole_import_when_a_row_fails() {
+        let (storage, crypto) = MemoryStorage::unlocked();
+        let existing = vault::add_text_item(&storage, &crypto, "Existing".to_string(), "text".to_string(), b"kept", Vec::new(), None).unwrap();
+
+        let fresh = new_item("Fresh".to_string(), "text/plain", None, Vec::new());
+        let mut clashing = new_item("Clashing".to_string(), "text/plain", None, Vec::new());
+        clashing.id = existing.id.clone();
+        let parsed = ParsedImport { items: vec![imported(fresh.clone(), Some(b"new")), imported(clashing, Some(b"other"))], result: ImportResult::default() };
+
+        assert!(store_items(&storage, &crypto, parsed, None).is_err());
+
+        assert!(storage.get_item(&fresh.id, &crypto).unwrap().is_none());
+        assert_eq!(storage.data_file_names().unwrap(), std::slice::from_ref(&existing.data_path));
+        assert_eq!(vault::item_content(&storage, &crypto, &existing.id).unwrap(), b"kept");
+    }
+}
@@ -0,0 +1,589 @@
+use super::{end_of_month, new_item, FolderBuilder, FAVORITE_TAG, ImportedItem, Importer, ParsedImport};
+use crate::crypto;
+use crate::error::Error;
+use crate::login::LoginFields;
+use crate::ssh::{self, SSH_KEY_ITEM_TYPE};
+use crate::storage::{ItemMetadata, PasswordHistoryEntry};
+use crate::Result;
+use aes::cipher::block_padding::Pkcs7;
+use aes::cipher::{BlockDecryptMut, KeyIvInit};
+use aes::Aes256;
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use chrono::{DateTime, Utc};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use log::{debug, info};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use uuid::Uuid;
+use zeroize::Zeroizing;
+
+const LOGIN: u8 = 1;
+const SECURE_NOTE: u8 = 2;
+const CARD: u8 = 3;
+const IDENTITY: u8 = 4;
+const SSH_KEY: u8 = 5;
+
+const KDF_PBKDF2: u8 = 0;
+const KDF_ARGON2ID: u8 = 1;
+/// The most PBKDF2 iterations the Bitwarden clients allow.
+const MAX_PBKDF2_ITERATIONS: u32 = 2_000_000;
+
+const LINKED_FIELD: u8 = 3;
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Export {
+    #[serde(default)]
+    encrypted: bool,
+    #[serde(default)]
+    password_protected: bool,
+    salt: Option<String>,
+    kdf_type: Option<u8>,
+    kdf_iterations: Option<u32>,
+    kdf_memory: Option<u32>,
+    kdf_parallelism: Option<u32>,
+    #[serde(rename = "encKeyValidation_DO_NOT_EDIT")]
+    enc_key_validation: Option<String>,
+    data: Option<String>,
+    #[serde(default)]
+    folders: Vec<Folder>,
+    #[serde(default)]
+    collections: Vec<Folder>,
+    #[serde(default)]
+    items: Vec<Item>,
+}
+
+#[derive(Deserialize)]
+struct Folder {
+    id: String,
+    name: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Item {
+    #[serde(rename = "type")]
+    item_type: u8,
+    name: Option<String>,
+    notes: Option<String>,
+    folder_id: Option<String>,
+    #[serde(default)]
+    collection_ids: Option<Vec<String>>,
+    #[serde(default)]
+    favorite: bool,
+    #[serde(default)]
+    fields: Option<Vec<Field>>,
+    login: Option<Login>,
+    card: Option<Card>,
+    identity: Option<Identity>,
+    ssh_key: Option<SshKey>,
+    #[serde(default)]
+    password_history: Option<Vec<PasswordHistory>>,
+    creation_date: Option<DateTime<Utc>>,
+    revision_date: Option<DateTime<Utc>>,
+    deleted_date: Option<DateTime<Utc>>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Field {
+    name: Option<String>,
+    value: Option<String>,
+    #[serde(rename = "type", default)]
+    field_type: u8,
+}
+
+#[derive(Deserialize)]
+struct Login {
+    username: Option<String>,
+    password: Option<String>,
+    totp: Option<String>,
+    #[serde(default)]
+    uris: Option<Vec<LoginUri>>,
+}
+
+#[derive(Deserialize)]
+struct LoginUri {
+    uri: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Card {
+    cardholder_name: Option<String>,
+    brand: Option<String>,
+    number: Option<String>,
+    exp_month: Option<String>,
+    exp_year: Option<String>,
+    code: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Identity {
+    title: Option<String>,
+    first_name: Option<String>,
+    middle_name: Option<String>,
+    last_name: Option<String>,
+    address1: Option<String>,
+    address2: Option<String>,
+    address3: Option<String>,
+    city: Option<String>,
+    state: Option<String>,
+    postal_code: Option<String>,
+    country: Option<String>,
+    company: Option<String>,
+    email: Option<String>,
+    phone: Option<String>,
+    ssn: Option<String>,
+    username: Option<String>,
+    passport_number: Option<String>,
+    license_number: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SshKey {
+    private_key: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PasswordHistory {
+    last_used_date: Option<DateTime<Utc>>,
+    password: Option<String>,
+}
+
+/// Imports Bitwarden JSON exports, plain or protected by an export password.
+/// Folders and organization collections become folders, favorites get the
+/// `favorite` tag, and items in the trash are skipped.
+pub struct BitwardenImporter {
+    password: Option<Zeroizing<String>>,
+}
+
+impl BitwardenImporter {
+    pub fn new(password: Option<String>) -> Self {
+        Self { password: password.map(Zeroizing::new) }
+    }
+
+    fn decrypt_export(&self, export: &Export) -> Result<Export> {
+        if !export.password_protected {
+            return Err(Error::InvalidInput(
+                "This Bitwarden export is encrypted with your account key. Export it again with a file password instead.".into(),
+            ));
+        }
+        let password = self
+            .password
+            .as_deref()
+            .ok_or_else(|| Error::InvalidInput("This Bitwarden export is protected by a password".into()))?;
+        let missing = |field: &str| Error::InvalidInput(format!("Encrypted Bitwarden export is missing '{}'", field));
+
+        let salt = export.salt.as_deref().ok_or_else(|| missing("salt"))?;
+        let key = derive_key(password, salt, export)?;
+        let validation = export.enc_key_validation.as_deref().ok_or_else(|| missing("encKeyValidation_DO_NOT_EDIT"))?;
+        EncString::parse(validation)?
+            .decrypt(&key)
+            .map_err(|_| Error::Decryption("Invalid Bitwarden export password".into()))?;
+
+        let data = EncString::parse(export.data.as_deref().ok_or_else(|| missing("data"))?)?.decrypt(&key)?;
+        Ok(serde_json::from_slice(&data)?)
+    }
+}
+
+impl Importer for BitwardenImporter {
+    fn name(&self) -> &'static str {
+        "Bitwarden"
+    }
+
+    fn parse(&self, data: &[u8]) -> Result<ParsedImport> {
+        let mut export: Export = serde_json::from_slice(data)?;
+        if export.encrypted {
+            export = self.decrypt_export(&export)?;
+        }
+
+        let mut parsed = ParsedImport::default();
+        let mut folders = FolderBuilder::default();
+        let mut folder_ids: HashMap<&str, Option<String>> = HashMap::new();
+        for folder in &export.folders {
+            folder_ids.insert(&folder.id, folders.folder_for(&folder.name, &mut parsed.items));
+        }
+        let collection_names: HashMap<&str, &str> = export.collections.iter().map(|c| (c.id.as_str(), c.name.as_str())).collect();
+
+        for (index, item) in export.items.iter().enumerate() {
+            if item.deleted_date.is_some() {
+                debug!("Skipping Bitwarden item in trash");
+                continue;
+            }
+
+            // Items from organization exports have collections instead of a folder.
+            let collections: Vec<&str> = item
+                .collection_ids
+                .iter()
+                .flatten()
+                .filter_map(|id| collection_names.get(id.as_str()).copied())
+                .collect();
+            let parent_id = match (&item.folder_id, collections.first()) {
+                (Some(folder_id), _) => folder_ids.get(folder_id.as_str()).cloned().flatten(),
+                (None, Some(collection)) => folders.folder_for(collection, &mut parsed.items),
+                (None, None) => None,
+            };
+
+            match item_to_import(item, parent_id) {
+                Ok(imported) => parsed.items.push(imported),
+                Err(e) => parsed.result.record_error(index + 1, e),
+            }
+        }
+
+        info!("Parsed {} Bitwarden items, {} errors", export.items.len(), parsed.result.error_count);
+        Ok(parsed)
+    }
+}
+
+fn item_to_import(item: &Item, parent_id: Option<String>) -> Result<ImportedItem> {
+    let name = item
+        .name
+        .as_deref()
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .ok_or_else(|| Error::InvalidInput("Item has no name".into()))?
+        .to_string();
+
+    let mut fields = LoginFields {
+        notes: item.notes.clone(),
+        ..Default::default()
+    };
+    let mut item_type = "text/plain";
+    let mut metadata = None;
+    let mut expires_at = None;
+    let mut content = None;
+
+    match item.item_type {
+        LOGIN => {
+            if let Some(login) = &item.login {
+                fields.username = login.username.clone();
+                fields.password = login.password.clone();
+                fields.totp = login.totp.clone();
+                let mut uris = login.uris.iter().flatten().filter_map(|uri| uri.uri.as_deref());
+                fields.url = uris.next().map(str::to_string);
+                for (index, uri) in uris.enumerate() {
+                    fields.add_field(&format!("URL {}", index + 2), uri);
+                }
+            }
+        }
+        SECURE_NOTE => {}
+        CARD => {
+            if let Some(card) = &item.card {
+                add_fields(&mut fields, [
+                    ("Cardholder Name", &card.cardholder_name),
+                    ("Brand", &card.brand),
+                    ("Number", &card.number),
+                    ("Security Code", &card.code),
+                ]);
+                if let (Some(month), Some(year)) = (&card.exp_month, &card.exp_year) {
+                    fields.add_field("Expiration", &format!("{:0>2}/{}", month.trim(), year.trim()));
+                    expires_at = card_expiry(month, year);
+                }
+            }
+        }
+        IDENTITY => {
+            if let Some(identity) = &item.identity {
+                add_identity_fields(&mut fields, identity);
+            }
+        }
+        SSH_KEY => {
+            let private_key = item
+                .ssh_key
+                .as_ref()
+                .and_then(|key| key.private_key.as_deref())
+                .ok_or_else(|| Error::InvalidInput(format!("SSH key '{}' has no private key", name)))?;
+            let key = ssh::import(private_key, None, &name)?;
+            item_type = SSH_KEY_ITEM_TYPE;
+            metadata = Some(ItemMetadata::SshKey(key.metadata));
+            content = Some(key.private_key.as_bytes().to_vec());
+        }
+        other => return Err(Error::InvalidInput(format!("Unsupported Bitwarden item type {}", other))),
+    }
+
+    for field in item.fields.iter().flatten() {
+        if field.field_type == LINKED_FIELD {
+            continue;
+        }
+        if let (Some(label), Some(value)) = (&field.name, &field.value) {
+            fields.add_field(label, value);
+        }
+    }
+
+    let content = match content {
+        Some(content) => content,
+        None => {
+            let text = fields.to_content();
+            if text.is_empty() {
+                return Err(Error::InvalidInput(format!("Item '{}' has no content to store", name)));
+            }
+            text.into_bytes()
+        }
+    };
+
+    let tags = if item.favorite { vec![FAVORITE_TAG.to_string()] } else { Vec::new() };
+    let mut vault_item = new_item(name, item_type, parent_id, tags);
+    vault_item.created_at = item.creation_date.unwrap_or(vault_item.created_at);
+    vault_item.updated_at = item.revision_date.unwrap_or(vault_item.updated_at);
+    vault_item.expires_at = expires_at;
+    vault_item.metadata = metadata;
+
+    let password_history = item
+        .password_history
+        .iter()
+        .flatten()
+        .filter_map(|entry| {
+            Some(PasswordHistoryEntry {
+                id: Uuid::new_v4().to_string(),
+                item_id: vault_item.id.clone(),
+                password: entry.password.clone()?,
+                changed_at: entry.last_used_date.unwrap_or(vault_item.updated_at),
+            })
+        })
+        .collect();
+
+    Ok(ImportedItem {
+        vault_item,
+        content: Some(content),
+        password_history,
+    })
+}
+
+fn add_fields<const N: usize>(fields: &mut LoginFields, values: [(&str, &Option<String>); N]) {
+    for (label, value) in values {
+        if let Some(value) = value {
+            fields.add_field(label, value);
+        }
+    }
+}
+
+fn add_identity_fields(fields: &mut LoginFields, identity: &Identity) {
+    let full_name = [&identity.title, &identity.first_name, &identity.middle_name, &identity.last_name]
+        .into_iter()
+        .flatten()
+        .map(|part| part.trim())
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ");
+    fields.add_field("Name", &full_name);
+    fields.username = identity.username.clone();
+
+    let address = [&identity.address1, &identity.address2, &identity.address3]
+        .into_iter()
+        .flatten()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<_>>()
+        .join(", ");
+    fields.add_field("Address", &address);
+
+    add_fields(fields, [
+        ("City", &identity.city),
+        ("State", &identity.state),
+        ("Postal Code", &identity.postal_code),
+        ("Country", &identity.country),
+        ("Company", &identity.company),
+        ("Email", &identity.email),
+        ("Phone", &identity.phone),
+        ("SSN", &identity.ssn),
+        ("Passport Number", &identity.passport_number),
+        ("License Number", &identity.license_number),
+    ]);
+}
+
+fn card_expiry(month: &str, year: &str) -> Option<DateTime<Utc>> {
+    let mut year: i32 = year.trim().parse().ok()?;
+    if year < 100 {
+        year += 2000;
+    }
+    end_of_month(year, month.trim().parse().ok()?)
+}
+
+struct ExportKey {
+    enc_key: Zeroizing<Vec<u8>>,
+    mac_key: Zeroizing<Vec<u8>>,
+}
+
+/// Derives the export key like the Bitwarden clients: PBKDF2-SHA256 or Argon2id
+/// over the password, stretched into encryption and MAC keys with HKDF-Expand.
+fn derive_key(password: &str, salt: &str, export: &Export) -> Result<ExportKey> {
+    let iterations = export.kdf_iterations.ok_or_else(|| Error::InvalidInput("Encrypted Bitwarden export is missing 'kdfIterations'".into()))?;
+    let mut master_key = Zeroizing::new([0u8; 32]);
+
+    match export.kdf_type.unwrap_or(KDF_PBKDF2) {
+        KDF_PBKDF2 => {
+            if iterations > MAX_PBKDF2_ITERATIONS {
+                return Err(Error::KeyDerivation(format!("PBKDF2 iteration count {} is too large", iterations)));
+            }
+            pbkdf2::pbkdf2_hmac::<Sha256>(password.as_bytes(), salt.as_bytes(), iterations, master_key.as_mut());
+        }
+        KDF_ARGON2ID => {
+            let memory_mib = export.kdf_memory.ok_or_else(|| Error::InvalidInput("Encrypted Bitwarden export is missing 'kdfMemory'".into()))?;
+            let parallelism = export.kdf_parallelism.ok_or_else(|| Error::InvalidInput("Encrypted Bitwarden export is missing 'kdfParallelism'".into()))?;
+            let memory_kib = memory_mib.checked_mul(1024).ok_or_else(|| Error::KeyDerivation("Argon2 memory cost is too large".into()))?;
+            let params = crypto::untrusted_argon2_params(memory_kib, iterations, parallelism, 32)?;
+            argon2::Argon2::new(argon2::Algorithm::Argon2id, argon2::Version::V0x13, params)
+                .hash_password_into(password.as_bytes(), &Sha256::digest(salt.as_bytes()), master_key.as_mut())
+                .map_err(|e| Error::KeyDerivation(e.to_string()))?;
+        }
+        other => return Err(Error::InvalidInput(format!("Unsupported Bitwarden KDF type {}", other))),
+    }
+
+    let hkdf = Hkdf::<Sha256>::from_prk(master_key.as_ref()).map_err(|e| Error::KeyDerivation(e.to_string()))?;
+    let mut enc_key = Zeroizing::new(vec![0u8; 32]);
+    let mut mac_key = Zeroizing::new(vec![0u8; 32]);
+    hkdf.expand(b"enc", &mut enc_key).map_err(|e| Error::KeyDerivation(e.to_string()))?;
+    hkdf.expand(b"mac", &mut mac_key).map_err(|e| Error::KeyDerivation(e.to_string()))?;
+    Ok(ExportKey { enc_key, mac_key })
+}
+
+/// A Bitwarden `EncString` of type 2: `2.iv|ciphertext|mac`, AES-256-CBC with HMAC-SHA256.
+struct EncString {
+    iv: Vec<u8>,
+    ciphertext: Vec<u8>,
+    mac: Vec<u8>,
+}
+
+impl EncString {
+    fn parse(value: &str) -> Result<Self> {
+        let invalid = || Error::InvalidInput("Invalid Bitwarden encrypted value".into());
+        let (enc_type, rest) = value.split_once('.').ok_or_else(invalid)?;
+        if enc_type != "2" {
+            return Err(Error::InvalidInput(format!("Unsupported Bitwarden encryption type {}", enc_type)));
+        }
+
+        let parts = rest
+            .split('|')
+            .map(|part| STANDARD.decode(part).map_err(|_| invalid()))
+            .collect::<Result<Vec<_>>>()?;
+        match <[Vec<u8>; 3]>::try_from(parts) {
+            Ok([iv, ciphertext, mac]) => Ok(Self { iv, ciphertext, mac }),
+            Err(_) => Err(invalid()),
+        }
+    }
+
+    fn decrypt(&self, key: &ExportKey) -> Result<Vec<u8>> {
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&key.mac_key).map_err(|e| Error::Decryption(e.to_string()))?;
+        mac.update(&self.iv);
+        mac.update(&self.ciphertext);
+        mac.verify_slice(&self.mac).map_err(|_| Error::Decryption("Bitwarden data failed authentication".into()))?;
+
+        cbc::Decryptor::<Aes256>::new_from_slices(&key.enc_key, &self.iv)
+            .map_err(|e| Error::Decryption(e.to_string()))?
+            .decrypt_padded_vec_mut::<Pkcs7>(&self.ciphertext)
+            .map_err(|e| Error::Decryption(e.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aes::cipher::BlockEncryptMut;
+    use serde_json::json;
+
+    const PASSWORD: &str = "export password";
+
+    fn plain_export() -> serde_json::Value {
+        json!({
+            "encrypted": false,
+            "folders": [{ "id": "f1", "name": "Work" }],
+            "items": [
+                {
+                    "type": 1,
+                    "name": "Mail",
+                    "folderId": "f1",
+                    "favorite": true,
+                    "login": { "username": "me", "password": "hunter2", "uris": [{ "uri": "https://mail.example" }] },
+                    "passwordHistory": [{ "password": "hunter1", "lastUsedDate": "2024-01-01T00:00:00Z" }]
+                },
+                { "type": 3, "name": "Visa", "card": { "number": "4111", "expMonth": "2", "expYear": "2030" } },
+                { "type": 2, "name": "Trashed", "notes": "gone", "deletedDate": "2024-01-01T00:00:00Z" },
+                { "type": 2, "notes": "no name" }
+            ]
+        })
+    }
+
+    fn encrypt(key: &ExportKey, plaintext: &[u8]) -> String {
+        let iv = [4u8; 16];
+        let ciphertext = cbc::Encryptor::<Aes256>::new_from_slices(&key.enc_key, &iv).unwrap().encrypt_padded_vec_mut::<Pkcs7>(plaintext);
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&key.mac_key).unwrap();
+        mac.update(&iv);
+        mac.update(&ciphertext);
+        format!("2.{}|{}|{}", STANDARD.encode(iv), STANDARD.encode(&ciphertext), STANDARD.encode(mac.finalize().into_bytes()))
+    }
+
+    /// A password-protected export of `plain_export` using the given KDF settings.
+    fn encrypted_export(kdf: serde_json::Value) -> Vec<u8> {
+        let mut export = json!({ "encrypted": true, "passwordProtected": true, "salt": "c2FsdA==" });
+        export.as_object_mut().unwrap().extend(kdf.as_object().unwrap().clone());
+        let key = derive_key(PASSWORD, "c2FsdA==", &serde_json::from_value(export.clone()).unwrap()).unwrap();
+        export["encKeyValidation_DO_NOT_EDIT"] = json!(encrypt(&key, Uuid::new_v4().to_string().as_bytes()));
+        export["data"] = json!(encrypt(&key, plain_export().to_string().as_bytes()));
+        export.to_string().into_bytes()
+    }
+
+    fn parse(password: Option<&str>, data: &[u8]) -> Result<ParsedImport> {
+        BitwardenImporter::new(password.map(str::to_string)).parse(data)
+    }
+
+    fn assert_plain_items(parsed: &ParsedImport) {
+        let names: Vec<&str> = parsed.items.iter().map(|item| item.vault_item.name.as_str()).collect();
+        assert_eq!(names, ["Work", "Mail", "Visa"]);
+        assert_eq!(parsed.result.error_count, 1);
+
+        let (folder, mail, card) = (&parsed.items[0], &parsed.items[1], &parsed.items[2]);
+        assert_eq!(mail.vault_item.parent_id.as_ref(), Some(&folder.vault_item.id));
+        assert_eq!(mail.vault_item.tags, [FAVORITE_TAG]);
+        let content = LoginFields::parse(std::str::from_utf8(mail.content.as_deref().unwrap()).unwrap());
+        assert_eq!(content.password.as_deref(), Some("hunter2"));
+        assert_eq!(content.url.as_deref(), Some("https://mail.example"));
+        assert_eq!(mail.password_history[0].password, "hunter1");
+        assert_eq!(card.vault_item.expires_at, end_of_month(2030, 2));
+    }
+
+    #[test]
+    fn parses_plain_exports() {
+        assert_plain_items(&parse(None, plain_export().to_string().as_bytes()).unwrap());
+    }
+
+    #[test]
+    fn decrypts_pbkdf2_exports() {
+        let data = encrypted_export(json!({ "kdfType": KDF_PBKDF2, "kdfIterations": 1000 }));
+        assert_plain_items(&parse(Some(PASSWORD), &data).unwrap());
+        assert!(matches!(parse(Some("wrong"), &data), Err(Error::Decryption(_))));
+        assert!(matches!(parse(None, &data), Err(Error::InvalidInput(_))));
+    }
+
+    #[test]
+    fn decrypts_argon2_exports() {
+        let data = encrypted_export(json!({ "kdfType": KDF_ARGON2ID, "kdfIterations": 2, "kdfMemory": 1, "kdfParallelism": 1 }));
+        assert_plain_items(&parse(Some(PASSWORD), &data).unwrap());
+        assert!(matches!(parse(Some("wrong"), &data), Err(Error::Decryption(_))));
+    }
+
+    #[test]
+    fn rejects_account_key_exports() {
+        let data = json!({ "encrypted": true, "encKeyValidation_DO_NOT_EDIT": "2.AA==|AA==|AA==", "data": "2.AA==|AA==|AA==" });
+        assert!(matches!(parse(Some(PASSWORD), data.to_string().as_bytes()), Err(Error::InvalidInput(_))));
+    }
+
+    #[test]
+    fn rejects_kdf_costs_over_the_limits() {
+        for kdf in [
+            json!({ "kdfType": KDF_PBKDF2, "kdfIterations": MAX_PBKDF2_ITERATIONS + 1 }),
+            json!({ "kdfType": KDF_ARGON2ID, "kdfIterations": 3, "kdfMemory": 4096, "kdfParallelism": 4 }),
+            json!({ "kdfType": KDF_ARGON2ID, "kdfIterations": 3, "kdfMemory": u32::MAX, "kdfParallelism": 4 }),
+            json!({ "kdfType": KDF_ARGON2ID, "kdfIterations": 3, "kdfMemory": 64, "kdfParallelism": 1000 }),
+        ] {
+            let mut export = json!({ "encrypted": true, "passwordProtected": true, "salt": "c2FsdA==", "encKeyValidation_DO_NOT_EDIT": "2.AA==|AA==|AA==", "data": "2.AA==|AA==|AA==" });
+            export.as_object_mut().unwrap().extend(kdf.as_object().unwrap().clone());
+            let result = parse(Some(PASSWORD), export.to_string().as_bytes());
+            assert!(matches!(result, Err(Error::KeyDerivation(_))), "{} was accepted", kdf);
+        }
+    }
+}
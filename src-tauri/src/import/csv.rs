@@ -0,0 +1,504 @@
+use super::{new_item, FolderBuilder, ImportedItem, Importer, ParsedImport};
+use crate::error::Error;
+use crate::login::LoginFields;
+use crate::Result;
+use ::csv::{ReaderBuilder, StringRecord};
+use log::{debug, info};
+use serde::{Deserialize, Serialize};
+
+const DELIMITER_CANDIDATES: [u8; 4] = [b',', b';', b'\t', b'|'];
+const DELIMITER_SAMPLE_RECORDS: usize = 20;
+const ENCODING_SAMPLE_BYTES: usize = 1024;
+const SAMPLE_ROWS: usize = 5;
+
+// Header names used by common exporters, in order of preference. Matched case-insensitively.
+const TITLE_HEADERS: [&str; 4] = ["account", "title", "name", "hostname"];
+const USERNAME_HEADERS: [&str; 5] = ["login name", "username", "login_username", "login", "user"];
+const PASSWORD_HEADERS: [&str; 2] = ["password", "login_password"];
+const URL_HEADERS: [&str; 4] = ["web site", "url", "website", "login_uri"];
+const NOTES_HEADERS: [&str; 4] = ["comments", "notes", "extra", "note"];
+const TAGS_HEADERS: [&str; 2] = ["tags", "labels"];
+const FOLDER_HEADERS: [&str; 4] = ["folder", "group", "grouping", "path"];
+const TOTP_HEADERS: [&str; 4] = ["totp", "login_totp", "otpauth", "one-time password"];
+
+/// A CSV column, addressed by header name or by zero-based position.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum CsvColumn {
+    Index(usize),
+    Header(String),
+}
+
+/// Which column feeds each item field. Unmapped fields are left empty.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
+pub struct CsvColumnMapping {
+    pub title: Option<CsvColumn>,
+    pub username: Option<CsvColumn>,
+    pub password: Option<CsvColumn>,
+    pub url: Option<CsvColumn>,
+    pub notes: Option<CsvColumn>,
+    pub tags: Option<CsvColumn>,
+    /// Folder path, with `/` separating nested folders.
+    pub folder: Option<CsvColumn>,
+    pub totp: Option<CsvColumn>,
+}
+
+impl CsvColumnMapping {
+    /// Guesses a mapping from the header names written by common password managers and browsers.
+    pub fn detect(headers: &[String]) -> Self {
+        let find = |candidates: &[&str]| {
+            candidates.iter().find_map(|candidate| {
+                headers
+                    .iter()
+                    .find(|header| header.trim().eq_ignore_ascii_case(candidate))
+                    .map(|header| CsvColumn::Header(header.clone()))
+            })
+        };
+
+        CsvColumnMapping {
+            title: find(&TITLE_HEADERS),
+            username: find(&USERNAME_HEADERS),
+            password: find(&PASSWORD_HEADERS),
+            url: find(&URL_HEADERS),
+            notes: find(&NOTES_HEADERS),
+            tags: find(&TAGS_HEADERS),
+            folder: find(&FOLDER_HEADERS),
+            totp: find(&TOTP_HEADERS),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        *self == CsvColumnMapping::default()
+    }
+
+    fn resolve(&self, headers: Option<&StringRecord>) -> Result<ResolvedMapping> {
+        let resolve = |column: &Option<CsvColumn>| -> Result<Option<usize>> {
+            match column {
+                None => Ok(None),
+                Some(CsvColumn::Index(index)) => Ok(Some(*index)),
+                Some(CsvColumn::Header(name)) => {
+                    let headers = headers.ok_or_else(|| {
+                        Error::InvalidInput(format!("Column '{}' is mapped by name, but the CSV has no header row", name))
+                    })?;
+                    headers
+                        .iter()
+                        .position(|header| header.trim().eq_ignore_ascii_case(name.trim()))
+                        .map(Some)
+                        .ok_or_else(|| Error::InvalidInput(format!("Column '{}' not found in the CSV header", name)))
+                }
+            }
+        };
+
+        Ok(ResolvedMapping {
+            title: resolve(&self.title)?,
+            username: resolve(&self.username)?,
+            password: resolve(&self.password)?,
+            url: resolve(&self.url)?,
+            notes: resolve(&self.notes)?,
+            tags: resolve(&self.tags)?,
+            folder: resolve(&self.folder)?,
+            totp: resolve(&self.totp)?,
+        })
+    }
+}
+
+struct ResolvedMapping {
+    title: Option<usize>,
+    username: Option<usize>,
+    password: Option<usize>,
+    url: Option<usize>,
+    notes: Option<usize>,
+    tags: Option<usize>,
+    folder: Option<usize>,
+    totp: Option<usize>,
+}
+
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
+pub enum TextEncoding {
+    Utf8,
+    Utf16Le,
+    Utf16Be,
+    /// Fallback for files that are not valid UTF-8, such as older Windows exports.
+    Latin1,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct CsvImportOptions {
+    /// Column mapping. When omitted, columns are detected from the header row.
+    #[serde(default)]
+    pub mapping: Option<CsvColumnMapping>,
+    /// Field delimiter. When omitted, it is detected from the content.
+    #[serde(default)]
+    pub delimiter: Option<char>,
+    /// Whether the first row holds column names. Headerless files need a
+    /// mapping by column position.
+    #[serde(default = "default_has_headers", rename = "hasHeaders")]
+    pub has_headers: bool,
+}
+
+fn default_has_headers() -> bool {
+    true
+}
+
+impl Default for CsvImportOptions {
+    fn default() -> Self {
+        Self {
+            mapping: None,
+            delimiter: None,
+            has_headers: default_has_headers(),
+        }
+    }
+}
+
+/// The detected shape of a CSV file, used to build a column mapping.
+#[derive(Debug, Serialize, Clone)]
+pub struct CsvLayout {
+    pub encoding: TextEncoding,
+    pub delimiter: char,
+    pub headers: Vec<String>,
+    pub sample_rows: Vec<Vec<String>>,
+    pub detected_mapping: CsvColumnMapping,
+}
+
+/// Imports logins from CSV files, either with a user-supplied column mapping or
+/// with columns detected from the headers written by common exporters.
+#[derive(Debug, Default)]
+pub struct CsvImporter {
+    options: CsvImportOptions,
+}
+
+impl CsvImporter {
+    pub fn new(options: CsvImportOptions) -> Self {
+        Self { options }
+    }
+
+    /// Decodes the file and reads its header and first rows without importing anything.
+    pub fn inspect(&self, data: &[u8]) -> Result<CsvLayout> {
+        let (text, encoding) = decode_text(data)?;
+        let delimiter = self.delimiter(&text)?;
+        let mut reader = self.reader(&text, delimiter);
+        let mut records = reader.records();
+
+        let headers: Vec<String> = if self.options.has_headers {
+            match records.next() {
+                Some(record) => record?.iter().map(|h| h.trim().to_string()).collect(),
+                None => Vec::new(),
+            }
+        } else {
+            Vec::new()
+        };
+        let sample_rows = records
+            .take(SAMPLE_ROWS)
+            .map(|record| Ok(record?.iter().map(str::to_string).collect()))
+            .collect::<Result<Vec<Vec<String>>>>()?;
+
+        Ok(CsvLayout {
+            encoding,
+            delimiter: delimiter as char,
+            detected_mapping: CsvColumnMapping::detect(&headers),
+            headers,
+            sample_rows,
+        })
+    }
+
+    fn delimiter(&self, text: &str) -> Result<u8> {
+        match self.options.delimiter {
+            Some(delimiter) if delimiter.is_ascii() => Ok(delimiter as u8),
+            Some(delimiter) => Err(Error::InvalidInput(format!("Unsupported CSV delimiter '{}'", delimiter))),
+            None => Ok(detect_delimiter(text)),
+        }
+    }
+
+    fn reader<'a>(&self, text: &'a str, delimiter: u8) -> ::csv::Reader<&'a [u8]> {
+        ReaderBuilder::new()
+            .has_headers(false)
+            .flexible(true)
+            .delimiter(delimiter)
+            .from_reader(text.as_bytes())
+    }
+}
+
+impl Importer for CsvImporter {
+    fn name(&self) -> &'static str {
+        "CSV"
+    }
+
+    fn parse(&self, data: &[u8]) -> Result<ParsedImport> {
+        let (text, encoding) = decode_text(data)?;
+        let delimiter = self.delimiter(&text)?;
+        debug!("CSV import: {:?} text, delimiter {:?}", encoding, delimiter as char);
+
+        let mut reader = self.reader(&text, delimiter);
+        let mut records = reader.records();
+
+        let headers = if self.options.has_headers {
+            records.next().transpose()?
+        } else {
+            None
+        };
+
+        let mapping = match &self.options.mapping {
+            Some(mapping) => mapping.clone(),
+            None => {
+                let header_names: Vec<String> = headers.iter().flatten().map(str::to_string).collect();
+                CsvColumnMapping::detect(&header_names)
+            }
+        };
+        if mapping.is_empty() {
+            return Err(Error::InvalidInput("No known columns found in the CSV file, a column mapping is required".into()));
+        }
+        let columns = mapping.resolve(headers.as_ref())?;
+
+        let mut parsed = ParsedImport::default();
+        let mut folders = FolderBuilder::default();
+        for (index, record) in records.enumerate() {
+            let row_number = index + 1;
+            match record {
+                Ok(record) if record.iter().all(|field| field.trim().is_empty()) => continue,
+                Ok(record) => match parse_csv_row(&record, &columns) {
+                    Ok((mut item, folder)) => {
+                        item.vault_item.parent_id = folder.and_then(|path| folders.folder_for(&path, &mut parsed.items));
+                        parsed.items.push(item);
+                    }
+                    Err(e) => parsed.result.record_error(row_number, e),
+                },
+                Err(e) => parsed.result.record_error(row_number, format!("Failed to read record: {}", e)),
+            }
+        }
+
+        info!("Parsed {} CSV rows, {} errors", parsed.items.len(), parsed.result.error_count);
+        Ok(parsed)
+    }
+}
+
+fn parse_csv_row(record: &StringRecord, columns: &ResolvedMapping) -> Result<(ImportedItem, Option<String>)> {
+    let field = |column: Option<usize>| {
+        column
+            .and_then(|index| record.get(index))
+            .map(|value| value.trim().to_string())
+            .filter(|value| !value.is_empty())
+    };
+
+    let url = field(columns.url);
+    let title = field(columns.title)
+        .or_else(|| {
+            url.as_ref().and_then(|url| {
+                url.replace("https://", "")
+                   .replace("http://", "")
+                   .split('/')
+                   .next()
+                   .map(|s| s.trim().to_string())
+            })
+        })
+        .filter(|title| !title.is_empty())
+        .ok_or_else(|| Error::InvalidInput("Row has no title".into()))?;
+
+    let fields = LoginFields {
+        username: field(columns.username),
+        password: field(columns.password),
+        url,
+        totp: field(columns.totp),
+        fields: Vec::new(),
+        notes: field(columns.notes),
+    };
+    let content = fields.to_content();
+    if content.is_empty() {
+        return Err(Error::InvalidInput(format!("Row '{}' has no content to store", title)));
+    }
+
+    let tags = field(columns.tags)
+        .unwrap_or_default()
+        .split(',')
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+        .collect();
+
+    let item = ImportedItem {
+        vault_item: new_item(title, "text/plain", None, tags),
+        content: Some(content.into_bytes()),
+        password_history: Vec::new(),
+    };
+    Ok((item, field(columns.folder)))
+}
+
+/// Decodes CSV bytes to text. A byte order mark decides the encoding when present;
+/// otherwise UTF-16 is recognised by its zero bytes, and anything that is not valid
+/// UTF-8 is read as Latin-1.
+pub fn decode_text(data: &[u8]) -> Result<(String, TextEncoding)> {
+    if let Some(rest) = data.strip_prefix(&[0xEF, 0xBB, 0xBF]) {
+        let text = std::str::from_utf8(rest).map_err(|e| Error::InvalidInput(format!("Invalid UTF-8 text: {}", e)))?;
+        return Ok((text.to_string(), TextEncoding::Utf8));
+    }
+    if let Some(rest) = data.strip_prefix(&[0xFF, 0xFE]) {
+        return Ok((decode_utf16(rest, u16::from_le_bytes)?, TextEncoding::Utf16Le));
+    }
+    if let Some(rest) = data.strip_prefix(&[0xFE, 0xFF]) {
+        return Ok((decode_utf16(rest, u16::from_be_bytes)?, TextEncoding::Utf16Be));
+    }
+
+    let sample = &data[..data.len().min(ENCODING_SAMPLE_BYTES)];
+    let pairs = sample.len() / 2;
+    if pairs > 0 {
+        let even_zeros = sample.iter().step_by(2).filter(|byte| **byte == 0).count();
+        let odd_zeros = sample.iter().skip(1).step_by(2).filter(|byte| **byte == 0).count();
+        // Mostly-ASCII UTF-16 text has a zero in every other byte.
+        if odd_zeros * 2 > pairs && even_zeros * 10 < pairs {
+            return Ok((decode_utf16(data, u16::from_le_bytes)?, TextEncoding::Utf16Le));
+        }
+        if even_zeros * 2 > pairs && odd_zeros * 10 < pairs {
+            return Ok((decode_utf16(data, u16::from_be_bytes)?, TextEncoding::Utf16Be));
+        }
+    }
+
+    match std::str::from_utf8(data) {
+        Ok(text) => Ok((text.to_string(), TextEncoding::Utf8)),
+        Err(_) => Ok((data.iter().map(|byte| *byte as char).collect(), TextEncoding::Latin1)),
+    }
+}
+
+fn decode_utf16(data: &[u8], to_unit: fn([u8; 2]) -> u16) -> Result<String> {
+    if data.len() % 2 != 0 {
+        return Err(Error::InvalidInput("UTF-16 text has an odd number of bytes".into()));
+    }
+    let units = data.chunks_exact(2).map(|pair| to_unit([pair[0], pair[1]]));
+    char::decode_utf16(units)
+        .collect::<std::result::Result<String, _>>()
+        .map_err(|e| Error::InvalidInput(format!("Invalid UTF-16 text: {}", e)))
+}
+
+/// Picks the candidate delimiter that splits the first records into the same,
+/// largest number of fields. Falls back to a comma.
+fn detect_delimiter(text: &str) -> u8 {
+    let records = sample_records(text);
+    DELIMITER_CANDIDATES
+        .iter()
+        .filter_map(|&delimiter| {
+            let counts: Vec<usize> = records
+                .iter()
+                .map(|record| record.bytes().filter(|byte| *byte == delimiter).count())
+                .collect();
+            let first = *counts.first()?;
+            if first == 0 {
+                return None;
+            }
+            let consistent = counts.iter().filter(|count| **count == first).count();
+            Some((consistent, first, delimiter))
+        })
+        .max_by_key(|(consistent, first, _)| (*consistent, *first))
+        .map(|(_, _, delimiter)| delimiter)
+        .unwrap_or(b',')
+}
+
+/// Splits the start of the text into records with quoted sections removed, so
+/// delimiters and line breaks inside quotes are not counted.
+fn sample_records(text: &str) -> Vec<String> {
+    let mut records = Vec::new();
+    let mut current = String::new();
+    let mut in_quotes = false;
+
+    for c in text.chars() {
+        match c {
+            '"' => in_quotes = !in_quotes,
+            '\n' if !in_quotes => {
+                if !current.trim().is_empty() {
+                    records.push(std::mem::take(&mut current));
+                    if records.len() == DELIMITER_SAMPLE_RECORDS {
+                        return records;
+                    }
+                }
+                current.clear();
+            }
+            _ if !in_quotes => current.push(c),
+            _ => {}
+        }
+    }
+    if !current.trim().is_empty() {
+        records.push(current);
+    }
+    records
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn utf16(text: &str, to_bytes: fn(u16) -> [u8; 2]) -> Vec<u8> {
+        text.encode_utf16().flat_map(to_bytes).collect()
+    }
+
+    #[test]
+    fn decodes_text_encodings() {
+        let text = "Title,Password\nCafé,pässwörd\n";
+        let with_bom = |bom: &[u8], body: Vec<u8>| [bom.to_vec(), body].concat();
+        let cases: Vec<(&str, Vec<u8>, TextEncoding)> = vec![
+            ("UTF-8", text.as_bytes().to_vec(), TextEncoding::Utf8),
+            ("UTF-8 BOM", with_bom(&[0xEF, 0xBB, 0xBF], text.as_bytes().to_vec()), TextEncoding::Utf8),
+            ("UTF-16LE BOM", with_bom(&[0xFF, 0xFE], utf16(text, u16::to_le_bytes)), TextEncoding::Utf16Le),
+            ("UTF-16BE BOM", with_bom(&[0xFE, 0xFF], utf16(text, u16::to_be_bytes)), TextEncoding::Utf16Be),
+            ("UTF-16LE", utf16(text, u16::to_le_bytes), TextEncoding::Utf16Le),
+            ("UTF-16BE", utf16(text, u16::to_be_bytes), TextEncoding::Utf16Be),
+            ("Latin-1", text.chars().map(|c| c as u8).collect(), TextEncoding::Latin1),
+        ];
+        for (name, data, encoding) in cases {
+            assert_eq!(decode_text(&data).unwrap(), (text.to_string(), encoding), "{}", name);
+        }
+
+        assert_eq!(decode_text(b"").unwrap(), (String::new(), TextEncoding::Utf8));
+        assert!(decode_text(&[0xEF, 0xBB, 0xBF, 0xFF]).is_err());
+        assert!(decode_text(&[0xFF, 0xFE, b'a', 0, b'b']).is_err());
+        // A lone surrogate is not valid UTF-16.
+        assert!(decode_text(&[0xFF, 0xFE, 0x00, 0xD8, b'a', 0]).is_err());
+    }
+
+    #[test]
+    fn detects_delimiters() {
+        let cases = [
+            ("a,b,c\n1,2,3\n", b','),
+            ("a;b;c\n1;2,5;3\n4;5;6\n", b';'),
+            ("a\tb\n1\t\"x;y;z\"\n", b'\t'),
+            ("a|b\n1|2\n", b'|'),
+            ("\"x,y\";b\n\"1,2\";3\n", b';'),
+            ("\"multi\nline,with,commas\";b\n1;2\n", b';'),
+            ("single column\nvalue\n", b','),
+            ("", b','),
+        ];
+        for (text, delimiter) in cases {
+            assert_eq!(detect_delimiter(text) as char, delimiter as char, "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn detects_exporter_columns() {
+        let headers = |names: &[&str]| names.iter().map(|name| name.to_string()).collect::<Vec<_>>();
+        let header = |name: &str| Some(CsvColumn::Header(name.to_string()));
+
+        // Bitwarden
+        let mapping = CsvColumnMapping::detect(&headers(&["folder", "favorite", "type", "name", "notes", "fields", "login_uri", "login_username", "login_password", "login_totp"]));
+        assert_eq!(
+            mapping,
+            CsvColumnMapping {
+                title: header("name"),
+                username: header("login_username"),
+                password: header("login_password"),
+                url: header("login_uri"),
+                notes: header("notes"),
+                tags: None,
+                folder: header("folder"),
+                totp: header("login_totp"),
+            }
+        );
+
+        // KeePass, with a preferred header winning over a later candidate.
+        let mapping = CsvColumnMapping::detect(&headers(&["Group", "Title", "Username", "Password", "URL", "Notes", "Name"]));
+        assert_eq!(mapping.title, header("Title"));
+        assert_eq!(mapping.folder, header("Group"));
+        assert_eq!(mapping.url, header("URL"));
+
+        // Padded headers keep their original text so they resolve later.
+        let mapping = CsvColumnMapping::detect(&headers(&[" Account ", "Login Name", "Web Site", "Comments"]));
+        assert_eq!(mapping.title, header(" Account "));
+        assert_eq!(mapping.username, header("Login Name"));
+
+        assert!(CsvColumnMapping::detect(&headers(&["a", "b"])).is_empty());
+    }
+}
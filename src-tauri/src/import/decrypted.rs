@@ -0,0 +1,99 @@
+use super::{ImportedItem, Importer, ParsedImport};
+use crate::error::Error;
+use crate::storage::VaultItem;
+use crate::Result;
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use log::info;
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use uuid::Uuid;
+
+/// An entry of the JSON written by `export_decrypted_vault`.
+#[derive(Deserialize)]
+struct ExportedItem {
+    #[serde(flatten)]
+    item: VaultItem,
+    /// Base64 of the plaintext content, absent for folders.
+    content: Option<String>,
+}
+
+/// Reads back the decrypted JSON export. Items keep their timestamps, tags and
+/// metadata, and the folder hierarchy is rebuilt from `parent_id`. Ids are kept
+/// as they are unless `remap_ids` is set, in which case every item gets a fresh
+/// id so the export can be imported next to the items it came from.
+pub struct DecryptedExportImporter {
+    remap_ids: bool,
+}
+
+impl DecryptedExportImporter {
+    pub fn new(remap_ids: bool) -> Self {
+        Self { remap_ids }
+    }
+}
+
+impl Importer for DecryptedExportImporter {
+    fn name(&self) -> &'static str {
+        "Fetch export"
+    }
+
+    fn parse(&self, data: &[u8]) -> Result<ParsedImport> {
+        let exported: Vec<ExportedItem> = serde_json::from_slice(data)?;
+        let total = exported.len();
+
+        let mut ids: HashMap<String, String> = HashMap::new();
+        for entry in &exported {
+            let new_id = if self.remap_ids { Uuid::new_v4().to_string() } else { entry.item.id.clone() };
+            if ids.insert(entry.item.id.clone(), new_id).is_some() {
+                return Err(Error::InvalidInput(format!("Export contains item id {} more than once", entry.item.id)));
+            }
+        }
+
+        let mut parsed = ParsedImport::default();
+        for (index, entry) in parents_first(exported).into_iter().enumerate() {
+            let ExportedItem { mut item, content } = entry;
+            let content = match content.map(|content| STANDARD.decode(content)) {
+                Some(Ok(content)) => Some(content),
+                Some(Err(e)) => {
+                    parsed.result.record_error(index + 1, format!("Invalid content for '{}': {}", item.name, e));
+                    continue;
+                }
+                None => None,
+            };
+
+            item.id = ids[&item.id].clone();
+            // Items whose parent is not part of the export land in the import target.
+            item.parent_id = item.parent_id.and_then(|parent_id| ids.get(&parent_id).cloned());
+            item.data_path = String::new();
+            parsed.items.push(ImportedItem { vault_item: item, content, password_history: Vec::new() });
+        }
+
+        info!("Parsed {} items from a decrypted Fetch export, {} errors", total, parsed.result.error_count);
+        Ok(parsed)
+    }
+}
+
+/// Orders items so every folder comes before its contents, which lets
+/// `store_items` merge folders into existing ones.
+fn parents_first(items: Vec<ExportedItem>) -> Vec<ExportedItem> {
+    let ids: HashSet<String> = items.iter().map(|entry| entry.item.id.clone()).collect();
+    let mut children: HashMap<Option<String>, Vec<ExportedItem>> = HashMap::new();
+    for entry in items {
+        let parent_id = entry.item.parent_id.clone().filter(|parent_id| ids.contains(parent_id));
+        children.entry(parent_id).or_default().push(entry);
+    }
+
+    let mut ordered = Vec::with_capacity(ids.len());
+    let mut pending = children.remove(&None).unwrap_or_default();
+    while let Some(entry) = pending.pop() {
+        if let Some(mut contents) = children.remove(&Some(entry.item.id.clone())) {
+            pending.append(&mut contents);
+        }
+        ordered.push(entry);
+    }
+    // Anything left is part of a parent cycle; keep it at the top level rather than dropping it.
+    for mut entry in children.into_values().flatten() {
+        entry.item.parent_id = None;
+        ordered.push(entry);
+    }
+    ordered
+}
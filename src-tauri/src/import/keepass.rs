@@ -0,0 +1,146 @@
+use super::{new_item, ImportedItem, Importer, ParsedImport};
+use crate::error::Error;
+use crate::kdbx::{self, Database, DatabaseKey, Entry, Group};
+use crate::login::LoginFields;
+use crate::storage::PasswordHistoryEntry;
+use crate::Result;
+use chrono::Utc;
+use log::{debug, info};
+use uuid::Uuid;
+
+/// Fields KeePassXC and the KeeOtp plugin use for TOTP secrets.
+const TOTP_FIELDS: [&str; 2] = ["otp", "TOTP Seed"];
+const TOTP_SETTINGS_FIELD: &str = "TOTP Settings";
+
+/// Imports KDBX 3.1 and 4.x databases. Groups become folders, entries become
+/// login items with their password history, and attachments become file items
+/// under their entry. The recycle bin is skipped.
+pub struct KeePassImporter {
+    key: DatabaseKey,
+}
+
+impl KeePassImporter {
+    pub fn new(key: DatabaseKey) -> Self {
+        Self { key }
+    }
+}
+
+impl Importer for KeePassImporter {
+    fn name(&self) -> &'static str {
+        "KeePass"
+    }
+
+    fn parse(&self, data: &[u8]) -> Result<ParsedImport> {
+        let database = kdbx::read(data, &self.key)?;
+        let mut parsed = ParsedImport::default();
+        let mut entry_count = 0;
+        add_group_contents(&database, &database.root, None, &mut parsed, &mut entry_count);
+
+        info!("Parsed {} KeePass entries, {} errors", entry_count, parsed.result.error_count);
+        Ok(parsed)
+    }
+}
+
+fn add_group_contents(database: &Database, group: &Group, parent_id: Option<String>, parsed: &mut ParsedImport, entry_count: &mut usize) {
+    for entry in &group.entries {
+        *entry_count += 1;
+        match entry_items(entry, parent_id.clone()) {
+            Ok(items) => parsed.items.extend(items),
+            Err(e) => parsed.result.record_error(*entry_count, e),
+        }
+    }
+
+    for child in &group.groups {
+        if Some(child.uuid) == database.recycle_bin {
+            debug!("Skipping KeePass recycle bin group");
+            continue;
+        }
+
+        let name = if child.name.trim().is_empty() { "Untitled group".to_string() } else { child.name.clone() };
+        let mut folder = new_item(name, "folder", parent_id.clone(), Vec::new());
+        folder.created_at = child.times.created.unwrap_or(folder.created_at);
+        folder.updated_at = child.times.modified.unwrap_or(folder.updated_at);
+        let folder_id = folder.id.clone();
+        parsed.items.push(ImportedItem { vault_item: folder, content: None, password_history: Vec::new() });
+
+        add_group_contents(database, child, Some(folder_id), parsed, entry_count);
+    }
+}
+
+fn entry_items(entry: &Entry, parent_id: Option<String>) -> Result<Vec<ImportedItem>> {
+    let fields = login_fields(entry);
+    let title = entry
+        .get(kdbx::TITLE)
+        .or(fields.url.as_deref())
+        .map(str::to_string)
+        .ok_or_else(|| Error::InvalidInput("Entry has no title".into()))?;
+
+    let content = fields.to_content();
+    if content.is_empty() && entry.attachments.is_empty() {
+        return Err(Error::InvalidInput(format!("Entry '{}' has no content to store", title)));
+    }
+
+    let mut item = new_item(title, "text/plain", parent_id, entry.tags.clone());
+    item.created_at = entry.times.created.unwrap_or(item.created_at);
+    item.updated_at = entry.times.modified.unwrap_or(item.updated_at);
+    item.expires_at = entry.times.expires;
+    let item_id = item.id.clone();
+
+    let mut items = vec![ImportedItem {
+        vault_item: item,
+        content: Some(content.into_bytes()),
+        password_history: password_history(entry, &item_id),
+    }];
+
+    for attachment in &entry.attachments {
+        let mime_type = mime_guess::from_path(&attachment.name).first_or_octet_stream().to_string();
+        items.push(ImportedItem {
+            vault_item: new_item(attachment.name.clone(), &mime_type, Some(item_id.clone()), Vec::new()),
+            content: Some(attachment.data.clone()),
+            password_history: Vec::new(),
+        });
+    }
+    Ok(items)
+}
+
+fn login_fields(entry: &Entry) -> LoginFields {
+    let mut fields = LoginFields {
+        username: entry.get(kdbx::USERNAME).map(str::to_string),
+        password: entry.get(kdbx::PASSWORD).map(str::to_string),
+        url: entry.get(kdbx::URL).map(str::to_string),
+        totp: TOTP_FIELDS.iter().find_map(|key| entry.get(key)).map(str::to_string),
+        notes: entry.get(kdbx::NOTES).map(str::to_string),
+        ..Default::default()
+    };
+
+    for field in entry.custom_fields() {
+        if TOTP_FIELDS.contains(&field.key.as_str()) || field.key == TOTP_SETTINGS_FIELD {
+            continue;
+        }
+        fields.add_field(&field.key, &field.value);
+    }
+    fields
+}
+
+/// Turns the entry's history into password history, keeping each password that
+/// was replaced by a different one. The change time is when the next version
+/// was saved.
+fn password_history(entry: &Entry, item_id: &str) -> Vec<PasswordHistoryEntry> {
+    let versions: Vec<&Entry> = entry.history.iter().chain(std::iter::once(entry)).collect();
+    versions
+        .windows(2)
+        .filter_map(|pair| {
+            let (old, new) = (pair[0], pair[1]);
+            let password = old.get(kdbx::PASSWORD)?;
+            if new.get(kdbx::PASSWORD) == Some(password) {
+                return None;
+            }
+            Some(PasswordHistoryEntry {
+                id: Uuid::new_v4().to_string(),
+                item_id: item_id.to_string(),
+                password: password.to_string(),
+                changed_at: new.times.modified.unwrap_or_else(Utc::now),
+            })
+        })
+        .collect()
+}
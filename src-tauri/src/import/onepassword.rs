@@ -0,0 +1,481 @@
+use super::{end_of_month, new_item, FolderBuilder, ImportedItem, Importer, ParsedImport, FAVORITE_TAG};
+use crate::error::Error;
+use crate::login::LoginFields;
+use crate::ssh::{self, SSH_KEY_ITEM_TYPE};
+use crate::storage::{ItemMetadata, PasswordHistoryEntry};
+use crate::Result;
+use chrono::{DateTime, TimeZone, Utc};
+use log::{debug, info};
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::io::{Cursor, Read};
+use uuid::Uuid;
+use zip::ZipArchive;
+
+const EXPORT_DATA: &str = "export.data";
+const FILES_DIR: &str = "files/";
+/// Largest export data or attachment read from a `.1pux` archive.
+const MAX_ENTRY_SIZE: u64 = 512 * 1024 * 1024;
+
+const LOGIN: &str = "001";
+const CREDIT_CARD: &str = "002";
+const PASSWORD: &str = "005";
+const DOCUMENT: &str = "006";
+const SSH_KEY: &str = "114";
+
+const ARCHIVED_STATE: &str = "archived";
+const ARCHIVED_TAG: &str = "archived";
+const EXPIRY_FIELD: &str = "expiry";
+
+#[derive(Deserialize)]
+struct Export {
+    #[serde(default)]
+    accounts: Vec<Account>,
+}
+
+#[derive(Deserialize)]
+struct Account {
+    #[serde(default)]
+    vaults: Vec<Vault>,
+}
+
+#[derive(Deserialize)]
+struct Vault {
+    attrs: VaultAttrs,
+    #[serde(default)]
+    items: Vec<Item>,
+}
+
+#[derive(Deserialize)]
+struct VaultAttrs {
+    name: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Item {
+    #[serde(default)]
+    fav_index: u32,
+    created_at: Option<i64>,
+    updated_at: Option<i64>,
+    #[serde(default)]
+    state: String,
+    category_uuid: String,
+    #[serde(default)]
+    details: Details,
+    #[serde(default)]
+    overview: Overview,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+struct Details {
+    #[serde(default)]
+    login_fields: Vec<LoginField>,
+    notes_plain: Option<String>,
+    password: Option<String>,
+    #[serde(default)]
+    sections: Vec<Section>,
+    #[serde(default)]
+    password_history: Vec<PasswordHistory>,
+    document_attributes: Option<DocumentAttributes>,
+}
+
+#[derive(Deserialize)]
+struct LoginField {
+    value: Option<String>,
+    designation: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Section {
+    #[serde(default)]
+    fields: Vec<SectionField>,
+}
+
+#[derive(Deserialize)]
+struct SectionField {
+    title: Option<String>,
+    id: Option<String>,
+    /// A single-key object naming the field type, e.g. `{"concealed": "..."}`.
+    #[serde(default)]
+    value: HashMap<String, Value>,
+}
+
+#[derive(Deserialize)]
+struct PasswordHistory {
+    value: String,
+    time: Option<i64>,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct DocumentAttributes {
+    file_name: String,
+    document_id: String,
+}
+
+#[derive(Deserialize, Default)]
+struct Overview {
+    title: Option<String>,
+    url: Option<String>,
+    #[serde(default)]
+    urls: Vec<OverviewUrl>,
+    #[serde(default)]
+    tags: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct OverviewUrl {
+    url: String,
+}
+
+/// Imports 1Password `.1pux` exports. Each vault becomes a top-level folder,
+/// logins and passwords become login items, documents and attached files are
+/// stored as file items under their entry, and SSH keys become SSH key items.
+/// Other categories keep their section fields as custom fields.
+#[derive(Default)]
+pub struct OnePasswordImporter;
+
+impl OnePasswordImporter {
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+impl Importer for OnePasswordImporter {
+    fn name(&self) -> &'static str {
+        "1Password"
+    }
+
+    fn parse(&self, data: &[u8]) -> Result<ParsedImport> {
+        let mut archive = Archive::new(data)?;
+        let export: Export = serde_json::from_slice(&archive.read(EXPORT_DATA)?)?;
+
+        let mut parsed = ParsedImport::default();
+        let mut folders = FolderBuilder::default();
+        let mut item_count = 0;
+        for vault in export.accounts.iter().flat_map(|account| &account.vaults) {
+            let vault_id = folders.folder_for(&vault.attrs.name, &mut parsed.items);
+            for item in &vault.items {
+                item_count += 1;
+                match item_to_import(item, vault_id.clone(), &mut archive) {
+                    Ok(items) => parsed.items.extend(items),
+                    Err(e) => parsed.result.record_error(item_count, e),
+                }
+            }
+        }
+
+        info!("Parsed {} 1Password items, {} errors", item_count, parsed.result.error_count);
+        Ok(parsed)
+    }
+}
+
+/// The `.1pux` zip, with attachments indexed by their document id.
+struct Archive<'a> {
+    zip: ZipArchive<Cursor<&'a [u8]>>,
+    files: HashMap<String, String>,
+    max_entry_size: u64,
+}
+
+impl<'a> Archive<'a> {
+    fn new(data: &'a [u8]) -> Result<Self> {
+        let zip = ZipArchive::new(Cursor::new(data))?;
+        // Attachments are stored as `files/<documentId>__<fileName>`.
+        let files = zip
+            .file_names()
+            .filter_map(|name| {
+                let (document_id, _) = name.strip_prefix(FILES_DIR)?.split_once("__")?;
+                Some((document_id.to_string(), name.to_string()))
+            })
+            .collect();
+        Ok(Self { zip, files, max_entry_size: MAX_ENTRY_SIZE })
+    }
+
+    fn read(&mut self, name: &str) -> Result<Vec<u8>> {
+        let file = self.zip.by_name(name)?;
+        // The size in the zip header is not trusted; reading stops past the limit either way.
+        let mut data = Vec::new();
+        file.take(self.max_entry_size + 1).read_to_end(&mut data)?;
+        if data.len() as u64 > self.max_entry_size {
+            return Err(Error::InvalidInput(format!("'{}' in the 1Password export is too large", name)));
+        }
+        Ok(data)
+    }
+
+    fn read_document(&mut self, document: &DocumentAttributes) -> Result<Vec<u8>> {
+        let name = self
+            .files
+            .get(&document.document_id)
+            .cloned()
+            .ok_or_else(|| Error::InvalidInput(format!("Attachment '{}' is missing from the export", document.file_name)))?;
+        self.read(&name)
+    }
+}
+
+fn item_to_import(item: &Item, parent_id: Option<String>, archive: &mut Archive) -> Result<Vec<ImportedItem>> {
+    let details = &item.details;
+    let name = item
+        .overview
+        .title
+        .as_deref()
+        .map(str::trim)
+        .filter(|title| !title.is_empty())
+        .ok_or_else(|| Error::InvalidInput("Item has no title".into()))?
+        .to_string();
+
+    let mut fields = LoginFields {
+        notes: details.notes_plain.clone().filter(|notes| !notes.is_empty()),
+        ..Default::default()
+    };
+    if item.category_uuid == LOGIN || item.category_uuid == PASSWORD {
+        add_login_fields(&mut fields, item);
+    }
+
+    let mut attachments = Vec::new();
+    let mut ssh_private_key = None;
+    let mut expires_at = None;
+    for field in details.sections.iter().flat_map(|section| &section.fields) {
+        let Some((kind, value)) = field.value.iter().next() else { continue };
+        let label = field.title.as_deref().filter(|title| !title.is_empty()).or(field.id.as_deref()).unwrap_or_default();
+        match (kind.as_str(), value) {
+            ("file", value) => attachments.push(DocumentAttributes::deserialize(value)?),
+            ("sshKey", Value::Object(key)) => ssh_private_key = key.get("privateKey").and_then(Value::as_str),
+            ("totp", Value::String(totp)) if fields.totp.is_none() => fields.totp = Some(totp.clone()),
+            ("monthYear", Value::Number(number)) => {
+                // Stored as YYYYMM.
+                let Some(month_year) = number.as_u64() else { continue };
+                let (year, month) = ((month_year / 100) as i32, (month_year % 100) as u32);
+                fields.add_field(label, &format!("{:02}/{}", month, year));
+                if item.category_uuid == CREDIT_CARD && field.id.as_deref() == Some(EXPIRY_FIELD) {
+                    expires_at = end_of_month(year, month);
+                }
+            }
+            ("date", Value::Number(number)) => {
+                if let Some(date) = number.as_i64().and_then(timestamp) {
+                    fields.add_field(label, &date.format("%Y-%m-%d").to_string());
+                }
+            }
+            ("email", Value::Object(email)) => {
+                if let Some(address) = email.get("email_address").and_then(Value::as_str) {
+                    fields.add_field(label, address);
+                }
+            }
+            ("address", Value::Object(address)) => {
+                let parts: Vec<&str> = ["street", "city", "state", "zip", "country"]
+                    .iter()
+                    .filter_map(|key| address.get(*key).and_then(Value::as_str))
+                    .filter(|part| !part.is_empty())
+                    .collect();
+                fields.add_field(label, &parts.join(", "));
+            }
+            (_, Value::String(text)) => fields.add_field(label, text),
+            (kind, _) => debug!("Skipping 1Password field '{}' of type {}", label, kind),
+        }
+    }
+
+    let mut tags = item.overview.tags.clone();
+    if item.fav_index > 0 {
+        tags.push(FAVORITE_TAG.to_string());
+    }
+    if item.state == ARCHIVED_STATE {
+        tags.push(ARCHIVED_TAG.to_string());
+    }
+
+    let mut content = fields.to_content().into_bytes();
+    let mut item_type = "text/plain".to_string();
+    let mut metadata = None;
+    match (item.category_uuid.as_str(), ssh_private_key, &details.document_attributes) {
+        (SSH_KEY, Some(private_key), _) => {
+            let key = ssh::import(private_key, None, &name)?;
+            item_type = SSH_KEY_ITEM_TYPE.to_string();
+            metadata = Some(ItemMetadata::SshKey(key.metadata));
+            content = key.private_key.as_bytes().to_vec();
+        }
+        // A document without notes or fields is stored as the file itself.
+        (DOCUMENT, _, Some(document)) if content.is_empty() => {
+            item_type = mime_guess::from_path(&document.file_name).first_or_octet_stream().to_string();
+            content = archive.read_document(document)?;
+        }
+        (DOCUMENT, _, Some(document)) => attachments.push(document.clone()),
+        _ => {}
+    }
+    if content.is_empty() && attachments.is_empty() {
+        return Err(Error::InvalidInput(format!("Item '{}' has no content to store", name)));
+    }
+
+    let mut vault_item = new_item(name, &item_type, parent_id, tags);
+    vault_item.created_at = item.created_at.and_then(timestamp).unwrap_or(vault_item.created_at);
+    vault_item.updated_at = item.updated_at.and_then(timestamp).unwrap_or(vault_item.updated_at);
+    vault_item.expires_at = expires_at;
+    vault_item.metadata = metadata;
+    let item_id = vault_item.id.clone();
+
+    let password_history = details
+        .password_history
+        .iter()
+        .map(|entry| PasswordHistoryEntry {
+            id: Uuid::new_v4().to_string(),
+            item_id: item_id.clone(),
+            password: entry.value.clone(),
+            changed_at: entry.time.and_then(timestamp).unwrap_or(vault_item.updated_at),
+        })
+        .collect();
+
+    let mut items = vec![ImportedItem {
+        vault_item,
+        content: Some(content),
+        password_history,
+    }];
+    for attachment in &attachments {
+        let mime_type = mime_guess::from_path(&attachment.file_name).first_or_octet_stream().to_string();
+        items.push(ImportedItem {
+            vault_item: new_item(attachment.file_name.clone(), &mime_type, Some(item_id.clone()), Vec::new()),
+            content: Some(archive.read_document(attachment)?),
+            password_history: Vec::new(),
+        });
+    }
+    Ok(items)
+}
+
+fn add_login_fields(fields: &mut LoginFields, item: &Item) {
+    for login_field in &item.details.login_fields {
+        let value = login_field.value.clone().filter(|value| !value.is_empty());
+        match login_field.designation.as_deref() {
+            Some("username") => fields.username = value,
+            Some("password") => fields.password = value,
+            _ => {}
+        }
+    }
+    if fields.password.is_none() {
+        fields.password = item.details.password.clone().filter(|password| !password.is_empty());
+    }
+
+    let mut urls = item.overview.url.iter().chain(item.overview.urls.iter().map(|url| &url.url)).filter(|url| !url.is_empty());
+    fields.url = urls.next().cloned();
+    let mut extra = 1;
+    for url in urls {
+        if Some(url) == fields.url.as_ref() {
+            continue;
+        }
+        extra += 1;
+        fields.add_field(&format!("URL {}", extra), url);
+    }
+}
+
+fn timestamp(seconds: i64) -> Option<DateTime<Utc>> {
+    Utc.timestamp_opt(seconds, 0).single()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::io::Write;
+    use zip::write::FileOptions;
+    use zip::ZipWriter;
+
+    fn zip_of(entries: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, contents) in entries {
+            zip.start_file(*name, FileOptions::default()).unwrap();
+            zip.write_all(contents).unwrap();
+        }
+        zip.finish().unwrap().into_inner()
+    }
+
+    fn export_of(items: serde_json::Value) -> Vec<u8> {
+        json!({ "accounts": [{ "vaults": [{ "attrs": { "name": "Personal" }, "items": items }] }] }).to_string().into_bytes()
+    }
+
+    fn parse(entries: &[(&str, &[u8])]) -> Result<ParsedImport> {
+        OnePasswordImporter::new().parse(&zip_of(entries))
+    }
+
+    fn content(item: &ImportedItem) -> LoginFields {
+        LoginFields::parse(std::str::from_utf8(item.content.as_deref().unwrap()).unwrap())
+    }
+
+    #[test]
+    fn maps_categories_to_items() {
+        let export = export_of(json!([
+            {
+                "categoryUuid": LOGIN,
+                "favIndex": 1,
+                "overview": { "title": "Mail", "url": "https://mail.example", "urls": [{ "url": "https://mail.example" }, { "url": "https://alt.example" }], "tags": ["work"] },
+                "details": {
+                    "loginFields": [{ "value": "me", "designation": "username" }, { "value": "hunter2", "designation": "password" }],
+                    "passwordHistory": [{ "value": "hunter1", "time": 1_700_000_000 }]
+                }
+            },
+            {
+                "categoryUuid": CREDIT_CARD,
+                "state": ARCHIVED_STATE,
+                "overview": { "title": "Visa" },
+                "details": { "sections": [{ "fields": [
+                    { "title": "number", "id": "ccnum", "value": { "creditCardNumber": "4111" } },
+                    { "title": "expiry date", "id": EXPIRY_FIELD, "value": { "monthYear": 203002 } }
+                ] }] }
+            },
+            { "categoryUuid": DOCUMENT, "overview": { "title": "Scan" }, "details": { "documentAttributes": { "fileName": "scan.pdf", "documentId": "doc1" } } },
+            {
+                "categoryUuid": "003",
+                "overview": { "title": "Note with file" },
+                "details": { "notesPlain": "see file", "sections": [{ "fields": [{ "title": "file", "value": { "file": { "fileName": "a.txt", "documentId": "doc2" } } }] }] }
+            },
+            { "categoryUuid": "003", "overview": { "title": "Empty" } },
+            { "categoryUuid": LOGIN, "overview": {} }
+        ]));
+        let parsed = parse(&[(EXPORT_DATA, &export), ("files/doc1__scan.pdf", b"%PDF"), ("files/doc2__a.txt", b"attached")]).unwrap();
+
+        let names: Vec<&str> = parsed.items.iter().map(|item| item.vault_item.name.as_str()).collect();
+        assert_eq!(names, ["Personal", "Mail", "Visa", "Scan", "Note with file", "a.txt"]);
+        assert_eq!(parsed.result.error_count, 2);
+        let [vault, mail, card, scan, note, attachment] = &parsed.items[..] else { unreachable!() };
+
+        assert_eq!(mail.vault_item.parent_id.as_ref(), Some(&vault.vault_item.id));
+        assert_eq!(mail.vault_item.tags, ["work", FAVORITE_TAG]);
+        let login = content(mail);
+        assert_eq!((login.username.as_deref(), login.password.as_deref()), (Some("me"), Some("hunter2")));
+        assert_eq!(login.url.as_deref(), Some("https://mail.example"));
+        assert_eq!(login.get("URL 2"), Some("https://alt.example"));
+        assert_eq!(mail.password_history[0].password, "hunter1");
+
+        assert_eq!(card.vault_item.tags, [ARCHIVED_TAG]);
+        assert_eq!(card.vault_item.expires_at, end_of_month(2030, 2));
+        let card_content = std::str::from_utf8(card.content.as_deref().unwrap()).unwrap();
+        assert!(card_content.contains("number: 4111\n\nexpiry date: 02/2030"), "{}", card_content);
+
+        assert_eq!(scan.vault_item.item_type, "application/pdf");
+        assert_eq!(scan.content.as_deref(), Some(&b"%PDF"[..]));
+        assert_eq!(content(note).notes.as_deref(), Some("see file"));
+        assert_eq!(attachment.vault_item.parent_id.as_ref(), Some(&note.vault_item.id));
+        assert_eq!(attachment.content.as_deref(), Some(&b"attached"[..]));
+    }
+
+    #[test]
+    fn reports_missing_attachments_per_item() {
+        let export = export_of(json!([{ "categoryUuid": DOCUMENT, "overview": { "title": "Scan" }, "details": { "documentAttributes": { "fileName": "scan.pdf", "documentId": "gone" } } }]));
+        let parsed = parse(&[(EXPORT_DATA, &export)]).unwrap();
+        assert_eq!(parsed.items.len(), 1);
+        assert_eq!(parsed.result.error_count, 1);
+        assert!(parsed.result.errors[0].contains("missing"), "{:?}", parsed.result.errors);
+    }
+
+    #[test]
+    fn rejects_archives_without_export_data() {
+        assert!(parse(&[("other.json", b"{}")]).is_err());
+        assert!(OnePasswordImporter::new().parse(b"not a zip").is_err());
+    }
+
+    #[test]
+    fn bounds_entry_reads() {
+        let data = zip_of(&[(EXPORT_DATA, &[b' '; 2048])]);
+        let mut archive = Archive::new(&data).unwrap();
+        archive.max_entry_size = 2048;
+        assert_eq!(archive.read(EXPORT_DATA).unwrap().len(), 2048);
+        archive.max_entry_size = 2047;
+        assert!(matches!(archive.read(EXPORT_DATA), Err(Error::InvalidInput(_))));
+    }
+}
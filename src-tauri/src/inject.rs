@@ -0,0 +1,247 @@
+//! Secrets handed to other processes. Environment values of the form
+//! `FETCH://folder/item/field` are references to one field of a vault item;
+//! they are resolved just before a command starts, and the resolved values are
+//! masked again in whatever the command prints.
+
+use crate::crypto::Crypto;
+use crate::error::Error;
+use crate::storage::Storage;
+use crate::vault;
+use crate::Result;
+use log::debug;
+use std::io::{self, Write};
+use zeroize::Zeroizing;
+
+pub const REFERENCE_SCHEME: &str = "FETCH://";
+
+/// Written in place of a secret in masked output.
+pub const MASK: &[u8] = b"<concealed by fetch>";
+
+/// A reference to one field of a vault item.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SecretReference {
+    /// The item's path or id, as accepted by [`vault::find_item`].
+    pub item: String,
+    pub field: String,
+}
+
+impl SecretReference {
+    /// Parses `FETCH://<item path>/<field>`. Returns `None` for values that
+    /// are not references at all, so they can be passed through unchanged.
+    pub fn parse(value: &str) -> Option<Result<Self>> {
+        let reference = value.trim().strip_prefix(REFERENCE_SCHEME)?;
+        Some(match reference.trim_matches(vault::PATH_SEPARATOR).rsplit_once(vault::PATH_SEPARATOR) {
+            Some((item, field)) if !item.is_empty() && !field.is_empty() => Ok(SecretReference {
+                item: item.to_string(),
+                field: field.to_string(),
+            }),
+            _ => Err(Error::InvalidInput(format!(
+                "'{}' is not a valid reference. Use {}folder/item/field.",
+                value, REFERENCE_SCHEME
+            ))),
+        })
+    }
+
+    pub fn resolve(&self, storage: &dyn Storage, crypto: &Crypto) -> Result<Zeroizing<String>> {
+        let item = vault::find_item(storage, crypto, &self.item)?;
+        vault::item_field(storage, crypto, &item, &self.field)
+    }
+}
+
+/// Environment variables with every reference replaced by its value.
+pub struct ResolvedEnvironment {
+    pub variables: Vec<(String, Zeroizing<String>)>,
+    /// The values that came from the vault, for masking.
+    pub secrets: Vec<Zeroizing<String>>,
+}
+
+/// Resolves the references among `variables`. Other values are kept as they are.
+pub fn resolve_environment(
+    storage: &dyn Storage,
+    crypto: &Crypto,
+    variables: impl IntoIterator<Item = (String, String)>,
+) -> Result<ResolvedEnvironment> {
+    let mut resolved = ResolvedEnvironment { variables: Vec::new(), secrets: Vec::new() };
+    for (name, value) in variables {
+        let value = match SecretReference::parse(&value) {
+            Some(reference) => {
+                debug!("Resolving secret reference for {}", name);
+                let secret = reference?.resolve(storage, crypto).map_err(|e| {
+                    Error::InvalidInput(format!("Could not resolve {}: {}", name, e))
+                })?;
+                resolved.secrets.push(secret.clone());
+                secret
+            }
+            None => Zeroizing::new(value),
+        };
+        resolved.variables.push((name, value));
+    }
+    Ok(resolved)
+}
+
+/// Parses a `.env` file: `NAME=value` lines, optionally prefixed with
+/// `export`, with `#` comments and single- or double-quoted values.
+pub fn parse_env_file(content: &str) -> Result<Vec<(String, String)>> {
+    let mut variables = Vec::new();
+    for (number, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let Some((name, value)) = line.split_once('=') else {
+            return Err(Error::InvalidInput(format!("Line {} of the env file is not NAME=value", number + 1)));
+        };
+
+        let name = name.trim();
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return Err(Error::InvalidInput(format!("Line {} of the env file has an invalid name '{}'", number + 1, name)));
+        }
+        variables.push((name.to_string(), unquote(value.trim()).to_string()));
+    }
+    Ok(variables)
+}
+
+fn unquote(value: &str) -> &str {
+    for quote in ['"', '\''] {
+        if let Some(inner) = value.strip_prefix(quote).and_then(|rest| rest.strip_suffix(quote)) {
+            return inner;
+        }
+    }
+    // An unquoted value ends at a comment.
+    match value.find(" #") {
+        Some(comment) => value[..comment].trim_end(),
+        None => value,
+    }
+}
+
+/// Copies output to `inner`, writing [`MASK`] over every occurrence of a
+/// secret. Bytes that could be the start of a secret are held back until the
+/// next write shows whether they are, so a secret split across writes is
+/// still caught; [`OutputMasker::finish`] writes whatever is left.
+pub struct OutputMasker<W: Write> {
+    inner: W,
+    secrets: Vec<Zeroizing<Vec<u8>>>,
+    pending: Zeroizing<Vec<u8>>,
+}
+
+impl<W: Write> OutputMasker<W> {
+    pub fn new(inner: W, secrets: &[Zeroizing<String>]) -> Self {
+        let mut secrets: Vec<Zeroizing<Vec<u8>>> = secrets
+            .iter()
+            .filter(|secret| !secret.is_empty())
+            .map(|secret| Zeroizing::new(secret.as_bytes().to_vec()))
+            .collect();
+        // Longest first, so a secret containing another is masked whole.
+        secrets.sort_by_key(|secret| std::cmp::Reverse(secret.len()));
+        OutputMasker { inner, secrets, pending: Zeroizing::new(Vec::new()) }
+    }
+
+    pub fn finish(mut self) -> io::Result<W> {
+        self.flush_pending(true)?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+
+    fn flush_pending(&mut self, at_end: bool) -> io::Result<()> {
+        let mut output = Zeroizing::new(Vec::with_capacity(self.pending.len()));
+        let mut position = 0;
+        while position < self.pending.len() {
+            let rest = &self.pending[position..];
+            // Wait while the rest could still grow into a secret, even one that
+            // a shorter secret already matches, so the longer one is masked whole.
+            if !at_end && self.secrets.iter().any(|secret| secret.len() > rest.len() && secret.starts_with(rest)) {
+                break;
+            }
+            if let Some(secret) = self.secrets.iter().find(|secret| rest.starts_with(secret)) {
+                output.extend_from_slice(MASK);
+                position += secret.len();
+            } else {
+                output.push(rest[0]);
+                position += 1;
+            }
+        }
+
+        self.inner.write_all(&output)?;
+        self.pending.drain(..position);
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for OutputMasker<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.pending.extend_from_slice(buf);
+        self.flush_pending(false)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn masked(secrets: &[&str], writes: &[&str]) -> String {
+        let secrets: Vec<Zeroizing<String>> = secrets.iter().map(|secret| Zeroizing::new(secret.to_string())).collect();
+        let mut masker = OutputMasker::new(Vec::new(), &secrets);
+        for write in writes {
+            masker.write_all(write.as_bytes()).unwrap();
+        }
+        String::from_utf8(masker.finish().unwrap()).unwrap()
+    }
+
+    fn mask() -> &'static str {
+        std::str::from_utf8(MASK).unwrap()
+    }
+
+    #[test]
+    fn parses_env_files() {
+        let content = "# comment\n\nexport TOKEN=FETCH://Work/API/token\nPLAIN = value # trailing\nDOUBLE=\"a # b\"\nSINGLE='x=y'\nEMPTY=\nHASH=a#b\n";
+        let variables = parse_env_file(content).unwrap();
+        let expected = [
+            ("TOKEN", "FETCH://Work/API/token"),
+            ("PLAIN", "value"),
+            ("DOUBLE", "a # b"),
+            ("SINGLE", "x=y"),
+            ("EMPTY", ""),
+            ("HASH", "a#b"),
+        ];
+        assert_eq!(variables, expected.map(|(name, value)| (name.to_string(), value.to_string())));
+    }
+
+    #[test]
+    fn rejects_malformed_env_lines() {
+        assert!(parse_env_file("NO_EQUALS_SIGN").is_err());
+        assert!(parse_env_file("BAD-NAME=1").is_err());
+        assert!(parse_env_file("=value").is_err());
+    }
+
+    #[test]
+    fn parses_references() {
+        let reference = SecretReference::parse("FETCH://Work/Servers/db01/password").unwrap().unwrap();
+        assert_eq!(reference, SecretReference { item: "Work/Servers/db01".to_string(), field: "password".to_string() });
+        assert!(SecretReference::parse("plain value").is_none());
+        assert!(SecretReference::parse("FETCH://item-only").unwrap().is_err());
+    }
+
+    #[test]
+    fn masks_secrets_in_output() {
+        assert_eq!(masked(&["hunter2"], &["pw=hunter2; again hunter2\n"]), format!("pw={0}; again {0}\n", mask()));
+        assert_eq!(masked(&["hunter2"], &["hunt", "er"]), "hunter");
+    }
+
+    #[test]
+    fn masks_a_secret_split_across_writes() {
+        assert_eq!(masked(&["hunter2"], &["pw=hun", "te", "r2!"]), format!("pw={}!", mask()));
+    }
+
+    #[test]
+    fn masks_the_longer_of_two_secrets_sharing_a_prefix() {
+        assert_eq!(masked(&["abc", "abcdef"], &["abc", "def"]), mask());
+        assert_eq!(masked(&["abc", "abcdef"], &["abc", "dex"]), format!("{}dex", mask()));
+        assert_eq!(masked(&["abc", "abcdef"], &["abcd"]), format!("{}d", mask()));
+    }
+}
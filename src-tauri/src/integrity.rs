@@ -0,0 +1,226 @@
+use crate::crypto::Crypto;
+use crate::storage::Storage;
+use crate::Result;
+use log::{info, warn};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+
+/// A way in which `vault_items` and `data/` disagree with each other.
+#[derive(Debug, Serialize, Clone, PartialEq, Eq)]
+pub enum IntegrityIssue {
+    /// A row that fails to decrypt or parse. It makes every full read of the vault fail.
+    UndecryptableItem { id: String, error: String },
+    /// An item whose content file is gone, or that never had one.
+    MissingDataFile { id: String, name: String, data_path: String },
+    /// A file in `data/` that no item points to.
+    OrphanedDataFile { file_name: String },
+    /// An item whose parent does not exist, so it is not shown anywhere.
+    DanglingParent { id: String, name: String, parent_id: String },
+    /// Items that are each other's ancestors, so none of them are reachable.
+    ParentCycle { ids: Vec<String> },
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct IntegrityReport {
+    pub items_checked: usize,
+    pub data_files_checked: usize,
+    pub issues: Vec<IntegrityIssue>,
+}
+
+impl IntegrityReport {
+    pub fn is_healthy(&self) -> bool {
+        self.issues.is_empty()
+    }
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct RepairReport {
+    pub quarantined_items: usize,
+    pub quarantined_files: usize,
+    pub reparented_items: usize,
+    /// Whatever a fresh check still finds after the repair.
+    pub remaining: Vec<IntegrityIssue>,
+}
+
+/// Checks every row of the vault and every file in `data/` against each other.
+/// Nothing is changed.
+pub fn verify_vault(storage: &dyn Storage, crypto: &Crypto) -> Result<IntegrityReport> {
+    let (items, unreadable) = storage.scan_items(crypto)?;
+    let data_files = storage.data_file_names()?;
+    let mut issues = Vec::new();
+
+    for item in &unreadable {
+        issues.push(IntegrityIssue::UndecryptableItem { id: item.id.clone(), error: item.error.clone() });
+    }
+
+    let existing_files: HashSet<&str> = data_files.iter().map(String::as_str).collect();
+    for item in &items {
+        let has_file = !item.data_path.is_empty() && existing_files.contains(item.data_path.as_str());
+        if item.item_type != "folder" && !has_file {
+            issues.push(IntegrityIssue::MissingDataFile { id: item.id.clone(), name: item.name.clone(), data_path: item.data_path.clone() });
+        }
+    }
+
+    let referenced: HashSet<&str> = items.iter().map(|item| item.data_path.as_str()).filter(|path| !path.is_empty()).collect();
+    let mut orphaned: Vec<&String> = data_files.iter().filter(|file_name| !referenced.contains(file_name.as_str())).collect();
+    orphaned.sort();
+    if !orphaned.is_empty() && !unreadable.is_empty() {
+        // Undecryptable rows hide which files they point to.
+        warn!("{} orphaned data files may belong to undecryptable items.", orphaned.len());
+    }
+    for file_name in orphaned {
+        issues.push(IntegrityIssue::OrphanedDataFile { file_name: file_name.clone() });
+    }
+
+    // Undecryptable rows still have a plaintext id and parent, so they take part
+    // in the hierarchy checks.
+    let parents: HashMap<&str, Option<&str>> = items
+        .iter()
+        .map(|item| (item.id.as_str(), item.parent_id.as_deref()))
+        .chain(unreadable.iter().map(|item| (item.id.as_str(), item.parent_id.as_deref())))
+        .collect();
+    for item in &items {
+        if let Some(parent_id) = item.parent_id.as_deref().filter(|parent_id| !parents.contains_key(parent_id)) {
+            issues.push(IntegrityIssue::DanglingParent { id: item.id.clone(), name: item.name.clone(), parent_id: parent_id.to_string() });
+        }
+    }
+    for ids in find_cycles(&parents) {
+        issues.push(IntegrityIssue::ParentCycle { ids });
+    }
+
+    info!("Checked {} items and {} data files, found {} issues.", items.len() + unreadable.len(), data_files.len(), issues.len());
+    Ok(IntegrityReport { items_checked: items.len() + unreadable.len(), data_files_checked: data_files.len(), issues })
+}
+
+/// Fixes what `verify_vault` finds. Undecryptable items and items without
+/// content are moved to the quarantine table, orphaned files (including those
+/// of quarantined items) to `quarantine/`, items with a missing parent go to
+/// the top level, and each cycle is broken by moving one of its items to the
+/// top level. Nothing is deleted.
+pub fn repair_vault(storage: &dyn Storage, crypto: &Crypto) -> Result<RepairReport> {
+    let report = verify_vault(storage, crypto)?;
+
+    let mut quarantined = Vec::new();
+    for issue in &report.issues {
+        match issue {
+            IntegrityIssue::UndecryptableItem { id, error } => quarantined.push((id.clone(), format!("Undecryptable: {}", error))),
+            IntegrityIssue::MissingDataFile { id, data_path, .. } if data_path.is_empty() => quarantined.push((id.clone(), "No data file".to_string())),
+            IntegrityIssue::MissingDataFile { id, data_path, .. } => quarantined.push((id.clone(), format!("Data file {} is missing", data_path))),
+            IntegrityIssue::OrphanedDataFile { .. } | IntegrityIssue::DanglingParent { .. } | IntegrityIssue::ParentCycle { .. } => {}
+        }
+    }
+    storage.quarantine_items(&quarantined)?;
+
+    // Quarantined items no longer point to their files and can leave children
+    // without a parent, so check again before fixing files and the hierarchy.
+    let mut quarantined_files = 0;
+    let mut reparented_items = 0;
+    for issue in verify_vault(storage, crypto)?.issues {
+        let id = match issue {
+            IntegrityIssue::OrphanedDataFile { file_name } => {
+                storage.quarantine_data_file(&file_name)?;
+                quarantined_files += 1;
+                continue;
+            }
+            IntegrityIssue::DanglingParent { id, .. } => id,
+            IntegrityIssue::ParentCycle { ids } => ids.into_iter().min().unwrap_or_default(),
+            IntegrityIssue::UndecryptableItem { .. } | IntegrityIssue::MissingDataFile { .. } => continue,
+        };
+        storage.set_item_parent(&id, None)?;
+        reparented_items += 1;
+    }
+
+    let remaining = verify_vault(storage, crypto)?.issues;
+    info!(
+        "Vault repair quarantined {} items and {} files and moved {} items to the top level; {} issues remain.",
+        quarantined.len(),
+        quarantined_files,
+        reparented_items,
+        remaining.len()
+    );
+    Ok(RepairReport { quarantined_items: quarantined.len(), quarantined_files, reparented_items, remaining })
+}
+
+/// Each parent cycle once, as the ids on it. Items that merely hang below a
+/// cycle are not part of it.
+fn find_cycles(parents: &HashMap<&str, Option<&str>>) -> Vec<Vec<String>> {
+    let mut ids: Vec<&str> = parents.keys().copied().collect();
+    ids.sort_unstable();
+
+    let mut visited: HashSet<&str> = HashSet::new();
+    let mut cycles = Vec::new();
+    for start in ids {
+        let mut path: Vec<&str> = Vec::new();
+        let mut positions: HashMap<&str, usize> = HashMap::new();
+        let mut current = Some(start);
+        while let Some(id) = current {
+            if visited.contains(id) {
+                break;
+            }
+            if let Some(&position) = positions.get(id) {
+                cycles.push(path[position..].iter().map(|id| id.to_string()).collect());
+                break;
+            }
+            positions.insert(id, path.len());
+            path.push(id);
+            current = parents.get(id).copied().flatten();
+        }
+        visited.extend(path);
+    }
+    cycles
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+    use crate::vault;
+
+    #[test]
+    fn a_healthy_vault_needs_no_repair() {
+        let (storage, crypto) = MemoryStorage::unlocked();
+        vault::add_text_item(&storage, &crypto, "Note".to_string(), "text".to_string(), b"hello", Vec::new(), None).unwrap();
+
+        let report = repair_vault(&storage, &crypto).unwrap();
+        assert_eq!((report.quarantined_items, report.quarantined_files, report.reparented_items), (0, 0, 0));
+        assert!(report.remaining.is_empty());
+    }
+
+    #[test]
+    fn quarantines_an_undecryptable_item_and_its_file_in_one_pass() {
+        let (storage, crypto) = MemoryStorage::unlocked();
+        let kept = vault::add_text_item(&storage, &crypto, "Kept".to_string(), "text".to_string(), b"hello", Vec::new(), None).unwrap();
+
+        let mut other_crypto = Crypto::new();
+        other_crypto.unlock(&[9; 32]).unwrap();
+        let mut foreign = crate::import::new_item("Foreign".to_string(), "text/plain", None, Vec::new());
+        foreign.data_path = "foreign".to_string();
+        let mut child = crate::import::new_item("Child".to_string(), "text/plain", Some(foreign.id.clone()), Vec::new());
+        child.data_path = "child".to_string();
+        storage.add_items_atomically(&[(foreign.clone(), Some(b"unreadable".to_vec()))], &[], &other_crypto).unwrap();
+        storage.add_items_atomically(&[(child.clone(), Some(b"readable".to_vec()))], &[], &crypto).unwrap();
+        assert!(!verify_vault(&storage, &crypto).unwrap().is_healthy());
+
+        let report = repair_vault(&storage, &crypto).unwrap();
+
+        assert_eq!((report.quarantined_items, report.quarantined_files, report.reparented_items), (1, 1, 1));
+        assert!(report.remaining.is_empty());
+        assert_eq!(storage.data_file_names().unwrap().len(), 2);
+        assert!(storage.get_item(&kept.id, &crypto).unwrap().is_some());
+        assert_eq!(storage.get_item(&child.id, &crypto).unwrap().unwrap().parent_id, None);
+    }
+
+    #[test]
+    fn breaks_parent_cycles() {
+        let (storage, crypto) = MemoryStorage::unlocked();
+        let first = vault::add_folder(&storage, &crypto, "First".to_string(), None, None).unwrap();
+        let second = vault::add_folder(&storage, &crypto, "Second".to_string(), None, Some(first.id.clone())).unwrap();
+        storage.set_item_parent(&first.id, Some(&second.id)).unwrap();
+        assert!(matches!(verify_vault(&storage, &crypto).unwrap().issues.as_slice(), [IntegrityIssue::ParentCycle { ids }] if ids.len() == 2));
+
+        let report = repair_vault(&storage, &crypto).unwrap();
+
+        assert_eq!(report.reparented_items, 1);
+        assert!(report.remaining.is_empty());
+    }
+}
@@ -0,0 +1,1325 @@
+use crate::crypto;
+use crate::error::Error;
+use crate::Result;
+use aes::cipher::block_padding::Pkcs7;
+use aes::cipher::{BlockDecryptMut, BlockEncrypt, BlockEncryptMut, KeyInit, KeyIvInit, StreamCipher};
+use aes::Aes256;
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use chacha20::ChaCha20;
+use chrono::{DateTime, TimeZone, Utc};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use hmac::{Hmac, Mac};
+use log::{debug, info};
+use quick_xml::escape::escape;
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::Reader;
+use rand::{rngs::OsRng, RngCore};
+use salsa20::Salsa20;
+use sha2::{Digest, Sha256, Sha512};
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use twofish::Twofish;
+use uuid::Uuid;
+use zeroize::Zeroizing;
+
+const SIGNATURE_1: u32 = 0x9AA2_D903;
+const SIGNATURE_2: u32 = 0xB54B_FB67;
+const VERSION_4: u32 = 0x0004_0000;
+
+const CIPHER_AES256: [u8; 16] = [0x31, 0xC1, 0xF2, 0xE6, 0xBF, 0x71, 0x43, 0x50, 0xBE, 0x58, 0x05, 0x21, 0x6A, 0xFC, 0x5A, 0xFF];
+const CIPHER_TWOFISH: [u8; 16] = [0xAD, 0x68, 0xF2, 0x9F, 0x57, 0x6F, 0x4B, 0xB9, 0xA3, 0x6A, 0xD4, 0x7A, 0xF9, 0x65, 0x34, 0x6C];
+const CIPHER_CHACHA20: [u8; 16] = [0xD6, 0x03, 0x8A, 0x2B, 0x8B, 0x6F, 0x4C, 0xB5, 0xA5, 0x24, 0x33, 0x9A, 0x31, 0xDB, 0xB5, 0x9A];
+
+const KDF_AES: [u8; 16] = [0xC9, 0xD9, 0xF3, 0x9A, 0x62, 0x8A, 0x44, 0x60, 0xBF, 0x74, 0x0D, 0x08, 0xC1, 0x8A, 0x4F, 0xEA];
+const KDF_ARGON2D: [u8; 16] = [0xEF, 0x63, 0x6D, 0xDF, 0x8C, 0x29, 0x44, 0x4B, 0x91, 0xF7, 0xA9, 0xA4, 0x03, 0xE3, 0x0A, 0x0C];
+const KDF_ARGON2ID: [u8; 16] = [0x9E, 0x29, 0x8B, 0x19, 0x56, 0xDB, 0x47, 0x73, 0xB2, 0x3D, 0xFC, 0x3E, 0xC6, 0xF0, 0xA1, 0xE6];
+
+const INNER_STREAM_SALSA20: u32 = 2;
+const INNER_STREAM_CHACHA20: u32 = 3;
+const SALSA20_NONCE: [u8; 8] = [0xE8, 0x30, 0x09, 0x4B, 0x97, 0x20, 0x5D, 0x2A];
+
+// KeePass defaults for new KDBX 4 databases.
+const EXPORT_ARGON2_MEMORY: u64 = 64 * 1024 * 1024;
+const EXPORT_ARGON2_ITERATIONS: u64 = 2;
+const EXPORT_ARGON2_PARALLELISM: u32 = 2;
+const BLOCK_SIZE: usize = 1024 * 1024;
+/// AES-KDF rounds beyond this take minutes; KeePass calibrates to about a second.
+const MAX_AES_KDF_ROUNDS: u64 = 100_000_000;
+/// Largest decompressed payload or attachment, against gzip bombs.
+const MAX_DECOMPRESSED_SIZE: u64 = 512 * 1024 * 1024;
+/// Deepest element nesting accepted in the XML. The document is walked
+/// recursively (protected values, groups, entry history), so this bounds the stack.
+const MAX_XML_DEPTH: usize = 256;
+
+/// Seconds between 0001-01-01, the KDBX 4 time epoch, and the Unix epoch.
+const KDBX_EPOCH_OFFSET: i64 = 62_135_596_800;
+
+pub const TITLE: &str = "Title";
+pub const USERNAME: &str = "UserName";
+pub const PASSWORD: &str = "Password";
+pub const URL: &str = "URL";
+pub const NOTES: &str = "Notes";
+const STANDARD_FIELDS: [&str; 5] = [TITLE, USERNAME, PASSWORD, URL, NOTES];
+
+/// The password and key file that together unlock a KeePass database.
+pub struct DatabaseKey {
+    password: Option<Zeroizing<String>>,
+    keyfile: Option<Zeroizing<Vec<u8>>>,
+}
+
+impl DatabaseKey {
+    pub fn new(password: Option<String>, keyfile: Option<Vec<u8>>) -> Result<Self> {
+        if password.is_none() && keyfile.is_none() {
+            return Err(Error::InvalidInput("A KeePass password or key file is required".into()));
+        }
+        Ok(Self {
+            password: password.map(Zeroizing::new),
+            keyfile: keyfile.map(Zeroizing::new),
+        })
+    }
+
+    fn composite(&self) -> Result<Zeroizing<Vec<u8>>> {
+        let mut hasher = Sha256::new();
+        if let Some(password) = &self.password {
+            hasher.update(Sha256::digest(password.as_bytes()));
+        }
+        if let Some(keyfile) = &self.keyfile {
+            hasher.update(keyfile_key(keyfile)?);
+        }
+        Ok(Zeroizing::new(hasher.finalize().to_vec()))
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Times {
+    pub created: Option<DateTime<Utc>>,
+    pub modified: Option<DateTime<Utc>>,
+    /// Only set when the entry or group is marked as expiring.
+    pub expires: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct EntryField {
+    pub key: String,
+    pub value: String,
+    pub protected: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct Attachment {
+    pub name: String,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Entry {
+    pub uuid: Uuid,
+    pub fields: Vec<EntryField>,
+    pub tags: Vec<String>,
+    pub times: Times,
+    pub attachments: Vec<Attachment>,
+    /// Earlier versions of the entry, oldest first.
+    pub history: Vec<Entry>,
+}
+
+impl Entry {
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.fields
+            .iter()
+            .find(|field| field.key == key)
+            .map(|field| field.value.as_str())
+            .filter(|value| !value.is_empty())
+    }
+
+    pub fn set(&mut self, key: &str, value: impl Into<String>, protected: bool) {
+        let value = value.into();
+        match self.fields.iter_mut().find(|field| field.key == key) {
+            Some(field) => {
+                field.value = value;
+                field.protected = protected;
+            }
+            None => self.fields.push(EntryField { key: key.to_string(), value, protected }),
+        }
+    }
+
+    /// Fields other than title, username, password, URL and notes.
+    pub fn custom_fields(&self) -> impl Iterator<Item = &EntryField> {
+        self.fields.iter().filter(|field| !STANDARD_FIELDS.contains(&field.key.as_str()))
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Group {
+    pub uuid: Uuid,
+    pub name: String,
+    pub times: Times,
+    pub groups: Vec<Group>,
+    pub entries: Vec<Entry>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Database {
+    pub name: String,
+    pub root: Group,
+    pub recycle_bin: Option<Uuid>,
+}
+
+/// Reads a KDBX 3.1 or 4.x database.
+pub fn read(data: &[u8], key: &DatabaseKey) -> Result<Database> {
+    let mut reader = ByteReader::new(data);
+    if reader.u32()? != SIGNATURE_1 || reader.u32()? != SIGNATURE_2 {
+        return Err(Error::InvalidInput("Not a KeePass database".into()));
+    }
+    let major_version = (reader.u32()? >> 16) as u16;
+    if major_version != 3 && major_version != 4 {
+        return Err(Error::InvalidInput(format!("Unsupported KeePass database version {}", major_version)));
+    }
+
+    let header = OuterHeader::read(&mut reader, major_version)?;
+    let header_bytes = &data[..reader.pos];
+    debug!("Reading KDBX {} database", major_version);
+
+    let transformed_key = header.kdf.transform(&key.composite()?)?;
+    let cipher_key = Zeroizing::new(Sha256::new().chain_update(&header.master_seed).chain_update(&*transformed_key).finalize().to_vec());
+
+    let (xml, mut inner_stream, mut binaries) = if major_version == 4 {
+        let stored_hash = reader.take(32)?;
+        if Sha256::digest(header_bytes).as_slice() != stored_hash {
+            return Err(Error::InvalidInput("KeePass header is corrupted".into()));
+        }
+        let hmac_key = hmac_base_key(&header.master_seed, &transformed_key);
+        let stored_hmac = reader.take(32)?;
+        if block_hmac(&hmac_key, u64::MAX, header_bytes) != stored_hmac {
+            return Err(invalid_key());
+        }
+
+        let encrypted = read_hmac_blocks(&mut reader, &hmac_key)?;
+        let payload = decompress(header.compressed, decrypt_payload(&header.cipher, &cipher_key, &header.encryption_iv, &encrypted)?)?;
+        let mut inner = ByteReader::new(&payload);
+        let inner_header = InnerHeader::read(&mut inner)?;
+        let stream = InnerStream::new(inner_header.stream_id, &inner_header.stream_key)?;
+        (payload[inner.pos..].to_vec(), stream, inner_header.binaries)
+    } else {
+        let decrypted = decrypt_payload(&header.cipher, &cipher_key, &header.encryption_iv, reader.rest())?;
+        if decrypted.len() < 32 || decrypted[..32] != header.stream_start_bytes[..] {
+            return Err(invalid_key());
+        }
+        let payload = decompress(header.compressed, read_hashed_blocks(&decrypted[32..])?)?;
+        let stream = InnerStream::new(header.inner_stream_id, &header.protected_stream_key)?;
+        (payload, stream, Vec::new())
+    };
+
+    let mut document = parse_xml(&xml)?;
+    decrypt_protected_values(&mut document, &mut inner_stream)?;
+
+    let meta = document.child("Meta");
+    if major_version == 3 {
+        binaries = read_meta_binaries(meta)?;
+    }
+
+    let root_group = document
+        .child("Root")
+        .and_then(|root| root.child("Group"))
+        .ok_or_else(|| Error::InvalidInput("KeePass database has no root group".into()))?;
+
+    let database = Database {
+        name: meta.and_then(|meta| meta.child_text("DatabaseName")).unwrap_or_default().to_string(),
+        root: read_group(root_group, &binaries)?,
+        recycle_bin: meta
+            .filter(|meta| meta.child_text("RecycleBinEnabled") != Some("False"))
+            .and_then(|meta| meta.child_text("RecycleBinUUID"))
+            .and_then(decode_uuid),
+    };
+    info!("Read KeePass database '{}'", database.name);
+    Ok(database)
+}
+
+/// Writes a KDBX 4 database encrypted with AES-256 and an Argon2d key.
+pub fn write(database: &Database, key: &DatabaseKey) -> Result<Vec<u8>> {
+    let mut master_seed = vec![0u8; 32];
+    let mut encryption_iv = vec![0u8; 16];
+    let mut kdf_salt = vec![0u8; 32];
+    let mut stream_key = Zeroizing::new(vec![0u8; 64]);
+    OsRng.fill_bytes(&mut master_seed);
+    OsRng.fill_bytes(&mut encryption_iv);
+    OsRng.fill_bytes(&mut kdf_salt);
+    OsRng.fill_bytes(&mut stream_key);
+
+    let kdf = Kdf::Argon2 {
+        algorithm: argon2::Algorithm::Argon2d,
+        version: 0x13,
+        salt: kdf_salt.clone(),
+        iterations: EXPORT_ARGON2_ITERATIONS,
+        memory: EXPORT_ARGON2_MEMORY,
+        parallelism: EXPORT_ARGON2_PARALLELISM,
+    };
+    let transformed_key = kdf.transform(&key.composite()?)?;
+    let cipher_key = Zeroizing::new(Sha256::new().chain_update(&master_seed).chain_update(&*transformed_key).finalize().to_vec());
+    let hmac_key = hmac_base_key(&master_seed, &transformed_key);
+
+    let mut header = Vec::new();
+    header.extend_from_slice(&SIGNATURE_1.to_le_bytes());
+    header.extend_from_slice(&SIGNATURE_2.to_le_bytes());
+    header.extend_from_slice(&VERSION_4.to_le_bytes());
+    write_field(&mut header, 2, &CIPHER_AES256);
+    write_field(&mut header, 3, &1u32.to_le_bytes());
+    write_field(&mut header, 4, &master_seed);
+    write_field(&mut header, 7, &encryption_iv);
+    write_field(&mut header, 11, &write_variant_dictionary(&[
+        ("$UUID", Variant::Bytes(KDF_ARGON2D.to_vec())),
+        ("S", Variant::Bytes(kdf_salt)),
+        ("I", Variant::U64(EXPORT_ARGON2_ITERATIONS)),
+        ("M", Variant::U64(EXPORT_ARGON2_MEMORY)),
+        ("P", Variant::U32(EXPORT_ARGON2_PARALLELISM)),
+        ("V", Variant::U32(0x13)),
+    ]));
+    write_field(&mut header, 0, b"\r\n\r\n");
+
+    let mut xml_writer = XmlWriter::new(InnerStream::new(INNER_STREAM_CHACHA20, &stream_key)?);
+    xml_writer.database(database);
+    let XmlWriter { out: xml, binaries, .. } = xml_writer;
+
+    let mut payload = Vec::new();
+    write_field(&mut payload, 1, &INNER_STREAM_CHACHA20.to_le_bytes());
+    write_field(&mut payload, 2, &stream_key);
+    for binary in &binaries {
+        let mut field = Vec::with_capacity(binary.len() + 1);
+        field.push(0);
+        field.extend_from_slice(binary);
+        write_field(&mut payload, 3, &field);
+    }
+    write_field(&mut payload, 0, &[]);
+    payload.extend_from_slice(xml.as_bytes());
+
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(&payload)?;
+    let compressed = encoder.finish()?;
+    let encrypted = cbc::Encryptor::<Aes256>::new_from_slices(&cipher_key, &encryption_iv)
+        .map_err(|e| Error::Encryption(e.to_string()))?
+        .encrypt_padded_vec_mut::<Pkcs7>(&compressed);
+
+    let mut output = header.clone();
+    output.extend_from_slice(&Sha256::digest(&header));
+    output.extend_from_slice(&block_hmac(&hmac_key, u64::MAX, &header));
+    for (index, block) in encrypted.chunks(BLOCK_SIZE).chain(std::iter::once(&[][..])).enumerate() {
+        let mut sized = (block.len() as u32).to_le_bytes().to_vec();
+        sized.extend_from_slice(block);
+        output.extend_from_slice(&block_hmac(&hmac_key, index as u64, &sized));
+        output.extend_from_slice(&sized);
+    }
+
+    info!("Wrote KeePass database '{}' with {} attachments", database.name, binaries.len());
+    Ok(output)
+}
+
+fn invalid_key() -> Error {
+    Error::Decryption("Invalid KeePass password or key file".into())
+}
+
+fn truncated() -> Error {
+    Error::InvalidInput("KeePass database is truncated".into())
+}
+
+struct ByteReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> ByteReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        let end = self.pos.checked_add(len).filter(|end| *end <= self.data.len()).ok_or_else(truncated)?;
+        let slice = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(slice)
+    }
+
+    fn rest(&mut self) -> &'a [u8] {
+        let rest = &self.data[self.pos..];
+        self.pos = self.data.len();
+        rest
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+}
+
+fn le_u32(bytes: &[u8]) -> Result<u32> {
+    Ok(u32::from_le_bytes(bytes.try_into().map_err(|_| truncated())?))
+}
+
+fn le_u64(bytes: &[u8]) -> Result<u64> {
+    Ok(u64::from_le_bytes(bytes.try_into().map_err(|_| truncated())?))
+}
+
+enum Kdf {
+    Aes {
+        seed: Vec<u8>,
+        rounds: u64,
+    },
+    Argon2 {
+        algorithm: argon2::Algorithm,
+        version: u32,
+        salt: Vec<u8>,
+        iterations: u64,
+        memory: u64,
+        parallelism: u32,
+    },
+}
+
+impl Kdf {
+    fn from_parameters(parameters: &HashMap<String, Vec<u8>>) -> Result<Self> {
+        let get = |name: &str| {
+            parameters
+                .get(name)
+                .ok_or_else(|| Error::InvalidInput(format!("KeePass key derivation parameter '{}' is missing", name)))
+        };
+
+        match get("$UUID")?.as_slice() {
+            uuid if uuid == KDF_AES => Ok(Kdf::Aes {
+                seed: get("S")?.clone(),
+                rounds: le_u64(get("R")?)?,
+            }),
+            uuid if uuid == KDF_ARGON2D || uuid == KDF_ARGON2ID => Ok(Kdf::Argon2 {
+                algorithm: if uuid == KDF_ARGON2D { argon2::Algorithm::Argon2d } else { argon2::Algorithm::Argon2id },
+                version: le_u32(get("V")?)?,
+                salt: get("S")?.clone(),
+                iterations: le_u64(get("I")?)?,
+                memory: le_u64(get("M")?)?,
+                parallelism: le_u32(get("P")?)?,
+            }),
+            _ => Err(Error::InvalidInput("Unsupported KeePass key derivation function".into())),
+        }
+    }
+
+    fn transform(&self, composite_key: &[u8]) -> Result<Zeroizing<Vec<u8>>> {
+        match self {
+            Kdf::Aes { seed, rounds } => {
+                if *rounds > MAX_AES_KDF_ROUNDS {
+                    return Err(Error::KeyDerivation(format!("AES-KDF round count {} is too large", rounds)));
+                }
+                let cipher = Aes256::new_from_slice(seed).map_err(|e| Error::KeyDerivation(e.to_string()))?;
+                let mut blocks = [
+                    aes::Block::clone_from_slice(&composite_key[..16]),
+                    aes::Block::clone_from_slice(&composite_key[16..32]),
+                ];
+                for _ in 0..*rounds {
+                    cipher.encrypt_blocks(&mut blocks);
+                }
+                let mut hasher = Sha256::new();
+                hasher.update(blocks[0]);
+                hasher.update(blocks[1]);
+                Ok(Zeroizing::new(hasher.finalize().to_vec()))
+            }
+            Kdf::Argon2 { algorithm, version, salt, iterations, memory, parallelism } => {
+                let version = match version {
+                    0x10 => argon2::Version::V0x10,
+                    0x13 => argon2::Version::V0x13,
+                    other => return Err(Error::KeyDerivation(format!("Unsupported Argon2 version {:#x}", other))),
+                };
+                let memory_kib = u32::try_from(memory / 1024).map_err(|_| Error::KeyDerivation("Argon2 memory cost is too large".into()))?;
+                let iterations = u32::try_from(*iterations).map_err(|_| Error::KeyDerivation("Argon2 iteration count is too large".into()))?;
+                let params = crypto::untrusted_argon2_params(memory_kib, iterations, *parallelism, 32)?;
+
+                let mut output = Zeroizing::new(vec![0u8; 32]);
+                argon2::Argon2::new(*algorithm, version, params)
+                    .hash_password_into(composite_key, salt, &mut output)
+                    .map_err(|e| Error::KeyDerivation(e.to_string()))?;
+                Ok(output)
+            }
+        }
+    }
+}
+
+struct OuterHeader {
+    cipher: Vec<u8>,
+    compressed: bool,
+    master_seed: Vec<u8>,
+    encryption_iv: Vec<u8>,
+    kdf: Kdf,
+    // KDBX 3.1 only; KDBX 4 keeps these in the inner header.
+    protected_stream_key: Vec<u8>,
+    stream_start_bytes: Vec<u8>,
+    inner_stream_id: u32,
+}
+
+impl OuterHeader {
+    fn read(reader: &mut ByteReader, major_version: u16) -> Result<Self> {
+        let mut fields: HashMap<u8, &[u8]> = HashMap::new();
+        loop {
+            let id = reader.u8()?;
+            let len = if major_version >= 4 { reader.u32()? as usize } else { reader.u16()? as usize };
+            let value = reader.take(len)?;
+            if id == 0 {
+                break;
+            }
+            fields.insert(id, value);
+        }
+
+        let field = |id: u8, name: &str| -> Result<Vec<u8>> {
+            fields
+                .get(&id)
+                .map(|value| value.to_vec())
+                .ok_or_else(|| Error::InvalidInput(format!("KeePass header field '{}' is missing", name)))
+        };
+
+        let kdf = if major_version >= 4 {
+            Kdf::from_parameters(&read_variant_dictionary(&field(11, "KdfParameters")?)?)?
+        } else {
+            Kdf::Aes {
+                seed: field(5, "TransformSeed")?,
+                rounds: le_u64(&field(6, "TransformRounds")?)?,
+            }
+        };
+
+        Ok(Self {
+            cipher: field(2, "CipherID")?,
+            compressed: le_u32(&field(3, "CompressionFlags")?)? != 0,
+            master_seed: field(4, "MasterSeed")?,
+            encryption_iv: field(7, "EncryptionIV")?,
+            kdf,
+            protected_stream_key: fields.get(&8).map(|v| v.to_vec()).unwrap_or_default(),
+            stream_start_bytes: fields.get(&9).map(|v| v.to_vec()).unwrap_or_default(),
+            inner_stream_id: fields.get(&10).map(|v| le_u32(v)).transpose()?.unwrap_or_default(),
+        })
+    }
+}
+
+struct InnerHeader {
+    stream_id: u32,
+    stream_key: Zeroizing<Vec<u8>>,
+    binaries: Vec<Vec<u8>>,
+}
+
+impl InnerHeader {
+    fn read(reader: &mut ByteReader) -> Result<Self> {
+        let mut header = InnerHeader {
+            stream_id: 0,
+            stream_key: Zeroizing::new(Vec::new()),
+            binaries: Vec::new(),
+        };
+        loop {
+            let id = reader.u8()?;
+            let len = reader.u32()? as usize;
+            let value = reader.take(len)?;
+            match id {
+                0 => break,
+                1 => header.stream_id = le_u32(value)?,
+                2 => header.stream_key = Zeroizing::new(value.to_vec()),
+                // The first byte holds flags such as memory protection.
+                3 => header.binaries.push(value.get(1..).unwrap_or_default().to_vec()),
+                other => debug!("Skipping unknown inner header field {}", other),
+            }
+        }
+        Ok(header)
+    }
+}
+
+enum Variant {
+    U32(u32),
+    U64(u64),
+    Bytes(Vec<u8>),
+}
+
+/// Reads a KDBX 4 variant dictionary, keeping each value's raw little-endian bytes.
+fn read_variant_dictionary(data: &[u8]) -> Result<HashMap<String, Vec<u8>>> {
+    let mut reader = ByteReader::new(data);
+    let _version = reader.u16()?;
+    let mut values = HashMap::new();
+    loop {
+        let value_type = reader.u8()?;
+        if value_type == 0 {
+            break;
+        }
+        let key_len = reader.u32()? as usize;
+        let key = String::from_utf8_lossy(reader.take(key_len)?).into_owned();
+        let value_len = reader.u32()? as usize;
+        values.insert(key, reader.take(value_len)?.to_vec());
+    }
+    Ok(values)
+}
+
+fn write_variant_dictionary(values: &[(&str, Variant)]) -> Vec<u8> {
+    let mut out = 0x0100u16.to_le_bytes().to_vec();
+    for (key, value) in values {
+        let (value_type, bytes) = match value {
+            Variant::U32(value) => (0x04u8, value.to_le_bytes().to_vec()),
+            Variant::U64(value) => (0x05, value.to_le_bytes().to_vec()),
+            Variant::Bytes(value) => (0x42, value.clone()),
+        };
+        out.push(value_type);
+        out.extend_from_slice(&(key.len() as u32).to_le_bytes());
+        out.extend_from_slice(key.as_bytes());
+        out.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+        out.extend_from_slice(&bytes);
+    }
+    out.push(0);
+    out
+}
+
+/// Writes a KDBX 4 outer or inner header field.
+fn write_field(out: &mut Vec<u8>, id: u8, value: &[u8]) {
+    out.push(id);
+    out.extend_from_slice(&(value.len() as u32).to_le_bytes());
+    out.extend_from_slice(value);
+}
+
+fn hmac_base_key(master_seed: &[u8], transformed_key: &[u8]) -> Zeroizing<Vec<u8>> {
+    Zeroizing::new(
+        Sha512::new()
+            .chain_update(master_seed)
+            .chain_update(transformed_key)
+            .chain_update([1u8])
+            .finalize()
+            .to_vec(),
+    )
+}
+
+fn block_hmac(hmac_base_key: &[u8], index: u64, data: &[u8]) -> Vec<u8> {
+    let block_key = Sha512::new().chain_update(index.to_le_bytes()).chain_update(hmac_base_key).finalize();
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&block_key).expect("HMAC accepts any key length");
+    mac.update(&index.to_le_bytes());
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn read_hmac_blocks(reader: &mut ByteReader, hmac_key: &[u8]) -> Result<Vec<u8>> {
+    let mut payload = Vec::new();
+    for index in 0u64.. {
+        let stored_hmac = reader.take(32)?;
+        let size_bytes = reader.take(4)?;
+        let size = le_u32(size_bytes)? as usize;
+        let block = reader.take(size)?;
+
+        let mut sized = size_bytes.to_vec();
+        sized.extend_from_slice(block);
+        if block_hmac(hmac_key, index, &sized) != stored_hmac {
+            return Err(Error::InvalidInput(format!("KeePass data block {} is corrupted", index)));
+        }
+        if size == 0 {
+            break;
+        }
+        payload.extend_from_slice(block);
+    }
+    Ok(payload)
+}
+
+fn read_hashed_blocks(data: &[u8]) -> Result<Vec<u8>> {
+    let mut reader = ByteReader::new(data);
+    let mut payload = Vec::new();
+    loop {
+        let index = reader.u32()?;
+        let hash = reader.take(32)?;
+        let size = reader.u32()? as usize;
+        if size == 0 {
+            break;
+        }
+        let block = reader.take(size)?;
+        if Sha256::digest(block).as_slice() != hash {
+            return Err(Error::InvalidInput(format!("KeePass data block {} is corrupted", index)));
+        }
+        payload.extend_from_slice(block);
+    }
+    Ok(payload)
+}
+
+fn decrypt_payload(cipher: &[u8], key: &[u8], iv: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+    match cipher {
+        c if c == CIPHER_AES256 => cbc::Decryptor::<Aes256>::new_from_slices(key, iv)
+            .map_err(|e| Error::Decryption(e.to_string()))?
+            .decrypt_padded_vec_mut::<Pkcs7>(data)
+            .map_err(|_| invalid_key()),
+        c if c == CIPHER_TWOFISH => cbc::Decryptor::<Twofish>::new_from_slices(key, iv)
+            .map_err(|e| Error::Decryption(e.to_string()))?
+            .decrypt_padded_vec_mut::<Pkcs7>(data)
+            .map_err(|_| invalid_key()),
+        c if c == CIPHER_CHACHA20 => {
+            let mut buffer = data.to_vec();
+            ChaCha20::new_from_slices(key, iv)
+                .map_err(|e| Error::Decryption(e.to_string()))?
+                .apply_keystream(&mut buffer);
+            Ok(buffer)
+        }
+        _ => Err(Error::InvalidInput("Unsupported KeePass cipher".into())),
+    }
+}
+
+fn decompress(compressed: bool, data: Vec<u8>) -> Result<Vec<u8>> {
+    if !compressed {
+        return Ok(data);
+    }
+    let mut decompressed = Vec::new();
+    GzDecoder::new(data.as_slice()).take(MAX_DECOMPRESSED_SIZE + 1).read_to_end(&mut decompressed)?;
+    if decompressed.len() as u64 > MAX_DECOMPRESSED_SIZE {
+        return Err(Error::InvalidInput("KeePass database is too large once decompressed".into()));
+    }
+    Ok(decompressed)
+}
+
+/// The cipher that hides protected values inside the XML document.
+enum InnerStream {
+    None,
+    Salsa20(Box<Salsa20>),
+    ChaCha20(Box<ChaCha20>),
+}
+
+impl InnerStream {
+    fn new(id: u32, key: &[u8]) -> Result<Self> {
+        match id {
+            0 => Ok(InnerStream::None),
+            INNER_STREAM_SALSA20 => {
+                let key = Sha256::digest(key);
+                Ok(InnerStream::Salsa20(Box::new(Salsa20::new(&key, &SALSA20_NONCE.into()))))
+            }
+            INNER_STREAM_CHACHA20 => {
+                let hash = Sha512::digest(key);
+                let cipher = ChaCha20::new_from_slices(&hash[..32], &hash[32..44]).map_err(|e| Error::Crypto(e.to_string()))?;
+                Ok(InnerStream::ChaCha20(Box::new(cipher)))
+            }
+            other => Err(Error::InvalidInput(format!("Unsupported KeePass inner stream cipher {}", other))),
+        }
+    }
+
+    fn apply(&mut self, data: &mut [u8]) {
+        match self {
+            InnerStream::None => {}
+            InnerStream::Salsa20(cipher) => cipher.apply_keystream(data),
+            InnerStream::ChaCha20(cipher) => cipher.apply_keystream(data),
+        }
+    }
+}
+
+/// Derives the 32-byte key from a KeePass key file: XML (version 1 or 2), raw
+/// 32 bytes, 64 hex characters, or the SHA-256 of any other file.
+fn keyfile_key(data: &[u8]) -> Result<Vec<u8>> {
+    if let Some(key) = xml_keyfile_key(data) {
+        return key;
+    }
+    if data.len() == 32 {
+        return Ok(data.to_vec());
+    }
+    if data.len() == 64 {
+        if let Some(key) = std::str::from_utf8(data).ok().and_then(decode_hex) {
+            return Ok(key);
+        }
+    }
+    Ok(Sha256::digest(data).to_vec())
+}
+
+fn xml_keyfile_key(data: &[u8]) -> Option<Result<Vec<u8>>> {
+    let document = parse_xml(data).ok().filter(|node| node.name == "KeyFile")?;
+    let key_data = document.child("Key").and_then(|key| key.child("Data"))?;
+    let version = document.child("Meta").and_then(|meta| meta.child_text("Version")).unwrap_or("1.0");
+
+    let key = if version.starts_with('2') {
+        let hex: String = key_data.text.chars().filter(|c| !c.is_whitespace()).collect();
+        decode_hex(&hex).ok_or_else(|| Error::InvalidInput("Invalid KeePass key file data".into()))
+    } else {
+        STANDARD
+            .decode(key_data.text.trim())
+            .map_err(|_| Error::InvalidInput("Invalid KeePass key file data".into()))
+    };
+    Some(key)
+}
+
+fn decode_hex(text: &str) -> Option<Vec<u8>> {
+    if text.len() % 2 != 0 {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| text.get(i..i + 2).and_then(|pair| u8::from_str_radix(pair, 16).ok()))
+        .collect()
+}
+
+#[derive(Debug, Default)]
+struct XmlNode {
+    name: String,
+    attributes: Vec<(String, String)>,
+    text: String,
+    children: Vec<XmlNode>,
+}
+
+impl XmlNode {
+    fn from_start(start: &BytesStart) -> Result<Self> {
+        let mut node = XmlNode {
+            name: String::from_utf8_lossy(start.name().as_ref()).into_owned(),
+            ..Default::default()
+        };
+        for attribute in start.attributes() {
+            let attribute = attribute.map_err(xml_error)?;
+            let value = attribute.unescape_value().map_err(xml_error)?;
+            node.attributes.push((String::from_utf8_lossy(attribute.key.as_ref()).into_owned(), value.into_owned()));
+        }
+        Ok(node)
+    }
+
+    fn attribute(&self, name: &str) -> Option<&str> {
+        self.attributes.iter().find(|(key, _)| key == name).map(|(_, value)| value.as_str())
+    }
+
+    fn child(&self, name: &str) -> Option<&XmlNode> {
+        self.children.iter().find(|child| child.name == name)
+    }
+
+    fn children_named<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a XmlNode> {
+        self.children.iter().filter(move |child| child.name == name)
+    }
+
+    fn child_text(&self, name: &str) -> Option<&str> {
+        self.child(name).map(|child| child.text.as_str())
+    }
+}
+
+fn xml_error(err: impl std::fmt::Display) -> Error {
+    Error::InvalidInput(format!("Invalid KeePass XML: {}", err))
+}
+
+fn parse_xml(data: &[u8]) -> Result<XmlNode> {
+    let mut reader = Reader::from_reader(data);
+    let mut stack: Vec<XmlNode> = Vec::new();
+    let mut root = None;
+    let mut buf = Vec::new();
+
+    loop {
+        let finished = match reader.read_event_into(&mut buf).map_err(xml_error)? {
+            Event::Start(start) => {
+                if stack.len() >= MAX_XML_DEPTH {
+                    return Err(xml_error("elements are nested too deeply"));
+                }
+                stack.push(XmlNode::from_start(&start)?);
+                None
+            }
+            Event::Empty(start) => Some(XmlNode::from_start(&start)?),
+            Event::End(_) => Some(stack.pop().ok_or_else(|| xml_error("unbalanced end tag"))?),
+            Event::Text(text) => {
+                if let Some(node) = stack.last_mut() {
+                    node.text.push_str(&text.unescape().map_err(xml_error)?);
+                }
+                None
+            }
+            Event::CData(data) => {
+                if let Some(node) = stack.last_mut() {
+                    node.text.push_str(&String::from_utf8_lossy(&data));
+                }
+                None
+            }
+            Event::Eof => break,
+            _ => None,
+        };
+
+        if let Some(node) = finished {
+            match stack.last_mut() {
+                Some(parent) => parent.children.push(node),
+                None => root = Some(node),
+            }
+        }
+        buf.clear();
+    }
+
+    root.ok_or_else(|| xml_error("empty document"))
+}
+
+/// Decrypts `Protected="True"` values in document order, which is the order the
+/// inner stream cipher was applied in. A value that is not UTF-8 keeps its
+/// readable parts rather than failing the whole database.
+fn decrypt_protected_values(node: &mut XmlNode, stream: &mut InnerStream) -> Result<()> {
+    if node.attribute("Protected") == Some("True") {
+        let mut value = Zeroizing::new(STANDARD.decode(node.text.trim()).map_err(xml_error)?);
+        stream.apply(&mut value);
+        node.text = String::from_utf8_lossy(&value).into_owned();
+    }
+    for child in &mut node.children {
+        decrypt_protected_values(child, stream)?;
+    }
+    Ok(())
+}
+
+fn read_meta_binaries(meta: Option<&XmlNode>) -> Result<Vec<Vec<u8>>> {
+    let mut by_id: Vec<(usize, Vec<u8>)> = Vec::new();
+    for binary in meta.and_then(|meta| meta.child("Binaries")).into_iter().flat_map(|b| b.children_named("Binary")) {
+        let id = binary.attribute("ID").and_then(|id| id.parse().ok()).ok_or_else(|| xml_error("binary without an ID"))?;
+        let data = STANDARD.decode(binary.text.trim()).map_err(xml_error)?;
+        let data = decompress(binary.attribute("Compressed") == Some("True"), data)?;
+        by_id.push((id, data));
+    }
+
+    let mut binaries = vec![Vec::new(); by_id.iter().map(|(id, _)| id + 1).max().unwrap_or(0)];
+    for (id, data) in by_id {
+        binaries[id] = data;
+    }
+    Ok(binaries)
+}
+
+fn read_group(node: &XmlNode, binaries: &[Vec<u8>]) -> Result<Group> {
+    let mut group = Group {
+        uuid: node.child_text("UUID").and_then(decode_uuid).unwrap_or_default(),
+        name: node.child_text("Name").unwrap_or_default().to_string(),
+        times: read_times(node.child("Times")),
+        ..Default::default()
+    };
+    for child in &node.children {
+        match child.name.as_str() {
+            "Group" => group.groups.push(read_group(child, binaries)?),
+            "Entry" => group.entries.push(read_entry(child, binaries)?),
+            _ => {}
+        }
+    }
+    Ok(group)
+}
+
+fn read_entry(node: &XmlNode, binaries: &[Vec<u8>]) -> Result<Entry> {
+    let mut entry = Entry {
+        uuid: node.child_text("UUID").and_then(decode_uuid).unwrap_or_default(),
+        tags: node
+            .child_text("Tags")
+            .unwrap_or_default()
+            .split([';', ','])
+            .map(|tag| tag.trim().to_string())
+            .filter(|tag| !tag.is_empty())
+            .collect(),
+        times: read_times(node.child("Times")),
+        ..Default::default()
+    };
+
+    for child in &node.children {
+        match child.name.as_str() {
+            "String" => {
+                let Some(key) = child.child_text("Key") else { continue };
+                let value = child.child("Value");
+                entry.fields.push(EntryField {
+                    key: key.to_string(),
+                    value: value.map(|v| v.text.clone()).unwrap_or_default(),
+                    protected: value.and_then(|v| v.attribute("Protected")) == Some("True"),
+                });
+            }
+            "Binary" => {
+                let Some(name) = child.child_text("Key") else { continue };
+                let data = child
+                    .child("Value")
+                    .and_then(|value| value.attribute("Ref"))
+                    .and_then(|reference| reference.parse::<usize>().ok())
+                    .and_then(|reference| binaries.get(reference))
+                    .ok_or_else(|| xml_error(format!("attachment '{}' refers to a missing binary", name)))?;
+                entry.attachments.push(Attachment { name: name.to_string(), data: data.clone() });
+            }
+            "History" => {
+                for old in child.children_named("Entry") {
+                    entry.history.push(read_entry(old, binaries)?);
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(entry)
+}
+
+fn read_times(node: Option<&XmlNode>) -> Times {
+    let Some(node) = node else { return Times::default() };
+    let time = |name: &str| node.child_text(name).and_then(parse_time);
+    Times {
+        created: time("CreationTime"),
+        modified: time("LastModificationTime"),
+        expires: if node.child_text("Expires") == Some("True") { time("ExpiryTime") } else { None },
+    }
+}
+
+/// KDBX 3.1 stores ISO 8601 times; KDBX 4 stores base64 seconds since year 1.
+fn parse_time(text: &str) -> Option<DateTime<Utc>> {
+    let text = text.trim();
+    if let Ok(time) = DateTime::parse_from_rfc3339(text) {
+        return Some(time.with_timezone(&Utc));
+    }
+    let bytes: [u8; 8] = STANDARD.decode(text).ok()?.try_into().ok()?;
+    Utc.timestamp_opt(i64::from_le_bytes(bytes) - KDBX_EPOCH_OFFSET, 0).single()
+}
+
+fn format_time(time: DateTime<Utc>) -> String {
+    STANDARD.encode((time.timestamp() + KDBX_EPOCH_OFFSET).to_le_bytes())
+}
+
+fn decode_uuid(text: &str) -> Option<Uuid> {
+    let bytes = STANDARD.decode(text.trim()).ok()?;
+    Uuid::from_slice(&bytes).ok()
+}
+
+struct XmlWriter {
+    out: String,
+    stream: InnerStream,
+    binaries: Vec<Vec<u8>>,
+}
+
+impl XmlWriter {
+    fn new(stream: InnerStream) -> Self {
+        Self {
+            out: String::from("<?xml version=\"1.0\" encoding=\"utf-8\" standalone=\"yes\"?>\n"),
+            stream,
+            binaries: Vec::new(),
+        }
+    }
+
+    fn open(&mut self, name: &str) {
+        self.out.push_str(&format!("<{}>", name));
+    }
+
+    fn close(&mut self, name: &str) {
+        self.out.push_str(&format!("</{}>\n", name));
+    }
+
+    fn element(&mut self, name: &str, text: &str) {
+        self.out.push_str(&format!("<{0}>{1}</{0}>\n", name, escape(text)));
+    }
+
+    fn database(&mut self, database: &Database) {
+        let now = Utc::now();
+        self.open("KeePassFile");
+        self.open("Meta");
+        self.element("Generator", "Fetch");
+        self.element("DatabaseName", &database.name);
+        self.element("DatabaseNameChanged", &format_time(now));
+        self.element("RecycleBinEnabled", "False");
+        self.open("MemoryProtection");
+        self.element("ProtectTitle", "False");
+        self.element("ProtectUserName", "False");
+        self.element("ProtectPassword", "True");
+        self.element("ProtectURL", "False");
+        self.element("ProtectNotes", "False");
+        self.close("MemoryProtection");
+        self.close("Meta");
+        self.open("Root");
+        self.group(&database.root);
+        self.element("DeletedObjects", "");
+        self.close("Root");
+        self.close("KeePassFile");
+    }
+
+    fn group(&mut self, group: &Group) {
+        self.open("Group");
+        self.element("UUID", &STANDARD.encode(group.uuid.as_bytes()));
+        self.element("Name", &group.name);
+        self.times(&group.times);
+        self.element("IsExpanded", "True");
+        for entry in &group.entries {
+            self.entry(entry, true);
+        }
+        for child in &group.groups {
+            self.group(child);
+        }
+        self.close("Group");
+    }
+
+    fn entry(&mut self, entry: &Entry, with_history: bool) {
+        self.open("Entry");
+        self.element("UUID", &STANDARD.encode(entry.uuid.as_bytes()));
+        self.element("Tags", &entry.tags.join(";"));
+        self.times(&entry.times);
+        for field in &entry.fields {
+            self.open("String");
+            self.element("Key", &field.key);
+            if field.protected {
+                let mut value = field.value.clone().into_bytes();
+                self.stream.apply(&mut value);
+                self.out.push_str(&format!("<Value Protected=\"True\">{}</Value>\n", STANDARD.encode(&value)));
+            } else {
+                self.element("Value", &field.value);
+            }
+            self.close("String");
+        }
+        for attachment in &entry.attachments {
+            self.binaries.push(attachment.data.clone());
+            self.open("Binary");
+            self.element("Key", &attachment.name);
+            self.out.push_str(&format!("<Value Ref=\"{}\"/>\n", self.binaries.len() - 1));
+            self.close("Binary");
+        }
+        if with_history && !entry.history.is_empty() {
+            self.open("History");
+            for old in &entry.history {
+                self.entry(old, false);
+            }
+            self.close("History");
+        }
+        self.close("Entry");
+    }
+
+    fn times(&mut self, times: &Times) {
+        let now = Utc::now();
+        let created = times.created.unwrap_or(now);
+        let modified = times.modified.unwrap_or(created);
+        self.open("Times");
+        self.element("CreationTime", &format_time(created));
+        self.element("LastModificationTime", &format_time(modified));
+        self.element("LastAccessTime", &format_time(modified));
+        self.element("ExpiryTime", &format_time(times.expires.unwrap_or(modified)));
+        self.element("Expires", if times.expires.is_some() { "True" } else { "False" });
+        self.element("UsageCount", "0");
+        self.element("LocationChanged", &format_time(modified));
+        self.close("Times");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROTECTED_STREAM_KEY: [u8; 32] = [5; 32];
+    const ATTACHMENT: &[u8] = b"attached notes";
+
+    fn password_key(password: &str) -> DatabaseKey {
+        DatabaseKey::new(Some(password.to_string()), None).unwrap()
+    }
+
+    fn sample_database() -> Database {
+        let mut entry = Entry { uuid: Uuid::new_v4(), tags: vec!["work".to_string()], ..Default::default() };
+        entry.set(TITLE, "Mail", false);
+        entry.set(USERNAME, "alice", false);
+        entry.set(PASSWORD, "s3cret & <safe>", true);
+        entry.set("PIN", "1234", true);
+        entry.attachments.push(Attachment { name: "notes.txt".to_string(), data: ATTACHMENT.to_vec() });
+        let mut old = entry.clone();
+        old.set(PASSWORD, "older", true);
+        old.attachments.clear();
+        entry.history.push(old);
+
+        let folder = Group { uuid: Uuid::new_v4(), name: "Email".to_string(), entries: vec![entry], ..Default::default() };
+        Database {
+            name: "Round trip".to_string(),
+            root: Group { uuid: Uuid::new_v4(), name: "Root".to_string(), groups: vec![folder], ..Default::default() },
+            recycle_bin: None,
+        }
+    }
+
+    fn assert_sample(database: &Database) {
+        assert_eq!(database.name, "Round trip");
+        let entry = &database.root.groups[0].entries[0];
+        assert_eq!(database.root.groups[0].name, "Email");
+        assert_eq!(entry.get(TITLE), Some("Mail"));
+        assert_eq!(entry.get(PASSWORD), Some("s3cret & <safe>"));
+        assert_eq!(entry.custom_fields().map(|field| (field.key.as_str(), field.value.as_str())).collect::<Vec<_>>(), [("PIN", "1234")]);
+        assert_eq!(entry.tags, ["work"]);
+        assert_eq!(entry.attachments[0].data, ATTACHMENT);
+        assert_eq!(entry.history[0].get(PASSWORD), Some("older"));
+    }
+
+    #[test]
+    fn round_trips_with_a_password() {
+        let data = write(&sample_database(), &password_key("hunter2")).unwrap();
+        assert_sample(&read(&data, &password_key("hunter2")).unwrap());
+        assert!(matches!(read(&data, &password_key("hunter3")), Err(Error::Decryption(_))));
+    }
+
+    #[test]
+    fn round_trips_with_a_key_file() {
+        let keyfile = b"any file can be a key file".to_vec();
+        let data = write(&sample_database(), &DatabaseKey::new(None, Some(keyfile.clone())).unwrap()).unwrap();
+        assert_sample(&read(&data, &DatabaseKey::new(None, Some(keyfile)).unwrap()).unwrap());
+    }
+
+    #[test]
+    fn round_trips_with_a_password_and_key_file() {
+        let keyfile = vec![9u8; 32];
+        let key = DatabaseKey::new(Some("hunter2".to_string()), Some(keyfile.clone())).unwrap();
+        let data = write(&sample_database(), &key).unwrap();
+        assert_sample(&read(&data, &DatabaseKey::new(Some("hunter2".to_string()), Some(keyfile)).unwrap()).unwrap());
+        assert!(read(&data, &password_key("hunter2")).is_err());
+    }
+
+    /// The XML of a database with one entry, whose password is `password`
+    /// protected with `stream` and whose attachment is binary 0.
+    fn fixture_xml(stream: &mut InnerStream, password: &[u8], meta: &str) -> String {
+        let mut protected = password.to_vec();
+        stream.apply(&mut protected);
+        format!(
+            "<?xml version=\"1.0\" encoding=\"utf-8\"?><KeePassFile><Meta><DatabaseName>Fixture</DatabaseName>{}</Meta>\
+             <Root><Group><Name>Root</Name><Group><Name>Email</Name><Entry>\
+             <Tags>work;mail</Tags><Times><Expires>True</Expires><ExpiryTime>2030-01-02T03:04:05Z</ExpiryTime></Times>\
+             <String><Key>Title</Key><Value>Mail</Value></String>\
+             <String><Key>Password</Key><Value Protected=\"True\">{}</Value></String>\
+             <Binary><Key>notes.txt</Key><Value Ref=\"0\"/></Binary>\
+             </Entry></Group></Group></Root></KeePassFile>",
+            meta,
+            STANDARD.encode(protected)
+        )
+    }
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    /// A KDBX 3.1 database as KeePass 2 writes it: AES-KDF, AES-256, Salsa20
+    /// for protected values and the attachment in the XML.
+    fn kdbx3_fixture(password: &str) -> Vec<u8> {
+        let (master_seed, transform_seed, iv, start_bytes) = ([1u8; 32], [2u8; 32], [3u8; 16], [4u8; 32]);
+        let rounds = 1000u64;
+
+        let mut data = Vec::new();
+        data.extend_from_slice(&SIGNATURE_1.to_le_bytes());
+        data.extend_from_slice(&SIGNATURE_2.to_le_bytes());
+        data.extend_from_slice(&0x0003_0001u32.to_le_bytes());
+        let fields: [(u8, &[u8]); 11] = [
+            (2, &CIPHER_AES256),
+            (3, &1u32.to_le_bytes()),
+            (4, &master_seed),
+            (5, &transform_seed),
+            (6, &rounds.to_le_bytes()),
+            (7, &iv),
+            (8, &PROTECTED_STREAM_KEY),
+            (9, &start_bytes),
+            (10, &INNER_STREAM_SALSA20.to_le_bytes()),
+            (1, b"ignored comment"),
+            (0, b"\r\n\r\n"),
+        ];
+        for (id, value) in fields {
+            data.push(id);
+            data.extend_from_slice(&(value.len() as u16).to_le_bytes());
+            data.extend_from_slice(value);
+        }
+
+        let binaries = format!("<Binaries><Binary ID=\"0\" Compressed=\"True\">{}</Binary></Binaries>", STANDARD.encode(gzip(ATTACHMENT)));
+        let mut stream = InnerStream::new(INNER_STREAM_SALSA20, &PROTECTED_STREAM_KEY).unwrap();
+        let block = gzip(fixture_xml(&mut stream, b"correct horse", &binaries).as_bytes());
+        let mut plaintext = start_bytes.to_vec();
+        plaintext.extend_from_slice(&0u32.to_le_bytes());
+        plaintext.extend_from_slice(&Sha256::digest(&block));
+        plaintext.extend_from_slice(&(block.len() as u32).to_le_bytes());
+        plaintext.extend_from_slice(&block);
+        plaintext.extend_from_slice(&1u32.to_le_bytes());
+        plaintext.extend_from_slice(&[0; 32]);
+        plaintext.extend_from_slice(&0u32.to_le_bytes());
+
+        let transformed = Kdf::Aes { seed: transform_seed.to_vec(), rounds }.transform(&password_key(password).composite().unwrap()).unwrap();
+        let cipher_key = Sha256::new().chain_update(master_seed).chain_update(&*transformed).finalize();
+        let encrypted = cbc::Encryptor::<Aes256>::new_from_slices(&cipher_key, &iv).unwrap().encrypt_padded_vec_mut::<Pkcs7>(&plaintext);
+        data.extend_from_slice(&encrypted);
+        data
+    }
+
+    /// A KDBX 4 database as KeePassXC writes it: Argon2id, ChaCha20 for both
+    /// the payload and protected values, no compression and the attachment in
+    /// the inner header.
+    fn kdbx4_fixture(password: &str, protected_password: &[u8]) -> Vec<u8> {
+        let (master_seed, kdf_salt, iv) = ([1u8; 32], [2u8; 32], [3u8; 12]);
+
+        let mut header = Vec::new();
+        header.extend_from_slice(&SIGNATURE_1.to_le_bytes());
+        header.extend_from_slice(&SIGNATURE_2.to_le_bytes());
+        header.extend_from_slice(&0x0004_0001u32.to_le_bytes());
+        write_field(&mut header, 2, &CIPHER_CHACHA20);
+        write_field(&mut header, 3, &0u32.to_le_bytes());
+        write_field(&mut header, 4, &master_seed);
+        write_field(&mut header, 7, &iv);
+        write_field(&mut header, 11, &write_variant_dictionary(&[
+            ("$UUID", Variant::Bytes(KDF_ARGON2ID.to_vec())),
+            ("S", Variant::Bytes(kdf_salt.to_vec())),
+            ("I", Variant::U64(2)),
+            ("M", Variant::U64(1024 * 1024)),
+            ("P", Variant::U32(1)),
+            ("V", Variant::U32(0x13)),
+        ]));
+        write_field(&mut header, 0, b"\r\n\r\n");
+
+        let mut payload = Vec::new();
+        write_field(&mut payload, 1, &INNER_STREAM_CHACHA20.to_le_bytes());
+        write_field(&mut payload, 2, &PROTECTED_STREAM_KEY);
+        write_field(&mut payload, 3, &[[1u8].as_slice(), ATTACHMENT].concat());
+        write_field(&mut payload, 0, &[]);
+        let mut stream = InnerStream::new(INNER_STREAM_CHACHA20, &PROTECTED_STREAM_KEY).unwrap();
+        payload.extend_from_slice(fixture_xml(&mut stream, protected_password, "").as_bytes());
+
+        let kdf = Kdf::Argon2 { algorithm: argon2::Algorithm::Argon2id, version: 0x13, salt: kdf_salt.to_vec(), iterations: 2, memory: 1024 * 1024, parallelism: 1 };
+        let transformed = kdf.transform(&password_key(password).composite().unwrap()).unwrap();
+        let cipher_key = Sha256::new().chain_update(master_seed).chain_update(&*transformed).finalize();
+        let hmac_key = hmac_base_key(&master_seed, &transformed);
+        ChaCha20::new_from_slices(&cipher_key, &iv).unwrap().apply_keystream(&mut payload);
+
+        let mut data = header.clone();
+        data.extend_from_slice(&Sha256::digest(&header));
+        data.extend_from_slice(&block_hmac(&hmac_key, u64::MAX, &header));
+        for (index, block) in [payload.as_slice(), &[]].into_iter().enumerate() {
+            let mut sized = (block.len() as u32).to_le_bytes().to_vec();
+            sized.extend_from_slice(block);
+            data.extend_from_slice(&block_hmac(&hmac_key, index as u64, &sized));
+            data.extend_from_slice(&sized);
+        }
+        data
+    }
+
+    fn assert_fixture(database: &Database) {
+        assert_eq!(database.name, "Fixture");
+        let folder = &database.root.groups[0];
+        assert_eq!(folder.name, "Email");
+        let entry = &folder.entries[0];
+        assert_eq!(entry.get(TITLE), Some("Mail"));
+        assert_eq!(entry.tags, ["work", "mail"]);
+        assert_eq!(entry.times.expires, Utc.with_ymd_and_hms(2030, 1, 2, 3, 4, 5).single());
+        assert_eq!(entry.attachments[0].name, "notes.txt");
+        assert_eq!(entry.attachments[0].data, ATTACHMENT);
+    }
+
+    #[test]
+    fn reads_a_kdbx3_database() {
+        let data = kdbx3_fixture("correct");
+        let database = read(&data, &password_key("correct")).unwrap();
+        assert_fixture(&database);
+        assert_eq!(database.root.groups[0].entries[0].get(PASSWORD), Some("correct horse"));
+        assert!(matches!(read(&data, &password_key("wrong")), Err(Error::Decryption(_))));
+    }
+
+    #[test]
+    fn reads_a_kdbx4_database() {
+        let data = kdbx4_fixture("correct", b"correct horse");
+        let database = read(&data, &password_key("correct")).unwrap();
+        assert_fixture(&database);
+        assert_eq!(database.root.groups[0].entries[0].get(PASSWORD), Some("correct horse"));
+        assert!(matches!(read(&data, &password_key("wrong")), Err(Error::Decryption(_))));
+    }
+
+    #[test]
+    fn keeps_a_protected_value_that_is_not_utf8() {
+        let data = kdbx4_fixture("correct", b"pa\xffss");
+        let database = read(&data, &password_key("correct")).unwrap();
+        assert_eq!(database.root.groups[0].entries[0].get(PASSWORD), Some("pa\u{FFFD}ss"));
+    }
+
+    #[test]
+    fn rejects_a_corrupted_block() {
+        let mut data = kdbx4_fixture("correct", b"correct horse");
+        let last = data.len() - 40;
+        data[last] ^= 1;
+        assert!(matches!(read(&data, &password_key("correct")), Err(Error::InvalidInput(_))));
+    }
+
+    #[test]
+    fn rejects_deeply_nested_xml() {
+        let xml = format!("{}{}", "<Group>".repeat(MAX_XML_DEPTH + 1), "</Group>".repeat(MAX_XML_DEPTH + 1));
+        assert!(parse_xml(xml.as_bytes()).is_err());
+        let xml = format!("{}{}", "<Group>".repeat(MAX_XML_DEPTH), "</Group>".repeat(MAX_XML_DEPTH));
+        assert!(parse_xml(xml.as_bytes()).is_ok());
+    }
+
+    #[test]
+    fn reads_xml_key_files() {
+        let version_2 = b"<?xml version=\"1.0\"?><KeyFile><Meta><Version>2.0</Version></Meta><Key><Data Hash=\"0\">0102 0304</Data></Key></KeyFile>";
+        assert_eq!(keyfile_key(version_2).unwrap(), [1, 2, 3, 4]);
+        let version_1 = b"<KeyFile><Meta><Version>1.00</Version></Meta><Key><Data>AQIDBA==</Data></Key></KeyFile>";
+        assert_eq!(keyfile_key(version_1).unwrap(), [1, 2, 3, 4]);
+        assert_eq!(keyfile_key(&[7; 32]).unwrap(), [7; 32]);
+        assert_eq!(keyfile_key(b"short").unwrap(), Sha256::digest(b"short").to_vec());
+    }
+}
@@ -1,10 +1,26 @@
+pub mod backup;
+pub mod breach;
+pub mod certificate;
 pub mod crypto;
 pub mod error;
+pub mod expiry;
+pub mod export;
+pub mod import;
+pub mod inject;
+pub mod integrity;
+pub mod kdbx;
+pub mod login;
+pub mod ssh;
 pub mod storage;
+pub mod tamper;
+pub mod template;
+pub mod unlock;
+pub mod vault;
 
 use error::Error;
 pub type Result<T> = std::result::Result<T, Error>;
 
+#[cfg(feature = "desktop")]
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
 pub fn init() -> Result<tauri::App> {
   tauri::Builder::default()
@@ -15,6 +31,7 @@ pub fn init() -> Result<tauri::App> {
         .map_err(Into::into)
 }
 
+#[cfg(feature = "desktop")]
 pub fn init_plugins<R: tauri::Runtime>() -> tauri::plugin::TauriPlugin<R> {
           tauri_plugin_log::Builder::default()
             .level(log::LevelFilter::Info)
@@ -0,0 +1,293 @@
+use crate::storage::VaultItem;
+use serde::Serialize;
+use std::collections::HashMap;
+
+const USERNAME_LABEL: &str = "Username:";
+const PASSWORD_LABEL: &str = "Password:";
+const URL_LABEL: &str = "URL:";
+const TOTP_LABEL: &str = "TOTP:";
+const NOTES_LABEL: &str = "Notes:";
+
+/// The fields of a login stored as a text item, in the `Label: value` layout
+/// written by the CSV importer.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct LoginFields {
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub url: Option<String>,
+    /// A TOTP secret or `otpauth://` URI.
+    pub totp: Option<String>,
+    /// Extra `Label: value` lines, such as custom fields from other password managers.
+    pub fields: Vec<(String, String)>,
+    pub notes: Option<String>,
+}
+
+impl LoginFields {
+    pub fn parse(content: &str) -> Self {
+        Self::parse_with_password_line(content).0
+    }
+
+    /// Parses `content`, also returning the index of the line the password was read from.
+    fn parse_with_password_line(content: &str) -> (Self, Option<usize>) {
+        let mut fields = LoginFields::default();
+        let mut notes: Option<String> = None;
+        let mut password_line = None;
+
+        for (index, line) in content.lines().enumerate() {
+            if let Some(notes) = notes.as_mut() {
+                notes.push('\n');
+                notes.push_str(line);
+                continue;
+            }
+
+            if let Some(value) = line.strip_prefix(USERNAME_LABEL) {
+                fields.username = non_empty(value);
+            } else if let Some(value) = line.strip_prefix(PASSWORD_LABEL) {
+                fields.password = non_empty(value);
+                password_line = fields.password.as_ref().map(|_| index);
+            } else if let Some(value) = line.strip_prefix(URL_LABEL) {
+                fields.url = non_empty(value);
+            } else if let Some(value) = line.strip_prefix(TOTP_LABEL) {
+                fields.totp = non_empty(value);
+            } else if let Some(value) = line.strip_prefix(NOTES_LABEL) {
+                notes = Some(value.trim_start().to_string());
+            } else if let Some((label, value)) = line.split_once(": ").filter(|_| fields.has_standard_fields()) {
+                // Only after a login field, so a note such as "Meeting: Tuesday"
+                // is not mistaken for a login.
+                if let (Some(label), Some(value)) = (non_empty(label), non_empty(value)) {
+                    fields.fields.push((label, value));
+                }
+            }
+        }
+
+        fields.notes = notes.and_then(|n| non_empty(&n));
+        (fields, password_line)
+    }
+
+    /// Looks up a field by label, ignoring case: `username`, `password`,
+    /// `url`, `totp`, `notes` or the label of a custom field.
+    pub fn get(&self, label: &str) -> Option<&str> {
+        let label = label.trim().trim_end_matches(':');
+        let standard = [
+            (USERNAME_LABEL, &self.username),
+            (PASSWORD_LABEL, &self.password),
+            (URL_LABEL, &self.url),
+            (TOTP_LABEL, &self.totp),
+            (NOTES_LABEL, &self.notes),
+        ];
+        for (standard_label, value) in standard {
+            if standard_label.trim_end_matches(':').eq_ignore_ascii_case(label) {
+                return value.as_deref();
+            }
+        }
+        self.fields
+            .iter()
+            .find(|(custom_label, _)| custom_label.eq_ignore_ascii_case(label))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn is_login(&self) -> bool {
+        self.password.is_some()
+    }
+
+    /// Whether any field other than notes is set.
+    pub fn has_fields(&self) -> bool {
+        self.has_standard_fields() || !self.fields.is_empty()
+    }
+
+    fn has_standard_fields(&self) -> bool {
+        self.username.is_some() || self.password.is_some() || self.url.is_some() || self.totp.is_some()
+    }
+
+    /// Adds a custom field. Multi-line values do not fit the `Label: value`
+    /// layout, so they are appended to the notes instead.
+    pub fn add_field(&mut self, label: &str, value: &str) {
+        let (label, value) = (label.trim(), value.trim());
+        if value.is_empty() {
+            return;
+        }
+        if value.contains('\n') || label.is_empty() {
+            let section = format!("{}:\n{}", label, value);
+            self.notes = Some(match self.notes.take() {
+                Some(notes) => format!("{}\n\n{}", notes, section),
+                None => section,
+            });
+        } else {
+            self.fields.push((label.to_string(), value.to_string()));
+        }
+    }
+
+    pub fn to_content(&self) -> String {
+        let mut content = String::new();
+        for (label, value) in [
+            (USERNAME_LABEL, &self.username),
+            (PASSWORD_LABEL, &self.password),
+            (URL_LABEL, &self.url),
+            (TOTP_LABEL, &self.totp),
+        ] {
+            if let Some(value) = value.as_deref().map(str::trim).filter(|v| !v.is_empty()) {
+                content.push_str(&format!("{} {}\n\n", label, value));
+            }
+        }
+        for (label, value) in &self.fields {
+            content.push_str(&format!("{}: {}\n\n", label, value));
+        }
+        if let Some(notes) = self.notes.as_deref().map(str::trim).filter(|v| !v.is_empty()) {
+            content.push_str(&format!("{} {}\n\n", NOTES_LABEL, notes));
+        }
+        content.trim_end().to_string()
+    }
+}
+
+/// Returns the password held by an item, if it has one. Login text items carry
+/// it on a `Password:` line; `key` items are the secret itself.
+pub fn password_of(item: &VaultItem, content: &str) -> Option<String> {
+    if item.item_type == "key" {
+        return non_empty(content);
+    }
+    if item.item_type.starts_with("text/") {
+        return LoginFields::parse(content).password;
+    }
+    None
+}
+
+/// Rewrites an item's content with a new password, keeping every other field.
+/// Returns `None` when the item does not hold a password.
+pub fn replace_password(item: &VaultItem, content: &str, new_password: &str) -> Option<String> {
+    if item.item_type == "key" {
+        return Some(new_password.to_string());
+    }
+    if !item.item_type.starts_with("text/") {
+        return None;
+    }
+
+    // Only the line the password field is read from changes. Lines that look
+    // like one inside the notes, and the line endings, are kept as they are.
+    let (_, password_line) = LoginFields::parse_with_password_line(content);
+    let password_line = password_line?;
+    Some(
+        content
+            .split_inclusive('\n')
+            .enumerate()
+            .map(|(index, line)| {
+                if index == password_line {
+                    let ending = &line[line.trim_end_matches(['\r', '\n']).len()..];
+                    format!("{} {}{}", PASSWORD_LABEL, new_password, ending)
+                } else {
+                    line.to_string()
+                }
+            })
+            .collect(),
+    )
+}
+
+/// A login's current password together with its earlier values.
+pub struct AuditedLogin {
+    pub item: VaultItem,
+    pub password: String,
+    pub previous_passwords: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct PasswordReuse {
+    pub id: String,
+    pub name: String,
+    /// Names of the other items sharing this password.
+    pub reused_by: Vec<String>,
+    /// Whether the password was already used by this item before.
+    pub matches_previous_password: bool,
+}
+
+pub fn find_reused_passwords(logins: &[AuditedLogin]) -> Vec<PasswordReuse> {
+    let mut by_password: HashMap<&str, Vec<&VaultItem>> = HashMap::new();
+    for login in logins {
+        by_password.entry(login.password.as_str()).or_default().push(&login.item);
+    }
+
+    logins
+        .iter()
+        .filter_map(|login| {
+            let reused_by: Vec<String> = by_password[login.password.as_str()]
+                .iter()
+                .filter(|other| other.id != login.item.id)
+                .map(|other| other.name.clone())
+                .collect();
+            let matches_previous_password = login.previous_passwords.contains(&login.password);
+
+            if reused_by.is_empty() && !matches_previous_password {
+                return None;
+            }
+            Some(PasswordReuse {
+                id: login.item.id.clone(),
+                name: login.item.name.clone(),
+                reused_by,
+                matches_previous_password,
+            })
+        })
+        .collect()
+}
+
+fn non_empty(value: &str) -> Option<String> {
+    let value = value.trim();
+    if value.is_empty() {
+        None
+    } else {
+        Some(value.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::import::new_item;
+
+    fn item(name: &str, item_type: &str) -> VaultItem {
+        new_item(name.to_string(), item_type, None, Vec::new())
+    }
+
+    #[test]
+    fn replaces_only_the_password_field() {
+        let login = item("Mail", "text/plain");
+        let content = "Username: me\n\nPassword: old\n\nNotes: reset\nPassword: old\n";
+        assert_eq!(replace_password(&login, content, "new").unwrap(), "Username: me\n\nPassword: new\n\nNotes: reset\nPassword: old\n");
+
+        let crlf = "Password: old\r\nURL: https://mail.example";
+        assert_eq!(replace_password(&login, crlf, "new").unwrap(), "Password: new\r\nURL: https://mail.example");
+
+        let edited = replace_password(&login, content, "new").unwrap();
+        assert_eq!(LoginFields::parse(&edited), LoginFields { password: Some("new".to_string()), ..LoginFields::parse(content) });
+    }
+
+    #[test]
+    fn does_not_invent_a_password() {
+        let note = item("Note", "text/plain");
+        assert_eq!(replace_password(&note, "Notes: Password: old\nPassword: old", "new"), None);
+        assert_eq!(replace_password(&note, "Username: me\nPassword:\n", "new"), None);
+        assert_eq!(replace_password(&note, "just text", "new"), None);
+        assert_eq!(replace_password(&item("photo", "image/png"), "Password: old", "new"), None);
+        assert_eq!(replace_password(&item("API", "key"), "old\n", "new").as_deref(), Some("new"));
+    }
+
+    #[test]
+    fn finds_reused_passwords() {
+        let login = |name: &str, password: &str, previous: &[&str]| AuditedLogin {
+            item: item(name, "text/plain"),
+            password: password.to_string(),
+            previous_passwords: previous.iter().map(|p| p.to_string()).collect(),
+        };
+        let logins = [
+            login("Mail", "shared", &[]),
+            login("Bank", "unique", &["older"]),
+            login("Shop", "shared", &[]),
+            login("Forum", "again", &["again"]),
+        ];
+
+        let reuse = find_reused_passwords(&logins);
+        let summary: Vec<(&str, Vec<String>, bool)> = reuse.iter().map(|r| (r.name.as_str(), r.reused_by.clone(), r.matches_previous_password)).collect();
+        assert_eq!(
+            summary,
+            [("Mail", vec!["Shop".to_string()], false), ("Shop", vec!["Mail".to_string()], false), ("Forum", vec![], true)]
+        );
+        assert!(find_reused_passwords(&[]).is_empty());
+    }
+}
@@ -1,24 +1,40 @@
-use std::fs::{self, File};
-use std::io::{Read, Write};
-use std::path::Path;
+use std::fs;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
 use std::sync::Mutex;
-use tauri::{AppHandle, Manager, State, Wry};
-use walkdir::WalkDir;
-use zip::write::{FileOptions, ZipWriter};
-use chrono::Utc;
+use tauri::{AppHandle, Emitter, Manager, RunEvent, State, Wry};
+use tauri_plugin_notification::NotificationExt;
+use chrono::{DateTime, Duration, Utc};
 use log::{error, info, warn, debug, trace};
 use uuid::Uuid;
 use serde::{Deserialize, Serialize};
-use base64::{engine::general_purpose::STANDARD, Engine as _};
 use rand::seq::SliceRandom;
-use csv::ReaderBuilder;
 
+use fetch::backup::{self, BackupCheck, BackupIdentity, BackupInfo, BackupKeyPair, BackupRecipient, BackupSchedule, ConflictPolicy, Manifest, MergeResult};
+use fetch::breach::{BreachResult, PwnedPasswords};
+use fetch::certificate::{self, CertificatePair};
 use fetch::crypto::{Crypto, KeyDerivationStrength};
 use fetch::error::{Error, Result};
-use fetch::storage::{Storage, VaultItem, SortOrder};
+use fetch::expiry::{self, ReminderTracker, UpcomingExpiration};
+use fetch::import::{self, BitwardenImporter, CsvImportOptions, CsvImporter, CsvLayout, DecryptedExportImporter, ImportPreview, ImportResult, Importer, KeePassImporter, OnePasswordImporter};
+use fetch::integrity::{self, IntegrityReport, RepairReport};
+use fetch::{export, kdbx};
+use fetch::login::{self, AuditedLogin, PasswordReuse};
+use fetch::ssh::{self, SshKey, SshKeyAlgorithm, SSH_KEY_ITEM_TYPE};
+use fetch::storage::{ItemMetadata, PasswordHistoryEntry, SqliteStorage, Storage, VaultItem, SortOrder};
+use fetch::tamper::{self, VaultStateCheck};
+use fetch::template::{self, RenderReport};
+use fetch::unlock::{self, UnlockReport};
+use fetch::vault;
+
+const EXPIRY_CHECK_INTERVAL_SECS: u64 = 15 * 60;
+const DEFAULT_EXPIRY_WINDOW_DAYS: i64 = 30;
+const BACKUP_PROGRESS_EVENT: &str = "backup-progress";
+const BACKUP_CHECK_INTERVAL_SECS: u64 = 5 * 60;
 
 pub struct VaultState {
-    storage: Mutex<Storage>,
+    /// The app keeps its vault in `SqliteStorage`; commands only rely on the trait.
+    storage: Mutex<Box<dyn Storage>>,
     crypto: Mutex<Crypto>,
 }
 
@@ -39,6 +55,8 @@ pub struct AddFileItemArgs {
     tags: Vec<String>,
     #[serde(rename = "parentId")]
     parent_id: Option<String>,
+    #[serde(default)]
+    passphrase: Option<String>,
 }
 
 #[derive(Deserialize)]
@@ -62,6 +80,44 @@ pub struct ExportVaultArgs {
     master_key: String,
 }
 
+#[derive(Deserialize)]
+pub struct ExportBackupArgs {
+    destination: String,
+}
+
+#[derive(Deserialize)]
+pub struct SealedBackupExportArgs {
+    destination: String,
+    passphrase: Option<String>,
+    #[serde(default)]
+    public_keys: Vec<String>,
+}
+
+#[derive(Deserialize)]
+pub struct RestoreBackupArgs {
+    file_path: String,
+    /// Master key of the vault in the backup.
+    master_key: String,
+    /// Master key of the vault being replaced; not needed before a vault is set up.
+    current_master_key: Option<String>,
+    /// Opens a sealed `.fetchbak`. `backup_private_key` takes precedence when both are given.
+    backup_passphrase: Option<String>,
+    backup_private_key: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct MergeBackupArgs {
+    file_path: String,
+    /// Master key of the vault in the archive, not of the current one.
+    master_key: String,
+    backup_passphrase: Option<String>,
+    backup_private_key: Option<String>,
+    #[serde(rename = "parentId")]
+    parent_id: Option<String>,
+    #[serde(default)]
+    policy: ConflictPolicy,
+}
+
 #[derive(serde::Deserialize)]
 pub struct DeleteVaultArgs {
     master_key: String,
@@ -93,58 +149,110 @@ pub struct DeleteTagArgs {
 #[derive(Deserialize)]
 pub struct CsvImportArgs {
     #[serde(rename = "csvContent")]
-    csv_content: String,
+    csv_content: Option<String>,
+    file_path: Option<String>,
+    #[serde(rename = "parentId")]
+    parent_id: Option<String>,
+    #[serde(default)]
+    options: CsvImportOptions,
+}
+
+
+#[derive(Deserialize)]
+pub struct UpdateItemContentArgs {
+    id: String,
+    content: String,
+}
+
+#[derive(Deserialize)]
+pub struct RestorePasswordArgs {
+    #[serde(rename = "itemId")]
+    item_id: String,
+    #[serde(rename = "historyId")]
+    history_id: String,
+}
+
+#[derive(Deserialize)]
+pub struct SetItemExpiryArgs {
+    id: String,
+    #[serde(rename = "expiresAt")]
+    expires_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Deserialize)]
+pub struct GenerateSshKeyArgs {
+    name: String,
+    algorithm: SshKeyAlgorithm,
+    bits: Option<usize>,
+    comment: Option<String>,
+    tags: Vec<String>,
+    #[serde(rename = "parentId")]
+    parent_id: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct ImportSshKeyArgs {
+    name: String,
+    file_path: String,
+    passphrase: Option<String>,
+    tags: Vec<String>,
     #[serde(rename = "parentId")]
     parent_id: Option<String>,
 }
 
 #[derive(Deserialize)]
-pub struct CsvRow {
-    #[serde(rename = "Account")]
-    account: Option<String>,
-    #[serde(rename = "Login Name")]
-    login_name: Option<String>,
-    #[serde(rename = "Password")]
+pub struct KeePassImportArgs {
+    file_path: String,
     password: Option<String>,
-    #[serde(rename = "Web Site")]
-    web_site: Option<String>,
-    #[serde(rename = "Comments")]
-    comments: Option<String>,
-    // Standard password manager format
-    #[serde(rename = "Title")]
-    title: Option<String>,
-    #[serde(rename = "Username")]
-    username: Option<String>,
-    #[serde(rename = "URL")]
-    url: Option<String>,
-    #[serde(rename = "Notes")]
-    notes: Option<String>,
-    #[serde(rename = "Tags")]
-    tags: Option<String>,
-    
-    // Browser export format (Firefox/Chrome)
-    #[serde(rename = "url")]
-    url_browser: Option<String>,
-    #[serde(rename = "username")]
-    username_browser: Option<String>,
-    #[serde(rename = "password")]
-    password_browser: Option<String>,
-    #[serde(rename = "name")]
-    name_browser: Option<String>,
-    #[serde(rename = "hostname")]
-    hostname_browser: Option<String>,
-    #[serde(rename = "httpRealm")]
-    http_realm: Option<String>,
-    #[serde(rename = "formActionOrigin")]
-    form_action_origin: Option<String>,
-    #[serde(rename = "guid")]
-    guid: Option<String>,
-    #[serde(rename = "timeCreated")]
-    time_created: Option<String>,
-    #[serde(rename = "timeLastUsed")]
-    time_last_used: Option<String>,
-    #[serde(rename = "timePasswordChanged")]
-    time_password_changed: Option<String>,
+    key_file_path: Option<String>,
+    #[serde(rename = "parentId")]
+    parent_id: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct BitwardenImportArgs {
+    file_path: String,
+    password: Option<String>,
+    #[serde(rename = "parentId")]
+    parent_id: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct OnePasswordImportArgs {
+    file_path: String,
+    #[serde(rename = "parentId")]
+    parent_id: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct DecryptedExportImportArgs {
+    file_path: String,
+    /// Give every imported item a new id instead of keeping the exported one.
+    #[serde(rename = "remapIds", default)]
+    remap_ids: bool,
+    #[serde(rename = "parentId")]
+    parent_id: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct KeePassExportArgs {
+    /// Asked for again, as the export holds every item decrypted.
+    master_key: String,
+    password: Option<String>,
+    key_file_path: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct RenderTemplateArgs {
+    template_path: String,
+    output_path: String,
+    /// Shred the rendered file this many seconds after writing it.
+    shred_after_secs: Option<u64>,
+}
+
+#[derive(Deserialize)]
+pub struct BreachCheckArgs {
+    path: Option<String>,
 }
 
 #[derive(Serialize)]
@@ -169,15 +277,22 @@ fn main() {
                 std::fs::create_dir_all(&vault_path).expect("Failed to create vault directory");
             }
 
-            let storage = Storage::new(vault_path)?;
+            // Shreds rendered files that came due while the app was closed.
+            tauri::async_runtime::spawn(run_pending_shreds(vault_path.clone()));
+
+            let storage = SqliteStorage::new(vault_path)?;
             let crypto = Crypto::new();
             let vault_state = VaultState {
-                storage: Mutex::new(storage),
+                storage: Mutex::new(Box::new(storage)),
                 crypto: Mutex::new(crypto),
             };
 
             app.manage(vault_state);
 
+            let app_handle = app.handle().clone();
+            tauri::async_runtime::spawn(run_expiry_reminders(app_handle.clone()));
+            tauri::async_runtime::spawn(run_scheduled_backups(app_handle));
+
             Ok(())
         })
         .invoke_handler(tauri::generate_handler![
@@ -185,6 +300,7 @@ fn main() {
             initialize_vault,
             unlock_vault,
             lock_vault,
+            check_vault_state,
             get_vault_items,
             add_text_item,
             add_file_item,
@@ -194,6 +310,18 @@ fn main() {
             update_master_key,
             export_decrypted_vault,
             export_encrypted_vault,
+            export_encrypted_vault_to_file,
+            export_sealed_backup,
+            generate_backup_key_pair,
+            import_decrypted_export,
+            restore_encrypted_backup,
+            merge_encrypted_backup,
+            verify_vault,
+            repair_vault,
+            get_backup_schedule,
+            set_backup_schedule,
+            list_backups,
+            verify_backups,
             delete_vault,
             get_vault_status,
             get_key_derivation_strength,
@@ -201,9 +329,47 @@ fn main() {
             rename_tag,
             delete_tag,
             import_csv,
+            inspect_csv_import,
+            preview_csv_import,
+            import_keepass,
+            export_keepass,
+            import_bitwarden,
+            import_onepassword,
+            check_breaches_offline,
+            update_item_content,
+            get_password_history,
+            restore_password,
+            audit_password_reuse,
+            set_item_expiry,
+            get_upcoming_expirations,
+            get_certificate_pairs,
+            generate_ssh_key,
+            import_ssh_key,
+            export_ssh_public_key,
+            render_template,
         ])
-        .run(tauri::generate_context!())
-        .expect("error while running tauri application");
+        .build(tauri::generate_context!())
+        .expect("error while building tauri application")
+        .run(|app_handle, event| {
+            if let RunEvent::Exit = event {
+                shred_due_on_exit(app_handle);
+            }
+        });
+}
+
+/// Shreds rendered files that are due by the time the app exits. Later ones
+/// stay recorded and are shredded on the next start.
+fn shred_due_on_exit(app_handle: &AppHandle<Wry>) {
+    let vault_path = match app_handle.path().app_data_dir() {
+        Ok(app_data_dir) => app_data_dir.join("vault"),
+        Err(e) => {
+            error!("Failed to find the vault to shred rendered files: {}", e);
+            return;
+        }
+    };
+    if let Err(e) = template::shred_due(&vault_path, Utc::now()) {
+        error!("Failed to shred rendered files: {}", e);
+    }
 }
 
 #[tauri::command]
@@ -244,52 +410,31 @@ async fn initialize_vault(args: InitializeVaultArgs, state: State<'_, VaultState
     let storage = state.storage.lock().unwrap();
     let mut crypto = state.crypto.lock().unwrap();
 
-    if storage.is_initialized() {
-        error!("Attempted to initialize an already initialized vault.");
-        return Err(Error::VaultAlreadyInitialized);
-    }
-    
-    let strength = args.strength.unwrap_or_default();
-    info!("Generating salt and deriving key with strength: {:?}", strength);
-    let salt = Crypto::generate_salt();
-    let derived_key = crypto.derive_key(&args.master_key, &salt, strength)?;
-
-    info!("Storing salt and strength.");
-    storage.initialize(&salt, strength)?;
-
-    info!("Unlocking crypto with new key.");
-    crypto.unlock(&derived_key)?;
-
-    info!("Creating and storing verification token.");
-    let verification_data = Crypto::generate_verification_token();
-    let encrypted_token = crypto.encrypt(&verification_data)?;
-    storage.store_verification_token(&encrypted_token)?;
-
+    vault::initialize(storage.as_ref(), &mut crypto, &args.master_key, args.strength.unwrap_or_default())?;
     info!("Vault initialized successfully.");
     Ok(())
 }
 
 #[tauri::command]
-async fn unlock_vault(master_key: String, state: State<'_, VaultState>) -> Result<()> {
+async fn unlock_vault(master_key: String, state: State<'_, VaultState>) -> Result<UnlockReport> {
     info!("Attempting to unlock vault.");
     let storage = state.storage.lock().unwrap();
     let mut crypto = state.crypto.lock().unwrap();
 
-    let salt = storage.get_salt()?;
-    let strength = storage.get_key_derivation_strength()?;
-    let verification_token = storage.get_verification_token()?;
-
-    let derived_key = crypto.derive_key(&master_key, &salt, strength)?;
-    crypto.unlock(&derived_key)?;
+    vault::unlock(storage.as_ref(), &mut crypto, &master_key, Utc::now()).map_err(|e| {
+        error!("Vault unlock failed: {}", e);
+        e
+    })
+}
 
-    if crypto.decrypt(&verification_token).is_ok() {
-        info!("Vault unlocked successfully with strength {:?}", strength);
-        return Ok(());
+#[tauri::command]
+async fn check_vault_state(state: State<'_, VaultState>) -> Result<VaultStateCheck> {
+    let storage = state.storage.lock().unwrap();
+    let crypto = state.crypto.lock().unwrap();
+    if !crypto.is_unlocked() {
+        return Err(Error::VaultLocked);
     }
-
-    error!("Invalid master key provided during unlock attempt.");
-    crypto.lock();
-    Err(Error::InvalidMasterKey)
+    tamper::check_vault_state(storage.as_ref(), &crypto)
 }
 
 #[tauri::command]
@@ -319,11 +464,6 @@ async fn add_text_item(args: AddTextItemArgs, state: State<'_, VaultState>) -> R
     info!("Adding text item: {}", args.name);
     trace!("Received content length: {}", args.content.len());
 
-    if args.name.trim().is_empty() || args.content.is_empty() {
-        warn!("Attempted to add text item with empty name or content.");
-        return Err(Error::InvalidInput("Item name and content cannot be empty".into()));
-    }
-    
     let storage = state.storage.lock().unwrap();
     let crypto = state.crypto.lock().unwrap();
 
@@ -332,33 +472,7 @@ async fn add_text_item(args: AddTextItemArgs, state: State<'_, VaultState>) -> R
         return Err(Error::VaultLocked);
     }
 
-    let now = Utc::now();
-    let data_path = Uuid::new_v4().to_string(); 
-    debug!("Generated data_path for text item: {}", data_path);
-
-    let item = VaultItem {
-        id: Uuid::new_v4().to_string(),
-        parent_id: args.parent_id,
-        name: args.name,
-        data_path: data_path.clone(), 
-        item_type: if args.item_type == "text" { "text/plain".to_string() } else { args.item_type }, 
-        folder_type: None,
-        tags: args.tags,
-        created_at: now,
-        updated_at: now,
-    };
-
-    let encrypted_content = crypto.encrypt(args.content.as_bytes())?;
-    
-    debug!("Encrypted content size for text item: {} bytes", encrypted_content.len());
-    let full_file_path = storage.get_vault_path().join("data").join(&data_path);
-    debug!("Attempting to write encrypted text content to: {}", full_file_path.display());
-
-    storage.write_encrypted_file(&encrypted_content, &data_path)?;
-    debug!("Successfully wrote encrypted file for text item ID: {}", item.id);
-
-    storage.add_item(&item, &crypto)?;
-    info!("Text item '{}' added successfully.", item.name);
+    vault::add_text_item(storage.as_ref(), &crypto, args.name, args.item_type, args.content.as_bytes(), args.tags, args.parent_id)?;
     Ok(())
 }
 
@@ -374,38 +488,7 @@ async fn add_file_item(args: AddFileItemArgs, state: State<'_, VaultState>) -> R
         return Err(Error::VaultLocked);
     }
 
-    let file_path = Path::new(&args.file_path);
-    let file_content = fs::read(file_path)?;
-    
-    let guess = mime_guess::from_path(file_path).first_or_octet_stream();
-    let mime_type = guess.to_string();
-    
-    let now = Utc::now();
-    let data_path = Uuid::new_v4().to_string();
-
-    let item = VaultItem {
-        id: Uuid::new_v4().to_string(),
-        parent_id: args.parent_id,
-        name: args.name,
-        data_path: data_path.clone(),
-        item_type: mime_type,
-        folder_type: None,
-        tags: args.tags,
-        created_at: now,
-        updated_at: now,
-    };
-
-    let encrypted_content = crypto.encrypt(&file_content)?;
-    
-    debug!("Encrypted content size for file item: {} bytes", encrypted_content.len());
-    let full_file_path = storage.get_vault_path().join("data").join(&data_path);
-    debug!("Attempting to write encrypted file content to: {}", full_file_path.display());
-
-    storage.write_encrypted_file(&encrypted_content, &data_path)?;
-    debug!("Successfully wrote encrypted file for file item ID: {}", item.id);
-    storage.add_item(&item, &crypto)?;
-    
-    info!("File item '{}' added successfully.", item.name);
+    vault::add_file_item(storage.as_ref(), &crypto, args.name, Path::new(&args.file_path), args.passphrase.as_deref(), args.tags, args.parent_id)?;
     Ok(())
 }
 
@@ -413,11 +496,6 @@ async fn add_file_item(args: AddFileItemArgs, state: State<'_, VaultState>) -> R
 async fn add_folder(args: AddFolderArgs, state: State<'_, VaultState>) -> Result<()> {
     info!("Adding folder: {}", args.name);
 
-    if args.name.trim().is_empty() {
-        warn!("Attempted to add folder with empty name.");
-        return Err(Error::InvalidInput("Folder name cannot be empty".into()));
-    }
-    
     let storage = state.storage.lock().unwrap();
     let crypto = state.crypto.lock().unwrap();
 
@@ -426,23 +504,7 @@ async fn add_folder(args: AddFolderArgs, state: State<'_, VaultState>) -> Result
         return Err(Error::VaultLocked);
     }
 
-    let now = Utc::now();
-
-    let item = VaultItem {
-        id: Uuid::new_v4().to_string(),
-        parent_id: args.parent_id,
-        name: args.name,
-        data_path: "".to_string(),
-        item_type: "folder".to_string(),
-        folder_type: args.folder_type,
-        tags: vec![], 
-        created_at: now,
-        updated_at: now,
-    };
-    
-    storage.add_item(&item, &crypto)?;
-
-    info!("Folder '{}' added successfully.", item.name);
+    vault::add_folder(storage.as_ref(), &crypto, args.name, args.folder_type, args.parent_id)?;
     Ok(())
 }
 
@@ -455,19 +517,17 @@ async fn get_item_content(id: String, state: State<'_, VaultState>) -> Result<Ve
         return Err(Error::VaultLocked);
     }
 
-    let item = storage.get_item(&id, &crypto)?.ok_or_else(|| Error::ItemNotFound(id.clone()))?;
-    storage.read_encrypted_file(&item.data_path, &crypto)
+    vault::item_content(storage.as_ref(), &crypto, &id)
 }
 
 #[tauri::command]
 async fn delete_item(id: String, state: State<'_, VaultState>) -> Result<bool> {
-    info!("Recursively deleting item with id: {}", id);
     let storage = state.storage.lock().unwrap();
     let crypto = state.crypto.lock().unwrap();
     if !crypto.is_unlocked() {
         return Err(Error::VaultLocked);
     }
-    storage.delete_item_and_descendants(&id, &crypto)?;
+    vault::delete_item(storage.as_ref(), &crypto, &id)?;
     Ok(true)
 }
 
@@ -476,211 +536,307 @@ async fn update_master_key(args: UpdateMasterKeyArgs, state: State<'_, VaultStat
     info!("Starting master key update process.");
     let storage = state.storage.lock().unwrap();
     let mut crypto = state.crypto.lock().unwrap();
+    vault::change_master_key(storage.as_ref(), &mut crypto, &args.current_key, &args.new_key, args.strength, Utc::now()).map_err(|e| {
+        error!("Master key update failed: {}", e);
+        e
+    })?;
+    info!("Master key updated successfully.");
+    Ok(())
+}
 
-    let current_salt = storage.get_salt()?;
-    let current_strength = storage.get_key_derivation_strength()?;
-    let verification_token = storage.get_verification_token()?;
-    let derived_key = crypto.derive_key(&args.current_key, &current_salt, current_strength)?;
-    
-    crypto.unlock(&derived_key)?;
-    if crypto.decrypt(&verification_token).is_err() {
-        crypto.lock();
-        error!("Invalid current master key during update attempt.");
-        return Err(Error::InvalidMasterKey);
-    }
-    info!("Current master key verified.");
+#[tauri::command]
+async fn export_decrypted_vault(args: ExportVaultArgs, state: State<'_, VaultState>) -> Result<String> {
+    info!("Exporting decrypted vault.");
 
-    let new_strength = args.strength.unwrap_or(current_strength);
-    let new_salt = Crypto::generate_salt();
-    let new_derived_key = crypto.derive_key(&args.new_key, &new_salt, new_strength)?;
-    let mut temp_crypto_for_reencrypt = Crypto::new();
-    temp_crypto_for_reencrypt.unlock(&new_derived_key)?;
+    let storage = state.storage.lock().unwrap();
+    let mut crypto = state.crypto.lock().unwrap();
+    *crypto = unlock::verify_master_key(storage.as_ref(), &args.master_key, Utc::now())?;
+
+    let export = vault::decrypted_export(storage.as_ref(), &crypto)?;
+    info!("Decrypted vault export successful.");
+    Ok(export)
+}
 
-    let all_items = storage.get_all_items_recursive(&crypto)?;
-    info!("Re-encrypting {} items with new master key...", all_items.len());
+#[tauri::command]
+async fn import_decrypted_export(args: DecryptedExportImportArgs, state: State<'_, VaultState>) -> Result<ImportResult> {
+    info!("Importing decrypted vault export from {}", args.file_path);
 
-    for item in &all_items {
-        storage.update_item_fields(item, &temp_crypto_for_reencrypt)?;
+    let storage = state.storage.lock().unwrap();
+    let crypto = state.crypto.lock().unwrap();
 
-        if !item.data_path.is_empty() {
-             let decrypted_content = storage.read_encrypted_file(&item.data_path, &crypto)?;
-             let re_encrypted_content = temp_crypto_for_reencrypt.encrypt(&decrypted_content)?;
-             storage.write_encrypted_file(&re_encrypted_content, &item.data_path)?;
-        }
+    if !crypto.is_unlocked() {
+        error!("Vault is locked, cannot import decrypted export.");
+        return Err(Error::VaultLocked);
     }
-    
-    let decrypted_verification_token = crypto.decrypt(&verification_token)?;
-    let new_encrypted_token = temp_crypto_for_reencrypt.encrypt(&decrypted_verification_token)?;
-    storage.store_verification_token(&new_encrypted_token)?;
 
-    storage.update_salt(&new_salt)?;
-    storage.set_key_derivation_strength(new_strength)?;
+    let data = fs::read(&args.file_path)?;
+    let importer = DecryptedExportImporter::new(args.remap_ids);
+    let parsed = importer.parse(&data)?;
 
-    *crypto = temp_crypto_for_reencrypt;
+    if !args.remap_ids {
+        import::ensure_ids_are_new(storage.as_ref(), &crypto, &parsed)?;
+    }
+    let result = import::store_items(storage.as_ref(), &crypto, parsed, args.parent_id)?;
 
-    info!("Master key updated successfully.");
-    Ok(())
+    info!("{} import finished. Imported {} items, {} errors.", importer.name(), result.success_count, result.error_count);
+    vault::record_write(storage.as_ref(), &crypto);
+    Ok(result)
 }
 
 #[tauri::command]
-async fn export_decrypted_vault(args: ExportVaultArgs, state: State<'_, VaultState>) -> Result<String> {
-    info!("Exporting decrypted vault.");
+async fn export_encrypted_vault(state: State<'_, VaultState>) -> Result<Vec<u8>> {
+    info!("Exporting encrypted vault as a zip archive.");
+    let storage = state.storage.lock().unwrap();
+    let (cursor, _) = backup::write_archive(backup::vault_directory(storage.as_ref())?, Cursor::new(Vec::new()), |_| {})?;
 
-    let derived_key = {
-        let storage = state.storage.lock().unwrap();
-        let salt = storage.get_salt()?;
-        let temp_crypto = Crypto::new();
-        let strength = storage.get_key_derivation_strength()?;
-        let verification_token = storage.get_verification_token()?;
-
-        let key = temp_crypto.derive_key(&args.master_key, &salt, strength)?;
-        let mut checker_crypto = Crypto::new();
-        checker_crypto.unlock(&key)?;
-        
-        if checker_crypto.decrypt(&verification_token).is_err() {
-            return Err(Error::InvalidMasterKey);
-        }
-        key
-    };
+    info!("Encrypted vault export successful.");
+    Ok(cursor.into_inner())
+}
 
-    let mut crypto = state.crypto.lock().unwrap();
-    crypto.unlock(&derived_key)?;
-    
+#[tauri::command]
+async fn export_encrypted_vault_to_file(args: ExportBackupArgs, app_handle: AppHandle<Wry>, state: State<'_, VaultState>) -> Result<Manifest> {
+    info!("Exporting encrypted vault to {}", args.destination);
     let storage = state.storage.lock().unwrap();
-    let items = storage.get_all_items_recursive(&crypto)?;
-    let mut decrypted_items = Vec::new();
-
-    for item in items {
-         if !item.data_path.is_empty() {
-            let content = storage.read_encrypted_file(&item.data_path, &crypto)?;
-            let mut decrypted_item = serde_json::to_value(item)?;
-            decrypted_item["content"] = serde_json::Value::String(STANDARD.encode(&content));
-            decrypted_items.push(decrypted_item);
-        } else {
-             decrypted_items.push(serde_json::to_value(item)?);
+
+    let manifest = backup::export_archive(backup::vault_directory(storage.as_ref())?, Path::new(&args.destination), |progress| {
+        if let Err(e) = app_handle.emit(BACKUP_PROGRESS_EVENT, progress.clone()) {
+            warn!("Failed to emit backup progress: {}", e);
         }
-    }
-    
-    info!("Decrypted vault export successful.");
-    Ok(serde_json::to_string_pretty(&decrypted_items)?)
+    })?;
+
+    info!("Encrypted vault export to file successful.");
+    Ok(manifest)
 }
 
 #[tauri::command]
-async fn export_encrypted_vault(state: State<'_, VaultState>) -> Result<Vec<u8>> {
-    info!("Exporting encrypted vault as a zip archive.");
+async fn export_sealed_backup(args: SealedBackupExportArgs, app_handle: AppHandle<Wry>, state: State<'_, VaultState>) -> Result<Manifest> {
+    info!("Exporting sealed backup to {}", args.destination);
+
+    let mut recipients = Vec::new();
+    if let Some(passphrase) = args.passphrase.filter(|passphrase| !passphrase.is_empty()) {
+        recipients.push(BackupRecipient::Passphrase(zeroize::Zeroizing::new(passphrase)));
+    }
+    for public_key in &args.public_keys {
+        recipients.push(BackupRecipient::public_key(public_key)?);
+    }
+
     let storage = state.storage.lock().unwrap();
-    let vault_path = storage.get_vault_path();
-    
-    let buffer = {
-        let buffer: Vec<u8> = Vec::new();
-        let cursor = std::io::Cursor::new(buffer);
-        let mut zip = ZipWriter::new(cursor);
-        
-        let options = FileOptions::default().compression_method(zip::CompressionMethod::Stored);
-
-        let walkdir = WalkDir::new(vault_path);
-        let it = walkdir.into_iter();
-
-        for entry in it.filter_map(|e| e.ok()) {
-            let path = entry.path();
-            let name = path.strip_prefix(Path::new(vault_path)).unwrap();
-
-            if path.is_file() {
-                zip.start_file(name.to_str().unwrap(), options)?;
-                let mut f = File::open(path)?;
-                let mut contents = Vec::new();
-                f.read_to_end(&mut contents)?;
-                zip.write_all(&contents)?;
-            } else if !name.as_os_str().is_empty() {
-                zip.add_directory(name.to_str().unwrap(), options)?;
-            }
+    let manifest = backup::export_sealed_archive(backup::vault_directory(storage.as_ref())?, Path::new(&args.destination), &recipients, |progress| {
+        if let Err(e) = app_handle.emit(BACKUP_PROGRESS_EVENT, progress.clone()) {
+            warn!("Failed to emit backup progress: {}", e);
         }
-        
-        let cursor = zip.finish()?;
-        cursor.into_inner()
-    };
-    
-    info!("Encrypted vault export successful.");
-    Ok(buffer)
+    })?;
+
+    info!("Sealed backup export successful.");
+    Ok(manifest)
 }
 
 #[tauri::command]
-async fn delete_vault(args: DeleteVaultArgs, app_handle: AppHandle<Wry>, state: State<'_, VaultState>) -> Result<()> {
-    info!("Starting vault deletion process.");
-    
-    {
-        let storage = state.storage.lock().unwrap();
-        let salt = storage.get_salt()?;
-        let strength = storage.get_key_derivation_strength()?;
-        let temp_crypto = Crypto::new();
-        let verification_token = storage.get_verification_token()?;
-
-        let key = temp_crypto.derive_key(&args.master_key, &salt, strength)?;
-        let mut checker_crypto = Crypto::new();
-        checker_crypto.unlock(&key)?;
-        
-        if checker_crypto.decrypt(&verification_token).is_err() {
-            return Err(Error::InvalidMasterKey);
-        }
+fn generate_backup_key_pair() -> BackupKeyPair {
+    info!("Generating backup key pair.");
+    backup::generate_key_pair()
+}
+
+fn backup_identity(passphrase: Option<String>, private_key: Option<&str>) -> Result<Option<BackupIdentity>> {
+    match (private_key.filter(|key| !key.trim().is_empty()), passphrase.filter(|passphrase| !passphrase.is_empty())) {
+        (Some(private_key), _) => BackupIdentity::private_key(private_key).map(Some),
+        (None, Some(passphrase)) => Ok(Some(BackupIdentity::Passphrase(zeroize::Zeroizing::new(passphrase)))),
+        (None, None) => Ok(None),
     }
+}
 
-    let vault_path = app_handle.path().app_data_dir().unwrap().join("vault");
-    
-    if vault_path.exists() {
-        let mut crypto = state.crypto.lock().unwrap();
-        crypto.lock();
-        let _storage_lock = state.storage.lock().unwrap(); 
-        
-        fs::remove_dir_all(&vault_path)?;
-        info!("Vault directory deleted successfully.");
+#[tauri::command]
+async fn restore_encrypted_backup(args: RestoreBackupArgs, state: State<'_, VaultState>) -> Result<()> {
+    info!("Restoring vault from encrypted backup {}", args.file_path);
+
+    let mut storage = state.storage.lock().unwrap();
+    let mut crypto = state.crypto.lock().unwrap();
+
+    if storage.is_initialized() {
+        if !crypto.is_unlocked() {
+            error!("Vault is locked, cannot replace it with a backup.");
+            return Err(Error::VaultLocked);
+        }
+        let current_master_key = args
+            .current_master_key
+            .as_deref()
+            .ok_or_else(|| Error::InvalidInput("The current master key is required to replace the vault".into()))?;
+        unlock::verify_master_key(storage.as_ref(), current_master_key, Utc::now())?;
     }
-    
-    Ok(())
+
+    let identity = backup_identity(args.backup_passphrase, args.backup_private_key.as_deref())?;
+    let data = backup::read_backup(Path::new(&args.file_path), identity.as_ref())?;
+    backup::restore_archive(&mut storage, &mut crypto, &data, &args.master_key)?;
+    storage.migrate(Some(&crypto))?;
+    // The restored database carries an older seal; move it ahead of the anchor.
+    tamper::seal_vault_state(storage.as_ref(), &crypto)
 }
 
 #[tauri::command]
-async fn get_all_tags(state: State<'_, VaultState>) -> Result<Vec<String>> {
+async fn merge_encrypted_backup(args: MergeBackupArgs, state: State<'_, VaultState>) -> Result<MergeResult> {
+    info!("Merging vault archive {} into the current vault", args.file_path);
+
     let storage = state.storage.lock().unwrap();
     let crypto = state.crypto.lock().unwrap();
+
     if !crypto.is_unlocked() {
+        error!("Vault is locked, cannot merge a vault archive.");
         return Err(Error::VaultLocked);
     }
-    
-    let all_items = storage.get_all_items_recursive(&crypto)?;
-    let mut tags: Vec<String> = all_items.into_iter()
-        .flat_map(|item| item.tags)
-        .collect();
-    
-    tags.sort_unstable();
-    tags.dedup(); // Remove duplicates
-    
-    Ok(tags)
+
+    let identity = backup_identity(args.backup_passphrase, args.backup_private_key.as_deref())?;
+    let data = backup::read_backup(Path::new(&args.file_path), identity.as_ref())?;
+    let result = backup::merge_archive(storage.as_ref(), &crypto, &data, &args.master_key, args.parent_id, args.policy)?;
+    vault::record_write(storage.as_ref(), &crypto);
+    Ok(result)
 }
 
 #[tauri::command]
-async fn rename_tag(args: RenameTagArgs, state: State<'_, VaultState>) -> Result<()> {
-    info!("Renaming tag from '{}' to '{}'", args.old_tag_name, args.new_tag_name);
-    if args.new_tag_name.trim().is_empty() {
-        warn!("Attempted to rename tag to an empty string.");
-        return Err(Error::InvalidInput("New tag name cannot be empty".into()));
-    }
-    
+async fn verify_vault(state: State<'_, VaultState>) -> Result<IntegrityReport> {
+    info!("Verifying vault integrity.");
     let storage = state.storage.lock().unwrap();
     let crypto = state.crypto.lock().unwrap();
-
     if !crypto.is_unlocked() {
-        error!("Vault is locked, cannot rename tag.");
+        error!("Vault is locked, cannot verify it.");
         return Err(Error::VaultLocked);
     }
-    
-    storage.rename_tag_in_all_items(&args.old_tag_name, &args.new_tag_name, &crypto)?;
-    info!("Tag '{}' successfully renamed to '{}'.", args.old_tag_name, args.new_tag_name);
-    Ok(())
+    integrity::verify_vault(storage.as_ref(), &crypto)
 }
 
 #[tauri::command]
-async fn delete_tag(args: DeleteTagArgs, state: State<'_, VaultState>) -> Result<()> {
-    info!("Deleting tag: {}", args.tag_name);
+async fn repair_vault(state: State<'_, VaultState>) -> Result<RepairReport> {
+    info!("Repairing vault.");
+    let storage = state.storage.lock().unwrap();
+    let crypto = state.crypto.lock().unwrap();
+    if !crypto.is_unlocked() {
+        error!("Vault is locked, cannot repair it.");
+        return Err(Error::VaultLocked);
+    }
+
+    backup::backup_before_operation(storage.as_ref(), "repairing the vault")?;
+    let report = integrity::repair_vault(storage.as_ref(), &crypto)?;
+    vault::record_write(storage.as_ref(), &crypto);
+    Ok(report)
+}
+
+#[tauri::command]
+async fn get_backup_schedule(state: State<'_, VaultState>) -> Result<BackupSchedule> {
+    let storage = state.storage.lock().unwrap();
+    let crypto = state.crypto.lock().unwrap();
+    if !crypto.is_unlocked() {
+        return Err(Error::VaultLocked);
+    }
+    storage.get_backup_schedule()
+}
+
+#[tauri::command]
+async fn set_backup_schedule(schedule: BackupSchedule, state: State<'_, VaultState>) -> Result<()> {
+    info!("Updating automatic backup schedule.");
+    let storage = state.storage.lock().unwrap();
+    let crypto = state.crypto.lock().unwrap();
+    if !crypto.is_unlocked() {
+        error!("Vault is locked, cannot change the backup schedule.");
+        return Err(Error::VaultLocked);
+    }
+
+    schedule.validate(backup::vault_directory(storage.as_ref())?)?;
+    storage.set_backup_schedule(&schedule)?;
+    storage.seal_meta(&crypto)
+}
+
+/// The backups in the scheduled backup directory, newest first.
+#[tauri::command]
+async fn list_backups(state: State<'_, VaultState>) -> Result<Vec<BackupInfo>> {
+    let storage = state.storage.lock().unwrap();
+    let crypto = state.crypto.lock().unwrap();
+    if !crypto.is_unlocked() {
+        return Err(Error::VaultLocked);
+    }
+
+    match storage.get_backup_schedule()?.directory {
+        Some(directory) => backup::list_backups(&directory),
+        None => Ok(Vec::new()),
+    }
+}
+
+/// Checks every backup in the scheduled backup directory against its manifest.
+#[tauri::command]
+async fn verify_backups(state: State<'_, VaultState>) -> Result<Vec<BackupCheck>> {
+    info!("Verifying automatic backups.");
+    let storage = state.storage.lock().unwrap();
+    let crypto = state.crypto.lock().unwrap();
+    if !crypto.is_unlocked() {
+        return Err(Error::VaultLocked);
+    }
+
+    match storage.get_backup_schedule()?.directory {
+        Some(directory) => backup::verify_backups(&directory),
+        None => Ok(Vec::new()),
+    }
+}
+
+#[tauri::command]
+async fn delete_vault(args: DeleteVaultArgs, app_handle: AppHandle<Wry>, state: State<'_, VaultState>) -> Result<()> {
+    info!("Starting vault deletion process.");
+    
+    {
+        let storage = state.storage.lock().unwrap();
+        unlock::verify_master_key(storage.as_ref(), &args.master_key, Utc::now())?;
+        backup::backup_before_operation(storage.as_ref(), "deleting the vault")?;
+    }
+
+    let vault_path = app_handle.path().app_data_dir().unwrap().join("vault");
+    
+    if vault_path.exists() {
+        let mut crypto = state.crypto.lock().unwrap();
+        crypto.lock();
+        let _storage_lock = state.storage.lock().unwrap(); 
+        
+        fs::remove_dir_all(&vault_path)?;
+        tamper::remove_anchor(&vault_path)?;
+        backup::remove_migration_backups(&vault_path)?;
+        info!("Vault directory deleted successfully.");
+    }
+    
+    Ok(())
+}
+
+#[tauri::command]
+async fn get_all_tags(state: State<'_, VaultState>) -> Result<Vec<String>> {
+    let storage = state.storage.lock().unwrap();
+    let crypto = state.crypto.lock().unwrap();
+    if !crypto.is_unlocked() {
+        return Err(Error::VaultLocked);
+    }
+    vault::all_tags(storage.as_ref(), &crypto)
+}
+
+#[tauri::command]
+async fn rename_tag(args: RenameTagArgs, state: State<'_, VaultState>) -> Result<()> {
+    info!("Renaming tag from '{}' to '{}'", args.old_tag_name, args.new_tag_name);
+    if args.new_tag_name.trim().is_empty() {
+        warn!("Attempted to rename tag to an empty string.");
+        return Err(Error::InvalidInput("New tag name cannot be empty".into()));
+    }
+    
+    let storage = state.storage.lock().unwrap();
+    let crypto = state.crypto.lock().unwrap();
+
+    if !crypto.is_unlocked() {
+        error!("Vault is locked, cannot rename tag.");
+        return Err(Error::VaultLocked);
+    }
+    backup::backup_before_operation(storage.as_ref(), "renaming a tag")?;
+    
+    storage.rename_tag_in_all_items(&args.old_tag_name, &args.new_tag_name, &crypto)?;
+    info!("Tag '{}' successfully renamed to '{}'.", args.old_tag_name, args.new_tag_name);
+    vault::record_write(storage.as_ref(), &crypto);
+    Ok(())
+}
+
+#[tauri::command]
+async fn delete_tag(args: DeleteTagArgs, state: State<'_, VaultState>) -> Result<()> {
+    info!("Deleting tag: {}", args.tag_name);
     
     let storage = state.storage.lock().unwrap();
     let crypto = state.crypto.lock().unwrap();
@@ -692,11 +848,49 @@ async fn delete_tag(args: DeleteTagArgs, state: State<'_, VaultState>) -> Result
     
     storage.remove_tag_from_all_items(&args.tag_name, &crypto)?;
     info!("Tag '{}' successfully deleted from all items.", args.tag_name);
+    vault::record_write(storage.as_ref(), &crypto);
     Ok(())
 }
 
+fn read_csv_import_source(args: &CsvImportArgs) -> Result<Vec<u8>> {
+    let csv_data = match (&args.csv_content, &args.file_path) {
+        (Some(content), _) => content.clone().into_bytes(),
+        (None, Some(file_path)) => fs::read(file_path)?,
+        (None, None) => return Err(Error::InvalidInput("No CSV content or file provided".into())),
+    };
+    info!("CSV content length: {} bytes", csv_data.len());
+    Ok(csv_data)
+}
+
+#[tauri::command]
+async fn inspect_csv_import(args: CsvImportArgs, state: State<'_, VaultState>) -> Result<CsvLayout> {
+    info!("Inspecting CSV file for column mapping.");
+
+    let crypto = state.crypto.lock().unwrap();
+    if !crypto.is_unlocked() {
+        return Err(Error::VaultLocked);
+    }
+
+    let csv_data = read_csv_import_source(&args)?;
+    CsvImporter::new(args.options).inspect(&csv_data)
+}
+
 #[tauri::command]
-async fn import_csv(args: CsvImportArgs, state: State<'_, VaultState>) -> Result<()> {
+async fn preview_csv_import(args: CsvImportArgs, state: State<'_, VaultState>) -> Result<ImportPreview> {
+    info!("Previewing CSV import.");
+
+    let crypto = state.crypto.lock().unwrap();
+    if !crypto.is_unlocked() {
+        return Err(Error::VaultLocked);
+    }
+
+    let csv_data = read_csv_import_source(&args)?;
+    let parsed = CsvImporter::new(args.options).parse(&csv_data)?;
+    Ok(import::preview(&parsed))
+}
+
+#[tauri::command]
+async fn import_csv(args: CsvImportArgs, state: State<'_, VaultState>) -> Result<ImportResult> {
     info!("Importing CSV content.");
 
     let storage = state.storage.lock().unwrap();
@@ -707,141 +901,507 @@ async fn import_csv(args: CsvImportArgs, state: State<'_, VaultState>) -> Result
         return Err(Error::VaultLocked);
     }
 
-    let csv_content = &args.csv_content;
-    let parent_id = args.parent_id;
-
-    info!("CSV content length: {} bytes", csv_content.len());
-    info!("CSV content preview: {}", &csv_content[..csv_content.len().min(200)]);
-
-    let mut reader = ReaderBuilder::new()
-        .has_headers(true)
-        .flexible(true)
-        .from_reader(csv_content.as_bytes());
-
-    let mut imported_count = 0;
-    let mut row_count = 0;
-
-    for result in reader.deserialize() {
-        row_count += 1;
-        match result {
-            Ok(row) => {
-                let row: CsvRow = row;
-                
-                // Extract data from either format
-                let title = row.account
-                    .or(row.title)
-                    .or(row.name_browser)
-                    .or(row.hostname_browser)
-                    .or_else(|| {
-                        row.web_site.as_ref()
-                            .or(row.url.as_ref())
-                            .or(row.url_browser.as_ref())
-                            .and_then(|url| {
-                                url.replace("https://", "")
-                                   .replace("http://", "")
-                                   .split('/')
-                                   .next()
-                                   .map(|s| s.to_string())
-                            })
-                    });
-                
-                let username = row.login_name.or(row.username).or(row.username_browser);
-                let password = row.password.or(row.password_browser);
-                let url = row.web_site.or(row.url).or(row.url_browser);
-                let notes = row.comments.or(row.notes);
-                let tags = row.tags;
-                
-                info!("Processing row {}: title = {:?}, username = {:?}", row_count, title, username);
-                
-                // Skip rows without a title
-                if title.is_none() || title.as_ref().unwrap().trim().is_empty() {
-                    info!("Skipping row {} - no title", row_count);
-                    continue;
-                }
+    let csv_data = read_csv_import_source(&args)?;
+    let importer = CsvImporter::new(args.options);
+    let parsed = importer.parse(&csv_data)?;
+    let result = import::store_items(storage.as_ref(), &crypto, parsed, args.parent_id)?;
 
-                // Create the content for the password item
-                let mut content = String::new();
-                if let Some(username_val) = &username {
-                    if !username_val.trim().is_empty() {
-                        content.push_str(&format!("Username: {}\n\n", username_val.trim()));
-                    }
-                }
-                if let Some(password_val) = &password {
-                    if !password_val.trim().is_empty() {
-                        content.push_str(&format!("Password: {}\n\n", password_val.trim()));
-                    }
-                }
-                if let Some(url_val) = &url {
-                    if !url_val.trim().is_empty() {
-                        content.push_str(&format!("URL: {}\n\n", url_val.trim()));
-                    }
-                }
-                if let Some(notes_val) = &notes {
-                    if !notes_val.trim().is_empty() {
-                        content.push_str(&format!("Notes: {}\n\n", notes_val.trim()));
-                    }
-                }
-                let content = content.trim_end().to_string();
-                info!("Created content for row {}: '{}' (length: {})", row_count, content, content.len());
-                
-                // Skip rows with no content
-                if content.trim().is_empty() {
-                    info!("Skipping row {} - no content to store", row_count);
+    info!("{} import finished. Imported {} items, {} errors.", importer.name(), result.success_count, result.error_count);
+    vault::record_write(storage.as_ref(), &crypto);
+    Ok(result)
+}
+
+fn keepass_key(password: Option<String>, key_file_path: Option<&str>) -> Result<kdbx::DatabaseKey> {
+    let keyfile = key_file_path.map(fs::read).transpose()?;
+    kdbx::DatabaseKey::new(password, keyfile)
+}
+
+#[tauri::command]
+async fn import_keepass(args: KeePassImportArgs, state: State<'_, VaultState>) -> Result<ImportResult> {
+    info!("Importing KeePass database from {}", args.file_path);
+
+    let storage = state.storage.lock().unwrap();
+    let crypto = state.crypto.lock().unwrap();
+
+    if !crypto.is_unlocked() {
+        error!("Vault is locked, cannot import KeePass database.");
+        return Err(Error::VaultLocked);
+    }
+
+    let data = fs::read(&args.file_path)?;
+    let importer = KeePassImporter::new(keepass_key(args.password, args.key_file_path.as_deref())?);
+    let parsed = importer.parse(&data)?;
+    let result = import::store_items(storage.as_ref(), &crypto, parsed, args.parent_id)?;
+
+    info!("{} import finished. Imported {} items, {} errors.", importer.name(), result.success_count, result.error_count);
+    vault::record_write(storage.as_ref(), &crypto);
+    Ok(result)
+}
+
+#[tauri::command]
+async fn export_keepass(args: KeePassExportArgs, state: State<'_, VaultState>) -> Result<Vec<u8>> {
+    info!("Exporting vault as a KeePass database.");
+
+    let storage = state.storage.lock().unwrap();
+    let crypto = state.crypto.lock().unwrap();
+
+    if !crypto.is_unlocked() {
+        error!("Vault is locked, cannot export KeePass database.");
+        return Err(Error::VaultLocked);
+    }
+    unlock::verify_master_key(storage.as_ref(), &args.master_key, Utc::now())?;
+
+    let key = keepass_key(args.password, args.key_file_path.as_deref())?;
+    let database = export::to_keepass(storage.as_ref(), &crypto)?;
+    kdbx::write(&database, &key)
+}
+
+#[tauri::command]
+async fn import_bitwarden(args: BitwardenImportArgs, state: State<'_, VaultState>) -> Result<ImportResult> {
+    info!("Importing Bitwarden export from {}", args.file_path);
+
+    let storage = state.storage.lock().unwrap();
+    let crypto = state.crypto.lock().unwrap();
+
+    if !crypto.is_unlocked() {
+        error!("Vault is locked, cannot import Bitwarden export.");
+        return Err(Error::VaultLocked);
+    }
+
+    let data = fs::read(&args.file_path)?;
+    let importer = BitwardenImporter::new(args.password);
+    let parsed = importer.parse(&data)?;
+    let result = import::store_items(storage.as_ref(), &crypto, parsed, args.parent_id)?;
+
+    info!("{} import finished. Imported {} items, {} errors.", importer.name(), result.success_count, result.error_count);
+    vault::record_write(storage.as_ref(), &crypto);
+    Ok(result)
+}
+
+#[tauri::command]
+async fn import_onepassword(args: OnePasswordImportArgs, state: State<'_, VaultState>) -> Result<ImportResult> {
+    info!("Importing 1Password export from {}", args.file_path);
+
+    let storage = state.storage.lock().unwrap();
+    let crypto = state.crypto.lock().unwrap();
+
+    if !crypto.is_unlocked() {
+        error!("Vault is locked, cannot import 1Password export.");
+        return Err(Error::VaultLocked);
+    }
+
+    let data = fs::read(&args.file_path)?;
+    let importer = OnePasswordImporter::new();
+    let parsed = importer.parse(&data)?;
+    let result = import::store_items(storage.as_ref(), &crypto, parsed, args.parent_id)?;
+
+    info!("{} import finished. Imported {} items, {} errors.", importer.name(), result.success_count, result.error_count);
+    vault::record_write(storage.as_ref(), &crypto);
+    Ok(result)
+}
+
+#[tauri::command]
+async fn check_breaches_offline(args: BreachCheckArgs, state: State<'_, VaultState>) -> Result<Vec<BreachResult>> {
+    info!("Checking stored passwords against the local Pwned Passwords list.");
+
+    let storage = state.storage.lock().unwrap();
+    let crypto = state.crypto.lock().unwrap();
+
+    if !crypto.is_unlocked() {
+        error!("Vault is locked, cannot check passwords.");
+        return Err(Error::VaultLocked);
+    }
+
+    let pwned_path = match args.path {
+        Some(path) => {
+            let path = std::path::PathBuf::from(path);
+            storage.set_pwned_passwords_path(&path)?;
+            path
+        }
+        None => storage.get_pwned_passwords_path()?
+            .ok_or_else(|| Error::InvalidInput("No Pwned Passwords file or directory configured".into()))?,
+    };
+    let pwned = PwnedPasswords::open(&pwned_path)?;
+
+    let mut results = Vec::new();
+    for item in storage.get_all_items_recursive(&crypto)? {
+        if item.data_path.is_empty() {
+            continue;
+        }
+        let content = storage.read_encrypted_file(&item.data_path, &crypto)?;
+        let Some(password) = login::password_of(&item, &String::from_utf8_lossy(&content)) else {
+            continue;
+        };
+
+        let breach_count = pwned.breach_count(&password)?;
+        if breach_count > 0 {
+            warn!("Password of item '{}' appears in {} breaches.", item.name, breach_count);
+        }
+        results.push(BreachResult {
+            id: item.id,
+            name: item.name,
+            breach_count,
+        });
+    }
+
+    info!("Checked {} passwords against {:?} hashes.", results.len(), pwned.kind());
+    Ok(results)
+}
+
+/// Writes new content for an item, moving a replaced password into the item's history.
+fn replace_item_content(storage: &dyn Storage, crypto: &Crypto, item: &mut VaultItem, new_content: &str) -> Result<()> {
+    let old_content = storage.read_encrypted_file(&item.data_path, crypto)?;
+    let old_password = login::password_of(item, &String::from_utf8_lossy(&old_content));
+    let new_password = login::password_of(item, new_content);
+
+    let now = Utc::now();
+    if let Some(old_password) = old_password {
+        if new_password.as_deref() != Some(old_password.as_str()) {
+            debug!("Password of item {} changed, recording previous value.", item.id);
+            storage.add_password_history_entry(&PasswordHistoryEntry {
+                id: Uuid::new_v4().to_string(),
+                item_id: item.id.clone(),
+                password: old_password,
+                changed_at: now,
+            }, crypto)?;
+        }
+    }
+
+    let encrypted_content = crypto.encrypt(new_content.as_bytes())?;
+    storage.write_encrypted_file(&encrypted_content, &item.data_path)?;
+    item.updated_at = now;
+    storage.update_item_fields(item, crypto)
+}
+
+#[tauri::command]
+async fn update_item_content(args: UpdateItemContentArgs, state: State<'_, VaultState>) -> Result<()> {
+    info!("Updating content of item: {}", args.id);
+
+    if args.content.is_empty() {
+        warn!("Attempted to update item with empty content.");
+        return Err(Error::InvalidInput("Item content cannot be empty".into()));
+    }
+
+    let storage = state.storage.lock().unwrap();
+    let crypto = state.crypto.lock().unwrap();
+
+    if !crypto.is_unlocked() {
+        error!("Vault is locked, cannot update item.");
+        return Err(Error::VaultLocked);
+    }
+
+    let mut item = storage.get_item(&args.id, &crypto)?.ok_or_else(|| Error::ItemNotFound(args.id.clone()))?;
+    if item.data_path.is_empty() {
+        return Err(Error::InvalidInput("Item has no content to update".into()));
+    }
+
+    replace_item_content(storage.as_ref(), &crypto, &mut item, &args.content)?;
+    info!("Content of item '{}' updated successfully.", item.name);
+    vault::record_write(storage.as_ref(), &crypto);
+    Ok(())
+}
+
+#[tauri::command]
+async fn get_password_history(id: String, state: State<'_, VaultState>) -> Result<Vec<PasswordHistoryEntry>> {
+    let storage = state.storage.lock().unwrap();
+    let crypto = state.crypto.lock().unwrap();
+
+    if !crypto.is_unlocked() {
+        return Err(Error::VaultLocked);
+    }
+
+    storage.get_password_history(&id, &crypto)
+}
+
+#[tauri::command]
+async fn restore_password(args: RestorePasswordArgs, state: State<'_, VaultState>) -> Result<()> {
+    info!("Restoring previous password {} of item {}", args.history_id, args.item_id);
+
+    let storage = state.storage.lock().unwrap();
+    let crypto = state.crypto.lock().unwrap();
+
+    if !crypto.is_unlocked() {
+        error!("Vault is locked, cannot restore password.");
+        return Err(Error::VaultLocked);
+    }
+
+    let mut item = storage.get_item(&args.item_id, &crypto)?.ok_or_else(|| Error::ItemNotFound(args.item_id.clone()))?;
+    let entry = storage.get_password_history(&item.id, &crypto)?
+        .into_iter()
+        .find(|entry| entry.id == args.history_id)
+        .ok_or_else(|| Error::ItemNotFound(args.history_id.clone()))?;
+
+    let content = storage.read_encrypted_file(&item.data_path, &crypto)?;
+    let restored_content = login::replace_password(&item, &String::from_utf8_lossy(&content), &entry.password)
+        .ok_or_else(|| Error::InvalidInput("Item does not hold a password".into()))?;
+
+    replace_item_content(storage.as_ref(), &crypto, &mut item, &restored_content)?;
+    info!("Password of item '{}' restored successfully.", item.name);
+    vault::record_write(storage.as_ref(), &crypto);
+    Ok(())
+}
+
+#[tauri::command]
+async fn audit_password_reuse(state: State<'_, VaultState>) -> Result<Vec<PasswordReuse>> {
+    info!("Auditing stored passwords for reuse.");
+
+    let storage = state.storage.lock().unwrap();
+    let crypto = state.crypto.lock().unwrap();
+
+    if !crypto.is_unlocked() {
+        return Err(Error::VaultLocked);
+    }
+
+    let mut logins = Vec::new();
+    for item in storage.get_all_items_recursive(&crypto)? {
+        if item.data_path.is_empty() {
+            continue;
+        }
+        let content = storage.read_encrypted_file(&item.data_path, &crypto)?;
+        let Some(password) = login::password_of(&item, &String::from_utf8_lossy(&content)) else {
+            continue;
+        };
+        let previous_passwords = storage.get_password_history(&item.id, &crypto)?
+            .into_iter()
+            .map(|entry| entry.password)
+            .collect();
+        logins.push(AuditedLogin { item, password, previous_passwords });
+    }
+
+    let reused = login::find_reused_passwords(&logins);
+    info!("Audited {} passwords, {} reused.", logins.len(), reused.len());
+    Ok(reused)
+}
+
+/// Periodically notifies about items whose expiry date is approaching, while the vault is unlocked.
+async fn run_expiry_reminders(app_handle: AppHandle<Wry>) {
+    let mut tracker = ReminderTracker::new();
+    // The first tick completes at once, so due items are reported without waiting a full interval.
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(EXPIRY_CHECK_INTERVAL_SECS));
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+
+        let due = {
+            let state = app_handle.state::<VaultState>();
+            let storage = state.storage.lock().unwrap();
+            let crypto = state.crypto.lock().unwrap();
+            if !crypto.is_unlocked() {
+                continue;
+            }
+            match storage.get_all_items_recursive(&crypto) {
+                Ok(items) => tracker.due(&items, Utc::now()),
+                Err(e) => {
+                    error!("Failed to load items for expiry reminders: {}", e);
                     continue;
                 }
-                
-                // Parse tags
-                let tags_vec: Vec<String> = tags
-                    .unwrap_or_default()
-                    .split(',')
-                    .map(|s| s.trim().to_string())
-                    .filter(|s| !s.is_empty())
-                    .collect();
-
-                info!("Parsed tags for row {}: {:?}", row_count, tags_vec);
-
-                // Create the vault item
-                let item = VaultItem {
-                    id: Uuid::new_v4().to_string(),
-                    parent_id: parent_id.clone(),
-                    name: title.unwrap().trim().to_string(),
-                    data_path: "".to_string(),
-                    item_type: "text/plain".to_string(),
-                    folder_type: None,
-                    tags: tags_vec,
-                    created_at: Utc::now(),
-                    updated_at: Utc::now(),
-                };
-
-                info!("Created vault item for row {}: {} (id: {})", row_count, item.name, item.id);
-
-                // Add the item to storage
-                storage.add_item(&item, &crypto)?;
-                info!("Added item to storage for row {}", row_count);
-                
-                // Write the content to a file
-                let file_name = format!("{}.txt", item.id);
-                info!("Writing content to file: {} (content length: {})", file_name, content.len());
-                let encrypted_content = crypto.encrypt(content.as_bytes())?;
-                storage.write_encrypted_file(&encrypted_content, &file_name)?;
-                info!("Successfully wrote encrypted content to file: {}", file_name);
-                
-                // Update the item with the correct data path
-                let mut updated_item = item.clone();
-                updated_item.data_path = file_name.clone();
-                storage.update_item_fields(&updated_item, &crypto)?;
-                info!("Updated item data_path to: {}", file_name);
-                
-                imported_count += 1;
-                info!("Successfully imported row {}: {}", row_count, item.name);
             }
-            Err(e) => {
-                error!("Error parsing row {}: {}", row_count, e);
-                return Err(Error::Csv(format!("Error parsing row {}: {}", row_count, e)));
+        };
+
+        for expiration in due {
+            debug!("Sending expiry reminder for item {}", expiration.id);
+            let title = if expiration.expired { "Item expired" } else { "Item expiring soon" };
+            if let Err(e) = app_handle
+                .notification()
+                .builder()
+                .title(title)
+                .body(expiration.reminder_message())
+                .show()
+            {
+                error!("Failed to show expiry notification: {}", e);
             }
         }
     }
+}
+
+/// Takes a backup whenever the configured interval has passed since the newest one.
+async fn run_scheduled_backups(app_handle: AppHandle<Wry>) {
+    loop {
+        tokio::time::sleep(std::time::Duration::from_secs(BACKUP_CHECK_INTERVAL_SECS)).await;
+
+        let backup = {
+            let state = app_handle.state::<VaultState>();
+            let storage = state.storage.lock().unwrap();
+            backup::run_scheduled_backup(storage.as_ref(), Utc::now())
+        };
+        if let Err(e) = backup {
+            error!("Failed to take scheduled backup: {}", e);
+        }
+    }
+}
+
+#[tauri::command]
+async fn set_item_expiry(args: SetItemExpiryArgs, state: State<'_, VaultState>) -> Result<()> {
+    info!("Setting expiry of item {} to {:?}", args.id, args.expires_at);
 
-    info!("CSV import successful. Processed {} rows, imported {} items.", row_count, imported_count);
+    let storage = state.storage.lock().unwrap();
+    let crypto = state.crypto.lock().unwrap();
+
+    if !crypto.is_unlocked() {
+        error!("Vault is locked, cannot set item expiry.");
+        return Err(Error::VaultLocked);
+    }
+
+    let mut item = storage.get_item(&args.id, &crypto)?.ok_or_else(|| Error::ItemNotFound(args.id.clone()))?;
+    item.expires_at = args.expires_at;
+    item.updated_at = Utc::now();
+    storage.update_item_fields(&item, &crypto)?;
+    vault::record_write(storage.as_ref(), &crypto);
     Ok(())
-}
\ No newline at end of file
+}
+
+#[tauri::command]
+async fn get_upcoming_expirations(days: Option<i64>, state: State<'_, VaultState>) -> Result<Vec<UpcomingExpiration>> {
+    let storage = state.storage.lock().unwrap();
+    let crypto = state.crypto.lock().unwrap();
+
+    if !crypto.is_unlocked() {
+        return Err(Error::VaultLocked);
+    }
+
+    let items = storage.get_all_items_recursive(&crypto)?;
+    let window = Duration::days(days.unwrap_or(DEFAULT_EXPIRY_WINDOW_DAYS));
+    Ok(expiry::upcoming_expirations(&items, Utc::now(), window))
+}
+
+#[tauri::command]
+async fn get_certificate_pairs(state: State<'_, VaultState>) -> Result<Vec<CertificatePair>> {
+    let storage = state.storage.lock().unwrap();
+    let crypto = state.crypto.lock().unwrap();
+
+    if !crypto.is_unlocked() {
+        return Err(Error::VaultLocked);
+    }
+
+    let certificate_items: Vec<_> = storage.get_all_items_recursive(&crypto)?
+        .into_iter()
+        .filter_map(|item| match item.metadata {
+            Some(ItemMetadata::X509(metadata)) => Some((item.id, metadata)),
+            _ => None,
+        })
+        .collect();
+
+    Ok(certificate::find_pairs(&certificate_items))
+}
+
+fn add_ssh_key_item(storage: &dyn Storage, crypto: &Crypto, name: String, tags: Vec<String>, parent_id: Option<String>, key: SshKey) -> Result<()> {
+    let now = Utc::now();
+    let data_path = Uuid::new_v4().to_string();
+
+    let item = VaultItem {
+        id: Uuid::new_v4().to_string(),
+        parent_id,
+        name,
+        data_path: data_path.clone(),
+        item_type: SSH_KEY_ITEM_TYPE.to_string(),
+        folder_type: None,
+        tags,
+        created_at: now,
+        updated_at: now,
+        expires_at: None,
+        metadata: Some(ItemMetadata::SshKey(key.metadata)),
+    };
+
+    let encrypted_content = crypto.encrypt(key.private_key.as_bytes())?;
+    storage.write_encrypted_file(&encrypted_content, &data_path)?;
+    storage.add_item(&item, crypto)?;
+
+    info!("SSH key '{}' added successfully.", item.name);
+    vault::record_write(storage, crypto);
+    Ok(())
+}
+
+#[tauri::command]
+async fn generate_ssh_key(args: GenerateSshKeyArgs, state: State<'_, VaultState>) -> Result<()> {
+    info!("Generating {:?} SSH key: {}", args.algorithm, args.name);
+
+    if args.name.trim().is_empty() {
+        warn!("Attempted to generate SSH key with empty name.");
+        return Err(Error::InvalidInput("Item name cannot be empty".into()));
+    }
+
+    // RSA generation can take seconds, so it runs before the vault is locked for it.
+    let comment = args.comment.unwrap_or_else(|| args.name.clone());
+    let key = ssh::generate(args.algorithm, args.bits, &comment)?;
+
+    let storage = state.storage.lock().unwrap();
+    let crypto = state.crypto.lock().unwrap();
+
+    if !crypto.is_unlocked() {
+        error!("Vault is locked, cannot generate SSH key.");
+        return Err(Error::VaultLocked);
+    }
+
+    add_ssh_key_item(storage.as_ref(), &crypto, args.name, args.tags, args.parent_id, key)
+}
+
+#[tauri::command]
+async fn import_ssh_key(args: ImportSshKeyArgs, state: State<'_, VaultState>) -> Result<()> {
+    info!("Importing SSH key: {}", args.name);
+
+    let key_data = zeroize::Zeroizing::new(fs::read_to_string(&args.file_path)?);
+    let key = ssh::import(&key_data, args.passphrase.as_deref(), &args.name)?;
+
+    let storage = state.storage.lock().unwrap();
+    let crypto = state.crypto.lock().unwrap();
+
+    if !crypto.is_unlocked() {
+        error!("Vault is locked, cannot import SSH key.");
+        return Err(Error::VaultLocked);
+    }
+
+    add_ssh_key_item(storage.as_ref(), &crypto, args.name, args.tags, args.parent_id, key)
+}
+
+#[tauri::command]
+async fn export_ssh_public_key(id: String, state: State<'_, VaultState>) -> Result<String> {
+    let storage = state.storage.lock().unwrap();
+    let crypto = state.crypto.lock().unwrap();
+
+    if !crypto.is_unlocked() {
+        return Err(Error::VaultLocked);
+    }
+
+    let item = storage.get_item(&id, &crypto)?.ok_or_else(|| Error::ItemNotFound(id.clone()))?;
+    match item.metadata {
+        Some(ItemMetadata::SshKey(metadata)) => Ok(format!("{}\n", metadata.public_key)),
+        _ => Err(Error::InvalidInput("Item is not an SSH key".into())),
+    }
+}
+
+#[tauri::command]
+async fn render_template(args: RenderTemplateArgs, state: State<'_, VaultState>) -> Result<RenderReport> {
+    let storage = state.storage.lock().unwrap();
+    let crypto = state.crypto.lock().unwrap();
+
+    if !crypto.is_unlocked() {
+        error!("Vault is locked, cannot render template.");
+        return Err(Error::VaultLocked);
+    }
+
+    let shred_at = template::shred_time(Utc::now(), args.shred_after_secs)?;
+    let vault_path = backup::vault_directory(storage.as_ref())?.to_path_buf();
+    let output = PathBuf::from(args.output_path);
+    let placeholders = template::render_to_file(storage.as_ref(), &crypto, Path::new(&args.template_path), &output)?;
+
+    if let Some(shred_at) = shred_at {
+        // Recorded rather than only timed, so the file is still shredded if the app quits first.
+        template::schedule_shred(&vault_path, &output, shred_at)?;
+        tauri::async_runtime::spawn(run_pending_shreds(vault_path));
+    }
+    Ok(RenderReport { output, placeholders, shred_at })
+}
+
+/// Shreds pending rendered files as they come due, until none are left.
+async fn run_pending_shreds(vault_path: PathBuf) {
+    loop {
+        match template::shred_due(&vault_path, Utc::now()) {
+            Ok(Some(next)) => tokio::time::sleep((next - Utc::now()).to_std().unwrap_or_default()).await,
+            Ok(None) => return,
+            Err(e) => {
+                error!("Failed to shred rendered files: {}", e);
+                return;
+            }
+        }
+    }
+}
@@ -1,21 +1,24 @@
+use crate::backup::BackupSchedule;
+use crate::certificate::CertificateMetadata;
 use crate::crypto::{Crypto, KeyDerivationStrength};
+use crate::ssh::SshKeyMetadata;
 use crate::error::Error;
 use crate::Result;
 use chrono::{DateTime, Utc};
-use log::{error, info, debug, trace};
-use rusqlite::{params, Connection, Result as RusqliteResult, Row};
+use log::{error, info};
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use rand::{thread_rng, Rng};
 use serde::{Deserialize, Serialize};
 use std::fs;
-use std::path::{PathBuf, Path};
-use std::sync::Mutex;
-use std::io::{Write, Seek, SeekFrom};
-use rand::{thread_rng, Rng};
-use std::string::FromUtf8Error;
+use std::io::{self, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use zeroize::Zeroizing;
 
-#[cfg(unix)]
-use std::os::unix::fs::PermissionsExt;
-use std::fs::Permissions;
+mod memory;
+mod sqlite;
 
+pub use self::memory::MemoryStorage;
+pub use self::sqlite::SqliteStorage;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct VaultItem {
@@ -29,6 +32,18 @@ pub struct VaultItem {
     pub tags: Vec<String>,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
+    #[serde(default)]
+    pub expires_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub metadata: Option<ItemMetadata>,
+}
+
+/// Structured details extracted from an item's content.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ItemMetadata {
+    X509(CertificateMetadata),
+    SshKey(SshKeyMetadata),
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, Copy, Default)]
@@ -43,6 +58,7 @@ pub enum SortOrder {
 }
 
 impl SortOrder {
+    /// The `ORDER BY` clause `SqliteStorage` uses for this order.
     fn to_sql(&self) -> &'static str {
         match self {
             SortOrder::CreatedAtDesc => "ORDER BY created_at DESC",
@@ -72,359 +88,319 @@ impl Default for BruteForceConfig {
     }
 }
 
-pub struct Storage {
-    vault_path: PathBuf,
-    conn: Mutex<Connection>,
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PasswordHistoryEntry {
+    pub id: String,
+    pub item_id: String,
+    pub password: String,
+    pub changed_at: DateTime<Utc>,
 }
 
-impl Storage {
-    pub fn new(vault_path: PathBuf) -> Result<Self> {
-        fs::create_dir_all(&vault_path)?;
+/// A row of `vault_items` that could not be decrypted or parsed. Only the
+/// plaintext columns are known.
+#[derive(Debug, Serialize, Clone)]
+pub struct UnreadableItem {
+    pub id: String,
+    pub parent_id: Option<String>,
+    pub error: String,
+}
 
-        let db_path = vault_path.join("vault.db");
-        let conn = Connection::open(&db_path)?;
+/// The size and modification time of a content file. While both are
+/// unchanged the file is taken to be unchanged too.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub struct DataFileStamp {
+    pub size: u64,
+    pub modified: DateTime<Utc>,
+}
 
-        #[cfg(unix)]
-        {
-            let perms = Permissions::from_mode(0o600);
-            if let Err(e) = fs::set_permissions(&db_path, perms) {
-                error!("Failed to set permissions for database file {}: {}", db_path.display(), e);      
-            }
-        }
+/// Meta values that weaken the vault if edited: a wrong KDF label stops the
+/// vault from unlocking, and the others loosen the lockout or redirect backups.
+/// Each is stored with a MAC keyed from the vault key. They stay readable while
+/// the vault is locked; edits are caught at the next unlock.
+pub const AUTHENTICATED_META_KEYS: [&str; 4] = ["kdf_strength", "brute_force_config", "backup_schedule", "failed_attempts_total_at_unlock"];
+const META_MAC_PREFIX: &str = "mac:";
+/// Appended to the verification token once a vault keeps MACs. The token only
+/// opens with the vault key, so unlike the plaintext `schema_version` this
+/// cannot be undone to make stripped MACs look like a vault that never had any.
+const META_MAC_TOKEN_MARKER: &[u8] = b"fetch:meta-macs";
+
+/// The newest schema this build knows. A vault with a higher version was
+/// written by a newer release and is not opened.
+pub const SCHEMA_VERSION: u32 = 5;
+
+/// A vault item as a backend keeps it: the id and parent in the clear so the
+/// tree can be walked without the key, every other field encrypted on its own.
+#[derive(Debug, Clone)]
+pub struct EncryptedItem {
+    pub id: String,
+    pub parent_id: Option<String>,
+    pub name: Vec<u8>,
+    pub item_type: Vec<u8>,
+    pub data_path: Vec<u8>,
+    pub folder_type: Option<Vec<u8>>,
+    pub tags: Vec<u8>,
+    pub created_at: Vec<u8>,
+    pub updated_at: Vec<u8>,
+    pub expires_at: Option<Vec<u8>>,
+    pub metadata: Option<Vec<u8>>,
+}
 
-        conn.execute(
-            "CREATE TABLE IF NOT EXISTS vault_items (
-                id TEXT PRIMARY KEY,
-                parent_id TEXT,
-                name BLOB NOT NULL,
-                item_type BLOB NOT NULL,
-                data_path BLOB NOT NULL,
-                folder_type BLOB,
-                tags BLOB,
-                created_at BLOB NOT NULL,
-                updated_at BLOB NOT NULL
-            )",
-            [],
-        )?;
-        
-        conn.execute(
-            "CREATE TABLE IF NOT EXISTS vault_meta (
-                key TEXT PRIMARY KEY,
-                value TEXT NOT NULL
-            )",
-            [],
-        )?;
+impl EncryptedItem {
+    pub fn encrypt(item: &VaultItem, crypto: &Crypto) -> Result<Self> {
+        let tags_json = serde_json::to_string(&item.tags)?;
+        Ok(Self {
+            id: item.id.clone(),
+            parent_id: item.parent_id.clone(),
+            name: crypto.encrypt(item.name.as_bytes())?,
+            item_type: crypto.encrypt(item.item_type.as_bytes())?,
+            data_path: crypto.encrypt(item.data_path.as_bytes())?,
+            folder_type: item.folder_type.as_ref().map(|ft| crypto.encrypt(ft.as_bytes())).transpose()?,
+            tags: crypto.encrypt(tags_json.as_bytes())?,
+            created_at: crypto.encrypt(item.created_at.to_rfc3339().as_bytes())?,
+            updated_at: crypto.encrypt(item.updated_at.to_rfc3339().as_bytes())?,
+            expires_at: item.expires_at.map(|expires_at| crypto.encrypt(expires_at.to_rfc3339().as_bytes())).transpose()?,
+            metadata: match &item.metadata {
+                Some(metadata) => Some(crypto.encrypt(serde_json::to_string(metadata)?.as_bytes())?),
+                None => None,
+            },
+        })
+    }
 
-        fs::create_dir_all(vault_path.join("data"))?;
+    pub fn decrypt(&self, crypto: &Crypto) -> Result<VaultItem> {
+        let tags_json = decrypt_text(crypto, &self.tags, "tags")?;
+        Ok(VaultItem {
+            id: self.id.clone(),
+            parent_id: self.parent_id.clone(),
+            name: decrypt_text(crypto, &self.name, "name")?,
+            item_type: decrypt_text(crypto, &self.item_type, "item_type")?,
+            data_path: decrypt_text(crypto, &self.data_path, "data_path")?,
+            folder_type: self.folder_type.as_ref().map(|ft| decrypt_text(crypto, ft, "folder_type")).transpose()?,
+            tags: serde_json::from_str(&tags_json).unwrap_or_else(|_| vec![]),
+            created_at: decrypt_timestamp(crypto, &self.created_at, "created_at")?,
+            updated_at: decrypt_timestamp(crypto, &self.updated_at, "updated_at")?,
+            expires_at: self.expires_at.as_ref().map(|expires_at| decrypt_timestamp(crypto, expires_at, "expires_at")).transpose()?,
+            metadata: match &self.metadata {
+                Some(metadata) => Some(
+                    serde_json::from_str(&decrypt_text(crypto, metadata, "metadata")?)
+                        .map_err(|e| Error::Storage(format!("Invalid metadata: {}", e)))?,
+                ),
+                None => None,
+            },
+        })
+    }
+}
+
+/// A password history entry as a backend keeps it.
+#[derive(Debug, Clone)]
+pub struct EncryptedHistoryEntry {
+    pub id: String,
+    pub item_id: String,
+    pub password: Vec<u8>,
+    pub changed_at: Vec<u8>,
+}
 
+impl EncryptedHistoryEntry {
+    pub fn encrypt(entry: &PasswordHistoryEntry, crypto: &Crypto) -> Result<Self> {
         Ok(Self {
-            vault_path,
-            conn: Mutex::new(conn),
+            id: entry.id.clone(),
+            item_id: entry.item_id.clone(),
+            password: crypto.encrypt(entry.password.as_bytes())?,
+            changed_at: crypto.encrypt(entry.changed_at.to_rfc3339().as_bytes())?,
         })
     }
 
-    fn row_to_vault_item(row: &Row, crypto: &Crypto) -> RusqliteResult<VaultItem> {
-        let encrypted_name: Vec<u8> = row.get(2)?;
-        let name = String::from_utf8(crypto.decrypt(&encrypted_name).map_err(|e| rusqlite::Error::FromSqlConversionFailure(2, rusqlite::types::Type::Blob, e.into()))?)
-            .map_err(|e: FromUtf8Error| rusqlite::Error::FromSqlConversionFailure(2, rusqlite::types::Type::Blob, Box::new(e)))?;
+    pub fn decrypt(&self, crypto: &Crypto) -> Result<PasswordHistoryEntry> {
+        Ok(PasswordHistoryEntry {
+            id: self.id.clone(),
+            item_id: self.item_id.clone(),
+            password: decrypt_text(crypto, &self.password, "password")?,
+            changed_at: decrypt_timestamp(crypto, &self.changed_at, "changed_at")?,
+        })
+    }
+}
 
-        let encrypted_item_type: Vec<u8> = row.get(3)?;
-        let item_type = String::from_utf8(crypto.decrypt(&encrypted_item_type).map_err(|e| rusqlite::Error::FromSqlConversionFailure(3, rusqlite::types::Type::Blob, e.into()))?)
-            .map_err(|e: FromUtf8Error| rusqlite::Error::FromSqlConversionFailure(3, rusqlite::types::Type::Blob, Box::new(e)))?;
-        
-        let encrypted_data_path: Vec<u8> = row.get(4)?;
-        let data_path = String::from_utf8(crypto.decrypt(&encrypted_data_path).map_err(|e| rusqlite::Error::FromSqlConversionFailure(4, rusqlite::types::Type::Blob, e.into()))?)
-            .map_err(|e: FromUtf8Error| rusqlite::Error::FromSqlConversionFailure(4, rusqlite::types::Type::Blob, Box::new(e)))?;
-
-        let encrypted_folder_type: Option<Vec<u8>> = row.get(5)?;
-        let folder_type = match encrypted_folder_type {
-            Some(encrypted) => Some(String::from_utf8(crypto.decrypt(&encrypted).map_err(|e| rusqlite::Error::FromSqlConversionFailure(5, rusqlite::types::Type::Blob, e.into()))?)
-                .map_err(|e: FromUtf8Error| rusqlite::Error::FromSqlConversionFailure(5, rusqlite::types::Type::Blob, Box::new(e)))?),
-            None => None,
-        };
+fn decrypt_text(crypto: &Crypto, encrypted: &[u8], field: &str) -> Result<String> {
+    String::from_utf8(crypto.decrypt(encrypted)?).map_err(|e| Error::Decryption(format!("{} is not valid UTF-8: {}", field, e)))
+}
 
-        let encrypted_tags: Vec<u8> = row.get(6)?;
-        let tags_json = String::from_utf8(crypto.decrypt(&encrypted_tags).map_err(|e| rusqlite::Error::FromSqlConversionFailure(6, rusqlite::types::Type::Blob, e.into()))?)
-            .map_err(|e: FromUtf8Error| rusqlite::Error::FromSqlConversionFailure(6, rusqlite::types::Type::Blob, Box::new(e)))?;
-        let tags: Vec<String> = serde_json::from_str(&tags_json).unwrap_or_else(|_| vec![]);
+fn decrypt_timestamp(crypto: &Crypto, encrypted: &[u8], field: &str) -> Result<DateTime<Utc>> {
+    decrypt_text(crypto, encrypted, field)?.parse().map_err(|e| Error::Storage(format!("Invalid {}: {}", field, e)))
+}
 
-        let encrypted_created_at: Vec<u8> = row.get(7)?;
-        let created_at_str = String::from_utf8(crypto.decrypt(&encrypted_created_at).map_err(|e| rusqlite::Error::FromSqlConversionFailure(7, rusqlite::types::Type::Blob, e.into()))?)
-            .map_err(|e: FromUtf8Error| rusqlite::Error::FromSqlConversionFailure(7, rusqlite::types::Type::Blob, Box::new(e)))?;
-        let created_at = created_at_str.parse().map_err(|e| rusqlite::Error::FromSqlConversionFailure(7, rusqlite::types::Type::Text, Box::new(e)))?;
-        
-        let encrypted_updated_at: Vec<u8> = row.get(8)?;
-        let updated_at_str = String::from_utf8(crypto.decrypt(&encrypted_updated_at).map_err(|e| rusqlite::Error::FromSqlConversionFailure(8, rusqlite::types::Type::Blob, e.into()))?)
-            .map_err(|e: FromUtf8Error| rusqlite::Error::FromSqlConversionFailure(8, rusqlite::types::Type::Blob, Box::new(e)))?;
-        let updated_at = updated_at_str.parse().map_err(|e| rusqlite::Error::FromSqlConversionFailure(8, rusqlite::types::Type::Text, Box::new(e)))?;
+fn meta_mac_input(key: &str, value: &str) -> Vec<u8> {
+    let mut input = Vec::with_capacity(key.len() + value.len() + 1);
+    input.extend_from_slice(key.as_bytes());
+    input.push(0);
+    input.extend_from_slice(value.as_bytes());
+    input
+}
 
-        Ok(VaultItem {
-            id: row.get(0)?,
-            parent_id: row.get(1)?,
-            name,
-            item_type,
-            data_path,
-            folder_type,
-            tags,
-            created_at,
-            updated_at,
-        })
+/// The stored form of the MAC over an authenticated meta value.
+fn meta_mac(crypto: &Crypto, key: &str, value: &str) -> Result<String> {
+    Ok(STANDARD.encode(crypto.mac(&meta_mac_input(key, value))?))
+}
+
+/// Overwrites a file with zeros, ones and then a random byte before deleting
+/// it. A failed pass is logged and the remaining passes still run.
+pub fn shred_file(file_path: &Path) -> io::Result<()> {
+    info!("Shredding file: {}", file_path.display());
+    for pattern_byte in [0x00, 0xFF, thread_rng().gen()] {
+        if let Err(e) = write_shred_pattern(file_path, pattern_byte) {
+            error!("Failed to shred file ({:#04x}) {}: {}", pattern_byte, file_path.display(), e);
+        }
     }
+    fs::remove_file(file_path)
+}
 
-    pub fn add_item(&self, item: &VaultItem, crypto: &Crypto) -> Result<()> {
-        let conn = self.conn.lock().unwrap();
-        let tags_json = serde_json::to_string(&item.tags)?;
+fn write_shred_pattern(file_path: &Path, pattern_byte: u8) -> io::Result<()> {
+    let mut file = fs::OpenOptions::new().write(true).read(true).open(file_path)?;
+    let file_size = file.metadata()?.len();
+    let buffer_size = 4096;
+    let buffer = vec![pattern_byte; buffer_size];
 
-        let encrypted_name = crypto.encrypt(item.name.as_bytes())?;
-        let encrypted_item_type = crypto.encrypt(item.item_type.as_bytes())?;
-        let encrypted_data_path = crypto.encrypt(item.data_path.as_bytes())?;
-        let encrypted_tags = crypto.encrypt(tags_json.as_bytes())?;
-        let encrypted_folder_type = match &item.folder_type {
-            Some(ft) => Some(crypto.encrypt(ft.as_bytes())?),
-            None => None,
-        };
-        let encrypted_created_at = crypto.encrypt(item.created_at.to_rfc3339().as_bytes())?;
-        let encrypted_updated_at = crypto.encrypt(item.updated_at.to_rfc3339().as_bytes())?;
-
-        conn.execute(
-            "INSERT INTO vault_items (id, parent_id, name, item_type, data_path, folder_type, tags, created_at, updated_at) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
-            params![
-                item.id,
-                item.parent_id,
-                encrypted_name,
-                encrypted_item_type,
-                encrypted_data_path,
-                encrypted_folder_type,
-                encrypted_tags,
-                encrypted_created_at,
-                encrypted_updated_at,
-            ],
-        )?;
-        Ok(())
+    file.seek(SeekFrom::Start(0))?;
+
+    let mut bytes_written = 0;
+    while bytes_written < file_size {
+        let to_write = std::cmp::min(buffer_size as u64, file_size - bytes_written) as usize;
+        file.write_all(&buffer[..to_write])?;
+        bytes_written += to_write as u64;
     }
-    
-    pub fn update_item_fields(&self, item: &VaultItem, crypto: &Crypto) -> Result<()> {
-        let conn = self.conn.lock().unwrap();
-        let tags_json = serde_json::to_string(&item.tags)?;
+    file.sync_all()
+}
 
-        let encrypted_name = crypto.encrypt(item.name.as_bytes())?;
-        let encrypted_item_type = crypto.encrypt(item.item_type.as_bytes())?;
-        let encrypted_data_path = crypto.encrypt(item.data_path.as_bytes())?;
-        let encrypted_tags = crypto.encrypt(tags_json.as_bytes())?;
-        let encrypted_folder_type = match &item.folder_type {
-            Some(ft) => Some(crypto.encrypt(ft.as_bytes())?),
-            None => None,
-        };
-        let encrypted_created_at = crypto.encrypt(item.created_at.to_rfc3339().as_bytes())?;
-        let encrypted_updated_at = crypto.encrypt(item.updated_at.to_rfc3339().as_bytes())?;
-        
-        conn.execute(
-            "UPDATE vault_items SET name = ?2, item_type = ?3, data_path = ?4, folder_type = ?5, tags = ?6, created_at = ?7, updated_at = ?8 WHERE id = ?1",
-            params![
-                item.id,
-                encrypted_name,
-                encrypted_item_type,
-                encrypted_data_path,
-                encrypted_folder_type,
-                encrypted_tags,
-                encrypted_created_at,
-                encrypted_updated_at,
-            ],
-        )?;
+/// Where a vault keeps its items, password history, content files and
+/// settings. Items are encrypted before they reach the backend, so a backend
+/// only ever holds ciphertext and the plaintext tree links.
+///
+/// `SqliteStorage` is the vault on disk; `MemoryStorage` keeps everything in
+/// memory. Settings, MACs and tag edits are built on the required methods and
+/// behave the same on both.
+pub trait Storage: Send {
+    /// The schema version of the stored vault, `SCHEMA_VERSION` once fully migrated.
+    fn schema_version(&self) -> Result<u32>;
 
-        Ok(())
-    }
+    /// Applies pending schema migrations. Without `crypto` the ones that need
+    /// the vault key are left for later.
+    fn migrate(&self, crypto: Option<&Crypto>) -> Result<()>;
+
+    /// The directory the vault lives in, for backends that keep one. Archive
+    /// backups, restores and merges need it.
+    fn get_vault_path(&self) -> Option<&Path>;
+
+    fn is_initialized(&self) -> bool;
+
+    fn get_salt(&self) -> Result<Vec<u8>>;
+
+    fn update_salt(&self, new_salt: &[u8]) -> Result<()>;
+
+    fn get_verification_token(&self) -> Result<Vec<u8>>;
+
+    fn store_verification_token(&self, token: &[u8]) -> Result<()>;
+
+    /// Raw access to the vault's settings. Prefer the typed accessors, which
+    /// know each value's format.
+    fn get_meta_value(&self, key: &str) -> Result<Option<String>>;
+
+    fn set_meta_value(&self, key: &str, value: &str) -> Result<()>;
+
+    fn delete_meta_value(&self, key: &str) -> Result<()>;
+
+    fn add_item(&self, item: &VaultItem, crypto: &Crypto) -> Result<()>;
 
-    pub fn get_items(
+    /// Like `add_items_atomically`, but first deletes the items in `replaced_ids`
+    /// and everything under them in the same transaction. Their data files are
+    /// shredded only after the transaction commits.
+    fn replace_items_atomically(
+        &self,
+        replaced_ids: &[String],
+        items: &[(VaultItem, Option<Vec<u8>>)],
+        password_history: &[PasswordHistoryEntry],
+        crypto: &Crypto,
+    ) -> Result<()>;
+
+    fn update_item_fields(&self, item: &VaultItem, crypto: &Crypto) -> Result<()>;
+
+    /// Updates the fields of several items in one transaction.
+    fn update_items_atomically(&self, items: &[VaultItem], crypto: &Crypto) -> Result<()>;
+
+    fn get_items(
         &self,
         parent_id: Option<String>,
         item_type_filter: Option<String>,
         order_by: Option<SortOrder>,
         crypto: &Crypto,
-    ) -> Result<Vec<VaultItem>> {
-        let conn = self.conn.lock().unwrap();
-        let sort_clause = order_by.unwrap_or_default().to_sql();
-    
-        let all_items_result: RusqliteResult<Vec<VaultItem>> = if let Some(pid) = parent_id {
-            let sql = format!("SELECT * FROM vault_items WHERE parent_id = ?1 {}", sort_clause);
-            let mut stmt = conn.prepare(&sql)?;
-            let item_iter = stmt.query_map(params![pid], |row| Self::row_to_vault_item(row, crypto))?;
-            item_iter.collect()
-        } else {
-            let sql = format!("SELECT * FROM vault_items WHERE parent_id IS NULL {}", sort_clause);
-            let mut stmt = conn.prepare(&sql)?;
-            let item_iter = stmt.query_map(params![], |row| Self::row_to_vault_item(row, crypto))?;
-            item_iter.collect()
-        };
-        
-        let all_items = all_items_result?;
-    
-        if let Some(filter) = item_type_filter {
-            let filtered_items = all_items
-                .into_iter()
-                .filter(|item| {
-                    if item.item_type == "folder" {
-                        item.folder_type.as_deref() == Some(&filter)
-                    } else {
-                        item.item_type.starts_with(&filter)
-                    }
-                })
-                .collect();
-            Ok(filtered_items)
-        } else {
-            Ok(all_items)
-        }
-    }
-    
-    pub fn get_all_items_recursive(&self, crypto: &Crypto) -> Result<Vec<VaultItem>> {
-        let conn = self.conn.lock().unwrap();
-        let mut stmt = conn.prepare("SELECT * FROM vault_items")?;
-        let item_iter = stmt.query_map([], |row| Self::row_to_vault_item(row, crypto))?;
+    ) -> Result<Vec<VaultItem>>;
 
-        let mut items = Vec::new();
-        for item in item_iter {
-            items.push(item?);
-        }
-        Ok(items)
-    }
+    fn get_all_items_recursive(&self, crypto: &Crypto) -> Result<Vec<VaultItem>>;
 
-    pub fn get_item(&self, id: &str, crypto: &Crypto) -> Result<Option<VaultItem>> {
-        let conn = self.conn.lock().unwrap();
-        let mut stmt = conn.prepare("SELECT * FROM vault_items WHERE id = ?1")?;
-        let mut rows = stmt.query_map(params![id], |row| Self::row_to_vault_item(row, crypto))?;
-        rows.next().transpose().map_err(Error::from)
-    }
+    /// Like `get_all_items_recursive`, but items that fail to decrypt are set
+    /// aside instead of failing the whole read.
+    fn scan_items(&self, crypto: &Crypto) -> Result<(Vec<VaultItem>, Vec<UnreadableItem>)>;
 
-    fn write_shred_pattern(file_path: &Path, pattern_byte: u8) -> std::io::Result<()> {
-        info!("Shredding file: {}", file_path.display());
-        let mut file = fs::OpenOptions::new().write(true).read(true).open(file_path)?;
-        let file_size = file.metadata()?.len();
-        let buffer_size = 4096; 
-        let buffer = vec![pattern_byte; buffer_size];
+    fn get_item(&self, id: &str, crypto: &Crypto) -> Result<Option<VaultItem>>;
 
-        file.seek(SeekFrom::Start(0))?; 
+    /// Moves items out of the vault into quarantine, still encrypted, together
+    /// with the reason they were set aside. Their data files are left alone.
+    fn quarantine_items(&self, items: &[(String, String)]) -> Result<()>;
 
-        let mut bytes_written = 0;
-        while bytes_written < file_size {
-            let to_write = std::cmp::min(buffer_size as u64, file_size - bytes_written) as usize;
-            file.write_all(&buffer[..to_write])?;
-            bytes_written += to_write as u64;
-        }
-        file.flush()?;
-        Ok(())
-    }
+    /// Sets an item's parent without decrypting it, so items that only have a
+    /// broken link can be fixed.
+    fn set_item_parent(&self, id: &str, parent_id: Option<&str>) -> Result<()>;
 
-    pub fn delete_item_and_descendants(&self, id: &str, crypto: &Crypto) -> Result<()> {
-        let mut conn = self.conn.lock().unwrap();
-        let tx = conn.transaction()?;
-    
-        let mut ids_to_delete = Vec::new();
-        let mut queue = vec![id.to_string()];
-    
-        {
-            let mut get_children_stmt = tx.prepare("SELECT id FROM vault_items WHERE parent_id = ?1")?;
-            while let Some(current_id) = queue.pop() {
-                let children_ids: Vec<String> = get_children_stmt
-                    .query_map(params![&current_id], |row| row.get(0))?
-                    .collect::<RusqliteResult<_>>()?;
-    
-                queue.extend(children_ids);
-                ids_to_delete.push(current_id);
-            }
-        }
-    
-        if ids_to_delete.is_empty() {
-            tx.commit()?;
-            return Ok(());
-        }
-    
-        let data_paths: Vec<String> = {
-            let placeholders = ids_to_delete.iter().map(|_| "?").collect::<Vec<_>>().join(",");
-            let sql = format!("SELECT * FROM vault_items WHERE id IN ({})", placeholders);
-            let params_from_ids = rusqlite::params_from_iter(ids_to_delete.iter());
-    
-            let mut stmt = tx.prepare(&sql)?;
-            let item_iter = stmt.query_map(params_from_ids, |row| Self::row_to_vault_item(row, crypto))?;
-            
-            item_iter
-                .filter_map(|item_result| item_result.ok())
-                .map(|item| item.data_path)
-                .filter(|path| !path.is_empty())
-                .collect()
-        };
-    
-        {
-            let placeholders = ids_to_delete.iter().map(|_| "?").collect::<Vec<_>>().join(",");
-            let sql = format!("DELETE FROM vault_items WHERE id IN ({})", placeholders);
-            let params_from_ids = rusqlite::params_from_iter(ids_to_delete.iter());
-            tx.execute(&sql, params_from_ids)?;
-        }
-    
-        tx.commit()?;
-    
-        let data_dir = self.vault_path.join("data");
-        for path in data_paths {
-            if path.is_empty() { continue; }
-            let file_path = data_dir.join(path);
-            if file_path.exists() {
-                if let Err(e) = Self::write_shred_pattern(&file_path, 0x00) { 
-                    error!("Failed to shred file (zeros) {}: {}", file_path.display(), e);
-                }
-                if let Err(e) = Self::write_shred_pattern(&file_path, 0xFF) { 
-                    error!("Failed to shred file (ones) {}: {}", file_path.display(), e);
-                }
-                let random_byte: u8 = thread_rng().gen(); 
-                if let Err(e) = Self::write_shred_pattern(&file_path, random_byte) { 
-                    error!("Failed to shred file (random) {}: {}", file_path.display(), e);
-                }
+    fn delete_item_and_descendants(&self, id: &str, crypto: &Crypto) -> Result<()>;
 
-                if let Err(e) = fs::remove_file(&file_path) {
-                    error!("Failed to delete data file {}: {}", file_path.display(), e);
-                }
-            }
-        }
-    
-        Ok(())
-    }
+    fn add_password_history_entry(&self, entry: &PasswordHistoryEntry, crypto: &Crypto) -> Result<()>;
 
-    pub fn is_initialized(&self) -> bool {
-        self.vault_path.join("salt").exists() && self.vault_path.join("verify").exists()
-    }
+    /// Returns the previous passwords of an item, newest first.
+    fn get_password_history(&self, item_id: &str, crypto: &Crypto) -> Result<Vec<PasswordHistoryEntry>>;
 
-    pub fn get_salt(&self) -> Result<Vec<u8>> {
-        fs::read(self.vault_path.join("salt")).map_err(Error::from)
-    }
+    fn reencrypt_password_history(&self, old_crypto: &Crypto, new_crypto: &Crypto) -> Result<()>;
 
-    pub fn get_verification_token(&self) -> Result<Vec<u8>> {
-        fs::read(self.vault_path.join("verify")).map_err(Error::from)
-    }
+    /// The id and SHA-256 of every stored item, over the ciphertext. Nothing
+    /// is decrypted.
+    fn item_row_digests(&self) -> Result<Vec<(String, [u8; 32])>>;
 
-    pub fn store_verification_token(&self, token: &[u8]) -> Result<()> {
-        fs::write(self.vault_path.join("verify"), token).map_err(Error::from)
-    }
+    /// Like `item_row_digests`, for password history.
+    fn password_history_row_digests(&self) -> Result<Vec<(String, [u8; 32])>>;
 
-    pub fn update_salt(&self, new_salt: &[u8]) -> Result<()> {
-        fs::write(self.vault_path.join("salt"), new_salt).map_err(Error::from)
-    }
+    fn write_encrypted_file(&self, data: &[u8], file_name: &str) -> Result<()>;
+
+    /// A content file as stored, still encrypted.
+    fn read_data_file(&self, file_name: &str) -> Result<Vec<u8>>;
+
+    /// The names of the stored content files.
+    fn data_file_names(&self) -> Result<Vec<String>>;
+
+    /// The stamp of a content file, or `None` where the backend keeps none.
+    fn data_file_stamp(&self, file_name: &str) -> Result<Option<DataFileStamp>>;
+
+    /// Overwrites and deletes content files. Files that are missing are
+    /// skipped, and failures are only logged.
+    fn shred_data_files(&self, file_names: &[String]);
+
+    /// Sets a content file aside rather than deleting it.
+    fn quarantine_data_file(&self, file_name: &str) -> Result<()>;
+
+    /// The encrypted anchor of `tamper::seal_vault_state`. It is kept apart
+    /// from the rest of the vault, so that putting back an older vault does not
+    /// put back its anchor too.
+    fn read_anchor(&self) -> Result<Option<Vec<u8>>>;
 
-    fn get_meta_value(&self, key: &str) -> Result<Option<String>> {
-        let conn = self.conn.lock().unwrap();
-        let mut stmt = conn.prepare("SELECT value FROM vault_meta WHERE key = ?1")?;
-        let value: RusqliteResult<String> = stmt.query_row(params![key], |row| row.get(0));
-        Ok(value.ok())
+    fn write_anchor(&self, anchor: &[u8]) -> Result<()>;
+
+    /// Inserts items, their password history and their content in one transaction.
+    /// If anything fails, no items are kept and every data file written so far is removed.
+    fn add_items_atomically(&self, items: &[(VaultItem, Option<Vec<u8>>)], password_history: &[PasswordHistoryEntry], crypto: &Crypto) -> Result<()> {
+        self.replace_items_atomically(&[], items, password_history, crypto)
     }
 
-    fn set_meta_value(&self, key: &str, value: &str) -> Result<()> {
-        let conn = self.conn.lock().unwrap();
-        conn.execute(
-            "INSERT OR REPLACE INTO vault_meta (key, value) VALUES (?1, ?2)",
-            params![key, value],
-        )?;
-        Ok(())
+    fn read_encrypted_file(&self, file_name: &str, crypto: &Crypto) -> Result<Vec<u8>> {
+        crypto.decrypt(&self.read_data_file(file_name)?)
     }
 
-    pub fn initialize(&self, salt: &[u8], strength: KeyDerivationStrength) -> Result<()> {
-        fs::write(self.vault_path.join("salt"), salt)?;
+    fn initialize(&self, salt: &[u8], strength: KeyDerivationStrength) -> Result<()> {
+        self.update_salt(salt)?;
         self.set_key_derivation_strength(strength)?;
         self.set_brute_force_config(BruteForceConfig::default())?;
         self.set_failed_login_attempts(0)?;
@@ -432,7 +408,68 @@ impl Storage {
         Ok(())
     }
 
-    pub fn get_key_derivation_strength(&self) -> Result<KeyDerivationStrength> {
+    /// Stores a MAC for each of `AUTHENTICATED_META_KEYS` as they are now, and
+    /// marks the verification token to record that the vault keeps them. Call
+    /// after changing any of them while the vault is unlocked.
+    fn seal_meta(&self, crypto: &Crypto) -> Result<()> {
+        for key in AUTHENTICATED_META_KEYS {
+            let mac_key = format!("{}{}", META_MAC_PREFIX, key);
+            match self.get_meta_value(key)? {
+                Some(value) => self.set_meta_value(&mac_key, &meta_mac(crypto, key, &value)?)?,
+                None => self.delete_meta_value(&mac_key)?,
+            }
+        }
+
+        let token = Zeroizing::new(crypto.decrypt(&self.get_verification_token()?)?);
+        if !token.ends_with(META_MAC_TOKEN_MARKER) {
+            let mut marked = Zeroizing::new(token.to_vec());
+            marked.extend_from_slice(META_MAC_TOKEN_MARKER);
+            self.store_verification_token(&crypto.encrypt(&marked)?)?;
+            info!("Marked the vault as keeping MACs for its settings.");
+        }
+        Ok(())
+    }
+
+    /// The authenticated meta keys whose value does not match its MAC, or that
+    /// were added or removed without one. Empty for vaults that have never kept
+    /// MACs, which have none at all and an unmarked verification token.
+    fn tampered_meta_keys(&self, crypto: &Crypto) -> Result<Vec<String>> {
+        let mut macs = Vec::with_capacity(AUTHENTICATED_META_KEYS.len());
+        for key in AUTHENTICATED_META_KEYS {
+            macs.push((key, self.get_meta_value(key)?, self.get_meta_value(&format!("{}{}", META_MAC_PREFIX, key))?));
+        }
+        if macs.iter().all(|(_, _, mac)| mac.is_none()) {
+            let token = Zeroizing::new(crypto.decrypt(&self.get_verification_token()?)?);
+            if !token.ends_with(META_MAC_TOKEN_MARKER) {
+                return Ok(Vec::new());
+            }
+        }
+
+        let mut tampered = Vec::new();
+        for (key, value, mac) in macs {
+            let authentic = match (value, mac) {
+                (Some(value), Some(mac)) => match STANDARD.decode(mac) {
+                    Ok(mac) => crypto.verify_mac(&meta_mac_input(key, &value), &mac)?,
+                    Err(_) => false,
+                },
+                (None, None) => true,
+                _ => false,
+            };
+            if !authentic {
+                tampered.push(key.to_string());
+            }
+        }
+        Ok(tampered)
+    }
+
+    /// The strength recorded in the salt, or for older vaults the `kdf_strength`
+    /// label, which is only authenticated once the vault is unlocked.
+    fn get_key_derivation_strength(&self) -> Result<KeyDerivationStrength> {
+        if self.is_initialized() {
+            if let Some(strength) = KeyDerivationStrength::from_salt(&self.get_salt()?) {
+                return Ok(strength);
+            }
+        }
         let strength_str = self.get_meta_value("kdf_strength")?;
         
         Ok(match strength_str.as_deref() {
@@ -442,7 +479,7 @@ impl Storage {
         })
     }
 
-    pub fn set_key_derivation_strength(&self, strength: KeyDerivationStrength) -> Result<()> {
+    fn set_key_derivation_strength(&self, strength: KeyDerivationStrength) -> Result<()> {
         let strength_str = match strength {
             KeyDerivationStrength::Fast => "Fast",
             KeyDerivationStrength::Recommended => "Recommended",
@@ -452,7 +489,7 @@ impl Storage {
         Ok(())
     }
 
-    pub fn get_brute_force_config(&self) -> Result<BruteForceConfig> {
+    fn get_brute_force_config(&self) -> Result<BruteForceConfig> {
         let config_json = self.get_meta_value("brute_force_config")?;
         if let Some(json) = config_json {
             serde_json::from_str(&json).map_err(|e| Error::Storage(format!("Failed to parse brute force config: {}", e)))
@@ -461,23 +498,47 @@ impl Storage {
         }
     }
 
-    pub fn set_brute_force_config(&self, config: BruteForceConfig) -> Result<()> {
+    fn set_brute_force_config(&self, config: BruteForceConfig) -> Result<()> {
         let config_json = serde_json::to_string(&config)?;
         self.set_meta_value("brute_force_config", &config_json)?;
         Ok(())
     }
 
-    pub fn get_failed_login_attempts(&self) -> Result<u32> {
+    fn get_failed_login_attempts(&self) -> Result<u32> {
         let attempts_str = self.get_meta_value("failed_login_attempts")?;
         attempts_str.unwrap_or_else(|| "0".to_string()).parse().map_err(|e| Error::Storage(format!("Failed to parse failed login attempts: {}", e)))
     }
 
-    pub fn set_failed_login_attempts(&self, attempts: u32) -> Result<()> {
+    fn set_failed_login_attempts(&self, attempts: u32) -> Result<()> {
         self.set_meta_value("failed_login_attempts", &attempts.to_string())?;
         Ok(())
     }
 
-    pub fn get_last_failed_attempt_timestamp(&self) -> Result<Option<DateTime<Utc>>> {
+    /// Every failed unlock attempt since the vault was created. Unlike
+    /// `failed_login_attempts` it is never reset, so it cannot quietly go down.
+    fn get_failed_attempts_total(&self) -> Result<u64> {
+        let total_str = self.get_meta_value("failed_attempts_total")?;
+        total_str.unwrap_or_else(|| "0".to_string()).parse().map_err(|e| Error::Storage(format!("Failed to parse failed attempts total: {}", e)))
+    }
+
+    fn set_failed_attempts_total(&self, total: u64) -> Result<()> {
+        self.set_meta_value("failed_attempts_total", &total.to_string())?;
+        Ok(())
+    }
+
+    /// `failed_attempts_total` as of the last successful unlock. Authenticated,
+    /// so a total below it shows the counters were rolled back.
+    fn get_failed_attempts_total_at_unlock(&self) -> Result<u64> {
+        let total_str = self.get_meta_value("failed_attempts_total_at_unlock")?;
+        total_str.unwrap_or_else(|| "0".to_string()).parse().map_err(|e| Error::Storage(format!("Failed to parse failed attempts total at unlock: {}", e)))
+    }
+
+    fn set_failed_attempts_total_at_unlock(&self, total: u64) -> Result<()> {
+        self.set_meta_value("failed_attempts_total_at_unlock", &total.to_string())?;
+        Ok(())
+    }
+
+    fn get_last_failed_attempt_timestamp(&self) -> Result<Option<DateTime<Utc>>> {
         let timestamp_str = self.get_meta_value("last_failed_attempt_timestamp")?;
         if let Some(ts_str) = timestamp_str {
             if ts_str.is_empty() {
@@ -490,115 +551,154 @@ impl Storage {
         }
     }
 
-    pub fn set_last_failed_attempt_timestamp(&self, timestamp: Option<DateTime<Utc>>) -> Result<()> {
+    fn set_last_failed_attempt_timestamp(&self, timestamp: Option<DateTime<Utc>>) -> Result<()> {
         let ts_str = timestamp.map(|ts| ts.to_rfc3339()).unwrap_or_default();
         self.set_meta_value("last_failed_attempt_timestamp", &ts_str)?;
         Ok(())
     }
 
-    pub fn write_encrypted_file(&self, data: &[u8], file_name: &str) -> Result<()> {
-        let file_path = self.vault_path.join("data").join(file_name);
-        trace!("Writing encrypted file to: {}", file_path.display());
-        fs::write(file_path, data).map_err(Error::from)
+    /// The sealed digest of the vault's contents, as written by `tamper::seal_vault_state`.
+    fn get_vault_state_seal(&self) -> Result<Option<String>> {
+        self.get_meta_value("vault_state_seal")
     }
 
-    pub fn read_encrypted_file(&self, file_name: &str, crypto: &Crypto) -> Result<Vec<u8>> {
-        let file_path = self.vault_path.join("data").join(file_name);
-        trace!("Attempting to read encrypted file from: {}", file_path.display());
-        let encrypted_data = fs::read(&file_path)?;
-        debug!("Read {} bytes from encrypted file: {}", encrypted_data.len(), file_path.display());
-        crypto.decrypt(&encrypted_data)
+    fn set_vault_state_seal(&self, seal: &str) -> Result<()> {
+        self.set_meta_value("vault_state_seal", seal)?;
+        Ok(())
     }
 
-    pub fn get_vault_path(&self) -> &PathBuf {
-        &self.vault_path
+    fn get_pwned_passwords_path(&self) -> Result<Option<PathBuf>> {
+        Ok(self.get_meta_value("pwned_passwords_path")?
+            .filter(|path| !path.is_empty())
+            .map(PathBuf::from))
     }
 
-    pub fn rename_tag_in_all_items(&self, old_tag: &str, new_tag: &str, crypto: &Crypto) -> Result<()> {
+    fn set_pwned_passwords_path(&self, path: &Path) -> Result<()> {
+        self.set_meta_value("pwned_passwords_path", &path.to_string_lossy())?;
+        Ok(())
+    }
+
+    fn get_backup_schedule(&self) -> Result<BackupSchedule> {
+        let schedule_json = self.get_meta_value("backup_schedule")?;
+        if let Some(json) = schedule_json {
+            serde_json::from_str(&json).map_err(|e| Error::Storage(format!("Failed to parse backup schedule: {}", e)))
+        } else {
+            Ok(BackupSchedule::default())
+        }
+    }
+
+    fn set_backup_schedule(&self, schedule: &BackupSchedule) -> Result<()> {
+        let schedule_json = serde_json::to_string(schedule)?;
+        self.set_meta_value("backup_schedule", &schedule_json)?;
+        Ok(())
+    }
+
+    fn get_writes_since_backup(&self) -> Result<u32> {
+        let writes_str = self.get_meta_value("writes_since_backup")?;
+        writes_str.unwrap_or_else(|| "0".to_string()).parse().map_err(|e| Error::Storage(format!("Failed to parse writes since backup: {}", e)))
+    }
+
+    fn set_writes_since_backup(&self, writes: u32) -> Result<()> {
+        self.set_meta_value("writes_since_backup", &writes.to_string())?;
+        Ok(())
+    }
+
+
+    fn rename_tag_in_all_items(&self, old_tag: &str, new_tag: &str, crypto: &Crypto) -> Result<()> {
         info!("Attempting to rename tag: '{}' to '{}'", old_tag, new_tag);
         let mut items = self.get_all_items_recursive(crypto)?;
         info!("Found {} items. Processing tags...", items.len());
-        let mut conn = self.conn.lock().unwrap();
-        let tx = conn.transaction()?;
 
-        let mut changes_made = 0;
+        let mut changed = Vec::new();
         for item in &mut items {
             let mut updated = false;
             let mut new_tags: Vec<String> = Vec::new();
             for tag in &item.tags {
-                if tag == old_tag {
-                    if !new_tags.contains(&new_tag.to_string()) {
-                        new_tags.push(new_tag.to_string());
-                        updated = true;
-                    }
+                let renamed = if tag == old_tag {
+                    updated = true;
+                    new_tag
                 } else {
-                    new_tags.push(tag.clone());
+                    tag.as_str()
+                };
+                // An item that already has the new tag keeps it once.
+                if !new_tags.iter().any(|kept| kept == renamed) {
+                    new_tags.push(renamed.to_string());
                 }
             }
             if updated {
                 item.tags = new_tags;
                 item.updated_at = Utc::now();
-                self.update_item_fields_in_transaction(item, crypto, &tx)?;
-                changes_made += 1;
                 info!("Updated tags for item ID: {}", item.id);
+                changed.push(item.clone());
             }
         }
-        tx.commit()?;
-        info!("Transaction committed for rename_tag. Total items with tags renamed: {}", changes_made);
+        self.update_items_atomically(&changed, crypto)?;
+        info!("Transaction committed for rename_tag. Total items with tags renamed: {}", changed.len());
         Ok(())
     }
 
-    pub fn remove_tag_from_all_items(&self, tag_to_remove: &str, crypto: &Crypto) -> Result<()> {
+    fn remove_tag_from_all_items(&self, tag_to_remove: &str, crypto: &Crypto) -> Result<()> {
         info!("Attempting to delete tag: '{}'", tag_to_remove);
         let mut items = self.get_all_items_recursive(crypto)?;
         info!("Found {} items. Processing tags...", items.len());
-        let mut conn = self.conn.lock().unwrap();
-        let tx = conn.transaction()?;
 
-        let mut changes_made = 0;
+        let mut changed = Vec::new();
         for item in &mut items {
             let original_tag_count = item.tags.len();
             item.tags.retain(|tag| tag != tag_to_remove);
             if item.tags.len() != original_tag_count {
                 item.updated_at = Utc::now();
-                self.update_item_fields_in_transaction(item, crypto, &tx)?;
-                changes_made += 1;
                 info!("Removed tag from item ID: {}", item.id);
+                changed.push(item.clone());
             }
         }
-        tx.commit()?;
-        info!("Transaction committed for delete_tag. Total items with tag removed: {}", changes_made);
+        self.update_items_atomically(&changed, crypto)?;
+        info!("Transaction committed for delete_tag. Total items with tag removed: {}", changed.len());
         Ok(())
     }
+}
 
-    fn update_item_fields_in_transaction(&self, item: &VaultItem, crypto: &Crypto, tx: &rusqlite::Transaction) -> Result<()> {
-        let tags_json = serde_json::to_string(&item.tags)?;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vault;
 
-        let encrypted_name = crypto.encrypt(item.name.as_bytes())?;
-        let encrypted_item_type = crypto.encrypt(item.item_type.as_bytes())?;
-        let encrypted_data_path = crypto.encrypt(item.data_path.as_bytes())?;
-        let encrypted_tags = crypto.encrypt(tags_json.as_bytes())?;
-        let encrypted_folder_type = match &item.folder_type {
-            Some(ft) => Some(crypto.encrypt(ft.as_bytes())?),
-            None => None,
-        };
-        let encrypted_created_at = crypto.encrypt(item.created_at.to_rfc3339().as_bytes())?;
-        let encrypted_updated_at = crypto.encrypt(item.updated_at.to_rfc3339().as_bytes())?;
-        
-        tx.execute(
-            "UPDATE vault_items SET name = ?2, item_type = ?3, data_path = ?4, folder_type = ?5, tags = ?6, created_at = ?7, updated_at = ?8 WHERE id = ?1",
-            params![
-                item.id,
-                encrypted_name,
-                encrypted_item_type,
-                encrypted_data_path,
-                encrypted_folder_type,
-                encrypted_tags,
-                encrypted_created_at,
-                encrypted_updated_at,
-            ],
-        )?;
+    fn tagged(storage: &dyn Storage, crypto: &Crypto, name: &str, tags: &[&str]) -> VaultItem {
+        let tags = tags.iter().map(|tag| tag.to_string()).collect();
+        vault::add_text_item(storage, crypto, name.to_string(), "text".to_string(), b"content", tags, None).unwrap()
+    }
 
-        Ok(())
+    fn tags_of(storage: &dyn Storage, crypto: &Crypto, item: &VaultItem) -> Vec<String> {
+        storage.get_item(&item.id, crypto).unwrap().unwrap().tags
+    }
+
+    #[test]
+    fn renames_a_tag_without_duplicating_it() {
+        let (storage, crypto) = MemoryStorage::unlocked();
+        let old_only = tagged(&storage, &crypto, "a", &["old", "keep"]);
+        let both = tagged(&storage, &crypto, "b", &["old", "new"]);
+        let both_reversed = tagged(&storage, &crypto, "d", &["new", "old"]);
+        let neither = tagged(&storage, &crypto, "c", &["keep"]);
+
+        storage.rename_tag_in_all_items("old", "new", &crypto).unwrap();
+
+        assert_eq!(tags_of(&storage, &crypto, &old_only), ["new", "keep"]);
+        assert_eq!(tags_of(&storage, &crypto, &both), ["new"]);
+        assert_eq!(tags_of(&storage, &crypto, &both_reversed), ["new"]);
+        assert_eq!(tags_of(&storage, &crypto, &neither), ["keep"]);
+        assert_eq!(storage.get_item(&neither.id, &crypto).unwrap().unwrap().updated_at, neither.updated_at);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn removes_a_tag_from_every_item() {
+        let (storage, crypto) = MemoryStorage::unlocked();
+        let first = tagged(&storage, &crypto, "a", &["gone", "keep"]);
+        let second = tagged(&storage, &crypto, "b", &["gone"]);
+
+        storage.remove_tag_from_all_items("gone", &crypto).unwrap();
+
+        assert_eq!(tags_of(&storage, &crypto, &first), ["keep"]);
+        assert!(tags_of(&storage, &crypto, &second).is_empty());
+        assert_eq!(vault::all_tags(&storage, &crypto).unwrap(), ["keep"]);
+    }
+}
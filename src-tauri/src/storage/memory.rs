@@ -0,0 +1,418 @@
+//! A vault kept entirely in memory, for tests and tools that should not touch
+//! the disk. Items are encrypted exactly as on disk; only where they are kept
+//! differs.
+
+use super::{DataFileStamp, EncryptedHistoryEntry, EncryptedItem, PasswordHistoryEntry, SortOrder, Storage, UnreadableItem, VaultItem, SCHEMA_VERSION};
+use crate::crypto::Crypto;
+use crate::error::Error;
+use crate::Result;
+use chrono::{DateTime, Utc};
+use log::info;
+use sha2::{Digest, Sha256};
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use std::sync::Mutex;
+
+#[derive(Default)]
+pub struct MemoryStorage {
+    state: Mutex<MemoryState>,
+}
+
+#[derive(Default, Clone)]
+struct MemoryState {
+    salt: Option<Vec<u8>>,
+    verification_token: Option<Vec<u8>>,
+    meta: HashMap<String, String>,
+    items: BTreeMap<String, EncryptedItem>,
+    password_history: BTreeMap<String, EncryptedHistoryEntry>,
+    data_files: BTreeMap<String, Vec<u8>>,
+    /// Quarantined items with the reason and when they were set aside.
+    quarantined_items: BTreeMap<String, (EncryptedItem, String, DateTime<Utc>)>,
+    quarantined_files: BTreeMap<String, Vec<u8>>,
+    anchor: Option<Vec<u8>>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Runs `change` on a copy of the vault and keeps the copy only if it
+    /// succeeds, which gives every write the all-or-nothing behaviour of a
+    /// transaction.
+    fn transaction<T>(&self, change: impl FnOnce(&mut MemoryState) -> Result<T>) -> Result<T> {
+        let mut state = self.state.lock().unwrap();
+        let mut copy = state.clone();
+        let value = change(&mut copy)?;
+        *state = copy;
+        Ok(value)
+    }
+}
+
+impl MemoryState {
+    /// Removes an item, everything under it and their password history, and
+    /// returns the data files that belonged to them.
+    fn delete_item_and_descendants(&mut self, id: &str, crypto: &Crypto) -> Vec<String> {
+        let mut ids_to_delete = Vec::new();
+        let mut queue = vec![id.to_string()];
+        while let Some(current_id) = queue.pop() {
+            queue.extend(self.items.values().filter(|item| item.parent_id.as_deref() == Some(current_id.as_str())).map(|item| item.id.clone()));
+            ids_to_delete.push(current_id);
+        }
+
+        let mut data_paths = Vec::new();
+        for id in &ids_to_delete {
+            if let Some(item) = self.items.remove(id) {
+                if let Ok(item) = item.decrypt(crypto) {
+                    data_paths.push(item.data_path);
+                }
+            }
+        }
+        self.password_history.retain(|_, entry| !ids_to_delete.contains(&entry.item_id));
+        data_paths.retain(|path| !path.is_empty());
+        data_paths
+    }
+}
+
+fn digest_fields(fields: &[Option<&[u8]>]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    for field in fields {
+        match field {
+            None => hasher.update([0]),
+            Some(bytes) => {
+                hasher.update([1]);
+                hasher.update((bytes.len() as u64).to_be_bytes());
+                hasher.update(bytes);
+            }
+        }
+    }
+    hasher.finalize().into()
+}
+
+fn sort_items(items: &mut [VaultItem], order: SortOrder) {
+    match order {
+        SortOrder::CreatedAtDesc => items.sort_by_key(|item| Reverse(item.created_at)),
+        SortOrder::CreatedAtAsc => items.sort_by_key(|item| item.created_at),
+        SortOrder::NameAsc => items.sort_by(|a, b| a.name.cmp(&b.name)),
+        SortOrder::NameDesc => items.sort_by(|a, b| b.name.cmp(&a.name)),
+        SortOrder::UpdatedAtDesc => items.sort_by_key(|item| Reverse(item.updated_at)),
+        SortOrder::UpdatedAtAsc => items.sort_by_key(|item| item.updated_at),
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn schema_version(&self) -> Result<u32> {
+        Ok(SCHEMA_VERSION)
+    }
+
+    /// Nothing to migrate: the memory layout is always the current one.
+    fn migrate(&self, _crypto: Option<&Crypto>) -> Result<()> {
+        Ok(())
+    }
+
+    fn get_vault_path(&self) -> Option<&Path> {
+        None
+    }
+
+    fn is_initialized(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        state.salt.is_some() && state.verification_token.is_some()
+    }
+
+    fn get_salt(&self) -> Result<Vec<u8>> {
+        self.state.lock().unwrap().salt.clone().ok_or_else(|| Error::Storage("The vault has no salt".to_string()))
+    }
+
+    fn update_salt(&self, new_salt: &[u8]) -> Result<()> {
+        self.state.lock().unwrap().salt = Some(new_salt.to_vec());
+        Ok(())
+    }
+
+    fn get_verification_token(&self) -> Result<Vec<u8>> {
+        self.state.lock().unwrap().verification_token.clone().ok_or_else(|| Error::Storage("The vault has no verification token".to_string()))
+    }
+
+    fn store_verification_token(&self, token: &[u8]) -> Result<()> {
+        self.state.lock().unwrap().verification_token = Some(token.to_vec());
+        Ok(())
+    }
+
+    fn get_meta_value(&self, key: &str) -> Result<Option<String>> {
+        Ok(self.state.lock().unwrap().meta.get(key).cloned())
+    }
+
+    fn set_meta_value(&self, key: &str, value: &str) -> Result<()> {
+        self.state.lock().unwrap().meta.insert(key.to_string(), value.to_string());
+        Ok(())
+    }
+
+    fn delete_meta_value(&self, key: &str) -> Result<()> {
+        self.state.lock().unwrap().meta.remove(key);
+        Ok(())
+    }
+
+    fn add_item(&self, item: &VaultItem, crypto: &Crypto) -> Result<()> {
+        self.add_items_atomically(&[(item.clone(), None)], &[], crypto)
+    }
+
+    fn replace_items_atomically(
+        &self,
+        replaced_ids: &[String],
+        items: &[(VaultItem, Option<Vec<u8>>)],
+        password_history: &[PasswordHistoryEntry],
+        crypto: &Crypto,
+    ) -> Result<()> {
+        self.transaction(|state| {
+            let mut replaced_files = Vec::new();
+            for id in replaced_ids {
+                replaced_files.extend(state.delete_item_and_descendants(id, crypto));
+            }
+            for file_name in replaced_files {
+                state.data_files.remove(&file_name);
+            }
+            for (item, content) in items {
+                if state.items.contains_key(&item.id) {
+                    return Err(Error::Storage(format!("Database constraint violation: item {} already exists", item.id)));
+                }
+                if let Some(content) = content {
+                    state.data_files.insert(item.data_path.clone(), crypto.encrypt(content)?);
+                }
+                state.items.insert(item.id.clone(), EncryptedItem::encrypt(item, crypto)?);
+            }
+            for entry in password_history {
+                state.password_history.insert(entry.id.clone(), EncryptedHistoryEntry::encrypt(entry, crypto)?);
+            }
+            info!("Added {} items and replaced {} in one transaction.", items.len(), replaced_ids.len());
+            Ok(())
+        })
+    }
+
+    fn update_item_fields(&self, item: &VaultItem, crypto: &Crypto) -> Result<()> {
+        self.update_items_atomically(std::slice::from_ref(item), crypto)
+    }
+
+    /// Like an SQL `UPDATE`, ids that are not in the vault are skipped, and
+    /// the parent is left as it is.
+    fn update_items_atomically(&self, items: &[VaultItem], crypto: &Crypto) -> Result<()> {
+        self.transaction(|state| {
+            for item in items {
+                if let Some(stored) = state.items.get_mut(&item.id) {
+                    let parent_id = stored.parent_id.take();
+                    *stored = EncryptedItem { parent_id, ..EncryptedItem::encrypt(item, crypto)? };
+                }
+            }
+            Ok(())
+        })
+    }
+
+    /// Sorted by the decrypted fields.
+    fn get_items(
+        &self,
+        parent_id: Option<String>,
+        item_type_filter: Option<String>,
+        order_by: Option<SortOrder>,
+        crypto: &Crypto,
+    ) -> Result<Vec<VaultItem>> {
+        let mut items = self
+            .state
+            .lock()
+            .unwrap()
+            .items
+            .values()
+            .filter(|item| item.parent_id == parent_id)
+            .map(|item| item.decrypt(crypto))
+            .collect::<Result<Vec<_>>>()?;
+        sort_items(&mut items, order_by.unwrap_or_default());
+
+        if let Some(filter) = item_type_filter {
+            items.retain(|item| {
+                if item.item_type == "folder" {
+                    item.folder_type.as_deref() == Some(&filter)
+                } else {
+                    item.item_type.starts_with(&filter)
+                }
+            });
+        }
+        Ok(items)
+    }
+
+    fn get_all_items_recursive(&self, crypto: &Crypto) -> Result<Vec<VaultItem>> {
+        self.state.lock().unwrap().items.values().map(|item| item.decrypt(crypto)).collect()
+    }
+
+    fn scan_items(&self, crypto: &Crypto) -> Result<(Vec<VaultItem>, Vec<UnreadableItem>)> {
+        let mut items = Vec::new();
+        let mut unreadable = Vec::new();
+        for encrypted in self.state.lock().unwrap().items.values() {
+            match encrypted.decrypt(crypto) {
+                Ok(item) => items.push(item),
+                Err(e) => unreadable.push(UnreadableItem { id: encrypted.id.clone(), parent_id: encrypted.parent_id.clone(), error: e.to_string() }),
+            }
+        }
+        Ok((items, unreadable))
+    }
+
+    fn get_item(&self, id: &str, crypto: &Crypto) -> Result<Option<VaultItem>> {
+        self.state.lock().unwrap().items.get(id).map(|item| item.decrypt(crypto)).transpose()
+    }
+
+    fn quarantine_items(&self, items: &[(String, String)]) -> Result<()> {
+        let quarantined_at = Utc::now();
+        self.transaction(|state| {
+            for (id, reason) in items {
+                if let Some(item) = state.items.remove(id) {
+                    state.quarantined_items.insert(id.clone(), (item, reason.clone(), quarantined_at));
+                }
+            }
+            info!("Quarantined {} items.", items.len());
+            Ok(())
+        })
+    }
+
+    fn set_item_parent(&self, id: &str, parent_id: Option<&str>) -> Result<()> {
+        if let Some(item) = self.state.lock().unwrap().items.get_mut(id) {
+            item.parent_id = parent_id.map(str::to_string);
+        }
+        Ok(())
+    }
+
+    fn delete_item_and_descendants(&self, id: &str, crypto: &Crypto) -> Result<()> {
+        self.transaction(|state| {
+            for file_name in state.delete_item_and_descendants(id, crypto) {
+                state.data_files.remove(&file_name);
+            }
+            Ok(())
+        })
+    }
+
+    fn add_password_history_entry(&self, entry: &PasswordHistoryEntry, crypto: &Crypto) -> Result<()> {
+        let encrypted = EncryptedHistoryEntry::encrypt(entry, crypto)?;
+        let mut state = self.state.lock().unwrap();
+        if state.password_history.contains_key(&entry.id) {
+            return Err(Error::Storage(format!("Database constraint violation: password history entry {} already exists", entry.id)));
+        }
+        state.password_history.insert(entry.id.clone(), encrypted);
+        Ok(())
+    }
+
+    fn get_password_history(&self, item_id: &str, crypto: &Crypto) -> Result<Vec<PasswordHistoryEntry>> {
+        let mut entries = self
+            .state
+            .lock()
+            .unwrap()
+            .password_history
+            .values()
+            .filter(|entry| entry.item_id == item_id)
+            .map(|entry| entry.decrypt(crypto))
+            .collect::<Result<Vec<_>>>()?;
+        entries.sort_by_key(|entry| Reverse(entry.changed_at));
+        Ok(entries)
+    }
+
+    fn reencrypt_password_history(&self, old_crypto: &Crypto, new_crypto: &Crypto) -> Result<()> {
+        let count = self.transaction(|state| {
+            for entry in state.password_history.values_mut() {
+                *entry = EncryptedHistoryEntry::encrypt(&entry.decrypt(old_crypto)?, new_crypto)?;
+            }
+            Ok(state.password_history.len())
+        })?;
+        info!("Re-encrypted {} password history entries.", count);
+        Ok(())
+    }
+
+    fn item_row_digests(&self) -> Result<Vec<(String, [u8; 32])>> {
+        Ok(self
+            .state
+            .lock()
+            .unwrap()
+            .items
+            .values()
+            .map(|item| {
+                let digest = digest_fields(&[
+                    Some(item.id.as_bytes()),
+                    item.parent_id.as_deref().map(str::as_bytes),
+                    Some(&item.name),
+                    Some(&item.item_type),
+                    Some(&item.data_path),
+                    item.folder_type.as_deref(),
+                    Some(&item.tags),
+                    Some(&item.created_at),
+                    Some(&item.updated_at),
+                    item.expires_at.as_deref(),
+                    item.metadata.as_deref(),
+                ]);
+                (item.id.clone(), digest)
+            })
+            .collect())
+    }
+
+    fn password_history_row_digests(&self) -> Result<Vec<(String, [u8; 32])>> {
+        Ok(self
+            .state
+            .lock()
+            .unwrap()
+            .password_history
+            .values()
+            .map(|entry| {
+                let digest = digest_fields(&[Some(entry.id.as_bytes()), Some(entry.item_id.as_bytes()), Some(&entry.password), Some(&entry.changed_at)]);
+                (entry.id.clone(), digest)
+            })
+            .collect())
+    }
+
+    fn write_encrypted_file(&self, data: &[u8], file_name: &str) -> Result<()> {
+        self.state.lock().unwrap().data_files.insert(file_name.to_string(), data.to_vec());
+        Ok(())
+    }
+
+    fn read_data_file(&self, file_name: &str) -> Result<Vec<u8>> {
+        self.state.lock().unwrap().data_files.get(file_name).cloned().ok_or_else(|| Error::Io(format!("No data file {}", file_name)))
+    }
+
+    fn data_file_names(&self) -> Result<Vec<String>> {
+        Ok(self.state.lock().unwrap().data_files.keys().cloned().collect())
+    }
+
+    fn data_file_stamp(&self, _file_name: &str) -> Result<Option<DataFileStamp>> {
+        Ok(None)
+    }
+
+    fn shred_data_files(&self, file_names: &[String]) {
+        let mut state = self.state.lock().unwrap();
+        for file_name in file_names {
+            state.data_files.remove(file_name);
+        }
+    }
+
+    fn quarantine_data_file(&self, file_name: &str) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let data = state.data_files.remove(file_name).ok_or_else(|| Error::Io(format!("No data file {}", file_name)))?;
+        state.quarantined_files.insert(file_name.to_string(), data);
+        Ok(())
+    }
+
+    fn read_anchor(&self) -> Result<Option<Vec<u8>>> {
+        Ok(self.state.lock().unwrap().anchor.clone())
+    }
+
+    fn write_anchor(&self, anchor: &[u8]) -> Result<()> {
+        self.state.lock().unwrap().anchor = Some(anchor.to_vec());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+impl MemoryStorage {
+    /// An initialized vault and a crypto unlocked for it, set up with a fixed
+    /// key so tests do not pay for the KDF.
+    pub(crate) fn unlocked() -> (Self, Crypto) {
+        let storage = Self::new();
+        let mut crypto = Crypto::new();
+        crypto.unlock(&[7; 32]).unwrap();
+        storage.initialize(&Crypto::generate_salt(crate::crypto::KeyDerivationStrength::Fast), crate::crypto::KeyDerivationStrength::Fast).unwrap();
+        storage.store_verification_token(&crypto.encrypt(&Crypto::generate_verification_token()).unwrap()).unwrap();
+        storage.seal_meta(&crypto).unwrap();
+        (storage, crypto)
+    }
+}
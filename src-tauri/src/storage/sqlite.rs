@@ -0,0 +1,759 @@
+//! The vault on disk: a SQLite database for items, history and settings, with
+//! each item's content in its own file under `data/`.
+
+use super::{
+    meta_mac, shred_file, DataFileStamp, EncryptedHistoryEntry, EncryptedItem, PasswordHistoryEntry, SortOrder, Storage, UnreadableItem, VaultItem, AUTHENTICATED_META_KEYS,
+    META_MAC_PREFIX, SCHEMA_VERSION,
+};
+use crate::backup;
+use crate::crypto::Crypto;
+use crate::error::Error;
+use crate::Result;
+use chrono::{DateTime, Utc};
+use log::{debug, error, info, trace};
+use rusqlite::types::ValueRef;
+use rusqlite::{params, Connection, Result as RusqliteResult, Row};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+#[cfg(unix)]
+use std::os::unix::fs::PermissionsExt;
+use std::fs::Permissions;
+
+const ANCHOR_EXTENSION: &str = "anchor";
+
+/// One step of the schema's history. Versions are applied in order and each is
+/// recorded in `schema_version` in the same transaction as its changes.
+struct Migration {
+    version: u32,
+    description: &'static str,
+    /// Runs only once the vault is unlocked, with the vault key.
+    needs_key: bool,
+    apply: fn(&Connection, Option<&Crypto>) -> Result<()>,
+}
+
+// Vaults from before versioning start at 0, so every step must also cope with
+// a database that already has its changes.
+const MIGRATIONS: [Migration; 5] = [
+    Migration { version: 1, description: "create the vault tables", needs_key: false, apply: create_vault_tables },
+    Migration { version: 2, description: "add item expiry", needs_key: false, apply: add_expires_at_column },
+    Migration { version: 3, description: "add item metadata", needs_key: false, apply: add_metadata_column },
+    Migration { version: 4, description: "add the item quarantine", needs_key: false, apply: create_quarantine_table },
+    Migration { version: 5, description: "authenticate vault settings", needs_key: true, apply: authenticate_meta },
+];
+
+fn create_vault_tables(conn: &Connection, _crypto: Option<&Crypto>) -> Result<()> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS vault_items (
+            id TEXT PRIMARY KEY,
+            parent_id TEXT,
+            name BLOB NOT NULL,
+            item_type BLOB NOT NULL,
+            data_path BLOB NOT NULL,
+            folder_type BLOB,
+            tags BLOB,
+            created_at BLOB NOT NULL,
+            updated_at BLOB NOT NULL,
+            expires_at BLOB,
+            metadata BLOB
+        )",
+        [],
+    )?;
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS vault_meta (
+            key TEXT PRIMARY KEY,
+            value TEXT NOT NULL
+        )",
+        [],
+    )?;
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS password_history (
+            id TEXT PRIMARY KEY,
+            item_id TEXT NOT NULL,
+            password BLOB NOT NULL,
+            changed_at BLOB NOT NULL
+        )",
+        [],
+    )?;
+    Ok(())
+}
+
+fn add_expires_at_column(conn: &Connection, _crypto: Option<&Crypto>) -> Result<()> {
+    if conn.prepare("SELECT expires_at FROM vault_items LIMIT 0").is_err() {
+        info!("Adding expires_at column to vault_items.");
+        conn.execute("ALTER TABLE vault_items ADD COLUMN expires_at BLOB", [])?;
+    }
+    Ok(())
+}
+
+fn add_metadata_column(conn: &Connection, _crypto: Option<&Crypto>) -> Result<()> {
+    if conn.prepare("SELECT metadata FROM vault_items LIMIT 0").is_err() {
+        info!("Adding metadata column to vault_items.");
+        conn.execute("ALTER TABLE vault_items ADD COLUMN metadata BLOB", [])?;
+    }
+    Ok(())
+}
+
+fn create_quarantine_table(conn: &Connection, _crypto: Option<&Crypto>) -> Result<()> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS quarantined_items (
+            id TEXT PRIMARY KEY,
+            parent_id TEXT,
+            name BLOB NOT NULL,
+            item_type BLOB NOT NULL,
+            data_path BLOB NOT NULL,
+            folder_type BLOB,
+            tags BLOB,
+            created_at BLOB NOT NULL,
+            updated_at BLOB NOT NULL,
+            expires_at BLOB,
+            metadata BLOB,
+            reason TEXT NOT NULL,
+            quarantined_at TEXT NOT NULL
+        )",
+        [],
+    )?;
+    Ok(())
+}
+
+/// Stores the first MACs of a vault's settings. Unlocking then marks the
+/// verification token, which is what makes stripped MACs count as tampering.
+fn authenticate_meta(conn: &Connection, crypto: Option<&Crypto>) -> Result<()> {
+    let crypto = crypto.ok_or(Error::VaultLocked)?;
+    for key in AUTHENTICATED_META_KEYS {
+        let mac_key = format!("{}{}", META_MAC_PREFIX, key);
+        match SqliteStorage::read_meta(conn, key)? {
+            Some(value) => SqliteStorage::write_meta(conn, &mac_key, &meta_mac(crypto, key, &value)?)?,
+            None => SqliteStorage::remove_meta(conn, &mac_key)?,
+        }
+    }
+    Ok(())
+}
+
+pub struct SqliteStorage {
+    vault_path: PathBuf,
+    conn: Mutex<Connection>,
+}
+
+impl SqliteStorage {
+    /// Opens the vault, creating it if needed, and applies the migrations that
+    /// do not need the vault key. The rest run at unlock.
+    pub fn new(vault_path: PathBuf) -> Result<Self> {
+        let storage = Self::open(vault_path)?;
+        storage.apply_migrations(None, true)?;
+        Ok(storage)
+    }
+
+    /// Like `new`, for a copy extracted from an archive. The archive is its
+    /// own backup, so none is taken before migrating.
+    pub(crate) fn new_staged(vault_path: PathBuf) -> Result<Self> {
+        let storage = Self::open(vault_path)?;
+        storage.apply_migrations(None, false)?;
+        Ok(storage)
+    }
+
+    fn open(vault_path: PathBuf) -> Result<Self> {
+        fs::create_dir_all(&vault_path)?;
+
+        let db_path = vault_path.join("vault.db");
+        let conn = Connection::open(&db_path)?;
+
+        #[cfg(unix)]
+        {
+            let perms = Permissions::from_mode(0o600);
+            if let Err(e) = fs::set_permissions(&db_path, perms) {
+                error!("Failed to set permissions for database file {}: {}", db_path.display(), e);      
+            }
+        }
+
+        fs::create_dir_all(vault_path.join("data"))?;
+
+        Ok(Self {
+            vault_path,
+            conn: Mutex::new(conn),
+        })
+    }
+
+    /// `0` for a new database, and for vaults from before the schema was versioned.
+    fn read_schema_version(conn: &Connection) -> Result<u32> {
+        let has_meta: i64 = conn.query_row("SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = 'vault_meta'", [], |row| row.get(0))?;
+        if has_meta == 0 {
+            return Ok(0);
+        }
+        match Self::read_meta(conn, "schema_version")? {
+            Some(value) => value.parse().map_err(|_| Error::Storage(format!("Invalid schema version '{}'", value))),
+            None => Ok(0),
+        }
+    }
+
+    /// Applies the pending migrations in order, stopping at the first one that
+    /// needs the vault key if there is no `crypto`. Each migration runs in its
+    /// own transaction, and the vault is backed up before the first of them.
+    fn apply_migrations(&self, crypto: Option<&Crypto>, take_backup: bool) -> Result<()> {
+        let version = self.schema_version()?;
+        if version > SCHEMA_VERSION {
+            error!("Vault schema version {} is newer than the supported {}.", version, SCHEMA_VERSION);
+            return Err(Error::Storage(format!(
+                "This vault was written by a newer version of Fetch (schema version {}, this version supports up to {}). Update Fetch to open it.",
+                version, SCHEMA_VERSION
+            )));
+        }
+
+        let pending: Vec<&Migration> = MIGRATIONS
+            .iter()
+            .filter(|migration| migration.version > version)
+            .take_while(|migration| !migration.needs_key || crypto.is_some())
+            .collect();
+        if pending.is_empty() {
+            return Ok(());
+        }
+
+        let backup = if take_backup && self.is_initialized() {
+            let backup = backup::backup_before_migration(self)
+                .map_err(|e| Error::Storage(format!("Could not back up the vault before migrating its schema from version {}: {}", version, e)))?;
+            Some(backup.path)
+        } else {
+            None
+        };
+
+        let mut conn = self.conn.lock().unwrap();
+        for migration in pending {
+            let tx = conn.transaction()?;
+            let applied = (migration.apply)(&tx, crypto).and_then(|()| Self::write_meta(&tx, "schema_version", &migration.version.to_string()));
+            if let Err(e) = applied.and_then(|()| tx.commit().map_err(Error::from)) {
+                error!("Schema migration {} failed: {}", migration.version, e);
+                let restore_hint = backup.as_ref().map_or(String::new(), |path| format!(" A backup from before the migration is at {}.", path.display()));
+                return Err(Error::Storage(format!(
+                    "Could not migrate the vault to schema version {} ({}): {}.{}",
+                    migration.version, migration.description, e, restore_hint
+                )));
+            }
+            info!("Migrated vault schema to version {}: {}", migration.version, migration.description);
+        }
+        Ok(())
+    }
+
+    /// The anchor sits next to the vault directory rather than in it, so it
+    /// is not part of archive backups.
+    pub fn anchor_path(vault_path: &Path) -> PathBuf {
+        vault_path.with_extension(ANCHOR_EXTENSION)
+    }
+
+    fn row_to_encrypted_item(row: &Row) -> RusqliteResult<EncryptedItem> {
+        Ok(EncryptedItem {
+            id: row.get(0)?,
+            parent_id: row.get(1)?,
+            name: row.get(2)?,
+            item_type: row.get(3)?,
+            data_path: row.get(4)?,
+            folder_type: row.get(5)?,
+            tags: row.get(6)?,
+            created_at: row.get(7)?,
+            updated_at: row.get(8)?,
+            expires_at: row.get(9)?,
+            metadata: row.get(10)?,
+        })
+    }
+
+    fn row_to_encrypted_history_entry(row: &Row) -> RusqliteResult<EncryptedHistoryEntry> {
+        Ok(EncryptedHistoryEntry {
+            id: row.get(0)?,
+            item_id: row.get(1)?,
+            password: row.get(2)?,
+            changed_at: row.get(3)?,
+        })
+    }
+
+    fn query_items(conn: &Connection, sql: &str, params: impl rusqlite::Params, crypto: &Crypto) -> Result<Vec<VaultItem>> {
+        let mut stmt = conn.prepare(sql)?;
+        let rows = stmt.query_map(params, Self::row_to_encrypted_item)?.collect::<RusqliteResult<Vec<_>>>()?;
+        rows.iter().map(|row| row.decrypt(crypto)).collect()
+    }
+
+    fn insert_item(conn: &Connection, item: &VaultItem, crypto: &Crypto) -> Result<()> {
+        let row = EncryptedItem::encrypt(item, crypto)?;
+        conn.execute(
+            "INSERT INTO vault_items (id, parent_id, name, item_type, data_path, folder_type, tags, created_at, updated_at, expires_at, metadata) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+            params![
+                row.id,
+                row.parent_id,
+                row.name,
+                row.item_type,
+                row.data_path,
+                row.folder_type,
+                row.tags,
+                row.created_at,
+                row.updated_at,
+                row.expires_at,
+                row.metadata,
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn update_item_row(conn: &Connection, item: &VaultItem, crypto: &Crypto) -> Result<()> {
+        let row = EncryptedItem::encrypt(item, crypto)?;
+        conn.execute(
+            "UPDATE vault_items SET name = ?2, item_type = ?3, data_path = ?4, folder_type = ?5, tags = ?6, created_at = ?7, updated_at = ?8, expires_at = ?9, metadata = ?10 WHERE id = ?1",
+            params![
+                row.id,
+                row.name,
+                row.item_type,
+                row.data_path,
+                row.folder_type,
+                row.tags,
+                row.created_at,
+                row.updated_at,
+                row.expires_at,
+                row.metadata,
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn insert_password_history_entry(conn: &Connection, entry: &PasswordHistoryEntry, crypto: &Crypto) -> Result<()> {
+        let row = EncryptedHistoryEntry::encrypt(entry, crypto)?;
+        conn.execute(
+            "INSERT INTO password_history (id, item_id, password, changed_at) VALUES (?1, ?2, ?3, ?4)",
+            params![row.id, row.item_id, row.password, row.changed_at],
+        )?;
+        Ok(())
+    }
+
+    fn read_password_history(conn: &Connection, sql: &str, params: impl rusqlite::Params, crypto: &Crypto) -> Result<Vec<PasswordHistoryEntry>> {
+        let mut stmt = conn.prepare(sql)?;
+        let rows = stmt.query_map(params, Self::row_to_encrypted_history_entry)?.collect::<RusqliteResult<Vec<_>>>()?;
+        rows.iter().map(|row| row.decrypt(crypto)).collect()
+    }
+
+    /// Deletes an item, everything under it and their password history. Returns
+    /// the data files that belonged to them, to be shredded once the transaction commits.
+    fn delete_rows_and_descendants(conn: &Connection, id: &str, crypto: &Crypto) -> Result<Vec<String>> {
+        let mut ids_to_delete = Vec::new();
+        let mut queue = vec![id.to_string()];
+    
+        {
+            let mut get_children_stmt = conn.prepare("SELECT id FROM vault_items WHERE parent_id = ?1")?;
+            while let Some(current_id) = queue.pop() {
+                let children_ids: Vec<String> = get_children_stmt
+                    .query_map(params![&current_id], |row| row.get(0))?
+                    .collect::<RusqliteResult<_>>()?;
+    
+                queue.extend(children_ids);
+                ids_to_delete.push(current_id);
+            }
+        }
+    
+        let data_paths: Vec<String> = {
+            let placeholders = ids_to_delete.iter().map(|_| "?").collect::<Vec<_>>().join(",");
+            let sql = format!("SELECT * FROM vault_items WHERE id IN ({})", placeholders);
+            let params_from_ids = rusqlite::params_from_iter(ids_to_delete.iter());
+    
+            let mut stmt = conn.prepare(&sql)?;
+            let row_iter = stmt.query_map(params_from_ids, Self::row_to_encrypted_item)?;
+            
+            row_iter
+                .filter_map(|row| row.ok())
+                .filter_map(|row| row.decrypt(crypto).ok())
+                .map(|item| item.data_path)
+                .filter(|path| !path.is_empty())
+                .collect()
+        };
+    
+        {
+            let placeholders = ids_to_delete.iter().map(|_| "?").collect::<Vec<_>>().join(",");
+            let sql = format!("DELETE FROM vault_items WHERE id IN ({})", placeholders);
+            let params_from_ids = rusqlite::params_from_iter(ids_to_delete.iter());
+            conn.execute(&sql, params_from_ids)?;
+
+            let sql = format!("DELETE FROM password_history WHERE item_id IN ({})", placeholders);
+            let params_from_ids = rusqlite::params_from_iter(ids_to_delete.iter());
+            conn.execute(&sql, params_from_ids)?;
+        }
+
+        Ok(data_paths)
+    }
+
+    fn read_meta(conn: &Connection, key: &str) -> Result<Option<String>> {
+        let mut stmt = conn.prepare("SELECT value FROM vault_meta WHERE key = ?1")?;
+        let value: RusqliteResult<String> = stmt.query_row(params![key], |row| row.get(0));
+        Ok(value.ok())
+    }
+
+    fn write_meta(conn: &Connection, key: &str, value: &str) -> Result<()> {
+        conn.execute(
+            "INSERT OR REPLACE INTO vault_meta (key, value) VALUES (?1, ?2)",
+            params![key, value],
+        )?;
+        Ok(())
+    }
+
+    fn remove_meta(conn: &Connection, key: &str) -> Result<()> {
+        conn.execute("DELETE FROM vault_meta WHERE key = ?1", params![key])?;
+        Ok(())
+    }
+
+    fn row_digests(&self, sql: &str) -> Result<Vec<(String, [u8; 32])>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(sql)?;
+        let column_count = stmt.column_count();
+        let digests = stmt.query_map([], |row| {
+            let mut hasher = Sha256::new();
+            for index in 0..column_count {
+                // Tag each value with its type and length so adjacent columns cannot run together.
+                match row.get_ref(index)? {
+                    ValueRef::Null => hasher.update([0]),
+                    ValueRef::Integer(value) => {
+                        hasher.update([1]);
+                        hasher.update(value.to_be_bytes());
+                    }
+                    ValueRef::Real(value) => {
+                        hasher.update([2]);
+                        hasher.update(value.to_be_bytes());
+                    }
+                    ValueRef::Text(bytes) => {
+                        hasher.update([3]);
+                        hasher.update((bytes.len() as u64).to_be_bytes());
+                        hasher.update(bytes);
+                    }
+                    ValueRef::Blob(bytes) => {
+                        hasher.update([4]);
+                        hasher.update((bytes.len() as u64).to_be_bytes());
+                        hasher.update(bytes);
+                    }
+                }
+            }
+            Ok((row.get(0)?, hasher.finalize().into()))
+        })?;
+        digests.collect::<RusqliteResult<_>>().map_err(Error::from)
+    }
+}
+
+impl Storage for SqliteStorage {
+    fn schema_version(&self) -> Result<u32> {
+        Self::read_schema_version(&self.conn.lock().unwrap())
+    }
+
+    fn migrate(&self, crypto: Option<&Crypto>) -> Result<()> {
+        self.apply_migrations(crypto, true)
+    }
+
+    fn get_vault_path(&self) -> Option<&Path> {
+        Some(&self.vault_path)
+    }
+
+    fn is_initialized(&self) -> bool {
+        self.vault_path.join("salt").exists() && self.vault_path.join("verify").exists()
+    }
+
+    fn get_salt(&self) -> Result<Vec<u8>> {
+        fs::read(self.vault_path.join("salt")).map_err(Error::from)
+    }
+
+    fn update_salt(&self, new_salt: &[u8]) -> Result<()> {
+        fs::write(self.vault_path.join("salt"), new_salt).map_err(Error::from)
+    }
+
+    fn get_verification_token(&self) -> Result<Vec<u8>> {
+        fs::read(self.vault_path.join("verify")).map_err(Error::from)
+    }
+
+    fn store_verification_token(&self, token: &[u8]) -> Result<()> {
+        fs::write(self.vault_path.join("verify"), token).map_err(Error::from)
+    }
+
+    fn get_meta_value(&self, key: &str) -> Result<Option<String>> {
+        Self::read_meta(&self.conn.lock().unwrap(), key)
+    }
+
+    fn set_meta_value(&self, key: &str, value: &str) -> Result<()> {
+        Self::write_meta(&self.conn.lock().unwrap(), key, value)
+    }
+
+    fn delete_meta_value(&self, key: &str) -> Result<()> {
+        Self::remove_meta(&self.conn.lock().unwrap(), key)
+    }
+
+    fn add_item(&self, item: &VaultItem, crypto: &Crypto) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        Self::insert_item(&conn, item, crypto)
+    }
+
+    fn replace_items_atomically(
+        &self,
+        replaced_ids: &[String],
+        items: &[(VaultItem, Option<Vec<u8>>)],
+        password_history: &[PasswordHistoryEntry],
+        crypto: &Crypto,
+    ) -> Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        let mut written_files = Vec::new();
+        let mut replaced_files = Vec::new();
+
+        let outcome = (|| -> Result<()> {
+            for id in replaced_ids {
+                replaced_files.extend(Self::delete_rows_and_descendants(&tx, id, crypto)?);
+            }
+            for (item, content) in items {
+                if let Some(content) = content {
+                    let encrypted_content = crypto.encrypt(content)?;
+                    self.write_encrypted_file(&encrypted_content, &item.data_path)?;
+                    written_files.push(item.data_path.clone());
+                }
+                Self::insert_item(&tx, item, crypto)?;
+            }
+            for entry in password_history {
+                Self::insert_password_history_entry(&tx, entry, crypto)?;
+            }
+            Ok(())
+        })();
+
+        match outcome.and_then(|_| tx.commit().map_err(Error::from)) {
+            Ok(()) => {
+                info!("Added {} items and replaced {} in one transaction.", items.len(), replaced_ids.len());
+                self.shred_data_files(&replaced_files);
+                Ok(())
+            }
+            Err(e) => {
+                error!("Rolling back batch insert of {} items: {}", items.len(), e);
+                let data_dir = self.vault_path.join("data");
+                for file_name in written_files {
+                    if let Err(remove_err) = fs::remove_file(data_dir.join(&file_name)) {
+                        error!("Failed to remove data file {} during rollback: {}", file_name, remove_err);
+                    }
+                }
+                Err(e)
+            }
+        }
+    }
+
+    fn update_item_fields(&self, item: &VaultItem, crypto: &Crypto) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        Self::update_item_row(&conn, item, crypto)
+    }
+
+    fn update_items_atomically(&self, items: &[VaultItem], crypto: &Crypto) -> Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        for item in items {
+            Self::update_item_row(&tx, item, crypto)?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn get_items(
+        &self,
+        parent_id: Option<String>,
+        item_type_filter: Option<String>,
+        order_by: Option<SortOrder>,
+        crypto: &Crypto,
+    ) -> Result<Vec<VaultItem>> {
+        let conn = self.conn.lock().unwrap();
+        let sort_clause = order_by.unwrap_or_default().to_sql();
+    
+        let all_items = if let Some(pid) = parent_id {
+            let sql = format!("SELECT * FROM vault_items WHERE parent_id = ?1 {}", sort_clause);
+            Self::query_items(&conn, &sql, params![pid], crypto)?
+        } else {
+            let sql = format!("SELECT * FROM vault_items WHERE parent_id IS NULL {}", sort_clause);
+            Self::query_items(&conn, &sql, params![], crypto)?
+        };
+    
+        if let Some(filter) = item_type_filter {
+            let filtered_items = all_items
+                .into_iter()
+                .filter(|item| {
+                    if item.item_type == "folder" {
+                        item.folder_type.as_deref() == Some(&filter)
+                    } else {
+                        item.item_type.starts_with(&filter)
+                    }
+                })
+                .collect();
+            Ok(filtered_items)
+        } else {
+            Ok(all_items)
+        }
+    }
+    
+    fn get_all_items_recursive(&self, crypto: &Crypto) -> Result<Vec<VaultItem>> {
+        let conn = self.conn.lock().unwrap();
+        Self::query_items(&conn, "SELECT * FROM vault_items", [], crypto)
+    }
+
+    fn scan_items(&self, crypto: &Crypto) -> Result<(Vec<VaultItem>, Vec<UnreadableItem>)> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("SELECT * FROM vault_items")?;
+        let row_iter = stmt.query_map([], |row| {
+            let id: String = row.get(0)?;
+            let parent_id: Option<String> = row.get(1)?;
+            Ok((id, parent_id, Self::row_to_encrypted_item(row)))
+        })?;
+
+        let mut items = Vec::new();
+        let mut unreadable = Vec::new();
+        for row in row_iter {
+            let (id, parent_id, encrypted) = row?;
+            match encrypted.map_err(Error::from).and_then(|encrypted| encrypted.decrypt(crypto)) {
+                Ok(item) => items.push(item),
+                Err(e) => unreadable.push(UnreadableItem { id, parent_id, error: e.to_string() }),
+            }
+        }
+        Ok((items, unreadable))
+    }
+
+    fn get_item(&self, id: &str, crypto: &Crypto) -> Result<Option<VaultItem>> {
+        let conn = self.conn.lock().unwrap();
+        Ok(Self::query_items(&conn, "SELECT * FROM vault_items WHERE id = ?1", params![id], crypto)?.pop())
+    }
+
+    fn quarantine_items(&self, items: &[(String, String)]) -> Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        let quarantined_at = Utc::now().to_rfc3339();
+        for (id, reason) in items {
+            tx.execute(
+                "INSERT OR REPLACE INTO quarantined_items (id, parent_id, name, item_type, data_path, folder_type, tags, created_at, updated_at, expires_at, metadata, reason, quarantined_at)
+                 SELECT id, parent_id, name, item_type, data_path, folder_type, tags, created_at, updated_at, expires_at, metadata, ?2, ?3 FROM vault_items WHERE id = ?1",
+                params![id, reason, quarantined_at],
+            )?;
+            tx.execute("DELETE FROM vault_items WHERE id = ?1", params![id])?;
+        }
+        tx.commit()?;
+        info!("Quarantined {} items.", items.len());
+        Ok(())
+    }
+
+    fn set_item_parent(&self, id: &str, parent_id: Option<&str>) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute("UPDATE vault_items SET parent_id = ?2 WHERE id = ?1", params![id, parent_id])?;
+        Ok(())
+    }
+
+    fn delete_item_and_descendants(&self, id: &str, crypto: &Crypto) -> Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        let data_paths = Self::delete_rows_and_descendants(&tx, id, crypto)?;
+        tx.commit()?;
+
+        self.shred_data_files(&data_paths);
+        Ok(())
+    }
+
+    fn add_password_history_entry(&self, entry: &PasswordHistoryEntry, crypto: &Crypto) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        Self::insert_password_history_entry(&conn, entry, crypto)
+    }
+
+    fn get_password_history(&self, item_id: &str, crypto: &Crypto) -> Result<Vec<PasswordHistoryEntry>> {
+        let conn = self.conn.lock().unwrap();
+        let mut entries = Self::read_password_history(
+            &conn,
+            "SELECT id, item_id, password, changed_at FROM password_history WHERE item_id = ?1",
+            params![item_id],
+            crypto,
+        )?;
+        entries.sort_by_key(|entry| std::cmp::Reverse(entry.changed_at));
+        Ok(entries)
+    }
+
+    fn reencrypt_password_history(&self, old_crypto: &Crypto, new_crypto: &Crypto) -> Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+
+        let entries = Self::read_password_history(&tx, "SELECT id, item_id, password, changed_at FROM password_history", [], old_crypto)?;
+        for entry in &entries {
+            let row = EncryptedHistoryEntry::encrypt(entry, new_crypto)?;
+            tx.execute(
+                "UPDATE password_history SET password = ?2, changed_at = ?3 WHERE id = ?1",
+                params![row.id, row.password, row.changed_at],
+            )?;
+        }
+
+        tx.commit()?;
+        info!("Re-encrypted {} password history entries.", entries.len());
+        Ok(())
+    }
+
+    fn item_row_digests(&self) -> Result<Vec<(String, [u8; 32])>> {
+        self.row_digests("SELECT id, parent_id, name, item_type, data_path, folder_type, tags, created_at, updated_at, expires_at, metadata FROM vault_items")
+    }
+
+    fn password_history_row_digests(&self) -> Result<Vec<(String, [u8; 32])>> {
+        self.row_digests("SELECT id, item_id, password, changed_at FROM password_history")
+    }
+
+    fn write_encrypted_file(&self, data: &[u8], file_name: &str) -> Result<()> {
+        let file_path = self.vault_path.join("data").join(file_name);
+        trace!("Writing encrypted file to: {}", file_path.display());
+        fs::write(file_path, data).map_err(Error::from)
+    }
+
+    fn read_data_file(&self, file_name: &str) -> Result<Vec<u8>> {
+        let file_path = self.vault_path.join("data").join(file_name);
+        trace!("Attempting to read encrypted file from: {}", file_path.display());
+        let encrypted_data = fs::read(&file_path)?;
+        debug!("Read {} bytes from encrypted file: {}", encrypted_data.len(), file_path.display());
+        Ok(encrypted_data)
+    }
+
+    fn data_file_names(&self) -> Result<Vec<String>> {
+        let mut names = Vec::new();
+        for entry in fs::read_dir(self.vault_path.join("data"))? {
+            let entry = entry?;
+            if entry.file_type()?.is_file() {
+                names.push(entry.file_name().to_string_lossy().into_owned());
+            }
+        }
+        Ok(names)
+    }
+
+    fn data_file_stamp(&self, file_name: &str) -> Result<Option<DataFileStamp>> {
+        let metadata = fs::metadata(self.vault_path.join("data").join(file_name))?;
+        // Not every platform records modification times.
+        Ok(metadata.modified().ok().map(|modified| DataFileStamp { size: metadata.len(), modified: DateTime::<Utc>::from(modified) }))
+    }
+
+    /// Moves the file out of `data/` into `quarantine/`.
+    fn shred_data_files(&self, file_names: &[String]) {
+        let data_dir = self.vault_path.join("data");
+        for path in file_names {
+            if path.is_empty() { continue; }
+            let file_path = data_dir.join(path);
+            if file_path.exists() {
+                if let Err(e) = shred_file(&file_path) {
+                    error!("Failed to delete data file {}: {}", file_path.display(), e);
+                }
+            }
+        }
+    }
+
+    fn quarantine_data_file(&self, file_name: &str) -> Result<()> {
+        let quarantine_dir = self.vault_path.join("quarantine");
+        fs::create_dir_all(&quarantine_dir)?;
+        fs::rename(self.vault_path.join("data").join(file_name), quarantine_dir.join(file_name))?;
+        Ok(())
+    }
+
+    fn read_anchor(&self) -> Result<Option<Vec<u8>>> {
+        match fs::read(Self::anchor_path(&self.vault_path)) {
+            Ok(anchor) => Ok(Some(anchor)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn write_anchor(&self, anchor: &[u8]) -> Result<()> {
+        let anchor_path = Self::anchor_path(&self.vault_path);
+        fs::write(&anchor_path, anchor)?;
+        fs::File::open(&anchor_path)?.sync_all()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,382 @@
+//! Tamper evidence for the vault as a whole. Row-level encryption stops a
+//! file-level attacker from reading or forging items, but not from deleting
+//! rows, swapping data files or putting back an older `vault.db`.
+//!
+//! After every write the vault's state is sealed: a Merkle root over the
+//! SHA-256 of every item row, password history row and data file, together
+//! with those leaves and a counter that only goes up. The seal is encrypted
+//! with the vault key, so it cannot be forged, and stored in `vault_meta`.
+//! The counter and root are also written to an anchor kept apart from the
+//! vault (a file next to the vault directory on disk), which catches an entire
+//! older copy of the vault being put back.
+//!
+//! A check hashes every data file. Sealing after a write only hashes the data
+//! files whose size or modification time changed since the last seal, so
+//! writes stay cheap in large vaults.
+
+use crate::crypto::Crypto;
+use crate::error::Error;
+use crate::storage::{DataFileStamp, SqliteStorage, Storage};
+use crate::Result;
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use chrono::{Duration, Utc};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+const SEAL_VERSION: u32 = 1;
+
+const ITEM_PREFIX: &str = "item:";
+const HISTORY_PREFIX: &str = "history:";
+const DATA_FILE_PREFIX: &str = "file:";
+
+/// A file modified this recently may be written again without its stamp
+/// changing, so its digest is not reused.
+const STAMP_SETTLE_SECONDS: i64 = 2;
+
+const LEAF_TAG: u8 = 0x00;
+const NODE_TAG: u8 = 0x01;
+
+/// Leaf key to the SHA-256 of its row or file.
+type Leaves = BTreeMap<String, [u8; 32]>;
+
+#[derive(Serialize, Deserialize)]
+struct Seal {
+    version: u32,
+    counter: u64,
+    root: String,
+    /// Leaf key (`item:<id>`, `history:<id>` or `file:<name>`) to hex SHA-256.
+    leaves: BTreeMap<String, String>,
+    /// Stamps of the data files at sealing, by leaf key. The next seal reuses
+    /// the digest of a file whose stamp has not changed.
+    #[serde(default)]
+    stamps: BTreeMap<String, DataFileStamp>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Anchor {
+    counter: u64,
+    root: String,
+}
+
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
+pub enum VaultStateStatus {
+    /// The vault has not been sealed before, so there was nothing to compare against.
+    Unsealed,
+    Intact,
+    /// Items, history or data files were added, changed or removed outside the app.
+    Modified,
+    /// The database is older than the last state the app sealed.
+    RolledBack,
+    /// The anchor next to the vault is gone or does not open with the vault key.
+    AnchorMissing,
+    /// The seal is gone from the database although the vault has an anchor, as
+    /// when the seal row is deleted or a database from before sealing is put back.
+    SealMissing,
+    /// The seal in the database does not open with the vault key.
+    SealUnreadable,
+}
+
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
+pub enum EntryKind {
+    Item,
+    PasswordHistory,
+    DataFile,
+}
+
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
+pub enum ChangeKind {
+    Missing,
+    Modified,
+    Unexpected,
+}
+
+#[derive(Debug, Serialize, Clone, PartialEq, Eq)]
+pub struct StateChange {
+    pub kind: EntryKind,
+    pub id: String,
+    pub change: ChangeKind,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct VaultStateCheck {
+    pub status: VaultStateStatus,
+    /// The counter of the seal that was checked, `0` if there was none.
+    pub counter: u64,
+    pub changes: Vec<StateChange>,
+}
+
+impl VaultStateCheck {
+    pub fn is_intact(&self) -> bool {
+        matches!(self.status, VaultStateStatus::Intact | VaultStateStatus::Unsealed)
+    }
+}
+
+/// Records the vault's current state as the expected one. The counter moves
+/// past both the database's and the anchor's, so a restored backup is sealed
+/// ahead of the state it replaced.
+pub fn seal_vault_state(storage: &dyn Storage, crypto: &Crypto) -> Result<()> {
+    let previous = read_seal(storage, crypto).ok().flatten();
+    let (leaves, stamps) = current_leaves(storage, previous.as_ref())?;
+    let root = hex(&merkle_root(&leaves));
+
+    let sealed_counter = previous.map_or(0, |seal| seal.counter);
+    let anchor_counter = read_anchor(storage, crypto).ok().flatten().map_or(0, |anchor| anchor.counter);
+    let counter = sealed_counter.max(anchor_counter) + 1;
+
+    let seal = Seal {
+        version: SEAL_VERSION,
+        counter,
+        root: root.clone(),
+        leaves: leaves.iter().map(|(key, digest)| (key.clone(), hex(digest))).collect(),
+        stamps,
+    };
+    let encrypted_seal = crypto.encrypt(&serde_json::to_vec(&seal)?)?;
+    storage.set_vault_state_seal(&STANDARD.encode(encrypted_seal))?;
+
+    // Written after the seal: a crash in between leaves the anchor one step behind, which is accepted.
+    let encrypted_anchor = crypto.encrypt(&serde_json::to_vec(&Anchor { counter, root })?)?;
+    storage.write_anchor(&encrypted_anchor)?;
+
+    debug!("Sealed vault state {} over {} entries.", counter, leaves.len());
+    Ok(())
+}
+
+/// Compares the vault against its last seal and anchor. The vault is not
+/// changed; the next write seals whatever state it is in.
+pub fn check_vault_state(storage: &dyn Storage, crypto: &Crypto) -> Result<VaultStateCheck> {
+    let seal = match read_seal(storage, crypto) {
+        Ok(Some(seal)) => seal,
+        Ok(None) => {
+            // Only a vault that was never sealed has neither a seal nor an anchor.
+            let status = if storage.read_anchor()?.is_some() { VaultStateStatus::SealMissing } else { VaultStateStatus::Unsealed };
+            if status == VaultStateStatus::SealMissing {
+                warn!("Vault state seal is missing but the vault has an anchor.");
+            }
+            return Ok(VaultStateCheck { status, counter: 0, changes: Vec::new() });
+        }
+        Err(e) => {
+            warn!("Vault state seal could not be read: {}", e);
+            return Ok(VaultStateCheck { status: VaultStateStatus::SealUnreadable, counter: 0, changes: Vec::new() });
+        }
+    };
+
+    let (leaves, _) = current_leaves(storage, None)?;
+    let changes = if hex(&merkle_root(&leaves)) == seal.root { Vec::new() } else { diff_leaves(&seal.leaves, &leaves) };
+
+    let anchor = read_anchor(storage, crypto).unwrap_or_else(|e| {
+        warn!("Vault anchor could not be read: {}", e);
+        None
+    });
+    let status = match anchor {
+        Some(anchor) if anchor.counter > seal.counter => VaultStateStatus::RolledBack,
+        Some(anchor) if anchor.counter == seal.counter && anchor.root != seal.root => VaultStateStatus::RolledBack,
+        None => VaultStateStatus::AnchorMissing,
+        Some(_) if !changes.is_empty() => VaultStateStatus::Modified,
+        Some(_) => VaultStateStatus::Intact,
+    };
+
+    if status == VaultStateStatus::Intact {
+        info!("Vault state {} verified.", seal.counter);
+    } else {
+        warn!("Vault state check: {:?} with {} changed entries.", status, changes.len());
+    }
+    Ok(VaultStateCheck { status, counter: seal.counter, changes })
+}
+
+/// Removes the anchor of the vault at `vault_path`, for when the vault itself is deleted.
+pub fn remove_anchor(vault_path: &Path) -> Result<()> {
+    match fs::remove_file(SqliteStorage::anchor_path(vault_path)) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+fn read_seal(storage: &dyn Storage, crypto: &Crypto) -> Result<Option<Seal>> {
+    let Some(encoded) = storage.get_vault_state_seal()? else {
+        return Ok(None);
+    };
+    let encrypted = STANDARD.decode(encoded).map_err(|e| Error::Storage(format!("Invalid vault state seal: {}", e)))?;
+    let seal: Seal = serde_json::from_slice(&crypto.decrypt(&encrypted)?)?;
+    if seal.version != SEAL_VERSION {
+        return Err(Error::Storage(format!("Unsupported vault state seal version {}", seal.version)));
+    }
+    Ok(Some(seal))
+}
+
+fn read_anchor(storage: &dyn Storage, crypto: &Crypto) -> Result<Option<Anchor>> {
+    let Some(encrypted) = storage.read_anchor()? else {
+        return Ok(None);
+    };
+    Ok(Some(serde_json::from_slice(&crypto.decrypt(&encrypted)?)?))
+}
+
+/// Every row and data file of the vault, keyed by kind and id, and the stamps
+/// of the data files. Data files whose stamp matches the one in `previous` keep
+/// their sealed digest rather than being read again, so sealing after a write
+/// only hashes the files that changed.
+fn current_leaves(storage: &dyn Storage, previous: Option<&Seal>) -> Result<(Leaves, BTreeMap<String, DataFileStamp>)> {
+    let settled_before = Utc::now() - Duration::seconds(STAMP_SETTLE_SECONDS);
+    let mut leaves = BTreeMap::new();
+    let mut stamps = BTreeMap::new();
+    for (id, digest) in storage.item_row_digests()? {
+        leaves.insert(format!("{}{}", ITEM_PREFIX, id), digest);
+    }
+    for (id, digest) in storage.password_history_row_digests()? {
+        leaves.insert(format!("{}{}", HISTORY_PREFIX, id), digest);
+    }
+    for file_name in storage.data_file_names()? {
+        let key = format!("{}{}", DATA_FILE_PREFIX, file_name);
+        let stamp = storage.data_file_stamp(&file_name)?.filter(|stamp| stamp.modified < settled_before);
+        let sealed_digest = previous
+            .filter(|seal| stamp.is_some() && seal.stamps.get(&key) == stamp.as_ref())
+            .and_then(|seal| seal.leaves.get(&key))
+            .and_then(|digest| parse_hex(digest));
+        let digest = match sealed_digest {
+            Some(digest) => digest,
+            None => Sha256::digest(storage.read_data_file(&file_name)?).into(),
+        };
+        if let Some(stamp) = stamp {
+            stamps.insert(key.clone(), stamp);
+        }
+        leaves.insert(key, digest);
+    }
+    Ok((leaves, stamps))
+}
+
+/// Root of a binary Merkle tree over the leaves in key order. Leaves and inner
+/// nodes are hashed with different prefixes, and an odd node is carried up
+/// unchanged.
+fn merkle_root(leaves: &Leaves) -> [u8; 32] {
+    let mut level: Vec<[u8; 32]> = leaves
+        .iter()
+        .map(|(key, digest)| {
+            let mut hasher = Sha256::new();
+            hasher.update([LEAF_TAG]);
+            hasher.update((key.len() as u64).to_be_bytes());
+            hasher.update(key.as_bytes());
+            hasher.update(digest);
+            hasher.finalize().into()
+        })
+        .collect();
+    if level.is_empty() {
+        return Sha256::digest([]).into();
+    }
+
+    while level.len() > 1 {
+        level = level
+            .chunks(2)
+            .map(|pair| match pair {
+                [left, right] => {
+                    let mut hasher = Sha256::new();
+                    hasher.update([NODE_TAG]);
+                    hasher.update(left);
+                    hasher.update(right);
+                    hasher.finalize().into()
+                }
+                [single] => *single,
+                _ => unreachable!("chunks(2) yields one or two nodes"),
+            })
+            .collect();
+    }
+    level[0]
+}
+
+fn diff_leaves(sealed: &BTreeMap<String, String>, current: &Leaves) -> Vec<StateChange> {
+    let mut changes = Vec::new();
+    for (key, digest) in sealed {
+        match current.get(key) {
+            None => changes.push(state_change(key, ChangeKind::Missing)),
+            Some(current_digest) if hex(current_digest) != *digest => changes.push(state_change(key, ChangeKind::Modified)),
+            Some(_) => {}
+        }
+    }
+    for key in current.keys().filter(|key| !sealed.contains_key(*key)) {
+        changes.push(state_change(key, ChangeKind::Unexpected));
+    }
+    changes
+}
+
+fn state_change(key: &str, change: ChangeKind) -> StateChange {
+    let (kind, id) = if let Some(id) = key.strip_prefix(ITEM_PREFIX) {
+        (EntryKind::Item, id)
+    } else if let Some(id) = key.strip_prefix(HISTORY_PREFIX) {
+        (EntryKind::PasswordHistory, id)
+    } else {
+        (EntryKind::DataFile, key.strip_prefix(DATA_FILE_PREFIX).unwrap_or(key))
+    };
+    StateChange { kind, id: id.to_string(), change }
+}
+
+fn hex(digest: &[u8; 32]) -> String {
+    digest.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn parse_hex(hex: &str) -> Option<[u8; 32]> {
+    let mut digest = [0u8; 32];
+    if hex.len() != 2 * digest.len() {
+        return None;
+    }
+    for (byte, pair) in digest.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        *byte = u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok()?;
+    }
+    Some(digest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+    use crate::vault;
+
+    fn sealed_vault_with_item() -> (MemoryStorage, Crypto, String) {
+        let (storage, crypto) = MemoryStorage::unlocked();
+        let item = vault::add_text_item(&storage, &crypto, "Note".to_string(), "text".to_string(), b"hello", Vec::new(), None).unwrap();
+        (storage, crypto, item.id)
+    }
+
+    #[test]
+    fn a_vault_without_a_seal_or_anchor_is_unsealed() {
+        let (storage, crypto) = MemoryStorage::unlocked();
+        assert_eq!(check_vault_state(&storage, &crypto).unwrap().status, VaultStateStatus::Unsealed);
+    }
+
+    #[test]
+    fn a_sealed_vault_is_intact() {
+        let (storage, crypto, _) = sealed_vault_with_item();
+        let check = check_vault_state(&storage, &crypto).unwrap();
+        assert_eq!(check.status, VaultStateStatus::Intact);
+        assert!(check.changes.is_empty());
+    }
+
+    #[test]
+    fn a_row_deleted_outside_the_app_is_reported() {
+        let (storage, crypto, id) = sealed_vault_with_item();
+        storage.delete_item_and_descendants(&id, &crypto).unwrap();
+
+        let check = check_vault_state(&storage, &crypto).unwrap();
+        assert_eq!(check.status, VaultStateStatus::Modified);
+        assert!(check.changes.contains(&StateChange { kind: EntryKind::Item, id, change: ChangeKind::Missing }));
+    }
+
+    #[test]
+    fn a_deleted_seal_is_reported_when_the_anchor_remains() {
+        let (storage, crypto, _) = sealed_vault_with_item();
+        storage.delete_meta_value("vault_state_seal").unwrap();
+        assert_eq!(check_vault_state(&storage, &crypto).unwrap().status, VaultStateStatus::SealMissing);
+    }
+
+    #[test]
+    fn an_older_database_is_reported_as_rolled_back() {
+        let (storage, crypto, _) = sealed_vault_with_item();
+        let old_seal = storage.get_vault_state_seal().unwrap().unwrap();
+        vault::add_folder(&storage, &crypto, "Later".to_string(), None, None).unwrap();
+        storage.set_vault_state_seal(&old_seal).unwrap();
+
+        assert_eq!(check_vault_state(&storage, &crypto).unwrap().status, VaultStateStatus::RolledBack);
+    }
+}
@@ -0,0 +1,515 @@
+//! Config files rendered from vault secrets, so files such as a Kubernetes
+//! secret, `.npmrc` or `docker/config.json` can be committed as templates and
+//! only ever hold real values on the machine that renders them.
+//!
+//! A placeholder names an item and optionally one of its fields, and can be
+//! piped through filters:
+//!
+//! ```text
+//! password: {{ fetch "Prod DB" "password" }}
+//! auth: {{ fetch "Work/Registry" "token" | base64 }}
+//! ```
+//!
+//! Without a field the item's whole content is used. Items are found as by
+//! [`vault::find_item`]. Other `{{ ... }}` expressions are left as they are,
+//! so templates for other tools can be rendered too.
+
+use crate::crypto::Crypto;
+use crate::error::Error;
+use crate::storage::{self, Storage};
+use crate::vault;
+use crate::Result;
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use chrono::{DateTime, Duration, Utc};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Instant, SystemTime};
+use zeroize::Zeroizing;
+
+const OPEN: &str = "{{";
+const CLOSE: &str = "}}";
+const FUNCTION: &str = "fetch";
+
+/// Rendered files still to be shredded, kept next to the vault so a file whose
+/// timer never fired, because the app quit or the CLI was interrupted, is
+/// shredded the next time either starts.
+const PENDING_SHREDS_FILE: &str = "pending-shreds.json";
+
+/// Created next to the pending shreds file while it is read and rewritten, so
+/// the app and the CLI do not lose each other's updates.
+const PENDING_SHREDS_LOCK_FILE: &str = "pending-shreds.lock";
+/// A lock this old was left by a process that died and is taken over. The lock
+/// is only held to read and rewrite the small pending shreds file.
+const STALE_LOCK_AGE: std::time::Duration = std::time::Duration::from_secs(30);
+const LOCK_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+const LOCK_RETRY: std::time::Duration = std::time::Duration::from_millis(20);
+
+#[derive(Debug, Serialize, Clone)]
+pub struct RenderReport {
+    pub output: PathBuf,
+    /// Placeholders replaced with vault values.
+    pub placeholders: usize,
+    /// When the output is due to be shredded, if it is.
+    pub shred_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+struct PendingShred {
+    path: PathBuf,
+    shred_at: DateTime<Utc>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Token {
+    Word(String),
+    Text(String),
+    Pipe,
+}
+
+/// A parsed `{{ fetch ... }}` placeholder.
+#[derive(Debug, PartialEq, Eq)]
+struct Placeholder {
+    item: String,
+    field: Option<String>,
+    filters: Vec<Filter>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Filter {
+    /// Standard base64, as in Kubernetes secret `data` and docker `auth` values.
+    Base64,
+    /// Escaped for use inside a JSON string, without adding quotes.
+    Json,
+}
+
+impl Filter {
+    fn parse(name: &str) -> std::result::Result<Self, String> {
+        match name {
+            "base64" => Ok(Filter::Base64),
+            "json" => Ok(Filter::Json),
+            _ => Err(format!("unknown filter '{}'; use base64 or json", name)),
+        }
+    }
+
+    fn apply(self, value: &str) -> Zeroizing<String> {
+        Zeroizing::new(match self {
+            Filter::Base64 => STANDARD.encode(value),
+            Filter::Json => {
+                let quoted = Zeroizing::new(serde_json::Value::String(value.to_string()).to_string());
+                quoted[1..quoted.len() - 1].to_string()
+            }
+        })
+    }
+}
+
+/// Replaces every `fetch` placeholder in `template` with its value. Returns the
+/// rendered text and the number of placeholders replaced.
+pub fn render(storage: &dyn Storage, crypto: &Crypto, template: &str) -> Result<(Zeroizing<String>, usize)> {
+    let mut output = Zeroizing::new(String::with_capacity(template.len()));
+    let mut placeholders = 0;
+    let mut rest = template;
+
+    while let Some(start) = rest.find(OPEN) {
+        let Some(length) = rest[start + OPEN.len()..].find(CLOSE) else {
+            break;
+        };
+        let expression = &rest[start + OPEN.len()..start + OPEN.len() + length];
+        let end = start + OPEN.len() + length + CLOSE.len();
+
+        output.push_str(&rest[..start]);
+        match parse_placeholder(expression) {
+            None => output.push_str(&rest[start..end]),
+            Some(placeholder) => {
+                let line = template[..template.len() - rest.len() + start].matches('\n').count() + 1;
+                let placeholder = placeholder.map_err(|e| Error::InvalidInput(format!("Line {} of the template: {}", line, e)))?;
+                let value = resolve(storage, crypto, &placeholder).map_err(|e| {
+                    let message = match e {
+                        Error::InvalidInput(message) => message,
+                        e => e.to_string(),
+                    };
+                    Error::InvalidInput(format!("Line {} of the template: {}", line, message))
+                })?;
+                output.push_str(&value);
+                placeholders += 1;
+            }
+        }
+        rest = &rest[end..];
+    }
+    output.push_str(rest);
+    Ok((output, placeholders))
+}
+
+fn resolve(storage: &dyn Storage, crypto: &Crypto, placeholder: &Placeholder) -> Result<Zeroizing<String>> {
+    let item = vault::find_item(storage, crypto, &placeholder.item)?;
+    let mut value = match &placeholder.field {
+        Some(field) => vault::item_field(storage, crypto, &item, field)?,
+        None => {
+            let content = Zeroizing::new(vault::item_content(storage, crypto, &item.id)?);
+            Zeroizing::new(
+                String::from_utf8(content.to_vec())
+                    .map_err(|_| Error::InvalidInput(format!("'{}' holds binary content, which cannot be placed in a template", item.name)))?,
+            )
+        }
+    };
+    for filter in &placeholder.filters {
+        value = filter.apply(&value);
+    }
+    Ok(value)
+}
+
+/// Parses the inside of `{{ ... }}`. Returns `None` when it is not a `fetch`
+/// placeholder, and an error message when it is one but is malformed.
+fn parse_placeholder(expression: &str) -> Option<std::result::Result<Placeholder, String>> {
+    let expression = expression.trim();
+    let arguments = expression.strip_prefix(FUNCTION)?;
+    if !arguments.is_empty() && !arguments.starts_with(char::is_whitespace) {
+        return None;
+    }
+
+    Some(tokenize(arguments).and_then(|tokens| {
+        let mut tokens = tokens.into_iter();
+        let item = match tokens.next() {
+            Some(Token::Text(item)) => item,
+            _ => return Err(format!("{} needs a quoted item name", FUNCTION)),
+        };
+        let mut field = None;
+        let mut filters = Vec::new();
+        while let Some(token) = tokens.next() {
+            match token {
+                Token::Text(text) if field.is_none() && filters.is_empty() => field = Some(text),
+                Token::Pipe => match tokens.next() {
+                    Some(Token::Word(name)) => filters.push(Filter::parse(&name)?),
+                    _ => return Err("expected a filter name after '|'".to_string()),
+                },
+                Token::Text(text) => return Err(format!("unexpected \"{}\"", text)),
+                Token::Word(word) => return Err(format!("unexpected '{}'; quote item and field names", word)),
+            }
+        }
+        Ok(Placeholder { item, field, filters })
+    }))
+}
+
+fn tokenize(arguments: &str) -> std::result::Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = arguments.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => {}
+            '|' => tokens.push(Token::Pipe),
+            '"' => {
+                let mut text = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(escaped @ ('"' | '\\')) => text.push(escaped),
+                            _ => return Err("only \\\" and \\\\ can be escaped".to_string()),
+                        },
+                        Some(c) => text.push(c),
+                        None => return Err("unterminated quoted string".to_string()),
+                    }
+                }
+                tokens.push(Token::Text(text));
+            }
+            c => {
+                let mut word = c.to_string();
+                while let Some(&c) = chars.peek() {
+                    if c.is_whitespace() || c == '|' || c == '"' {
+                        break;
+                    }
+                    word.push(c);
+                    chars.next();
+                }
+                tokens.push(Token::Word(word));
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+/// Renders the template at `template_path` into `output_path`, which is
+/// created readable only by the current user.
+pub fn render_to_file(storage: &dyn Storage, crypto: &Crypto, template_path: &Path, output_path: &Path) -> Result<usize> {
+    info!("Rendering template {} to {}", template_path.display(), output_path.display());
+    let template = fs::read_to_string(template_path)?;
+    let (rendered, placeholders) = render(storage, crypto, &template)?;
+    vault::write_private_file(output_path, rendered.as_bytes())?;
+    info!("Rendered {} placeholders.", placeholders);
+    Ok(placeholders)
+}
+
+/// When a file rendered now should be shredded, given a delay in seconds.
+pub fn shred_time(now: DateTime<Utc>, shred_after_secs: Option<u64>) -> Result<Option<DateTime<Utc>>> {
+    shred_after_secs
+        .map(|secs| {
+            Duration::from_std(std::time::Duration::from_secs(secs))
+                .ok()
+                .and_then(|delay| now.checked_add_signed(delay))
+                .ok_or_else(|| Error::InvalidInput("Shred delay is too long".into()))
+        })
+        .transpose()
+}
+
+/// Records that the rendered file at `output_path` is due to be shredded at
+/// `shred_at`, for the vault at `vault_path`. Call [`shred_due`] at that time.
+pub fn schedule_shred(vault_path: &Path, output_path: &Path, shred_at: DateTime<Utc>) -> Result<()> {
+    let path = fs::canonicalize(output_path)?;
+    let file = pending_shreds_path(vault_path)?;
+    let _lock = PendingShredsLock::acquire(vault_path)?;
+    let mut shreds = read_pending_shreds(&file)?;
+    shreds.retain(|shred| shred.path != path);
+    shreds.push(PendingShred { path, shred_at });
+    write_pending_shreds(&file, &shreds)?;
+    info!("Rendered file {} is due to be shredded at {}", output_path.display(), shred_at);
+    Ok(())
+}
+
+/// Shreds the pending files whose time has come by `now` and forgets them. A
+/// file that fails to shred stays pending and is tried again next time.
+/// Returns when the next remaining file is due, if any is.
+pub fn shred_due(vault_path: &Path, now: DateTime<Utc>) -> Result<Option<DateTime<Utc>>> {
+    let file = pending_shreds_path(vault_path)?;
+    let due: Vec<PendingShred> = {
+        let _lock = PendingShredsLock::acquire(vault_path)?;
+        read_pending_shreds(&file)?.into_iter().filter(|shred| shred.shred_at <= now).collect()
+    };
+
+    // Shredding can take a while for large files, so it runs without the lock.
+    let mut shredded = Vec::new();
+    for shred in due {
+        match shred_output(&shred.path) {
+            Ok(()) => shredded.push(shred.path),
+            Err(e) => warn!("Failed to shred rendered file {}: {}", shred.path.display(), e),
+        }
+    }
+
+    let _lock = PendingShredsLock::acquire(vault_path)?;
+    let mut remaining = read_pending_shreds(&file)?;
+    if !shredded.is_empty() {
+        // A file scheduled again meanwhile keeps its new, later time.
+        remaining.retain(|shred| shred.shred_at > now || !shredded.contains(&shred.path));
+        write_pending_shreds(&file, &remaining)?;
+    }
+    Ok(remaining.iter().map(|shred| shred.shred_at).filter(|shred_at| *shred_at > now).min())
+}
+
+/// Held while the pending shreds file is read and rewritten, by any process.
+struct PendingShredsLock(PathBuf);
+
+impl PendingShredsLock {
+    fn acquire(vault_path: &Path) -> Result<Self> {
+        let path = pending_shreds_path(vault_path)?.with_file_name(PENDING_SHREDS_LOCK_FILE);
+        let started = Instant::now();
+        loop {
+            match fs::OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(_) => return Ok(Self(path)),
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                    let age = fs::metadata(&path)
+                        .and_then(|metadata| metadata.modified())
+                        .ok()
+                        .and_then(|modified| SystemTime::now().duration_since(modified).ok());
+                    if age.is_some_and(|age| age > STALE_LOCK_AGE) {
+                        warn!("Taking over the stale lock {}", path.display());
+                        let _ = fs::remove_file(&path);
+                    } else if started.elapsed() > LOCK_TIMEOUT {
+                        return Err(Error::Storage(format!("Timed out waiting for {}", path.display())));
+                    } else {
+                        std::thread::sleep(LOCK_RETRY);
+                    }
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+}
+
+impl Drop for PendingShredsLock {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.0) {
+            warn!("Failed to remove the lock {}: {}", self.0.display(), e);
+        }
+    }
+}
+
+fn pending_shreds_path(vault_path: &Path) -> Result<PathBuf> {
+    vault_path
+        .parent()
+        .map(|directory| directory.join(PENDING_SHREDS_FILE))
+        .ok_or_else(|| Error::InvalidInput(format!("Vault path {} has no parent directory", vault_path.display())))
+}
+
+fn read_pending_shreds(file: &Path) -> Result<Vec<PendingShred>> {
+    match fs::read(file) {
+        Ok(data) => Ok(serde_json::from_slice(&data)?),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn write_pending_shreds(file: &Path, shreds: &[PendingShred]) -> Result<()> {
+    if !shreds.is_empty() {
+        return vault::write_private_file(file, &serde_json::to_vec_pretty(shreds)?);
+    }
+    match fs::remove_file(file) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+/// Shreds a rendered file once its time is up. A file that is already gone
+/// was removed by someone else and is not an error.
+fn shred_output(output_path: &Path) -> Result<()> {
+    if !output_path.exists() {
+        info!("Rendered file {} is already gone.", output_path.display());
+        return Ok(());
+    }
+    storage::shred_file(output_path)?;
+    info!("Shredded rendered file {}", output_path.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    /// A vault path inside a fresh directory, which also holds the pending shreds file.
+    fn temp_vault() -> (PathBuf, PathBuf) {
+        let dir = std::env::temp_dir().join(format!("fetch-template-{}", Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        (dir.join("vault"), dir)
+    }
+
+    #[test]
+    fn concurrent_schedules_are_all_recorded() {
+        let (vault_path, dir) = temp_vault();
+        let shred_at = Utc::now() + Duration::hours(1);
+        let outputs: Vec<PathBuf> = (0..8).map(|i| dir.join(format!("out-{}", i))).collect();
+        for output in &outputs {
+            fs::write(output, "secret").unwrap();
+        }
+
+        std::thread::scope(|scope| {
+            for output in &outputs {
+                let vault_path = &vault_path;
+                scope.spawn(move || schedule_shred(vault_path, output, shred_at).unwrap());
+            }
+        });
+
+        let pending = read_pending_shreds(&pending_shreds_path(&vault_path).unwrap()).unwrap();
+        assert_eq!(pending.len(), outputs.len());
+        assert!(!dir.join(PENDING_SHREDS_LOCK_FILE).exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn shreds_due_files_and_keeps_later_ones() {
+        let (vault_path, dir) = temp_vault();
+        let now = Utc::now();
+        let (due, later) = (dir.join("due"), dir.join("later"));
+        fs::write(&due, "secret").unwrap();
+        fs::write(&later, "secret").unwrap();
+        schedule_shred(&vault_path, &due, now - Duration::seconds(1)).unwrap();
+        schedule_shred(&vault_path, &later, now + Duration::hours(1)).unwrap();
+
+        assert_eq!(shred_due(&vault_path, now).unwrap(), Some(now + Duration::hours(1)));
+        assert!(!due.exists());
+        assert!(later.exists());
+        assert_eq!(shred_due(&vault_path, now + Duration::hours(2)).unwrap(), None);
+        assert!(!later.exists());
+        assert!(!dir.join(PENDING_SHREDS_FILE).exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    fn placeholder(item: &str, field: Option<&str>, filters: Vec<Filter>) -> Option<std::result::Result<Placeholder, String>> {
+        Some(Ok(Placeholder { item: item.to_string(), field: field.map(str::to_string), filters }))
+    }
+
+    #[test]
+    fn parses_fetch_placeholders() {
+        assert_eq!(parse_placeholder(r#" fetch "Prod DB" "#), placeholder("Prod DB", None, vec![]));
+        assert_eq!(parse_placeholder(r#"fetch "Prod DB" "password""#), placeholder("Prod DB", Some("password"), vec![]));
+        assert_eq!(
+            parse_placeholder(r#"fetch "Work/Registry" "token" | base64|json"#),
+            placeholder("Work/Registry", Some("token"), vec![Filter::Base64, Filter::Json])
+        );
+        assert_eq!(parse_placeholder(r#"fetch "Say \"hi\" \\ bye" | json"#), placeholder(r#"Say "hi" \ bye"#, None, vec![Filter::Json]));
+    }
+
+    #[test]
+    fn leaves_other_expressions_alone() {
+        for expression in ["", " .Values.name ", "fetcher \"x\"", "fetch_all", "  include \"fetch\" "] {
+            assert_eq!(parse_placeholder(expression), None, "{:?}", expression);
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_placeholders() {
+        for (expression, message) in [
+            ("fetch", "needs a quoted item name"),
+            ("fetch Prod", "needs a quoted item name"),
+            (r#"fetch "Prod"#, "unterminated quoted string"),
+            (r#"fetch "Prod\n""#, "can be escaped"),
+            (r#"fetch "Prod" password"#, "quote item and field names"),
+            (r#"fetch "Prod" "a" "b""#, "unexpected \"b\""),
+            (r#"fetch "Prod" | base64 "field""#, "unexpected \"field\""),
+            (r#"fetch "Prod" |"#, "expected a filter name"),
+            (r#"fetch "Prod" | "base64""#, "expected a filter name"),
+            (r#"fetch "Prod" | hex"#, "unknown filter 'hex'"),
+        ] {
+            let error = parse_placeholder(expression).unwrap().unwrap_err();
+            assert!(error.contains(message), "{:?} gave {:?}", expression, error);
+        }
+    }
+
+    #[test]
+    fn tokenizes_words_pipes_and_quoted_text() {
+        assert_eq!(
+            tokenize(r#" "a b"|json  word"c" "#).unwrap(),
+            vec![Token::Text("a b".into()), Token::Pipe, Token::Word("json".into()), Token::Word("word".into()), Token::Text("c".into())]
+        );
+        assert_eq!(tokenize("").unwrap(), vec![]);
+    }
+
+    #[test]
+    fn renders_vault_values() {
+        let (storage, crypto) = storage::MemoryStorage::unlocked();
+        let content = b"Username: admin\nPassword: p\"w\n";
+        vault::add_text_item(&storage, &crypto, "Prod DB".into(), "text/plain".into(), content, Vec::new(), None).unwrap();
+
+        let template = "user: {{ fetch \"Prod DB\" \"username\" }}\n\
+                        auth: {{fetch \"Prod DB\" \"password\" | base64}}\n\
+                        json: \"{{ fetch \"Prod DB\" \"password\" | json }}\"\n\
+                        helm: {{ .Values.name }} {{ unclosed";
+        let (rendered, placeholders) = render(&storage, &crypto, template).unwrap();
+        assert_eq!(placeholders, 3);
+        assert_eq!(rendered.as_str(), "user: admin\nauth: cCJ3\njson: \"p\\\"w\"\nhelm: {{ .Values.name }} {{ unclosed");
+
+        let error = render(&storage, &crypto, "ok\n{{ fetch \"Prod DB\" \"missing\" }}").unwrap_err().to_string();
+        assert!(error.contains("Line 2"), "{}", error);
+        let error = render(&storage, &crypto, "{{ fetch \"Prod DB\" | hex }}").unwrap_err().to_string();
+        assert!(error.contains("Line 1") && error.contains("unknown filter"), "{}", error);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn rendered_files_are_private() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let (storage, crypto) = storage::MemoryStorage::unlocked();
+        vault::add_text_item(&storage, &crypto, "Token".into(), "text/plain".into(), b"Password: hunter2", Vec::new(), None).unwrap();
+        let (_, dir) = temp_vault();
+        let (template, output) = (dir.join("template"), dir.join("output"));
+        fs::write(&template, "{{ fetch \"Token\" \"password\" }}").unwrap();
+        fs::write(&output, "old, longer contents").unwrap();
+        fs::set_permissions(&output, fs::Permissions::from_mode(0o644)).unwrap();
+
+        assert_eq!(render_to_file(&storage, &crypto, &template, &output).unwrap(), 1);
+        assert_eq!(fs::read_to_string(&output).unwrap(), "hunter2");
+        assert_eq!(fs::metadata(&output).unwrap().permissions().mode() & 0o777, 0o600);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
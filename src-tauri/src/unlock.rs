@@ -0,0 +1,170 @@
+use crate::crypto::{Crypto, KeyDerivationStrength};
+use crate::error::Error;
+use crate::storage::{BruteForceConfig, Storage};
+use crate::tamper::VaultStateCheck;
+use crate::Result;
+use chrono::{DateTime, Duration, Utc};
+use log::{info, warn};
+use serde::Serialize;
+use zeroize::Zeroizing;
+
+#[derive(Debug, Serialize, Clone, Default)]
+pub struct UnlockReport {
+    /// Failed unlock attempts since the last successful unlock.
+    pub failed_attempts: u64,
+    /// Authenticated settings that were edited outside the app. They have been
+    /// put back to safe values and should be reviewed.
+    pub tampered_settings: Vec<String>,
+    pub vault_state: Option<VaultStateCheck>,
+}
+
+/// How long the vault stays locked out after too many failed attempts, if it is at `now`.
+pub fn lockout_remaining(storage: &dyn Storage, now: DateTime<Utc>) -> Result<Option<Duration>> {
+    let config = storage.get_brute_force_config().unwrap_or_else(|e| {
+        warn!("Using the default lockout settings: {}", e);
+        BruteForceConfig::default()
+    });
+    if !config.enabled || config.max_attempts == 0 {
+        return Ok(None);
+    }
+    if storage.get_failed_login_attempts().unwrap_or_default() < config.max_attempts {
+        return Ok(None);
+    }
+
+    let Some(last_attempt) = storage.get_last_failed_attempt_timestamp().unwrap_or_default() else {
+        return Ok(None);
+    };
+    let until = last_attempt + Duration::minutes(config.lockout_duration_minutes.into());
+    Ok((until > now).then(|| until - now))
+}
+
+/// Fails with `TooManyAttempts` while the vault is locked out. Anything that
+/// checks the master key goes through this, not just unlocking.
+pub fn ensure_not_locked_out(storage: &dyn Storage, now: DateTime<Utc>) -> Result<()> {
+    if let Some(remaining) = lockout_remaining(storage, now)? {
+        warn!("Master key check refused, vault is locked out for another {} seconds.", remaining.num_seconds());
+        return Err(Error::TooManyAttempts((remaining.num_seconds() + 59) / 60));
+    }
+    Ok(())
+}
+
+pub fn record_failed_attempt(storage: &dyn Storage, now: DateTime<Utc>) -> Result<()> {
+    let attempts = storage.get_failed_login_attempts().unwrap_or_default().saturating_add(1);
+    storage.set_failed_login_attempts(attempts)?;
+    storage.set_failed_attempts_total(storage.get_failed_attempts_total().unwrap_or_default().saturating_add(1))?;
+    storage.set_last_failed_attempt_timestamp(Some(now))?;
+    warn!("Failed unlock attempt {}.", attempts);
+    Ok(())
+}
+
+/// Checks the master key again for an operation that asks for it, such as a
+/// decrypted export, enforcing the lockout and counting a wrong key as a failed
+/// attempt. Returns a crypto unlocked with the vault key.
+pub fn verify_master_key(storage: &dyn Storage, master_key: &str, now: DateTime<Utc>) -> Result<Crypto> {
+    ensure_not_locked_out(storage, now)?;
+
+    let salt = storage.get_salt()?;
+    let strength = storage.get_key_derivation_strength()?;
+    let verification_token = storage.get_verification_token()?;
+
+    let mut crypto = Crypto::new();
+    let key = Zeroizing::new(crypto.derive_key(master_key, &salt, strength)?);
+    crypto.unlock(&key)?;
+    if crypto.decrypt(&verification_token).is_err() {
+        record_failed_attempt(storage, now)?;
+        return Err(Error::InvalidMasterKey);
+    }
+    Ok(crypto)
+}
+
+/// Unlocks `crypto` with the master key, enforcing the lockout. The KDF
+/// strength comes from the salt, so a wrong key costs one derivation. Once
+/// unlocked, the authenticated settings and the attempt counters are checked
+/// for tampering.
+pub fn unlock(storage: &dyn Storage, crypto: &mut Crypto, master_key: &str, now: DateTime<Utc>) -> Result<UnlockReport> {
+    ensure_not_locked_out(storage, now)?;
+
+    let salt = storage.get_salt()?;
+    let verification_token = storage.get_verification_token()?;
+    let strength = storage.get_key_derivation_strength()?;
+
+    let key = match crypto.derive_key(master_key, &salt, strength) {
+        Ok(key) => Zeroizing::new(key),
+        Err(e) => {
+            crypto.lock();
+            return Err(e);
+        }
+    };
+    crypto.unlock(&key)?;
+    if crypto.decrypt(&verification_token).is_err() {
+        crypto.lock();
+        record_failed_attempt(storage, now)?;
+        return Err(Error::InvalidMasterKey);
+    }
+
+    let report = check_settings(storage, crypto, strength);
+    if report.is_err() {
+        crypto.lock();
+    }
+    report
+}
+
+/// Resets authenticated settings that fail their MAC, and the attempt counters,
+/// then runs the migrations that were waiting for the vault key.
+fn check_settings(storage: &dyn Storage, crypto: &Crypto, strength: KeyDerivationStrength) -> Result<UnlockReport> {
+    let mut tampered_settings = storage.tampered_meta_keys(crypto)?;
+
+    let total = storage.get_failed_attempts_total().unwrap_or_default();
+    let total_at_unlock = if tampered_settings.iter().any(|key| key == "failed_attempts_total_at_unlock") {
+        total
+    } else {
+        storage.get_failed_attempts_total_at_unlock()?
+    };
+    if total < total_at_unlock {
+        tampered_settings.push("failed_attempts_total".to_string());
+    }
+
+    for key in &tampered_settings {
+        warn!("Vault setting '{}' was changed outside the app; resetting it.", key);
+        match key.as_str() {
+            "kdf_strength" => storage.set_key_derivation_strength(strength)?,
+            "brute_force_config" => storage.set_brute_force_config(BruteForceConfig::default())?,
+            "backup_schedule" => storage.set_backup_schedule(&Default::default())?,
+            _ => {}
+        }
+    }
+    storage.set_failed_login_attempts(0)?;
+    storage.set_last_failed_attempt_timestamp(None)?;
+    storage.set_failed_attempts_total_at_unlock(total)?;
+    storage.seal_meta(crypto)?;
+    storage.migrate(Some(crypto))?;
+
+    info!("Vault unlocked with strength {:?}.", strength);
+    Ok(UnlockReport { failed_attempts: total.saturating_sub(total_at_unlock), tampered_settings, vault_state: None })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    #[test]
+    fn unlocks_with_the_strength_recorded_in_the_salt() {
+        let storage = MemoryStorage::new();
+        let mut crypto = Crypto::new();
+        crate::vault::initialize(&storage, &mut crypto, "correct horse", KeyDerivationStrength::Fast).unwrap();
+        assert_eq!(KeyDerivationStrength::from_salt(&storage.get_salt().unwrap()), Some(KeyDerivationStrength::Fast));
+
+        storage.set_meta_value("kdf_strength", "Paranoid").unwrap();
+        assert_eq!(storage.get_key_derivation_strength().unwrap(), KeyDerivationStrength::Fast);
+
+        crypto.lock();
+        assert!(matches!(unlock(&storage, &mut crypto, "wrong", Utc::now()), Err(Error::InvalidMasterKey)));
+        assert!(!crypto.is_unlocked());
+
+        let report = unlock(&storage, &mut crypto, "correct horse", Utc::now()).unwrap();
+        assert_eq!(report.tampered_settings, vec!["kdf_strength".to_string()]);
+        assert_eq!(report.failed_attempts, 1);
+        assert_eq!(storage.get_meta_value("kdf_strength").unwrap().as_deref(), Some("Fast"));
+    }
+}
@@ -0,0 +1,550 @@
+//! Vault operations shared by the desktop app and `fetch-cli`. Each one takes
+//! the storage and the vault's `Crypto`; the callers decide how they are held
+//! and how the master key is obtained.
+
+use crate::backup;
+use crate::certificate;
+use crate::crypto::{Crypto, KeyDerivationStrength};
+use crate::error::Error;
+use crate::login::LoginFields;
+use crate::storage::{ItemMetadata, Storage, VaultItem};
+use crate::tamper::{self, VaultStateCheck, VaultStateStatus};
+use crate::unlock::{self, UnlockReport};
+use crate::Result;
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use chrono::{DateTime, Utc};
+use log::{debug, error, info, warn};
+use std::collections::HashMap;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use uuid::Uuid;
+use zeroize::Zeroizing;
+
+/// Separates folder names in an item path such as `Work/Servers/db01`.
+pub const PATH_SEPARATOR: char = '/';
+
+/// Sets up an empty vault protected by `master_key` and leaves `crypto` unlocked.
+pub fn initialize(storage: &dyn Storage, crypto: &mut Crypto, master_key: &str, strength: KeyDerivationStrength) -> Result<()> {
+    if storage.is_initialized() {
+        error!("Attempted to initialize an already initialized vault.");
+        return Err(Error::VaultAlreadyInitialized);
+    }
+
+    info!("Generating salt and deriving key with strength: {:?}", strength);
+    let salt = Crypto::generate_salt(strength);
+    let derived_key = crypto.derive_key(master_key, &salt, strength)?;
+
+    info!("Storing salt and strength.");
+    storage.initialize(&salt, strength)?;
+
+    info!("Unlocking crypto with new key.");
+    crypto.unlock(&derived_key)?;
+    // Without a verification token the vault does not count as initialized
+    // yet, so the migrations that need the key run without backing up an
+    // empty vault first.
+    storage.migrate(Some(crypto))?;
+
+    info!("Creating and storing verification token.");
+    let verification_data = Crypto::generate_verification_token();
+    let encrypted_token = crypto.encrypt(&verification_data)?;
+    storage.store_verification_token(&encrypted_token)?;
+    storage.seal_meta(crypto)?;
+    tamper::seal_vault_state(storage, crypto)
+}
+
+/// Unlocks `crypto` with the master key and checks the vault against its seal.
+pub fn unlock(storage: &dyn Storage, crypto: &mut Crypto, master_key: &str, now: DateTime<Utc>) -> Result<UnlockReport> {
+    let mut report = unlock::unlock(storage, crypto, master_key, now)?;
+    report.vault_state = check_unlocked_vault_state(storage, crypto);
+    Ok(report)
+}
+
+/// Re-encrypts the vault under `new_key` once `current_key` is verified, and
+/// leaves `crypto` unlocked with the new key. `strength` defaults to the
+/// current one.
+///
+/// Content is written to new files and the items switch to them in one
+/// transaction, so a failure up to that point leaves the vault as it was. The
+/// old files are shredded only once nothing refers to them.
+pub fn change_master_key(
+    storage: &dyn Storage,
+    crypto: &mut Crypto,
+    current_key: &str,
+    new_key: &str,
+    strength: Option<KeyDerivationStrength>,
+    now: DateTime<Utc>,
+) -> Result<()> {
+    let old_crypto = unlock::verify_master_key(storage, current_key, now)?;
+    info!("Current master key verified.");
+    let strength = match strength {
+        Some(strength) => strength,
+        None => storage.get_key_derivation_strength()?,
+    };
+    let verification_token = Zeroizing::new(old_crypto.decrypt(&storage.get_verification_token()?)?);
+    backup::backup_before_operation(storage, "changing the master key")?;
+
+    let new_salt = Crypto::generate_salt(strength);
+    let mut new_crypto = Crypto::new();
+    new_crypto.unlock(&old_crypto.derive_key(new_key, &new_salt, strength)?)?;
+
+    let mut items = storage.get_all_items_recursive(&old_crypto)?;
+    info!("Re-encrypting {} items with the new master key...", items.len());
+    let mut new_files = Vec::new();
+    let mut old_files = Vec::new();
+    let reencrypted = items
+        .iter_mut()
+        .filter(|item| !item.data_path.is_empty())
+        .try_for_each(|item| {
+            let content = Zeroizing::new(storage.read_encrypted_file(&item.data_path, &old_crypto)?);
+            let data_path = Uuid::new_v4().to_string();
+            storage.write_encrypted_file(&new_crypto.encrypt(&content)?, &data_path)?;
+            new_files.push(data_path.clone());
+            old_files.push(std::mem::replace(&mut item.data_path, data_path));
+            Ok(())
+        })
+        .and_then(|()| storage.update_items_atomically(&items, &new_crypto));
+    if let Err(e) = reencrypted {
+        storage.shred_data_files(&new_files);
+        return Err(e);
+    }
+
+    // The items are under the new key from here on. A failure before the salt
+    // is stored leaves a vault that only the backup above can restore.
+    storage.reencrypt_password_history(&old_crypto, &new_crypto)?;
+    storage.store_verification_token(&new_crypto.encrypt(&verification_token)?)?;
+    storage.update_salt(&new_salt)?;
+    storage.set_key_derivation_strength(strength)?;
+    storage.shred_data_files(&old_files);
+
+    *crypto = new_crypto;
+    storage.seal_meta(crypto)?;
+    tamper::seal_vault_state(storage, crypto)
+}
+
+/// Checks the vault against its seal after unlocking, sealing vaults that have
+/// never been sealed. A failed check is logged but does not keep the vault locked.
+fn check_unlocked_vault_state(storage: &dyn Storage, crypto: &Crypto) -> Option<VaultStateCheck> {
+    let check = tamper::check_vault_state(storage, crypto).and_then(|check| {
+        if check.status == VaultStateStatus::Unsealed {
+            tamper::seal_vault_state(storage, crypto)?;
+        }
+        Ok(check)
+    });
+    match check {
+        Ok(check) => Some(check),
+        Err(e) => {
+            error!("Failed to check vault state: {}", e);
+            None
+        }
+    }
+}
+
+/// Seals the vault's new state and counts the write towards the next automatic
+/// backup. Failures are logged rather than failing the write that triggered them.
+pub fn record_write(storage: &dyn Storage, crypto: &Crypto) {
+    if let Err(e) = tamper::seal_vault_state(storage, crypto) {
+        error!("Failed to seal vault state: {}", e);
+    }
+    if let Err(e) = backup::record_write(storage) {
+        error!("Failed to take automatic backup: {}", e);
+    }
+}
+
+/// Stores text content as a new item. An `item_type` of `text` is stored as `text/plain`.
+pub fn add_text_item(
+    storage: &dyn Storage,
+    crypto: &Crypto,
+    name: String,
+    item_type: String,
+    content: &[u8],
+    tags: Vec<String>,
+    parent_id: Option<String>,
+) -> Result<VaultItem> {
+    if name.trim().is_empty() || content.is_empty() {
+        warn!("Attempted to add text item with empty name or content.");
+        return Err(Error::InvalidInput("Item name and content cannot be empty".into()));
+    }
+
+    let now = Utc::now();
+    let item = VaultItem {
+        id: Uuid::new_v4().to_string(),
+        parent_id,
+        name,
+        data_path: Uuid::new_v4().to_string(),
+        item_type: if item_type == "text" { "text/plain".to_string() } else { item_type },
+        folder_type: None,
+        tags,
+        created_at: now,
+        updated_at: now,
+        expires_at: None,
+        metadata: None,
+    };
+    store_item(storage, crypto, item, content)
+}
+
+/// Stores a copy of a file as a new item, typed by its extension. Certificate
+/// files are parsed so their expiry and details are shown; `passphrase` opens
+/// encrypted PKCS#12 bundles.
+pub fn add_file_item(
+    storage: &dyn Storage,
+    crypto: &Crypto,
+    name: String,
+    file_path: &Path,
+    passphrase: Option<&str>,
+    tags: Vec<String>,
+    parent_id: Option<String>,
+) -> Result<VaultItem> {
+    let file_content = Zeroizing::new(fs::read(file_path)?);
+    let mime_type = mime_guess::from_path(file_path).first_or_octet_stream().to_string();
+
+    let certificate_metadata = if certificate::is_certificate_file(file_path) {
+        match certificate::parse(&file_content, passphrase) {
+            Ok(metadata) if !metadata.is_empty() => Some(metadata),
+            Ok(_) => None,
+            Err(e) => {
+                warn!("Could not parse certificate file {}: {}", file_path.display(), e);
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    let now = Utc::now();
+    let item = VaultItem {
+        id: Uuid::new_v4().to_string(),
+        parent_id,
+        name,
+        data_path: Uuid::new_v4().to_string(),
+        item_type: mime_type,
+        folder_type: None,
+        tags,
+        created_at: now,
+        updated_at: now,
+        expires_at: certificate_metadata.as_ref().and_then(|metadata| metadata.expires_at()),
+        metadata: certificate_metadata.map(ItemMetadata::X509),
+    };
+    store_item(storage, crypto, item, &file_content)
+}
+
+fn store_item(storage: &dyn Storage, crypto: &Crypto, item: VaultItem, content: &[u8]) -> Result<VaultItem> {
+    let encrypted_content = crypto.encrypt(content)?;
+    debug!("Encrypted content size for item: {} bytes", encrypted_content.len());
+    debug!("Attempting to write encrypted content to data file {}", item.data_path);
+
+    storage.write_encrypted_file(&encrypted_content, &item.data_path)?;
+    debug!("Successfully wrote encrypted file for item ID: {}", item.id);
+    storage.add_item(&item, crypto)?;
+
+    info!("Item '{}' added successfully.", item.name);
+    record_write(storage, crypto);
+    Ok(item)
+}
+
+pub fn add_folder(storage: &dyn Storage, crypto: &Crypto, name: String, folder_type: Option<String>, parent_id: Option<String>) -> Result<VaultItem> {
+    if name.trim().is_empty() {
+        warn!("Attempted to add folder with empty name.");
+        return Err(Error::InvalidInput("Folder name cannot be empty".into()));
+    }
+
+    let now = Utc::now();
+    let item = VaultItem {
+        id: Uuid::new_v4().to_string(),
+        parent_id,
+        name,
+        data_path: String::new(),
+        item_type: "folder".to_string(),
+        folder_type,
+        tags: vec![],
+        created_at: now,
+        updated_at: now,
+        expires_at: None,
+        metadata: None,
+    };
+    storage.add_item(&item, crypto)?;
+
+    info!("Folder '{}' added successfully.", item.name);
+    record_write(storage, crypto);
+    Ok(item)
+}
+
+pub fn item_content(storage: &dyn Storage, crypto: &Crypto, id: &str) -> Result<Vec<u8>> {
+    let item = storage.get_item(id, crypto)?.ok_or_else(|| Error::ItemNotFound(id.to_string()))?;
+    if item.data_path.is_empty() {
+        return Err(Error::InvalidInput(format!("'{}' is a folder and has no content", item.name)));
+    }
+    storage.read_encrypted_file(&item.data_path, crypto)
+}
+
+/// One field of an item's content. Text items are read as `Label: value`
+/// lines (see [`LoginFields`]); the password of a `key` item is its whole content.
+pub fn item_field(storage: &dyn Storage, crypto: &Crypto, item: &VaultItem, field: &str) -> Result<Zeroizing<String>> {
+    let content = Zeroizing::new(String::from_utf8_lossy(&item_content(storage, crypto, &item.id)?).into_owned());
+    if item.item_type == "key" && field.eq_ignore_ascii_case("password") {
+        return Ok(Zeroizing::new(content.trim().to_string()));
+    }
+    if !item.item_type.starts_with("text/") {
+        return Err(Error::InvalidInput(format!("'{}' is not a text item and has no fields", item.name)));
+    }
+    LoginFields::parse(&content)
+        .get(field)
+        .map(|value| Zeroizing::new(value.to_string()))
+        .ok_or_else(|| Error::InvalidInput(format!("'{}' has no field '{}'", item.name, field)))
+}
+
+/// Deletes an item, and everything inside it when it is a folder.
+pub fn delete_item(storage: &dyn Storage, crypto: &Crypto, id: &str) -> Result<()> {
+    info!("Recursively deleting item with id: {}", id);
+    storage.delete_item_and_descendants(id, crypto)?;
+    record_write(storage, crypto);
+    Ok(())
+}
+
+/// Moves an item into `parent_id` (the top level when `None`), renaming it
+/// when `name` is given. A folder cannot be moved into itself or its descendants.
+pub fn move_item(storage: &dyn Storage, crypto: &Crypto, id: &str, parent_id: Option<&str>, name: Option<String>) -> Result<VaultItem> {
+    let mut item = storage.get_item(id, crypto)?.ok_or_else(|| Error::ItemNotFound(id.to_string()))?;
+    info!("Moving item '{}' into {:?}", item.name, parent_id);
+
+    let mut ancestor = parent_id.map(str::to_string);
+    while let Some(ancestor_id) = ancestor {
+        if ancestor_id == item.id {
+            return Err(Error::InvalidInput(format!("Cannot move '{}' into itself", item.name)));
+        }
+        let folder = storage.get_item(&ancestor_id, crypto)?.ok_or_else(|| Error::ItemNotFound(ancestor_id.clone()))?;
+        if folder.item_type != "folder" {
+            return Err(Error::InvalidInput(format!("'{}' is not a folder", folder.name)));
+        }
+        ancestor = folder.parent_id;
+    }
+
+    storage.set_item_parent(&item.id, parent_id)?;
+    item.parent_id = parent_id.map(str::to_string);
+    if let Some(name) = name.filter(|name| *name != item.name) {
+        if name.trim().is_empty() {
+            return Err(Error::InvalidInput("Item name cannot be empty".into()));
+        }
+        item.name = name;
+        item.updated_at = Utc::now();
+        storage.update_item_fields(&item, crypto)?;
+    }
+    record_write(storage, crypto);
+    Ok(item)
+}
+
+/// Adds and removes tags on one item, keeping the remaining tags in order.
+pub fn update_tags(storage: &dyn Storage, crypto: &Crypto, id: &str, add: &[String], remove: &[String]) -> Result<VaultItem> {
+    let mut item = storage.get_item(id, crypto)?.ok_or_else(|| Error::ItemNotFound(id.to_string()))?;
+
+    let mut tags: Vec<String> = item.tags.iter().filter(|tag| !remove.contains(tag)).cloned().collect();
+    for tag in add.iter().map(|tag| tag.trim()) {
+        if tag.is_empty() {
+            return Err(Error::InvalidInput("Tag names cannot be empty".into()));
+        }
+        if !tags.iter().any(|existing| existing == tag) {
+            tags.push(tag.to_string());
+        }
+    }
+    if tags == item.tags {
+        return Ok(item);
+    }
+
+    item.tags = tags;
+    item.updated_at = Utc::now();
+    storage.update_item_fields(&item, crypto)?;
+    record_write(storage, crypto);
+    Ok(item)
+}
+
+/// Every tag in use, sorted and without duplicates.
+pub fn all_tags(storage: &dyn Storage, crypto: &Crypto) -> Result<Vec<String>> {
+    let mut tags: Vec<String> = storage.get_all_items_recursive(crypto)?
+        .into_iter()
+        .flat_map(|item| item.tags)
+        .collect();
+    tags.sort_unstable();
+    tags.dedup();
+    Ok(tags)
+}
+
+/// The whole vault as pretty-printed JSON: every item with its content base64-encoded.
+pub fn decrypted_export(storage: &dyn Storage, crypto: &Crypto) -> Result<String> {
+    let mut decrypted_items = Vec::new();
+    for item in storage.get_all_items_recursive(crypto)? {
+        if item.data_path.is_empty() {
+            decrypted_items.push(serde_json::to_value(item)?);
+        } else {
+            let content = storage.read_encrypted_file(&item.data_path, crypto)?;
+            let mut decrypted_item = serde_json::to_value(item)?;
+            decrypted_item["content"] = serde_json::Value::String(STANDARD.encode(&content));
+            decrypted_items.push(decrypted_item);
+        }
+    }
+    Ok(serde_json::to_string_pretty(&decrypted_items)?)
+}
+
+/// Finds an item by id or by its path of names from the top level, such as
+/// `Work/Servers/db01`. A bare name that is not at the top level may also
+/// match a single item anywhere in the vault. A reference matching more than
+/// one item is an error, since names are not unique.
+pub fn find_item(storage: &dyn Storage, crypto: &Crypto, reference: &str) -> Result<VaultItem> {
+    if let Some(item) = storage.get_item(reference, crypto)? {
+        return Ok(item);
+    }
+    match find_item_by_path(storage, crypto, reference) {
+        Err(Error::ItemNotFound(_)) if !reference.contains(PATH_SEPARATOR) => {
+            let matches: Vec<VaultItem> = storage.get_all_items_recursive(crypto)?
+                .into_iter()
+                .filter(|item| item.name == reference)
+                .collect();
+            single_match(reference, matches)
+        }
+        result => result,
+    }
+}
+
+fn find_item_by_path(storage: &dyn Storage, crypto: &Crypto, reference: &str) -> Result<VaultItem> {
+
+    let mut parent_id = None;
+    let mut found = None;
+    for name in reference.split(PATH_SEPARATOR).filter(|name| !name.is_empty()) {
+        let matches: Vec<VaultItem> = storage.get_items(parent_id.take(), None, None, crypto)?
+            .into_iter()
+            .filter(|item| item.name == name)
+            .collect();
+        let item = single_match(reference, matches)?;
+        parent_id = Some(item.id.clone());
+        found = Some(item);
+    }
+    found.ok_or_else(|| Error::ItemNotFound(reference.to_string()))
+}
+
+fn single_match(reference: &str, mut matches: Vec<VaultItem>) -> Result<VaultItem> {
+    match matches.len() {
+        0 => Err(Error::ItemNotFound(reference.to_string())),
+        1 => Ok(matches.remove(0)),
+        count => Err(Error::InvalidInput(format!(
+            "'{}' matches {} items. Refer to the item by its id instead.",
+            reference, count
+        ))),
+    }
+}
+
+/// The path of every item in `items`, by id. Items whose parent is not in
+/// `items` are placed at the top level.
+pub fn item_paths(items: &[VaultItem]) -> HashMap<String, String> {
+    let by_id: HashMap<&str, &VaultItem> = items.iter().map(|item| (item.id.as_str(), item)).collect();
+
+    items
+        .iter()
+        .map(|item| {
+            let mut names = vec![item.name.as_str()];
+            let mut parent_id = item.parent_id.as_deref();
+            while let Some(parent) = parent_id.and_then(|id| by_id.get(id)) {
+                // Guards against parent cycles, which integrity checks report separately.
+                if names.len() > items.len() {
+                    break;
+                }
+                names.push(parent.name.as_str());
+                parent_id = parent.parent_id.as_deref();
+            }
+            names.reverse();
+            (item.id.clone(), names.join(&PATH_SEPARATOR.to_string()))
+        })
+        .collect()
+}
+
+/// Writes decrypted data to a file only the current user can read. An existing
+/// file is truncated and has its permissions narrowed.
+pub fn write_private_file(path: &Path, contents: &[u8]) -> Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    let mut file = options.open(path)?;
+    #[cfg(unix)]
+    file.set_permissions(std::os::unix::fs::PermissionsExt::from_mode(0o600))?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{MemoryStorage, PasswordHistoryEntry};
+
+    #[test]
+    fn initializes_and_unlocks_with_the_master_key() {
+        let storage = MemoryStorage::new();
+        let mut crypto = Crypto::new();
+        initialize(&storage, &mut crypto, "correct horse", KeyDerivationStrength::Fast).unwrap();
+        assert!(crypto.is_unlocked());
+        assert!(storage.is_initialized());
+        assert!(matches!(initialize(&storage, &mut Crypto::new(), "again", KeyDerivationStrength::Fast), Err(Error::VaultAlreadyInitialized)));
+
+        let item = add_text_item(&storage, &crypto, "Note".to_string(), "text".to_string(), b"hello", Vec::new(), None).unwrap();
+
+        let mut crypto = Crypto::new();
+        let report = unlock(&storage, &mut crypto, "correct horse", Utc::now()).unwrap();
+        assert!(crypto.is_unlocked());
+        assert_eq!(report.failed_attempts, 0);
+        assert!(report.tampered_settings.is_empty());
+        assert_eq!(report.vault_state.unwrap().status, VaultStateStatus::Intact);
+        assert_eq!(item_content(&storage, &crypto, &item.id).unwrap(), b"hello");
+    }
+
+    #[test]
+    fn counts_wrong_keys_towards_the_lockout() {
+        let storage = MemoryStorage::new();
+        initialize(&storage, &mut Crypto::new(), "correct horse", KeyDerivationStrength::Fast).unwrap();
+        let max_attempts = storage.get_brute_force_config().unwrap().max_attempts;
+        storage.set_failed_login_attempts(max_attempts - 1).unwrap();
+        let now = Utc::now();
+
+        let mut crypto = Crypto::new();
+        assert!(matches!(unlock(&storage, &mut crypto, "wrong", now), Err(Error::InvalidMasterKey)));
+        assert!(!crypto.is_unlocked());
+        assert_eq!(storage.get_failed_login_attempts().unwrap(), max_attempts);
+        assert_eq!(storage.get_last_failed_attempt_timestamp().unwrap(), Some(now));
+
+        assert!(matches!(unlock(&storage, &mut crypto, "correct horse", now), Err(Error::TooManyAttempts(_))));
+        assert!(!crypto.is_unlocked());
+    }
+
+    #[test]
+    fn changes_the_master_key_and_keeps_content_and_history() {
+        let storage = MemoryStorage::new();
+        let mut crypto = Crypto::new();
+        initialize(&storage, &mut crypto, "correct horse", KeyDerivationStrength::Fast).unwrap();
+        let item = add_text_item(&storage, &crypto, "Note".to_string(), "text".to_string(), b"hello", Vec::new(), None).unwrap();
+        let folder = add_folder(&storage, &crypto, "Work".to_string(), None, None).unwrap();
+        let entry = PasswordHistoryEntry { id: Uuid::new_v4().to_string(), item_id: item.id.clone(), password: "old".to_string(), changed_at: Utc::now() };
+        storage.add_password_history_entry(&entry, &crypto).unwrap();
+
+        assert!(matches!(
+            change_master_key(&storage, &mut crypto, "wrong", "battery staple", None, Utc::now()),
+            Err(Error::InvalidMasterKey)
+        ));
+        storage.set_failed_login_attempts(0).unwrap();
+        change_master_key(&storage, &mut crypto, "correct horse", "battery staple", Some(KeyDerivationStrength::Recommended), Utc::now()).unwrap();
+        assert_eq!(item_content(&storage, &crypto, &item.id).unwrap(), b"hello");
+
+        let mut crypto = Crypto::new();
+        assert!(matches!(unlock(&storage, &mut crypto, "correct horse", Utc::now()), Err(Error::InvalidMasterKey)));
+        storage.set_failed_login_attempts(0).unwrap();
+        let report = unlock(&storage, &mut crypto, "battery staple", Utc::now()).unwrap();
+        assert!(report.tampered_settings.is_empty());
+        assert_eq!(report.vault_state.unwrap().status, VaultStateStatus::Intact);
+        assert_eq!(storage.get_key_derivation_strength().unwrap(), KeyDerivationStrength::Recommended);
+
+        let moved = storage.get_item(&item.id, &crypto).unwrap().unwrap();
+        assert_ne!(moved.data_path, item.data_path);
+        assert_eq!(storage.data_file_names().unwrap(), vec![moved.data_path]);
+        assert_eq!(item_content(&storage, &crypto, &item.id).unwrap(), b"hello");
+        assert_eq!(storage.get_item(&folder.id, &crypto).unwrap().unwrap().name, "Work");
+        assert_eq!(storage.get_password_history(&item.id, &crypto).unwrap()[0].password, "old");
+    }
+}